@@ -5,3 +5,4 @@ pub mod interrupts;
 pub mod memory;
 pub mod pci;
 pub mod pit;
+pub mod random;
@@ -55,7 +55,7 @@ pub fn get_secs() -> u64 {
 
 /// Converts tick into miliseconds since boot
 pub fn get_milis() -> u64 {
-    unsafe { TICK.load(Ordering::SeqCst) / TICK_FREQ * 1000 }
+    unsafe { TICK.load(Ordering::SeqCst) * 1000 / TICK_FREQ }
 }
 
 /// return ticks
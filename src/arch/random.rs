@@ -0,0 +1,79 @@
+//! Randomness for things like cryptographic keys and nonces.
+use crate::prelude::*;
+use core::arch::x86_64::_rdtsc;
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+/// Number of timestamp samples mixed into the pool when we have to fall back to it.
+const JITTER_SAMPLES: usize = 256;
+
+/// Fallback generator for cpus without `rdrand`, a hash of timing jitter in counter mode.
+struct Pool {
+    seed: [u8; 32],
+    counter: u64,
+}
+
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+/// Function fills `buf` with random bytes. `rdrand` is used if the cpu has it, otherwise the
+/// bytes come from timing jitter, which is a lot weaker.
+pub fn fill(buf: &mut [u8]) {
+    if let Some(rng) = RdRand::new() {
+        let mut filled = 0;
+
+        for chunk in buf.chunks_mut(8) {
+            // rdrand can fail transiently, intel recommends retrying ten times.
+            match (0..10).find_map(|_| rng.get_u64()) {
+                Some(x) => {
+                    chunk.copy_from_slice(&x.to_ne_bytes()[..chunk.len()]);
+                    filled += chunk.len();
+                }
+                None => break,
+            }
+        }
+
+        if filled == buf.len() {
+            return;
+        }
+    }
+
+    fill_jitter(buf);
+}
+
+/// Returns a random `u64`.
+pub fn u64() -> u64 {
+    let mut buf = [0; 8];
+    fill(&mut buf);
+    u64::from_ne_bytes(buf)
+}
+
+fn fill_jitter(buf: &mut [u8]) {
+    let mut pool = POOL.lock();
+    let pool = pool.get_or_insert_with(|| {
+        println!("random: rdrand unavailable, falling back to timing jitter");
+        Pool {
+            seed: [0; 32],
+            counter: 0,
+        }
+    });
+
+    // every request stirs in fresh samples, so a leaked state doesnt give away the next one.
+    let mut hasher = Sha256::new();
+    hasher.update(&pool.seed);
+    for _ in 0..JITTER_SAMPLES {
+        let start = unsafe { _rdtsc() };
+        hasher.update(&start.to_le_bytes());
+        hasher.update(&unsafe { _rdtsc() }.wrapping_sub(start).to_le_bytes());
+    }
+    pool.seed.copy_from_slice(&hasher.finalize());
+
+    for chunk in buf.chunks_mut(32) {
+        pool.counter += 1;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&pool.seed);
+        hasher.update(&pool.counter.to_le_bytes());
+        chunk.copy_from_slice(&hasher.finalize()[..chunk.len()]);
+    }
+}
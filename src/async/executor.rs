@@ -2,6 +2,8 @@ use super::{Task, TaskId};
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use x86_64::instructions::interrupts::{self, enable_and_hlt};
@@ -80,6 +82,42 @@ impl Executor {
             }
         }
     }
+
+    /// Function runs the executor until `future` completes and returns its output, tasks spawned
+    /// in the meantime are run as well. Mostly useful for tests, which cant run forever.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        let woken = Arc::new(FlagWaker(AtomicBool::new(true)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        futures_util::pin_mut!(future);
+
+        loop {
+            if woken.0.swap(false, Ordering::AcqRel) {
+                if let Poll::Ready(x) = future.as_mut().poll(&mut cx) {
+                    return x;
+                }
+            }
+
+            self.merge_spawn_queue();
+            self.run_ready_tasks();
+
+            interrupts::disable();
+            if self.task_queue.is_empty() && !woken.0.load(Ordering::Acquire) {
+                enable_and_hlt();
+            } else {
+                interrupts::enable();
+            }
+        }
+    }
+}
+
+/// Waker of the future passed to `block_on`, which isnt a task of its own.
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
 }
 
 struct TaskWaker {
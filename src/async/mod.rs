@@ -22,9 +22,14 @@ use super::prelude::*;
 
 lazy_static::lazy_static! {
     pub static ref SPAWN_QUEUE: Arc<SegQueue<Task>> = Arc::new(SegQueue::new());
-    static ref TIMER_QUEUE: Arc<Mutex<BTreeMap<Duration, Waker>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    /// Wakers of sleeping futures, keyed by their deadline and a sequence number so that
+    /// futures sleeping until the same moment dont replace each other.
+    static ref TIMER_QUEUE: Arc<Mutex<BTreeMap<(Duration, u64), Waker>>> = Arc::new(Mutex::new(BTreeMap::new()));
 }
 
+/// Sequence number of the next timer.
+static TIMER_SEQ: AtomicU64 = AtomicU64::new(0);
+
 pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
     SPAWN_QUEUE.push(Task::new(future));
 }
@@ -64,8 +69,8 @@ pub(crate) fn wake_tasks() {
 
     loop {
         if let Some((k, v)) = lock.pop_first() {
-            if k.as_millis() > current_milis {
-                super::arch::pit::notify_in(k.as_millis());
+            if k.0.as_millis() > current_milis {
+                super::arch::pit::notify_in(k.0.as_millis());
                 lock.insert(k, v);
                 return;
             }
@@ -83,7 +88,7 @@ fn push_timer(when: Duration, waker: Waker) {
     without_interrupts(move || {
         {
             let mut lock = TIMER_QUEUE.lock();
-            lock.insert(when, waker);
+            lock.insert((when, TIMER_SEQ.fetch_add(1, Ordering::Relaxed)), waker);
         }
         wake_tasks();
     });
@@ -91,12 +96,15 @@ fn push_timer(when: Duration, waker: Waker) {
 
 pub struct Sleep {
     yield_at: Duration,
+    /// Waker we last pushed a timer for, polling again with the same one doesnt push another.
+    registered: Option<Waker>,
 }
 
 impl Sleep {
     pub fn new(period: Duration) -> Self {
         Self {
             yield_at: Duration::from_millis(get_milis()) + period,
+            registered: None,
         }
     }
 }
@@ -104,15 +112,46 @@ impl Sleep {
 impl Future for Sleep {
     type Output = ();
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let current_milis = get_milis();
 
         if current_milis >= self.yield_at.as_millis() as u64 {
             return Poll::Ready(());
         }
 
-        push_timer(self.yield_at.clone(), cx.waker().clone());
+        let registered = self.registered.as_ref();
+        if !registered.map_or(false, |x| x.will_wake(cx.waker())) {
+            self.registered = Some(cx.waker().clone());
+            push_timer(self.yield_at.clone(), cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Function lets every other task that is ready run before continuing.
+pub async fn yield_now() {
+    let mut yielded = false;
+
+    futures_util::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
         Poll::Pending
+    })
+    .await
+}
+
+/// Function runs `future` for at most `period`, returns `None` if it didnt finish in time.
+pub async fn timeout<F: Future>(period: Duration, future: F) -> Option<F::Output> {
+    futures_util::pin_mut!(future);
+
+    match futures_util::future::select(future, Sleep::new(period)).await {
+        futures_util::future::Either::Left((x, _)) => Some(x),
+        futures_util::future::Either::Right(_) => None,
     }
 }
 
@@ -8,12 +8,20 @@ use crate::net::wire::eth2::Ether2Frame;
 use crate::net::wire::mac::Mac;
 use crate::prelude::*;
 
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
 use futures_util::sink::Sink;
 use futures_util::stream::Stream;
 
 pub mod keyboard;
+pub mod loopback;
+pub mod pcap;
 pub mod rtl8139;
 pub mod serial;
+pub mod testing;
 pub mod vga;
 
 /// Trait marks a barebones implementation of a driver.
@@ -29,8 +37,9 @@ pub trait Driver {
     fn init(&mut self) -> Self::Return;
 }
 
-/// Trait marks a network driver.
-pub trait NetworkDriver: Driver + Send {
+/// Trait marks a network driver. Network drivers dont have to be backed by hardware, as such this
+/// trait doesnt require `Driver`.
+pub trait NetworkDriver: Send {
     /// Stream from where we can acquire ether2 frames.
     type RxSink: Stream<Item = Vec<u8>> + Send + Unpin;
     /// Stream over which we can send packets.
@@ -40,4 +49,79 @@ pub trait NetworkDriver: Driver + Send {
     fn parts(&mut self) -> (Self::RxSink, Self::TxSink);
     /// Returns the active mac address of this device.
     fn mac(&self) -> Mac;
+
+    /// Returns the mtu the device starts out with.
+    fn mtu(&self) -> usize {
+        crate::net::DEFAULT_MTU
+    }
+
+    /// Returns the biggest mtu the device supports, drivers that can do jumbo frames should
+    /// override this.
+    fn max_mtu(&self) -> usize {
+        self.mtu()
+    }
+
+    /// Returns whether this is the loopback device, the only one allowed to receive traffic
+    /// from and to 127.0.0.0/8.
+    fn is_loopback(&self) -> bool {
+        false
+    }
+
+    /// Returns the hint the driver watches to switch between interrupts and polling, drivers
+    /// that cant switch dont need one.
+    fn rx_mode_hint(&self) -> Option<RxModeHint> {
+        None
+    }
+
+    /// Returns a stream yielding the state of the link every time it changes. Drivers that cant
+    /// tell return `None`, their link is assumed to always be up.
+    fn link_status(&mut self) -> Option<LinkStatusStream> {
+        None
+    }
+}
+
+/// State of the link of a network device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkState {
+    Up,
+    Down,
+}
+
+/// Stream of link state changes, returned by `NetworkDriver::link_status`.
+pub type LinkStatusStream = Pin<Box<dyn Stream<Item = LinkState> + Send>>;
+
+/// How a network driver learns about received frames.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RxMode {
+    /// The device raises a interrupt for every frame, which suits light traffic.
+    Interrupt,
+    /// The stack keeps draining the driver on its own, so interrupts can stay masked until the
+    /// hint goes back to `Interrupt`.
+    Polling,
+}
+
+/// Shared hint through which `NetworkDevice` tells a driver which `RxMode` suits the current
+/// load. Its only a hint, drivers are free to ignore it.
+#[derive(Clone, Default)]
+pub struct RxModeHint {
+    polling: Arc<AtomicBool>,
+}
+
+impl RxModeHint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mode(&self) -> RxMode {
+        if self.polling.load(Ordering::Relaxed) {
+            RxMode::Polling
+        } else {
+            RxMode::Interrupt
+        }
+    }
+
+    pub fn set_mode(&self, mode: RxMode) {
+        self.polling
+            .store(mode == RxMode::Polling, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,151 @@
+//! Pcap replay network driver.
+//! Feeds the frames of a pcap file, usually embedded with `include_bytes!`, into the network
+//! stack as if they were received by a NIC. Frames sent by the stack are discarded, install a
+//! capture with `net::pcap::start` to record them.
+//! ```rust
+//! let mut phy = PcapReplay::new(include_bytes!("../dump.dat"), mac)
+//!     .expect("invalid pcap")
+//!     .realtime(true);
+//!
+//! let mut netdev = NetworkDevice::new(&mut phy).await;
+//! netdev.set_ip(Ipv4Addr::new(192, 168, 100, 51)).await;
+//! netdev.run_forever().await
+//! ```
+use crate::arch::pit::get_milis;
+use crate::async_::Sleep;
+use crate::driver::NetworkDriver;
+use crate::net::pcap::PcapReader;
+use crate::net::wire::mac::Mac;
+use crate::prelude::*;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+
+/// Network driver replaying a pcap file.
+pub struct PcapReplay {
+    reader: PcapReader<'static>,
+    mac: Mac,
+    realtime: bool,
+}
+
+impl PcapReplay {
+    /// Function creates a new replay driver, returning `Err(())` if `data` isnt a pcap file
+    /// holding Ethernet frames.
+    ///
+    /// # Arguments
+    /// * `data` - The pcap file to replay.
+    /// * `mac` - The mac this device will claim to have.
+    pub fn new(data: &'static [u8], mac: Mac) -> Result<Self, ()> {
+        Ok(Self {
+            reader: PcapReader::new(data)?,
+            mac,
+            realtime: false,
+        })
+    }
+
+    /// Sets whether frames are replayed with the same spacing they were captured with, by
+    /// default they are replayed as fast as the stack accepts them.
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+}
+
+impl NetworkDriver for PcapReplay {
+    type RxSink = RxSink;
+    type TxSink = TxSink;
+
+    fn parts(&mut self) -> (Self::RxSink, Self::TxSink) {
+        let rx = RxSink {
+            reader: self.reader.clone(),
+            realtime: self.realtime,
+            epoch: None,
+            sleep: None,
+        };
+
+        (rx, TxSink)
+    }
+
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+}
+
+/// Receive side of a `PcapReplay`.
+pub struct RxSink {
+    reader: PcapReader<'static>,
+    realtime: bool,
+    /// Timestamp of the first record and the time at which we replayed it.
+    epoch: Option<(Duration, Duration)>,
+    /// Sleep until the next record is due, along with the record itself.
+    sleep: Option<(Sleep, &'static [u8])>,
+}
+
+impl Stream for RxSink {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if this.sleep.is_none() {
+            let record = match this.reader.next() {
+                Some(x) => x,
+                // Once we run out of frames we behave like a idle NIC, returning `None` would
+                // make `run_forever` spin on a finished stream.
+                None => return Poll::Pending,
+            };
+
+            if !this.realtime {
+                return Poll::Ready(Some(record.data.to_vec()));
+            }
+
+            let now = Duration::from_millis(get_milis());
+            let (first_ts, started_at) = *this.epoch.get_or_insert((record.timestamp, now));
+            let due = started_at + record.timestamp.checked_sub(first_ts).unwrap_or_default();
+
+            this.sleep = Some((
+                Sleep::new(due.checked_sub(now).unwrap_or_default()),
+                record.data,
+            ));
+        }
+
+        let (sleep, data) = this.sleep.as_mut().expect("pcap: missing pending record");
+        match Pin::new(sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let data = data.to_vec();
+                this.sleep = None;
+                Poll::Ready(Some(data))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Transmit side of a `PcapReplay`, everything sent here is dropped.
+pub struct TxSink;
+
+impl Sink<Vec<u8>> for TxSink {
+    type Error = ();
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, _: Vec<u8>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
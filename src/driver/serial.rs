@@ -10,6 +10,13 @@ lazy_static! {
         port.init();
         Mutex::new(port)
     };
+    /// Second serial port, kept free of text output so it can carry binary data such as packet
+    /// captures.
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(0x2f8) };
+        port.init();
+        Mutex::new(port)
+    };
 }
 
 pub fn _print(args: ::core::fmt::Arguments) {
@@ -0,0 +1,376 @@
+//! In-memory network driver used to test the network stack without any hardware.
+//!
+//! `PipeNic::pair` returns two ends of a virtual cable, every frame sent on one end shows up on
+//! the other. The link can be made lossy, reorder frames or delay them, all driven by a seeded
+//! prng so that the same seed always produces the same outcome. Every frame that crosses the
+//! link is captured, along with what happened to it.
+//!
+//! Because all the layers of our stack are global, only one end should be handed to a
+//! `NetworkDevice`. The other end is driven by hand with `PipeNic::send` and `PipeNic::recv`:
+//! ```rust
+//! let (mut nic, mut peer) = PipeNic::pair(
+//!     Mac::from([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]),
+//!     Mac::from([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd]),
+//! );
+//!
+//! let mut netdev = NetworkDevice::new(&mut nic).await;
+//! netdev.set_ip(Ipv4Addr::new(10, 0, 0, 1)).await;
+//! spawn(async move { netdev.run_forever().await });
+//!
+//! // the stack announces its new ip.
+//! let garp = Ether2Frame::from_bytes(peer.recv().await.unwrap()).unwrap();
+//! assert_eq!(garp.dtype(), EtherType::ARP);
+//! ```
+use crate::arch::pit::get_milis;
+use crate::async_::Sleep;
+use crate::driver::NetworkDriver;
+use crate::net::wire::mac::Mac;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use alloc::collections::BTreeMap;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::task::Waker;
+use core::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+/// How long (ms) a reordered frame is held back, frames sent in the meantime overtake it.
+const REORDER_HOLD: u64 = 5;
+
+/// Conditions applied to every frame sent over one direction of the link.
+#[derive(Clone, Debug)]
+pub struct LinkConditions {
+    /// Chance, in per mille, of a frame being dropped.
+    pub loss: u16,
+    /// Chance, in per mille, of a frame being held back so that the frames sent right after it
+    /// are delivered first.
+    pub reorder: u16,
+    /// Delay applied to every frame before it gets delivered.
+    pub delay: Duration,
+    /// Seed of the prng that decides which frames get dropped or reordered.
+    pub seed: u64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            loss: 0,
+            reorder: 0,
+            delay: Duration::from_millis(0),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+/// What happened to a captured frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fate {
+    /// Frame was delivered in order.
+    Delivered,
+    /// Frame was held back and delivered after the frames sent right after it.
+    Reordered,
+    /// Frame was dropped.
+    Dropped,
+}
+
+/// A frame that crossed the link.
+#[derive(Clone, Debug)]
+pub struct CapturedFrame {
+    /// Time (ms since boot) at which the frame was sent.
+    pub time: u64,
+    /// Mac of the end that sent the frame.
+    pub from: Mac,
+    /// What happened to the frame.
+    pub fate: Fate,
+    /// The raw frame.
+    pub data: Vec<u8>,
+}
+
+struct WireState {
+    conditions: LinkConditions,
+    /// State of our xorshift prng.
+    rng: u64,
+    /// Number of upcoming frames that will be dropped no matter the conditions.
+    drop_next: usize,
+}
+
+impl WireState {
+    fn new(conditions: LinkConditions) -> Self {
+        Self {
+            rng: conditions.seed.max(1),
+            conditions,
+            drop_next: 0,
+        }
+    }
+
+    /// Returns true with a chance of `per_mille`/1000.
+    fn roll(&mut self, per_mille: u16) -> bool {
+        if per_mille == 0 {
+            return false;
+        }
+
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        self.rng % 1000 < per_mille as u64
+    }
+}
+
+#[derive(Default)]
+struct InboxState {
+    /// Frames on their way, keyed by the time (ms) they arrive and the order they were sent in.
+    frames: BTreeMap<(u64, u64), Vec<u8>>,
+    /// Sequence number of the next frame.
+    seq: u64,
+    /// Task waiting for a frame.
+    waker: Option<Waker>,
+    /// Timer waking the task up once the first frame arrives.
+    timer: Option<(u64, Sleep)>,
+}
+
+/// Frames on their way to one end of the link. Delayed and reordered frames all wait in here,
+/// so their order only depends on when they were sent.
+#[derive(Default)]
+struct Inbox {
+    state: Mutex<InboxState>,
+}
+
+impl Inbox {
+    fn push(&self, arrives_at: u64, frame: Vec<u8>) {
+        let waker = {
+            let mut state = self.state.lock();
+            let seq = state.seq;
+            state.seq += 1;
+            state.frames.insert((arrives_at, seq), frame);
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Vec<u8>> {
+        let mut state = self.state.lock();
+
+        loop {
+            let (arrives_at, seq) = match state.frames.keys().next() {
+                Some(x) => *x,
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            };
+
+            let now = get_milis();
+            if arrives_at <= now {
+                state.timer = None;
+                return Poll::Ready(state.frames.remove(&(arrives_at, seq)).unwrap());
+            }
+
+            // frames sent from now on can still arrive before this one.
+            state.waker = Some(cx.waker().clone());
+
+            if state.timer.as_ref().map_or(true, |x| x.0 != arrives_at) {
+                let sleep = Sleep::new(Duration::from_millis(arrives_at - now));
+                state.timer = Some((arrives_at, sleep));
+            }
+
+            let (_, sleep) = state.timer.as_mut().unwrap();
+            if Pin::new(sleep).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// One direction of the link.
+struct Wire {
+    /// Inbound queue of the receiving end.
+    inbox: Arc<Inbox>,
+    state: Mutex<WireState>,
+    capture: Arc<Mutex<Vec<CapturedFrame>>>,
+}
+
+impl Wire {
+    fn send(&self, from: Mac, frame: Vec<u8>) -> Result<(), ()> {
+        let now = get_milis();
+
+        let (fate, arrives_at) = {
+            let mut state = self.state.lock();
+            let arrives_at = now + state.conditions.delay.as_millis() as u64;
+            let (loss, reorder) = (state.conditions.loss, state.conditions.reorder);
+
+            if state.drop_next > 0 || state.roll(loss) {
+                state.drop_next = state.drop_next.saturating_sub(1);
+                (Fate::Dropped, arrives_at)
+            } else if state.roll(reorder) {
+                (Fate::Reordered, arrives_at + REORDER_HOLD)
+            } else {
+                (Fate::Delivered, arrives_at)
+            }
+        };
+
+        if fate != Fate::Dropped {
+            self.inbox.push(arrives_at, frame.clone());
+        }
+
+        self.capture.lock().push(CapturedFrame {
+            time: now,
+            from,
+            fate,
+            data: frame,
+        });
+
+        Ok(())
+    }
+}
+
+/// One end of a in-memory link.
+pub struct PipeNic {
+    mac: Mac,
+    /// Frames sent to us, taken by `parts`.
+    inbox: Option<Arc<Inbox>>,
+    /// Our outgoing direction.
+    wire: Arc<Wire>,
+    capture: Arc<Mutex<Vec<CapturedFrame>>>,
+}
+
+impl PipeNic {
+    /// Function creates two interconnected ends with the given macs.
+    pub fn pair(a: Mac, b: Mac) -> (Self, Self) {
+        let capture = Arc::new(Mutex::new(Vec::new()));
+
+        let a_inbox = Arc::new(Inbox::default());
+        let b_inbox = Arc::new(Inbox::default());
+
+        let a_to_b = Arc::new(Wire {
+            inbox: b_inbox.clone(),
+            state: Mutex::new(WireState::new(LinkConditions::default())),
+            capture: capture.clone(),
+        });
+
+        let b_to_a = Arc::new(Wire {
+            inbox: a_inbox.clone(),
+            state: Mutex::new(WireState::new(LinkConditions::default())),
+            capture: capture.clone(),
+        });
+
+        let a = Self {
+            mac: a,
+            inbox: Some(a_inbox),
+            wire: a_to_b,
+            capture: capture.clone(),
+        };
+
+        let b = Self {
+            mac: b,
+            inbox: Some(b_inbox),
+            wire: b_to_a,
+            capture,
+        };
+
+        (a, b)
+    }
+
+    /// Sets the conditions applied to frames sent from this end.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.wire.state.lock() = WireState::new(conditions);
+    }
+
+    /// Drops the next `count` frames sent from this end.
+    pub fn drop_next(&self, count: usize) {
+        self.wire.state.lock().drop_next += count;
+    }
+
+    /// Returns every frame that crossed the link so far, in both directions.
+    pub fn captured(&self) -> Vec<CapturedFrame> {
+        self.capture.lock().clone()
+    }
+
+    /// Clears the capture log.
+    pub fn clear_captured(&self) {
+        self.capture.lock().clear();
+    }
+
+    /// Sends a raw frame to the other end.
+    pub fn send(&self, frame: Vec<u8>) -> Result<(), ()> {
+        self.wire.send(self.mac, frame)
+    }
+
+    /// Waits for the next frame sent to this end. Returns `None` once this end has been handed to
+    /// a `NetworkDevice`.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        let inbox = self.inbox.as_ref()?;
+        Some(poll_fn(|cx| inbox.poll_recv(cx)).await)
+    }
+}
+
+impl NetworkDriver for PipeNic {
+    type RxSink = RxSink;
+    type TxSink = TxSink;
+
+    fn parts(&mut self) -> (Self::RxSink, Self::TxSink) {
+        let inbox = self.inbox.take().expect("testing: parts() already taken");
+
+        (
+            RxSink { inbox },
+            TxSink {
+                mac: self.mac,
+                wire: self.wire.clone(),
+            },
+        )
+    }
+
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+}
+
+/// Receive side of a `PipeNic`.
+pub struct RxSink {
+    inbox: Arc<Inbox>,
+}
+
+impl Stream for RxSink {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inbox.poll_recv(cx).map(Some)
+    }
+}
+
+/// Transmit side of a `PipeNic`.
+pub struct TxSink {
+    mac: Mac,
+    wire: Arc<Wire>,
+}
+
+impl Sink<Vec<u8>> for TxSink {
+    type Error = ();
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.wire.send(self.mac, item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
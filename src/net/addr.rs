@@ -0,0 +1,291 @@
+//! Address types modeled after the ones in `std::net`.
+
+pub use super::wire::ipaddr::AddrParseError;
+pub use super::wire::ipaddr::Ipv4Addr;
+
+use crate::prelude::*;
+
+use core::fmt;
+use core::str::FromStr;
+
+/// A ip address, we only speak ipv4 for now.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+}
+
+impl IpAddr {
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            Self::V4(ip) => ip.is_unspecified(),
+        }
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        match self {
+            Self::V4(ip) => ip.is_loopback(),
+        }
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        match self {
+            Self::V4(ip) => ip.is_multicast(),
+        }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        true
+    }
+}
+
+impl From<Ipv4Addr> for IpAddr {
+    fn from(ip: Ipv4Addr) -> Self {
+        Self::V4(ip)
+    }
+}
+
+impl From<[u8; 4]> for IpAddr {
+    fn from(octets: [u8; 4]) -> Self {
+        Self::V4(octets.into())
+    }
+}
+
+impl FromStr for IpAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::V4(s.parse()?))
+    }
+}
+
+impl fmt::Display for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// A ipv4 address along with a port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct SocketAddrV4 {
+    ip: Ipv4Addr,
+    port: u16,
+}
+
+impl SocketAddrV4 {
+    pub const fn new(ip: Ipv4Addr, port: u16) -> Self {
+        Self { ip, port }
+    }
+
+    pub fn ip(&self) -> &Ipv4Addr {
+        &self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: Ipv4Addr) {
+        self.ip = ip;
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+}
+
+impl FromStr for SocketAddrV4 {
+    type Err = AddrParseError;
+
+    /// Parses a address like `10.0.0.1:80`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let idx = s.rfind(':').ok_or(AddrParseError(()))?;
+        let (ip, port) = (&s[..idx], &s[idx + 1..]);
+
+        let port = match port.parse() {
+            Ok(x) if port.bytes().all(|x| x.is_ascii_digit()) => x,
+            _ => return Err(AddrParseError(())),
+        };
+
+        Ok(Self::new(ip.parse()?, port))
+    }
+}
+
+impl fmt::Display for SocketAddrV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+/// A socket address, we only speak ipv4 for now.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SocketAddr {
+    V4(SocketAddrV4),
+}
+
+impl SocketAddr {
+    pub fn new(ip: IpAddr, port: u16) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::V4(SocketAddrV4::new(ip, port)),
+        }
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Self::V4(addr) => IpAddr::V4(*addr.ip()),
+        }
+    }
+
+    pub fn set_ip(&mut self, ip: IpAddr) {
+        match (self, ip) {
+            (Self::V4(addr), IpAddr::V4(ip)) => addr.set_ip(ip),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::V4(addr) => addr.port(),
+        }
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        match self {
+            Self::V4(addr) => addr.set_port(port),
+        }
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        true
+    }
+
+    /// Returns the ipv4 address this is.
+    pub(crate) fn v4(&self) -> SocketAddrV4 {
+        match self {
+            Self::V4(addr) => *addr,
+        }
+    }
+}
+
+impl From<SocketAddrV4> for SocketAddr {
+    fn from(addr: SocketAddrV4) -> Self {
+        Self::V4(addr)
+    }
+}
+
+impl<I: Into<IpAddr>> From<(I, u16)> for SocketAddr {
+    fn from((ip, port): (I, u16)) -> Self {
+        Self::new(ip.into(), port)
+    }
+}
+
+impl FromStr for SocketAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::V4(s.parse()?))
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Things that can be turned into one or more socket addresses, used by everything that binds
+/// or connects.
+///
+/// Strings only take ip literals as we dont have a resolver. A bare port means that port on all
+/// of our addresses.
+pub trait ToSocketAddrs {
+    type Iter: Iterator<Item = SocketAddr>;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError>;
+}
+
+type Single = core::option::IntoIter<SocketAddr>;
+
+impl ToSocketAddrs for SocketAddr {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        Ok(Some(*self).into_iter())
+    }
+}
+
+impl ToSocketAddrs for SocketAddrV4 {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        SocketAddr::V4(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (IpAddr, u16) {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        SocketAddr::from(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (Ipv4Addr, u16) {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        SocketAddr::from(*self).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for (&str, u16) {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        let ip: IpAddr = self.0.parse()?;
+        (ip, self.1).to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for str {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        self.parse::<SocketAddr>()?.to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for String {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        self.as_str().to_socket_addrs()
+    }
+}
+
+impl ToSocketAddrs for u16 {
+    type Iter = Single;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        (Ipv4Addr::UNSPECIFIED, *self).to_socket_addrs()
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToSocketAddrs for &T {
+    type Iter = T::Iter;
+
+    fn to_socket_addrs(&self) -> Result<Self::Iter, AddrParseError> {
+        (**self).to_socket_addrs()
+    }
+}
+
+/// Returns the first ipv4 address `addr` resolves to.
+pub(crate) fn first_v4<A: ToSocketAddrs>(addr: A) -> Result<SocketAddrV4, ()> {
+    addr.to_socket_addrs()
+        .map_err(|_| ())?
+        .next()
+        .map(|x| x.v4())
+        .ok_or(())
+}
@@ -1,21 +1,123 @@
-use super::wire::arp::ArpPacket;
 use super::wire::arp::ArpOpcode;
+use super::wire::arp::ArpPacket;
 use super::wire::eth2::Ether2Frame;
-use super::wire::mac::Mac;
+use super::wire::eth2::EtherType;
 use super::wire::ipaddr::Ipv4Addr;
+use super::wire::mac::Mac;
 use super::wire::Packet;
-use super::wire::eth2::EtherType;
+use super::stats;
+use super::stats::DropReason;
 
-use core::time::Duration;
-use crate::async_::Sleep;
+use crate::arch::pit::get_milis;
 use crate::collections::HashMap;
+use crate::collections::VecDeque;
+use crate::prelude::*;
 use crate::sync::RwLock;
 
+/// How long (ms) a confirmed entry is considered reachable before it goes stale.
+const REACHABLE_TIME: u64 = 30_000;
+/// How long (ms) a stale entry can still be used before it gets evicted.
+const STALE_TIME: u64 = 300_000;
+/// How long (ms) we remember a failed resolution before allowing another attempt.
+const FAILED_TIME: u64 = 20_000;
+/// Time (ms) between two consecutive requests for the same address.
+const RETRANS_TIME: u64 = 1_000;
+/// Number of requests we send out before marking an address as failed.
+const MAX_PROBES: usize = 3;
+/// Maximum number of frames we hold for a single destination while it is being resolved.
+const MAX_PENDING: usize = 16;
+
+/// State of a neighbor cache entry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ArpState {
+    /// We have sent out a request and are waiting for a reply.
+    Incomplete,
+    /// The mapping has been confirmed recently.
+    Reachable,
+    /// The mapping hasn't been confirmed in a while, it can still be used but will be re-probed.
+    Stale,
+    /// Resolution failed, packets to this address are dropped until the entry expires.
+    Failed,
+}
+
+/// A single entry in our neighbor cache.
+struct ArpEntry {
+    /// Current state of this entry.
+    state: ArpState,
+    /// Resolved mac, only set once the entry has been confirmed at least once.
+    mac: Option<Mac>,
+    /// Local ip and mac we use when probing this entry.
+    local: (Ipv4Addr, Mac),
+    /// Timestamp of the last state change.
+    updated_at: u64,
+    /// Timestamp of the last request we sent for this entry.
+    queried_at: u64,
+    /// Number of requests sent since the last confirmation.
+    probes: usize,
+    /// Frames waiting for this entry to be resolved.
+    pending: VecDeque<Ether2Frame>,
+}
+
+impl ArpEntry {
+    fn incomplete(local: (Ipv4Addr, Mac), now: u64) -> Self {
+        Self {
+            state: ArpState::Incomplete,
+            mac: None,
+            local,
+            updated_at: now,
+            queried_at: now,
+            probes: 1,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn reachable(mac: Mac, local: (Ipv4Addr, Mac), now: u64) -> Self {
+        Self {
+            state: ArpState::Reachable,
+            mac: Some(mac),
+            local,
+            updated_at: now,
+            queried_at: 0,
+            probes: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Marks this entry as reachable and returns all the frames that were waiting on it with
+    /// their destination filled in.
+    fn confirm(&mut self, mac: Mac, now: u64) -> Vec<Ether2Frame> {
+        self.state = ArpState::Reachable;
+        self.mac = Some(mac);
+        self.updated_at = now;
+        self.probes = 0;
+
+        self.pending
+            .drain(..)
+            .map(|mut frame| {
+                frame.set_dst(mac);
+                frame
+            })
+            .collect()
+    }
+}
+
+/// What to do with an outgoing frame after consulting the cache.
+enum TxAction {
+    /// Destination is known, send the frame now.
+    Send(Ether2Frame),
+    /// Frame has been queued, and a request has to be sent out.
+    Query(Ipv4Addr, Mac),
+    /// Destination is known but stale, send the frame and ask for a confirmation.
+    SendAndQuery(Ether2Frame, Ipv4Addr, Mac),
+    /// Frame has been queued or dropped, nothing else to do.
+    Nothing,
+}
+
 /// Struct represents the Arp layer of our network stack. As such all arp packets are proccessed by
 /// a static instance of this struct.
 pub struct Arp {
-    /// Hashmap maps ip addresses mapped to macs.
-    arp_table: RwLock<HashMap<Mac, Ipv4Addr>>,
+    /// Neighbor cache mapping remote ips to their entries.
+    arp_table: RwLock<HashMap<Ipv4Addr, ArpEntry>>,
     /// Hashmap of local ips mapped to device macs.
     local_arp_table: RwLock<HashMap<Ipv4Addr, Mac>>,
 }
@@ -29,62 +131,254 @@ impl Arp {
     }
 
     pub async fn handle_packet(&self, packet: ArpPacket, _: &Ether2Frame) -> Option<ArpPacket> {
-        let local_mac = self.local_arp_table.read().await.get(&packet.tip())?.clone();
+        stats::ARP.rx(packet.as_ref().len());
+
+        let now = get_milis();
+        let local_mac = self.local_arp_table.read().await.get(&packet.tip()).cloned();
+
+        // RFC 826: if we already know the sender we update its entry, otherwise we only add it
+        // if we are the target of the packet. Probes carry an unspecified sender and are never
+        // cached.
+        if packet.sip() != Ipv4Addr::new(0, 0, 0, 0) {
+            let ready = {
+                let mut table = self.arp_table.write().await;
 
-        self.arp_table.write().await.insert(packet.smac(), packet.sip());
+                match table.get_mut(&packet.sip()) {
+                    Some(entry) => entry.confirm(packet.smac(), now),
+                    None => {
+                        if let Some(local_mac) = local_mac {
+                            let local = (packet.tip(), local_mac);
+                            let entry = ArpEntry::reachable(packet.smac(), local, now);
+                            table.insert(packet.sip(), entry);
+                        }
 
-        let mut reply = packet.clone();
-        reply.set_tmac(reply.smac());
+                        Vec::new()
+                    }
+                }
+            };
+
+            for frame in ready {
+                super::ETHERNET_LAYER.handle_tx(frame).await;
+            }
+        }
+
+        // Only requests for one of our addresses get a reply.
+        if packet.opcode() != ArpOpcode::ArpRequest {
+            return None;
+        }
+
+        let local_mac = local_mac?;
+
+        let mut reply = ArpPacket::zeroed();
+        reply.set_tmac(packet.smac());
         reply.set_smac(local_mac);
-        reply.set_tip(reply.sip());
+        reply.set_tip(packet.sip());
         reply.set_sip(packet.tip());
         reply.set_opcode(ArpOpcode::ArpReply);
 
+        stats::ARP.tx(reply.as_ref().len());
+
         Some(reply)
     }
 
+    /// Function sends out a frame addressed to `dip`. If the mac of `dip` isnt known yet the
+    /// frame is queued and sent as soon as resolution completes, this function never blocks
+    /// waiting for a reply.
+    ///
+    /// # Arguments
+    /// * `frame` - The frame to send, its source mac must already be set.
+    /// * `dip` - The ip the frame is addressed to.
+    /// * `sip` - The local ip used when we have to query for `dip`.
+    pub async fn handle_tx(&self, mut frame: Ether2Frame, dip: Ipv4Addr, sip: Ipv4Addr) {
+        let now = get_milis();
+        let local_mac = frame.src();
+
+        let action = {
+            let mut table = self.arp_table.write().await;
+
+            match table.get_mut(&dip) {
+                Some(entry) => match entry.state {
+                    ArpState::Reachable | ArpState::Stale => {
+                        let mac = entry.mac.expect("arp: resolved entry without a mac");
+                        frame.set_dst(mac);
+
+                        // stale entries are still used, but we try to confirm them again.
+                        if entry.state == ArpState::Stale
+                            && now - entry.queried_at >= RETRANS_TIME
+                        {
+                            entry.queried_at = now;
+                            entry.probes += 1;
+                            let (lip, lmac) = entry.local;
+                            TxAction::SendAndQuery(frame, lip, lmac)
+                        } else {
+                            TxAction::Send(frame)
+                        }
+                    }
+                    ArpState::Incomplete => {
+                        stats::ARP_CACHE.miss();
+                        if entry.pending.len() >= MAX_PENDING {
+                            entry.pending.pop_front();
+                            stats::ARP.dropped(DropReason::Unresolved);
+                        }
+                        entry.pending.push_back(frame);
+                        TxAction::Nothing
+                    }
+                    ArpState::Failed => {
+                        stats::ARP_CACHE.miss();
+                        stats::ARP.dropped(DropReason::Unresolved);
+                        TxAction::Nothing
+                    }
+                },
+                None => {
+                    stats::ARP_CACHE.miss();
+                    let mut entry = ArpEntry::incomplete((sip, local_mac), now);
+                    entry.pending.push_back(frame);
+                    table.insert(dip, entry);
+
+                    TxAction::Query(sip, local_mac)
+                }
+            }
+        };
+
+        match action {
+            TxAction::Send(frame) => super::ETHERNET_LAYER.handle_tx(frame).await,
+            TxAction::Query(lip, lmac) => self.arp_query(dip, lip, lmac).await,
+            TxAction::SendAndQuery(frame, lip, lmac) => {
+                super::ETHERNET_LAYER.handle_tx(frame).await;
+                self.arp_query(dip, lip, lmac).await;
+            }
+            TxAction::Nothing => {}
+        }
+    }
+
+    /// Function ages the neighbor cache and retransmits requests for incomplete entries. It must
+    /// be called periodically, `NetworkDevice::run_forever` does this for us.
+    pub async fn poll_timers(&self) {
+        let now = get_milis();
+        let mut queries = Vec::new();
+
+        self.arp_table.write().await.retain(|ip, entry| match entry.state {
+            ArpState::Incomplete => {
+                if now - entry.queried_at < RETRANS_TIME {
+                    return true;
+                }
+
+                if entry.probes >= MAX_PROBES {
+                    stats::ARP_CACHE.failure();
+                    for _ in entry.pending.drain(..) {
+                        stats::ARP.dropped(DropReason::Unresolved);
+                    }
+
+                    entry.state = ArpState::Failed;
+                    entry.updated_at = now;
+                    return true;
+                }
+
+                entry.probes += 1;
+                entry.queried_at = now;
+                queries.push((*ip, entry.local));
+                true
+            }
+            ArpState::Reachable => {
+                if now - entry.updated_at >= REACHABLE_TIME {
+                    entry.state = ArpState::Stale;
+                    entry.updated_at = now;
+                }
+                true
+            }
+            ArpState::Stale => now - entry.updated_at < STALE_TIME,
+            ArpState::Failed => now - entry.updated_at < FAILED_TIME,
+        });
+
+        for (ip, (lip, lmac)) in queries {
+            self.arp_query(ip, lip, lmac).await;
+        }
+    }
+
     pub async fn register_local(&self, lip: Ipv4Addr, lmac: Mac) {
         self.local_arp_table.write().await.insert(lip, lmac);
     }
 
-    pub async fn resolve_ip(&self, ip: Ipv4Addr, local: Ipv4Addr) -> Option<Mac> {
-        // First check our local tables for whether we already have an entry.
-        self.arp_table.read().await.iter().find(|(_, x)| **x == ip).map(|(mac, _)| *mac);
-        // Get our local mac
-        let local_mac = self.local_arp_table.read().await.get(&local)?.clone();
-        
-        for _ in 0usize..5 {
-            self.arp_query(ip, local, local_mac).await;
+    /// Function forgets every local ip of the device with `lmac`, used once the device is gone.
+    pub async fn unregister_local(&self, lmac: Mac) {
+        self.local_arp_table.write().await.retain(|_, mac| *mac != lmac);
+    }
 
-            Sleep::new(Duration::from_millis(1000)).await;
+    /// Returns the local ips of the device with `lmac`.
+    pub async fn local_ips(&self, lmac: Mac) -> Vec<Ipv4Addr> {
+        self.local_arp_table
+            .read()
+            .await
+            .iter()
+            .filter(|(_, mac)| **mac == lmac)
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
 
-            if let Some(x) = self.arp_table.read().await.iter().find(|(_, x)| **x == ip).map(|(mac, _)| *mac) {
-                return Some(x);
-            }
+    /// Function returns the mac of `ip` if it is in our neighbor cache. It never sends out any
+    /// requests, use `handle_tx` for that.
+    pub async fn resolve_ip(&self, ip: Ipv4Addr) -> Option<Mac> {
+        let table = self.arp_table.read().await;
+        let entry = table.get(&ip)?;
+
+        match entry.state {
+            ArpState::Reachable | ArpState::Stale => entry.mac,
+            _ => None,
         }
+    }
 
-        // TODO: If we get here that means we have timeouted and we must notify the client maybe??
-        None
+    /// Returns the state of the neighbor cache entry for `ip`.
+    pub async fn state(&self, ip: Ipv4Addr) -> Option<ArpState> {
+        self.arp_table.read().await.get(&ip).map(|x| x.state)
+    }
+
+    /// Returns one of our local ips, used as the source of packets that arent tied to a
+    /// connection. Loopback addresses are only picked if we have nothing else.
+    pub async fn local_ip(&self) -> Option<Ipv4Addr> {
+        let table = self.local_arp_table.read().await;
+
+        table
+            .keys()
+            .find(|x| !x.is_loopback())
+            .or_else(|| table.keys().next())
+            .cloned()
     }
 
     pub async fn resolve_ip_local(&self, ip: Ipv4Addr) -> Option<Mac> {
-        self.local_arp_table.read().await.iter().find(|(x, _)| **x == ip).map(|(_, mac)| *mac)
+        let table = self.local_arp_table.read().await;
+
+        // the whole of 127.0.0.0/8 belongs to the loopback device.
+        match table.get(&ip) {
+            None if ip.is_loopback() => table.get(&Ipv4Addr::LOCALHOST).cloned(),
+            x => x.cloned(),
+        }
+    }
+
+    /// Returns whether `ip` is one of our own addresses, traffic to these never leaves the box.
+    pub async fn is_local(&self, ip: Ipv4Addr) -> bool {
+        ip.is_loopback() || self.local_arp_table.read().await.contains_key(&ip)
     }
 
     pub async fn arp_query(&self, ip: Ipv4Addr, local_ip: Ipv4Addr, local_mac: Mac) {
         let mut request = ArpPacket::zeroed();
-        request.set_tmac(Mac::multicast());
+        request.set_tmac(Mac::zeroed());
         request.set_smac(local_mac);
         request.set_tip(ip);
         request.set_sip(local_ip);
         request.set_opcode(ArpOpcode::ArpRequest);
 
-        let mut ethpacket = Ether2Frame::zeroed();
-        ethpacket.set_dst(Mac::multicast());
+        stats::ARP.tx(request.as_ref().len());
+
+        let mut ethpacket = Ether2Frame::encapsulate(request.into_buf());
+        ethpacket.set_dst(Mac::broadcast());
         ethpacket.set_src(local_mac);
         ethpacket.set_dtype(EtherType::ARP);
-        ethpacket.set_data(request.as_ref());
 
         super::ETHERNET_LAYER.handle_tx(ethpacket).await;
     }
+
+    /// Function sends out a gratuitous arp for `ip`, letting our neighbors update their caches.
+    pub async fn announce(&self, ip: Ipv4Addr, local_mac: Mac) {
+        self.arp_query(ip, ip, local_mac).await;
+    }
 }
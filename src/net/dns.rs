@@ -0,0 +1,310 @@
+//! A stub resolver for A records (RFC 1035) over udp.
+//!
+//! Nameservers have to be set with `DnsResolver::set_servers`, we dont learn them from dhcp.
+//! Truncated answers are used as they are, we dont retry them over tcp.
+use super::addr::IpAddr;
+use super::socks::UdpSocket;
+use super::wire::ipaddr::Ipv4Addr;
+
+use crate::arch::pit::get_milis;
+use crate::arch::random;
+use crate::async_;
+use crate::collections::HashMap;
+use crate::prelude::*;
+
+use core::time::Duration;
+
+use spin::RwLock;
+
+const DNS_PORT: u16 = 53;
+/// Biggest answer we take over udp without EDNS (RFC 1035 4.2.1).
+const MAX_UDP_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_NAME_ERROR: u16 = 3;
+
+/// How long we wait for a answer before asking again.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many times every nameserver is asked.
+const QUERY_ATTEMPTS: usize = 2;
+/// Longest time (s) we cache a answer for, whatever its ttl.
+const MAX_TTL: u32 = 3600;
+/// How long (s) we remember that a name doesnt exist.
+const NEGATIVE_TTL: u32 = 30;
+
+/// Errors that can be returned by `resolve`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DnsError {
+    /// There are no nameservers configured.
+    NoServers,
+    /// The name isnt a valid domain name.
+    InvalidName,
+    /// The name doesnt exist, or has no A records.
+    NotFound,
+    /// No nameserver answered in time.
+    Timeout,
+    /// The nameservers failed to answer the query.
+    ServerFailure,
+}
+
+struct CacheEntry {
+    addrs: Vec<Ipv4Addr>,
+    /// Time (ms) the entry expires at, `None` for static entries.
+    expires: Option<u64>,
+}
+
+pub struct DnsResolver {
+    servers: RwLock<Vec<Ipv4Addr>>,
+    /// Answers we got, keyed by lowercased name. A empty entry means the name doesnt exist.
+    cache: RwLock<HashMap<String, CacheEntry>>,
+}
+
+impl DnsResolver {
+    pub fn new() -> Self {
+        Self {
+            servers: RwLock::new(Vec::new()),
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the nameservers to ask, they are tried in order.
+    pub fn set_servers(&self, servers: &[Ipv4Addr]) {
+        *self.servers.write() = servers.to_vec();
+    }
+
+    pub fn servers(&self) -> Vec<Ipv4Addr> {
+        self.servers.read().clone()
+    }
+
+    /// Adds a static entry for `name`, like a line in `/etc/hosts`.
+    pub fn add_host(&self, name: &str, addr: Ipv4Addr) {
+        let mut cache = self.cache.write();
+        let entry = cache
+            .entry(name.to_ascii_lowercase())
+            .or_insert_with(|| CacheEntry {
+                addrs: Vec::new(),
+                expires: None,
+            });
+
+        if entry.expires.is_some() {
+            entry.addrs.clear();
+            entry.expires = None;
+        }
+
+        entry.addrs.push(addr);
+    }
+
+    /// Function forgets every cached answer, static entries are kept.
+    pub fn flush(&self) {
+        self.cache.write().retain(|_, x| x.expires.is_none());
+    }
+
+    /// Function returns the addresses of `name`. Ip literals are returned as they are.
+    pub async fn resolve(&self, name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+        if let Ok(addr) = name.parse::<Ipv4Addr>() {
+            return Ok(vec![addr]);
+        }
+
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.cached(&name) {
+            return if addrs.is_empty() {
+                Err(DnsError::NotFound)
+            } else {
+                Ok(addrs)
+            };
+        }
+
+        let query = build_query(&name)?;
+        let servers = self.servers();
+        if servers.is_empty() {
+            return Err(DnsError::NoServers);
+        }
+
+        let mut socket = UdpSocket::bind(0).map_err(|_| DnsError::ServerFailure)?;
+        let mut error = DnsError::Timeout;
+
+        for _ in 0..QUERY_ATTEMPTS {
+            for server in servers.iter() {
+                let mut query = query.clone();
+                let id = random::u64() as u16;
+                query[..2].copy_from_slice(&id.to_be_bytes());
+
+                if socket.send_to(&query, (*server, DNS_PORT)).await.is_err() {
+                    continue;
+                }
+
+                let answer = async_::timeout(QUERY_TIMEOUT, async {
+                    let mut buf = [0; MAX_UDP_LEN];
+
+                    // datagrams that arent the answer to our query are ignored.
+                    loop {
+                        let (len, src) = socket.recv_from(&mut buf).await.ok()?;
+                        if src.ip() == IpAddr::from(*server) && src.port() == DNS_PORT {
+                            if let Some(x) = parse_answer(&buf[..len], id, &query) {
+                                return Some(x);
+                            }
+                        }
+                    }
+                })
+                .await
+                .flatten();
+
+                match answer {
+                    Some(Ok((addrs, ttl))) => {
+                        self.insert(&name, addrs.clone(), ttl);
+                        return Ok(addrs);
+                    }
+                    Some(Err(DnsError::NotFound)) => {
+                        self.insert(&name, Vec::new(), NEGATIVE_TTL);
+                        return Err(DnsError::NotFound);
+                    }
+                    Some(Err(e)) => error = e,
+                    None => {}
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    fn cached(&self, name: &str) -> Option<Vec<Ipv4Addr>> {
+        let cache = self.cache.read();
+        let entry = cache.get(name)?;
+
+        match entry.expires {
+            Some(x) if x <= get_milis() => None,
+            _ => Some(entry.addrs.clone()),
+        }
+    }
+
+    fn insert(&self, name: &str, addrs: Vec<Ipv4Addr>, ttl: u32) {
+        let mut cache = self.cache.write();
+        let now = get_milis();
+
+        // dont let answers shadow static entries, and drop what expired while we are at it.
+        cache.retain(|_, x| x.expires.map_or(true, |x| x > now));
+        if cache.contains_key(name) {
+            return;
+        }
+
+        let expires = now + ttl.min(MAX_TTL) as u64 * 1000;
+        cache.insert(
+            name.into(),
+            CacheEntry {
+                addrs,
+                expires: Some(expires),
+            },
+        );
+    }
+}
+
+/// Returns a query for the A records of `name` with a id of 0.
+fn build_query(name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&[0, 0]);
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    if name.is_empty() || name.len() > 253 {
+        return Err(DnsError::InvalidName);
+    }
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+/// Parses the answer to `query`, returns the addresses in it along with the lowest ttl among
+/// them. Returns `None` if it isnt a answer to `query` at all.
+fn parse_answer(
+    buf: &[u8],
+    id: u16,
+    query: &[u8],
+) -> Option<Result<(Vec<Ipv4Addr>, u32), DnsError>> {
+    let u16_at = |x: usize| buf.get(x..x + 2).map(|x| u16::from_be_bytes([x[0], x[1]]));
+
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    // the question has to be ours, compared case insensitively (RFC 4343).
+    let question = &query[HEADER_LEN..];
+    let echoed = buf.get(HEADER_LEN..HEADER_LEN + question.len())?;
+    if u16_at(4)? != 1 || !echoed.eq_ignore_ascii_case(question) {
+        return None;
+    }
+
+    match flags & 0xf {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err(DnsError::NotFound)),
+        _ => return Some(Err(DnsError::ServerFailure)),
+    }
+
+    let answers = u16_at(6)?;
+    let mut pos = HEADER_LEN + question.len();
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+        let kind = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let record_ttl = buf.get(pos + 4..pos + 8)?;
+        let len = u16_at(pos + 8)? as usize;
+        let data = buf.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+
+        // cnames come along with the records of the name they point to, so we can skip them.
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            addrs.push(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
+            ttl = ttl.min(u32::from_be_bytes([
+                record_ttl[0],
+                record_ttl[1],
+                record_ttl[2],
+                record_ttl[3],
+            ]));
+        }
+    }
+
+    if addrs.is_empty() {
+        return Some(Err(DnsError::NotFound));
+    }
+
+    Some(Ok((addrs, ttl)))
+}
+
+/// Returns where the name starting at `pos` ends.
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // a compression pointer always ends the name.
+            x if x & 0xc0 == 0xc0 => return Some(pos + 2),
+            x if x & 0xc0 == 0 => pos += 1 + x as usize,
+            _ => return None,
+        }
+    }
+}
+
+/// Function returns the addresses of `name` from the global resolver.
+pub async fn resolve(name: &str) -> Result<Vec<Ipv4Addr>, DnsError> {
+    super::DNS_RESOLVER.resolve(name).await
+}
@@ -1,3 +1,4 @@
+use crate::prelude::*;
 use crate::collections::HashMap;
 use crate::sync::RwLock;
 use crate::sync::mpsc::UnboundedSender;
@@ -6,18 +7,174 @@ use super::wire::mac::Mac;
 use super::wire::ipv4::Ipv4;
 use super::wire::arp::ArpPacket;
 use super::wire::eth2::EtherType;
+use super::wire::eth2::VlanTag;
+use super::wire::ipaddr::Ipv4Addr;
 use super::wire::Packet;
+use super::pcap::Capture;
+use super::stats;
+use super::stats::DropReason;
+use super::filter::Action;
+use super::filter::Direction;
+use core::convert::TryInto;
+use spin::Mutex;
 
 type TxQueueSender = UnboundedSender<Ether2Frame>;
 
+/// Decides which destination macs a device accepts on top of its own and the broadcast address.
+#[derive(Default)]
+struct MacFilter {
+    /// Whether every frame is accepted, regardless of its destination.
+    promiscuous: bool,
+    /// Multicast groups joined on the device.
+    groups: Vec<Mac>,
+    /// Vlans the device has sub-interfaces on, tagged frames for any other vlan are dropped.
+    vlans: Vec<u16>,
+}
+
 pub struct Ethernet {
     tx_queue_map: RwLock<HashMap<Mac, TxQueueSender>>,
+    /// Destination filters of our devices, keyed by device mac.
+    filters: Mutex<HashMap<Mac, MacFilter>>,
+    /// Local ips that belong to a vlan sub-interface, every other ip is untagged.
+    ip_vlans: Mutex<HashMap<Ipv4Addr, u16>>,
+    /// Mtu of our devices, keyed by device mac.
+    mtus: Mutex<HashMap<Mac, usize>>,
+    /// Capture tap, every frame we send or receive is recorded if set.
+    capture: Mutex<Option<Capture>>,
 }
 
 impl Ethernet {
     pub fn new() -> Self {
         Self {
             tx_queue_map: RwLock::new(HashMap::new()),
+            filters: Mutex::new(HashMap::new()),
+            ip_vlans: Mutex::new(HashMap::new()),
+            mtus: Mutex::new(HashMap::new()),
+            capture: Mutex::new(None),
+        }
+    }
+
+    /// Installs or removes the capture tap, returning the previous one.
+    pub fn set_capture(&self, capture: Option<Capture>) -> Option<Capture> {
+        core::mem::replace(&mut *self.capture.lock(), capture)
+    }
+
+    /// Runs `f` on the current capture tap if there is one.
+    pub fn with_capture<T>(&self, f: impl FnOnce(&mut Capture) -> T) -> Option<T> {
+        self.capture.lock().as_mut().map(f)
+    }
+
+    fn tap(&self, frame: &Ether2Frame) {
+        if let Some(capture) = self.capture.lock().as_mut() {
+            capture.push(frame.as_ref());
+        }
+    }
+
+    /// Sets whether the device with `device_mac` accepts frames addressed to anyone.
+    pub fn set_promiscuous(&self, device_mac: Mac, promiscuous: bool) {
+        self.filters.lock().entry(device_mac).or_default().promiscuous = promiscuous;
+    }
+
+    /// Makes the device with `device_mac` accept frames sent to the multicast `group`.
+    pub fn join_multicast(&self, device_mac: Mac, group: Mac) {
+        let mut filters = self.filters.lock();
+        let groups = &mut filters.entry(device_mac).or_default().groups;
+
+        if group.is_multicast() && !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+
+    /// Stops the device with `device_mac` from accepting frames sent to `group`.
+    pub fn leave_multicast(&self, device_mac: Mac, group: Mac) {
+        if let Some(filter) = self.filters.lock().get_mut(&device_mac) {
+            filter.groups.retain(|x| *x != group);
+        }
+    }
+
+    /// Sets the mtu of the device with `device_mac`, the biggest payload a frame sent from it can
+    /// carry.
+    pub fn set_mtu(&self, device_mac: Mac, mtu: usize) {
+        self.mtus.lock().insert(device_mac, mtu);
+    }
+
+    /// Returns the mtu of the device with `device_mac`.
+    pub fn mtu(&self, device_mac: Mac) -> usize {
+        self.mtus
+            .lock()
+            .get(&device_mac)
+            .cloned()
+            .unwrap_or(super::DEFAULT_MTU)
+    }
+
+    /// Creates a sub-interface for vlan `id` on the device with `device_mac`.
+    pub fn add_vlan(&self, device_mac: Mac, id: u16) {
+        let mut filters = self.filters.lock();
+        let vlans = &mut filters.entry(device_mac).or_default().vlans;
+
+        if !vlans.contains(&id) {
+            vlans.push(id);
+        }
+    }
+
+    /// Removes the sub-interface for vlan `id` from the device with `device_mac`.
+    pub fn remove_vlan(&self, device_mac: Mac, id: u16) {
+        if let Some(filter) = self.filters.lock().get_mut(&device_mac) {
+            filter.vlans.retain(|x| *x != id);
+        }
+
+        self.ip_vlans.lock().retain(|_, x| *x != id);
+    }
+
+    /// Assigns the local `ip` to vlan `id`, or to the untagged interface if `id` is `None`.
+    pub fn set_ip_vlan(&self, ip: Ipv4Addr, id: Option<u16>) {
+        let mut ip_vlans = self.ip_vlans.lock();
+        match id {
+            Some(id) => ip_vlans.insert(ip, id),
+            None => ip_vlans.remove(&ip),
+        };
+    }
+
+    /// Returns the vlan traffic from or to `ip` is tagged with on the device with `device_mac`.
+    fn ip_vlan(&self, device_mac: Mac, ip: Ipv4Addr) -> Option<u16> {
+        let id = *self.ip_vlans.lock().get(&ip)?;
+        let filters = self.filters.lock();
+
+        // the loopback device shares our ips, but never has any vlans.
+        filters.get(&device_mac)?.vlans.iter().find(|x| **x == id).cloned()
+    }
+
+    fn has_vlan(&self, device_mac: Mac, id: u16) -> bool {
+        self.filters
+            .lock()
+            .get(&device_mac)
+            .map_or(false, |x| x.vlans.contains(&id))
+    }
+
+    /// Returns the sender and target ips of a ipv4 or arp frame.
+    fn frame_ips(frame: &Ether2Frame) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        let (sip, tip) = match frame.dtype() {
+            EtherType::IPv4 => (12..16, 16..20),
+            EtherType::ARP => (14..18, 24..28),
+            _ => return None,
+        };
+
+        let data = frame.data();
+        Some((
+            data.get(sip)?.try_into().ok()?,
+            data.get(tip)?.try_into().ok()?,
+        ))
+    }
+
+    /// Returns whether the device with `device_mac` should process a frame sent to `dst`.
+    fn accepts(&self, device_mac: Mac, dst: Mac) -> bool {
+        if dst == device_mac || dst.is_broadcast() {
+            return true;
+        }
+
+        match self.filters.lock().get(&device_mac) {
+            Some(filter) => filter.promiscuous || filter.groups.contains(&dst),
+            None => false,
         }
     }
 
@@ -28,41 +185,154 @@ impl Ethernet {
             .insert(device_mac, tx_queue);
     }
 
-    /// Function handles an incoming packet.
-    pub async fn handle_rx(&self, ctx: Ether2Frame, device_mac: Mac) -> Option<Ether2Frame> {
+    /// Function forgets the device with `device_mac`, frames sent from it afterwards are dropped.
+    pub async fn unregister_tx(&self, device_mac: Mac) {
+        self.tx_queue_map.write().await.remove(&device_mac);
+        self.filters.lock().remove(&device_mac);
+        self.mtus.lock().remove(&device_mac);
+    }
+
+    /// Function handles an incoming packet, received by the device with `device_mac`. `loopback`
+    /// tells whether that device is the loopback device.
+    pub async fn handle_rx(
+        &self,
+        ctx: Ether2Frame,
+        device_mac: Mac,
+        loopback: bool,
+    ) -> Option<Ether2Frame> {
+        self.tap(&ctx);
+        stats::ETHERNET.rx(ctx.as_ref().len());
+
+        // frames meant for other hosts have already been captured, thats all they are good for.
+        if !self.accepts(device_mac, ctx.dst()) {
+            stats::ETHERNET.dropped(DropReason::NotForUs);
+            return None;
+        }
+
+        if super::FIREWALL.check_ethernet(Direction::Ingress, &ctx) != Action::Accept {
+            stats::ETHERNET.dropped(DropReason::Filtered);
+            return None;
+        }
+
+        // the rest of the stack only sees untagged frames, we remember the vlan to tag the reply.
+        // Vlan 0 only carries a priority (802.1Q 9.6), those frames are treated as untagged.
+        let mut ctx = ctx;
+        let vlan = ctx.pop_vlan().map(|x| x.id).filter(|x| *x != 0);
+        if let Some(id) = vlan {
+            if !self.has_vlan(device_mac, id) {
+                stats::ETHERNET.dropped(DropReason::Unsupported);
+                return None;
+            }
+        }
+
+        // our ips are only reachable through the interface they were configured on.
+        if let Some((_, tip)) = Self::frame_ips(&ctx) {
+            if super::ARP_LAYER.is_local(tip).await && self.ip_vlan(device_mac, tip) != vlan {
+                stats::ETHERNET.dropped(DropReason::NotForUs);
+                return None;
+            }
+        }
+
+        // raw sockets get their own copy, the frame still goes through the stack if we know its
+        // type.
+        let raw = super::RAW_LAYER.deliver_ethernet(&ctx);
+
+        // upper layers get the payload without the frame being copied, `ctx` only keeps the
+        // header around.
+        let (ctx, payload) = ctx.split();
+
         let (data, frame_type) = match ctx.dtype() {
             EtherType::IPv4 => {
-                let pkt = Ipv4::from_bytes(ctx.data().to_vec()).ok()?;
+                let pkt = match Ipv4::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::IP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
                 (
-                    super::IP_LAYER.handle_packet(pkt, &ctx).await?.into_bytes(),
+                    super::IP_LAYER
+                        .handle_packet(pkt, &ctx, loopback)
+                        .await?
+                        .into_buf(),
                     EtherType::IPv4
                 )
             },
             EtherType::ARP => {
-                let pkt = ArpPacket::from_bytes(ctx.data().to_vec()).ok()?;
+                let pkt = match ArpPacket::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::ARP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
                 (
-                    super::ARP_LAYER.handle_packet(pkt, &ctx).await?.into_bytes(),
+                    super::ARP_LAYER.handle_packet(pkt, &ctx).await?.into_buf(),
                     EtherType::ARP,
                 )
             }
             _ => {
+                if !raw {
+                    stats::ETHERNET.dropped(DropReason::Unsupported);
+                }
                 return None;
             }
         };
 
-        let mut reply = Ether2Frame::zeroed();
+        let mut reply = Ether2Frame::encapsulate(data);
         reply.set_dst(ctx.src());
         reply.set_src(device_mac);
         reply.set_dtype(frame_type);
-        reply.set_data(data);
+
+        if let Some(id) = vlan {
+            reply.push_vlan(VlanTag::new(id));
+        }
+
+        if super::FIREWALL.check_ethernet(Direction::Egress, &reply) != Action::Accept {
+            stats::ETHERNET.dropped(DropReason::Filtered);
+            return None;
+        }
+
+        // replies dont go through `handle_tx`, so we have to record them here.
+        self.tap(&reply);
+        stats::ETHERNET.tx(reply.as_ref().len());
 
         Some(reply)
     }
 
     /// Function can be used to send data out.
-    pub async fn handle_tx(&self, packet: Ether2Frame) {
+    pub async fn handle_tx(&self, mut packet: Ether2Frame) {
+        // frames sent from a ip of a vlan sub-interface get that vlans tag.
+        if let Some((sip, _)) = Self::frame_ips(&packet) {
+            if let Some(id) = self.ip_vlan(packet.src(), sip) {
+                packet.push_vlan(VlanTag::new(id));
+            }
+        }
+
+        if super::FIREWALL.check_ethernet(Direction::Egress, &packet) != Action::Accept {
+            stats::ETHERNET.dropped(DropReason::Filtered);
+            return;
+        }
+
+        // the ip layer fragments to the mtu, anything bigger than it now would never make it.
+        let payload_len = packet.as_ref().len() - packet.header_len();
+        if payload_len > self.mtu(packet.src()) {
+            stats::ETHERNET.dropped(DropReason::TooBig);
+            return;
+        }
+
+        self.tap(&packet);
+
         if let Some(lock) = self.tx_queue_map.read().await.get(&packet.src()) {
-            lock.send(packet).expect("eth2: failed to write to netdev");
+            let len = packet.as_ref().len();
+
+            // the device may be shutting down, in which case its queue is already closed.
+            match lock.send(packet) {
+                Ok(_) => stats::ETHERNET.tx(len),
+                Err(_) => stats::ETHERNET.dropped(DropReason::Unresolved),
+            }
+        } else {
+            stats::ETHERNET.dropped(DropReason::Unresolved);
         }
     }
 }
@@ -0,0 +1,497 @@
+//! Packet filtering.
+//!
+//! Every packet goes through a set of ingress and egress hooks in the ethernet, ip and tcp layers.
+//! Hooks are plain closures returning a `Action`, on top of them the ip layer runs a small rule
+//! engine backed by a connection tracking table. Rules are checked in order and the first match
+//! decides what happens to a packet, packets no rule matches get the policy of their direction.
+//! ```rust
+//! // only the management subnet can reach the admin port.
+//! FIREWALL.add_rule(
+//!     Rule::ingress(Action::Accept)
+//!         .proto(Ipv4Proto::TCP)
+//!         .dst_port(8081)
+//!         .src(Cidr::new(Ipv4Addr::new(10, 0, 0, 0), 24)),
+//! );
+//! FIREWALL.add_rule(Rule::ingress(Action::Reject).proto(Ipv4Proto::TCP).dst_port(8081));
+//! ```
+use super::stats;
+use super::wire::eth2::Ether2Frame;
+use super::wire::ipaddr::Ipv4Addr;
+use super::wire::ipv4::Ipv4;
+use super::wire::ipv4::Ipv4Proto;
+use super::wire::tcp::Tcp;
+
+use crate::arch::pit::get_milis;
+use crate::collections::HashMap;
+use crate::prelude::*;
+
+use core::convert::TryInto;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use spin::Mutex;
+use spin::RwLock;
+
+/// How long (ms) we remember a tcp connection without seeing any of its packets.
+const CONNTRACK_TCP_TIMEOUT: u64 = 300_000;
+/// How long (ms) we remember any other flow without seeing any of its packets.
+const CONNTRACK_TIMEOUT: u64 = 30_000;
+/// Number of flows the connection tracking table holds by default.
+const CONNTRACK_CAPACITY: usize = 4096;
+/// Offset of the flags in a tcp header, and the bit of the RST flag.
+const TCP_FLAGS_OFFSET: usize = 13;
+const TCP_RST: u8 = 1 << 2;
+
+/// Which way a packet is going.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Packet was received by us.
+    Ingress,
+    /// Packet is being sent by us.
+    Egress,
+}
+
+/// What to do with a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Let the packet through.
+    Accept,
+    /// Silently drop the packet.
+    Drop,
+    /// Drop the packet and let the sender know. Tcp segments are answered with a RST, everything
+    /// else with a administratively prohibited icmp error. Rejected outgoing packets are simply
+    /// dropped.
+    Reject,
+}
+
+/// Connection tracking state of a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnState {
+    /// Packet belongs to a flow we havent seen a answer for yet.
+    New,
+    /// Packet belongs to a flow that has seen traffic in both directions.
+    Established,
+}
+
+/// A IPv4 subnet in CIDR notation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    addr: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates a new subnet, `prefix` is clamped to 32.
+    ///
+    /// # Arguments
+    /// * `addr` - Any address inside of the subnet.
+    /// * `prefix` - Number of leading bits that make up the network part.
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        Self {
+            addr,
+            prefix: prefix.min(32),
+        }
+    }
+
+    /// Creates a subnet holding a single address.
+    pub fn host(addr: Ipv4Addr) -> Self {
+        Self::new(addr, 32)
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    /// Returns whether `addr` is part of this subnet.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.mask();
+        u32::from_be_bytes(self.addr.octets()) & mask == u32::from_be_bytes(addr.octets()) & mask
+    }
+}
+
+/// Addressing information of a packet, as seen by the rule engine.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Flow {
+    pub proto: Ipv4Proto,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    /// Source port, only set for tcp and udp.
+    pub src_port: Option<u16>,
+    /// Destination port, only set for tcp and udp.
+    pub dst_port: Option<u16>,
+}
+
+impl Flow {
+    /// Function extracts the flow of a ip packet, peeking into its payload for the ports.
+    pub fn from_ipv4(packet: &Ipv4) -> Self {
+        let proto = packet.proto();
+        let data = packet.data();
+
+        let port = |offset: usize| -> Option<u16> {
+            match proto {
+                Ipv4Proto::TCP | Ipv4Proto::UDP => Some(u16::from_be_bytes(
+                    data.get(offset..offset + 2)?.try_into().ok()?,
+                )),
+                _ => None,
+            }
+        };
+
+        Self {
+            proto,
+            src: packet.sip(),
+            dst: packet.dip(),
+            src_port: port(0),
+            dst_port: port(2),
+        }
+    }
+
+    /// Function builds the flow of a tcp segment from the addresses it is sent between.
+    pub fn from_tcp(packet: &Tcp, src: Ipv4Addr, dst: Ipv4Addr) -> Self {
+        Self {
+            proto: Ipv4Proto::TCP,
+            src,
+            dst,
+            src_port: Some(packet.src()),
+            dst_port: Some(packet.dst()),
+        }
+    }
+
+    /// Key of the connection this flow belongs to, the same for both directions.
+    fn conn_key(&self, direction: Direction) -> ConnKey {
+        let src = (self.src, self.src_port.unwrap_or(0));
+        let dst = (self.dst, self.dst_port.unwrap_or(0));
+
+        match direction {
+            Direction::Ingress => (self.proto.raw(), dst, src),
+            Direction::Egress => (self.proto.raw(), src, dst),
+        }
+    }
+}
+
+/// A single filtering rule, built with `Rule::ingress` or `Rule::egress` and narrowed down with
+/// the builder methods. Fields that are left unset match anything.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    direction: Direction,
+    action: Action,
+    src: Option<Cidr>,
+    dst: Option<Cidr>,
+    proto: Option<Ipv4Proto>,
+    src_port: Option<(u16, u16)>,
+    dst_port: Option<(u16, u16)>,
+    state: Option<ConnState>,
+}
+
+impl Rule {
+    fn new(direction: Direction, action: Action) -> Self {
+        Self {
+            direction,
+            action,
+            src: None,
+            dst: None,
+            proto: None,
+            src_port: None,
+            dst_port: None,
+            state: None,
+        }
+    }
+
+    /// Creates a rule matching every packet we receive.
+    pub fn ingress(action: Action) -> Self {
+        Self::new(Direction::Ingress, action)
+    }
+
+    /// Creates a rule matching every packet we send.
+    pub fn egress(action: Action) -> Self {
+        Self::new(Direction::Egress, action)
+    }
+
+    /// Only match packets sent from `src`.
+    pub fn src(mut self, src: Cidr) -> Self {
+        self.src = Some(src);
+        self
+    }
+
+    /// Only match packets sent to `dst`.
+    pub fn dst(mut self, dst: Cidr) -> Self {
+        self.dst = Some(dst);
+        self
+    }
+
+    /// Only match packets carrying `proto`.
+    pub fn proto(mut self, proto: Ipv4Proto) -> Self {
+        self.proto = Some(proto);
+        self
+    }
+
+    /// Only match packets sent from `port`.
+    pub fn src_port(self, port: u16) -> Self {
+        self.src_ports(port, port)
+    }
+
+    /// Only match packets sent from a port between `start` and `end`, inclusive.
+    pub fn src_ports(mut self, start: u16, end: u16) -> Self {
+        self.src_port = Some((start, end));
+        self
+    }
+
+    /// Only match packets sent to `port`.
+    pub fn dst_port(self, port: u16) -> Self {
+        self.dst_ports(port, port)
+    }
+
+    /// Only match packets sent to a port between `start` and `end`, inclusive.
+    pub fn dst_ports(mut self, start: u16, end: u16) -> Self {
+        self.dst_port = Some((start, end));
+        self
+    }
+
+    /// Only match packets whose connection is in `state`.
+    pub fn state(mut self, state: ConnState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Only match packets belonging to a established connection, usually paired with
+    /// `Action::Accept` to let the answers to our own requests through.
+    pub fn established(self) -> Self {
+        self.state(ConnState::Established)
+    }
+
+    fn matches(&self, direction: Direction, flow: &Flow, state: ConnState) -> bool {
+        let port_matches = |range: Option<(u16, u16)>, port: Option<u16>| match (range, port) {
+            (None, _) => true,
+            (Some((start, end)), Some(port)) => start <= port && port <= end,
+            (Some(_), None) => false,
+        };
+
+        self.direction == direction
+            && self.src.map_or(true, |x| x.contains(flow.src))
+            && self.dst.map_or(true, |x| x.contains(flow.dst))
+            && self.proto.map_or(true, |x| x == flow.proto)
+            && port_matches(self.src_port, flow.src_port)
+            && port_matches(self.dst_port, flow.dst_port)
+            && self.state.map_or(true, |x| x == state)
+    }
+}
+
+pub type EthernetHook = Box<dyn Fn(Direction, &Ether2Frame) -> Action + Send + Sync>;
+pub type IpHook = Box<dyn Fn(Direction, &Ipv4) -> Action + Send + Sync>;
+pub type TcpHook = Box<dyn Fn(Direction, &Flow, &Tcp) -> Action + Send + Sync>;
+
+type ConnKey = (u8, (Ipv4Addr, u16), (Ipv4Addr, u16)); // proto, local, remote
+
+struct ConnEntry {
+    /// Direction of the first packet of the connection.
+    origin: Direction,
+    /// Whether we have seen a packet going the other way.
+    replied: bool,
+    last_seen: u64,
+}
+
+#[derive(Default)]
+struct Hooks {
+    ethernet: Vec<EthernetHook>,
+    ip: Vec<IpHook>,
+    tcp: Vec<TcpHook>,
+}
+
+/// Struct holds our hooks, rules and connection tracking table. A static instance is used by all
+/// the layers of our stack.
+pub struct Firewall {
+    hooks: RwLock<Hooks>,
+    rules: RwLock<Vec<Rule>>,
+    /// Policy for packets no rule matched, ingress and egress.
+    policy: RwLock<(Action, Action)>,
+    conntrack: Mutex<HashMap<ConnKey, ConnEntry>>,
+    /// Number of flows `conntrack` holds before it starts evicting them.
+    conntrack_capacity: AtomicUsize,
+}
+
+impl Firewall {
+    pub fn new() -> Self {
+        Self {
+            hooks: RwLock::new(Hooks::default()),
+            rules: RwLock::new(Vec::new()),
+            policy: RwLock::new((Action::Accept, Action::Accept)),
+            conntrack: Mutex::new(HashMap::new()),
+            conntrack_capacity: AtomicUsize::new(CONNTRACK_CAPACITY),
+        }
+    }
+
+    /// Sets the number of flows the connection tracking table holds, once it is full new flows
+    /// push out the one that has been idle the longest.
+    pub fn set_conntrack_capacity(&self, capacity: usize) {
+        let capacity = capacity.max(1);
+        self.conntrack_capacity.store(capacity, Ordering::Relaxed);
+
+        let mut conntrack = self.conntrack.lock();
+        while conntrack.len() > capacity {
+            Self::evict(&mut conntrack);
+        }
+    }
+
+    /// Appends `rule` to the end of the rule list.
+    pub fn add_rule(&self, rule: Rule) {
+        self.rules.write().push(rule);
+    }
+
+    /// Inserts `rule` at `index`, shifting the rules after it.
+    pub fn insert_rule(&self, index: usize, rule: Rule) {
+        let mut rules = self.rules.write();
+        let index = index.min(rules.len());
+        rules.insert(index, rule);
+    }
+
+    /// Removes every rule, the policies are left untouched.
+    pub fn clear_rules(&self) {
+        self.rules.write().clear();
+    }
+
+    /// Sets what happens to packets going in `direction` that no rule matched.
+    pub fn set_policy(&self, direction: Direction, action: Action) {
+        let mut policy = self.policy.write();
+        match direction {
+            Direction::Ingress => policy.0 = action,
+            Direction::Egress => policy.1 = action,
+        }
+    }
+
+    pub fn add_ethernet_hook(&self, hook: EthernetHook) {
+        self.hooks.write().ethernet.push(hook);
+    }
+
+    pub fn add_ip_hook(&self, hook: IpHook) {
+        self.hooks.write().ip.push(hook);
+    }
+
+    pub fn add_tcp_hook(&self, hook: TcpHook) {
+        self.hooks.write().tcp.push(hook);
+    }
+
+    /// Removes every hook.
+    pub fn clear_hooks(&self) {
+        *self.hooks.write() = Hooks::default();
+    }
+
+    /// Function runs the ethernet hooks on `frame`.
+    pub fn check_ethernet(&self, direction: Direction, frame: &Ether2Frame) -> Action {
+        Self::run_hooks(
+            self.hooks
+                .read()
+                .ethernet
+                .iter()
+                .map(|x| x(direction, frame)),
+        )
+    }
+
+    /// Function runs the ip hooks followed by the rules on `packet`. Accepted packets update the
+    /// connection tracking table.
+    pub fn check_ip(&self, direction: Direction, packet: &Ipv4) -> Action {
+        let action = Self::run_hooks(self.hooks.read().ip.iter().map(|x| x(direction, packet)));
+        if action != Action::Accept {
+            return action;
+        }
+
+        let rules = self.rules.read();
+        if rules.is_empty() {
+            return Action::Accept;
+        }
+
+        let flow = Flow::from_ipv4(packet);
+        let key = flow.conn_key(direction);
+        let now = get_milis();
+
+        let mut conntrack = self.conntrack.lock();
+        let state = match conntrack.get(&key) {
+            Some(x) if x.replied || x.origin != direction => ConnState::Established,
+            _ => ConnState::New,
+        };
+
+        let action = rules
+            .iter()
+            .find(|x| x.matches(direction, &flow, state))
+            .map(|x| x.action)
+            .unwrap_or_else(|| {
+                let policy = self.policy.read();
+                match direction {
+                    Direction::Ingress => policy.0,
+                    Direction::Egress => policy.1,
+                }
+            });
+
+        if action == Action::Accept {
+            let is_rst = flow.proto == Ipv4Proto::TCP
+                && packet
+                    .data()
+                    .get(TCP_FLAGS_OFFSET)
+                    .map_or(false, |x| x & TCP_RST != 0);
+
+            if is_rst {
+                conntrack.remove(&key);
+            } else {
+                let capacity = self.conntrack_capacity.load(Ordering::Relaxed);
+                if conntrack.len() >= capacity && !conntrack.contains_key(&key) {
+                    Self::evict(&mut conntrack);
+                }
+
+                let entry = conntrack.entry(key).or_insert(ConnEntry {
+                    origin: direction,
+                    replied: false,
+                    last_seen: now,
+                });
+                entry.replied |= entry.origin != direction;
+                entry.last_seen = now;
+            }
+        }
+
+        action
+    }
+
+    /// Function runs the tcp hooks on `packet`.
+    pub fn check_tcp(&self, direction: Direction, flow: &Flow, packet: &Tcp) -> Action {
+        Self::run_hooks(
+            self.hooks
+                .read()
+                .tcp
+                .iter()
+                .map(|x| x(direction, flow, packet)),
+        )
+    }
+
+    /// Function drops the flow that has been idle the longest, flows that never got a answer go
+    /// first since floods are made of those.
+    fn evict(conntrack: &mut HashMap<ConnKey, ConnEntry>) {
+        let victim = conntrack
+            .iter()
+            .min_by_key(|(_, x)| (x.replied, x.last_seen))
+            .map(|(key, _)| *key);
+
+        if let Some(key) = victim {
+            conntrack.remove(&key);
+            stats::CONNTRACK.evict();
+        }
+    }
+
+    fn run_hooks(mut verdicts: impl Iterator<Item = Action>) -> Action {
+        verdicts
+            .find(|x| *x != Action::Accept)
+            .unwrap_or(Action::Accept)
+    }
+
+    /// Function expires idle connections, `NetworkDevice::run_forever` calls this periodically.
+    pub fn poll_timers(&self) {
+        let now = get_milis();
+        let tcp = Ipv4Proto::TCP.raw();
+
+        self.conntrack.lock().retain(|key, entry| {
+            let timeout = if key.0 == tcp {
+                CONNTRACK_TCP_TIMEOUT
+            } else {
+                CONNTRACK_TIMEOUT
+            };
+
+            now - entry.last_seen < timeout
+        });
+    }
+}
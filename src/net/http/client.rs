@@ -0,0 +1,581 @@
+use super::conn::Connection;
+use super::conn::Framing;
+use super::conn::Stream;
+use super::parse_head;
+use super::Error;
+use super::Headers;
+use super::Method;
+use super::Response;
+
+use crate::arch::pit::get_milis;
+use crate::async_;
+use crate::collections::HashMap;
+use crate::net::dns;
+use crate::net::socks::TcpStream;
+use crate::net::tls::TlsConnector;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::time::Duration;
+
+use spin::Mutex;
+
+/// Connections in the pool are keyed by whether they are https, the host and the port.
+type PoolKey = (bool, String, u16);
+
+/// The parts of a url we need to send a request.
+#[derive(Clone, Debug)]
+pub(super) struct Url {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starting with a `/`.
+    pub target: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(Error::InvalidUrl);
+        };
+
+        let rest = rest.split('#').next().unwrap_or("");
+        let (authority, target) = match rest.find(|x| x == '/' || x == '?') {
+            Some(x) if rest[x..].starts_with('?') => (&rest[..x], format!("/{}", &rest[x..])),
+            Some(x) => (&rest[..x], rest[x..].into()),
+            None => (rest, "/".into()),
+        };
+
+        // credentials in urls are deprecated (RFC 7230 2.7.1), we dont send them.
+        if authority.contains('@') {
+            return Err(Error::InvalidUrl);
+        }
+
+        let default_port = if https { 443 } else { 80 };
+        let (host, port) = match authority.rfind(':') {
+            Some(x) => (
+                &authority[..x],
+                authority[x + 1..].parse().map_err(|_| Error::InvalidUrl)?,
+            ),
+            None => (authority, default_port),
+        };
+
+        if host.is_empty() || target.bytes().any(|x| x <= b' ') {
+            return Err(Error::InvalidUrl);
+        }
+
+        Ok(Self {
+            https,
+            host: host.to_ascii_lowercase(),
+            port,
+            target,
+        })
+    }
+
+    /// Returns `location` resolved against this url.
+    fn join(&self, location: &str) -> Result<Self, Error> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Self::parse(location);
+        }
+
+        let scheme = if self.https { "https" } else { "http" };
+        if location.starts_with("//") {
+            return Self::parse(&format!("{}:{}", scheme, location));
+        }
+
+        let target = if location.starts_with('/') {
+            location.into()
+        } else {
+            let path = self.target.split('?').next().unwrap_or("/");
+            let dir = &path[..path.rfind('/').map(|x| x + 1).unwrap_or(1)];
+            format!("{}{}", dir, location)
+        };
+
+        Ok(Self {
+            target,
+            ..self.clone()
+        })
+    }
+
+    fn key(&self) -> PoolKey {
+        (self.https, self.host.clone(), self.port)
+    }
+
+    /// Returns the value of the host header, the port is left out if it is the default one.
+    pub fn authority(&self) -> String {
+        match (self.https, self.port) {
+            (false, 80) | (true, 443) => self.host.clone(),
+            (_, port) => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+/// A connection waiting in the pool for its next request.
+struct Idle {
+    conn: Connection,
+    /// Time (ms) it was put in the pool.
+    since: u64,
+}
+
+#[derive(Clone)]
+struct Config {
+    timeout: Duration,
+    connect_timeout: Duration,
+    max_redirects: usize,
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+    max_header_size: usize,
+    max_body_size: usize,
+    user_agent: Option<String>,
+    tls: Option<TlsConnector>,
+}
+
+struct Inner {
+    config: Config,
+    pool: Mutex<HashMap<PoolKey, Vec<Idle>>>,
+}
+
+/// A http client, cheap to clone. Connections to a host are kept open and reused by later
+/// requests to it.
+///
+/// ```ignore
+/// let client = Client::builder().timeout(Duration::from_secs(5)).build();
+/// let response = client
+///     .post("http://10.0.0.2/users")
+///     .json("{\"name\":\"foo\"}")
+///     .send()
+///     .await?;
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        ClientBuilder::new().build()
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        RequestBuilder {
+            client: self.clone(),
+            method,
+            url: Url::parse(url),
+            headers: Headers::new(),
+            body: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Get, url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Head, url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Post, url)
+    }
+
+    pub fn put(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Put, url)
+    }
+
+    pub fn delete(&self, url: &str) -> RequestBuilder {
+        self.request(Method::Delete, url)
+    }
+
+    /// Function closes every idle connection in the pool.
+    pub fn clear_pool(&self) {
+        self.inner.pool.lock().clear();
+    }
+
+    /// Function sends a request, following redirects.
+    async fn execute(
+        &self,
+        mut method: Method,
+        mut url: Url,
+        mut headers: Headers,
+        mut body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let config = &self.inner.config;
+        let mut redirects = 0;
+
+        loop {
+            let response = self.send_once(&method, &url, &headers, &body).await?;
+
+            let location = match response.status {
+                301 | 302 | 303 | 307 | 308 => response.headers.get("location"),
+                _ => None,
+            };
+
+            let location = match location {
+                Some(x) if config.max_redirects > 0 => x,
+                _ => return Ok(response),
+            };
+
+            redirects += 1;
+            if redirects > config.max_redirects {
+                return Err(Error::TooManyRedirects);
+            }
+
+            let next = url.join(location)?;
+
+            // 303 always turns into a GET, and so do POSTs redirected with 301 or 302 in
+            // practice (RFC 7231 6.4).
+            let to_get = match response.status {
+                303 => method != Method::Head,
+                301 | 302 => method == Method::Post,
+                _ => false,
+            };
+
+            if to_get {
+                method = Method::Get;
+                body.clear();
+                headers.remove("content-type");
+            }
+
+            // dont hand our credentials to some other host.
+            if next.key() != url.key() {
+                headers.remove("authorization");
+                headers.remove("cookie");
+            }
+
+            url = next;
+        }
+    }
+
+    /// Function sends a single request, on a pooled connection if there is one. A pooled
+    /// connection the server closed in the meantime is retried on a fresh one, but only for
+    /// idempotent methods as the server might have acted on the request (RFC 7230 6.3.1).
+    async fn send_once(
+        &self,
+        method: &Method,
+        url: &Url,
+        headers: &Headers,
+        body: &[u8],
+    ) -> Result<Response, Error> {
+        let mut buf = format!("{} {} HTTP/1.1\r\n", method, url.target).into_bytes();
+        let mut headers = headers.clone();
+
+        if !headers.contains("host") {
+            headers.insert("host", &url.authority());
+        }
+
+        if !headers.contains("user-agent") {
+            if let Some(agent) = &self.inner.config.user_agent {
+                headers.insert("user-agent", agent);
+            }
+        }
+
+        match method {
+            Method::Post | Method::Put | Method::Patch => {
+                headers.insert("content-length", &body.len().to_string())
+            }
+            _ if !body.is_empty() => headers.insert("content-length", &body.len().to_string()),
+            _ => {}
+        }
+
+        headers.write_to(&mut buf);
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(body);
+
+        if let Some(conn) = self.pooled(url) {
+            match self.exchange(conn, method, url, &buf).await {
+                Err(Error::Closed) if method.is_idempotent() => {}
+                x => return x,
+            }
+        }
+
+        let conn = self.connect(url).await?;
+        self.exchange(conn, method, url, &buf).await
+    }
+
+    /// Function sends `request` on `conn` and reads the response, the connection goes back to
+    /// the pool if it can be reused.
+    async fn exchange(
+        &self,
+        mut conn: Connection,
+        method: &Method,
+        url: &Url,
+        request: &[u8],
+    ) -> Result<Response, Error> {
+        let config = &self.inner.config;
+        conn.write(request).await;
+
+        // interim responses are skipped, we never ask to switch protocols.
+        let (version, mut response) = loop {
+            let head = conn.read_head(config.max_header_size).await?;
+            let (start, headers) = parse_head(&head)?;
+            let (version, status) = parse_status(start)?;
+
+            if status >= 200 {
+                let mut response = Response::new(status);
+                response.headers = headers;
+                break (version, response);
+            }
+        };
+
+        let framing = if *method == Method::Head || response.is_bodyless() {
+            Framing::Empty
+        } else {
+            Framing::from_headers(&response.headers)?
+        };
+
+        response.body = conn.read_body(framing, config.max_body_size).await?;
+
+        let keep_alive = framing != Framing::UntilClose
+            && !response.headers.has_token("connection", "close")
+            && (version >= 1 || response.headers.has_token("connection", "keep-alive"));
+
+        if keep_alive {
+            self.release(url, conn);
+        }
+
+        Ok(response)
+    }
+
+    /// Returns a idle connection to the host of `url`, connections idle for too long are
+    /// dropped.
+    fn pooled(&self, url: &Url) -> Option<Connection> {
+        let mut pool = self.inner.pool.lock();
+        let idle = pool.get_mut(&url.key())?;
+        let now = get_milis();
+        let timeout = self.inner.config.idle_timeout.as_millis() as u64;
+
+        while let Some(x) = idle.pop() {
+            if now - x.since < timeout {
+                return Some(x.conn);
+            }
+        }
+
+        None
+    }
+
+    fn release(&self, url: &Url, conn: Connection) {
+        let mut pool = self.inner.pool.lock();
+        let idle = pool.entry(url.key()).or_insert_with(Vec::new);
+
+        if idle.len() >= self.inner.config.max_idle_per_host {
+            idle.remove(0);
+        }
+
+        idle.push(Idle {
+            conn,
+            since: get_milis(),
+        });
+    }
+
+    async fn connect(&self, url: &Url) -> Result<Connection, Error> {
+        let config = &self.inner.config;
+        open(url, config.tls.as_ref(), config.connect_timeout).await
+    }
+}
+
+/// Function opens a connection to the host of `url`, trying each of its addresses in turn.
+pub(super) async fn open(
+    url: &Url,
+    tls: Option<&TlsConnector>,
+    connect_timeout: Duration,
+) -> Result<Connection, Error> {
+    let tls = match (tls, url.https) {
+        (_, false) => None,
+        (Some(x), true) => Some(x),
+        (None, true) => return Err(Error::UnsupportedScheme),
+    };
+
+    let addrs = dns::resolve(&url.host).await.map_err(Error::Dns)?;
+
+    for addr in addrs {
+        let tcp = match async_::timeout(connect_timeout, TcpStream::connect((addr, url.port))).await
+        {
+            Some(Ok(x)) => x,
+            _ => continue,
+        };
+
+        let stream = match tls {
+            Some(tls) => Stream::Tls(tls.connect(&url.host, tcp).await.map_err(Error::Tls)?),
+            None => Stream::Tcp(tcp),
+        };
+
+        return Ok(Connection::new(stream));
+    }
+
+    Err(Error::Connect)
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the minor version and status of a status line.
+pub(super) fn parse_status(line: &str) -> Result<(u8, u16), Error> {
+    let mut parts = line.splitn(3, ' ');
+
+    let version = match parts.next() {
+        Some("HTTP/1.1") => 1,
+        Some("HTTP/1.0") => 0,
+        _ => return Err(Error::Malformed),
+    };
+
+    let status = parts.next().ok_or(Error::Malformed)?;
+    if status.len() != 3 || !status.bytes().all(|x| x.is_ascii_digit()) {
+        return Err(Error::Malformed);
+    }
+
+    Ok((version, status.parse().map_err(|_| Error::Malformed)?))
+}
+
+/// Options of a `Client`.
+pub struct ClientBuilder {
+    config: Config,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: Config {
+                timeout: Duration::from_secs(30),
+                connect_timeout: Duration::from_secs(10),
+                max_redirects: 10,
+                max_idle_per_host: 8,
+                idle_timeout: Duration::from_secs(30),
+                max_header_size: 16 * 1024,
+                max_body_size: 16 * 1024 * 1024,
+                user_agent: Some("vallicks".into()),
+                tls: None,
+            },
+        }
+    }
+
+    /// Sets how long a request can take as a whole, redirects included. Defaults to 30
+    /// seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Sets how long we wait for a single address of a host to accept a connection, defaults
+    /// to 10 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how many redirects are followed, 0 returns redirects as they are. Defaults to 10.
+    pub fn max_redirects(mut self, max: usize) -> Self {
+        self.config.max_redirects = max;
+        self
+    }
+
+    /// Sets how many idle connections we keep around per host, defaults to 8.
+    pub fn max_idle_per_host(mut self, max: usize) -> Self {
+        self.config.max_idle_per_host = max;
+        self
+    }
+
+    /// Sets how long a idle connection is kept around, defaults to 30 seconds.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the biggest response body we accept, defaults to 16 MiB.
+    pub fn max_body_size(mut self, max: usize) -> Self {
+        self.config.max_body_size = max;
+        self
+    }
+
+    /// Sets the user agent sent with every request, `None` sends none.
+    pub fn user_agent(mut self, agent: Option<&str>) -> Self {
+        self.config.user_agent = agent.map(Into::into);
+        self
+    }
+
+    /// Sets the connector https urls are opened with, without one they fail with
+    /// `Error::UnsupportedScheme`.
+    pub fn tls(mut self, connector: TlsConnector) -> Self {
+        self.config.tls = Some(connector);
+        self
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            inner: Arc::new(Inner {
+                config: self.config,
+                pool: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A request being built, sent with `send`.
+pub struct RequestBuilder {
+    client: Client,
+    method: Method,
+    url: Result<Url, Error>,
+    headers: Headers,
+    body: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl RequestBuilder {
+    /// Sets the header `name`, replacing any value it had.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn text(self, body: impl Into<String>) -> Self {
+        self.header("content-type", "text/plain; charset=utf-8")
+            .body(body.into())
+    }
+
+    pub fn json(self, body: impl Into<String>) -> Self {
+        self.header("content-type", "application/json")
+            .body(body.into())
+    }
+
+    /// Sets how long this request can take, instead of the timeout of the client.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Function sends the request and waits for the whole response.
+    pub async fn send(self) -> Result<Response, Error> {
+        let url = self.url?;
+        let timeout = self.timeout.unwrap_or(self.client.inner.config.timeout);
+        let client = self.client;
+
+        async_::timeout(
+            timeout,
+            client.execute(self.method, url, self.headers, self.body),
+        )
+        .await
+        .unwrap_or(Err(Error::Timeout))
+    }
+}
@@ -0,0 +1,274 @@
+//! Buffered reading of messages off a plain or TLS stream.
+
+use super::Error;
+use super::Headers;
+
+use crate::net::socks::TcpStream;
+use crate::net::tls::TlsStream;
+use crate::prelude::*;
+
+/// Size of the reads we do on the underlying stream.
+const READ_SIZE: usize = 4096;
+
+/// Longest chunk size line we accept, the size plus any extensions.
+const MAX_CHUNK_LINE: usize = 1024;
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub async fn read(&mut self, buffer: &mut [u8]) -> usize {
+        match self {
+            Self::Tcp(x) => x.read(buffer).await,
+            Self::Tls(x) => x.read(buffer).await,
+        }
+    }
+
+    pub async fn write(&mut self, item: &[u8]) {
+        match self {
+            Self::Tcp(x) => x.write(item).await,
+            Self::Tls(x) => x.write(item).await,
+        }
+    }
+
+    /// Function closes our side of the stream, we can still read until our peer closes too.
+    pub async fn close(&mut self) {
+        match self {
+            Self::Tcp(x) => x.shutdown().await,
+            Self::Tls(x) => x.close().await,
+        }
+    }
+}
+
+/// How the end of a message body is found (RFC 7230 3.3.3).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    /// The body ends when the connection closes, only for responses.
+    UntilClose,
+}
+
+impl Framing {
+    /// Returns the framing the headers of a message ask for, `UntilClose` if they dont ask for
+    /// any.
+    pub fn from_headers(headers: &Headers) -> Result<Self, Error> {
+        if headers.contains("transfer-encoding") {
+            let last = headers
+                .get_all("transfer-encoding")
+                .flat_map(|x| x.split(','))
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .last();
+
+            return match last {
+                Some(x) if x.eq_ignore_ascii_case("chunked") => Ok(Self::Chunked),
+                _ => Err(Error::UnsupportedEncoding),
+            };
+        }
+
+        let mut length = None;
+        for value in headers.get_all("content-length").flat_map(|x| x.split(',')) {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|x| x.is_ascii_digit()) {
+                return Err(Error::Malformed);
+            }
+
+            let value = value.parse::<usize>().map_err(|_| Error::BodyTooLarge)?;
+            if length.replace(value).map_or(false, |x| x != value) {
+                return Err(Error::Malformed);
+            }
+        }
+
+        Ok(length.map(Self::Length).unwrap_or(Self::UntilClose))
+    }
+}
+
+pub struct Connection {
+    pub stream: Stream,
+    /// Bytes read off the stream that havent been consumed yet.
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns whether there are bytes buffered that havent been consumed yet.
+    pub fn has_buffered(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Returns the bytes buffered that havent been consumed yet, for protocols that take over
+    /// the connection after a upgrade.
+    pub fn buffered(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    pub async fn write(&mut self, item: &[u8]) {
+        self.stream.write(item).await
+    }
+
+    pub async fn close(&mut self) {
+        self.stream.close().await
+    }
+
+    /// Function reads more bytes off the stream into our buffer.
+    pub async fn fill(&mut self) -> Result<(), Error> {
+        let mut buf = [0; READ_SIZE];
+        let len = self.stream.read(&mut buf).await;
+
+        if len == 0 {
+            return Err(Error::Closed);
+        }
+
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(())
+    }
+
+    /// Function reads a start line and headers, returns them without the empty line ending
+    /// them. Empty lines before the start line are skipped (RFC 7230 3.5).
+    pub async fn read_head(&mut self, limit: usize) -> Result<Vec<u8>, Error> {
+        let mut searched = 0;
+
+        loop {
+            while self.buf.starts_with(b"\r\n") || self.buf.starts_with(b"\n") {
+                let len = if self.buf[0] == b'\r' { 2 } else { 1 };
+                self.buf.drain(..len);
+                searched = 0;
+            }
+
+            if let Some(end) = find_head_end(&self.buf, searched) {
+                let head = self.buf[..end].to_vec();
+                let terminator = if self.buf[end + 1] == b'\r' { 3 } else { 2 };
+
+                self.buf.drain(..end + terminator);
+                return Ok(head);
+            }
+
+            if self.buf.len() > limit {
+                return Err(Error::HeadersTooLarge);
+            }
+
+            searched = self.buf.len().saturating_sub(2);
+            self.fill().await?;
+        }
+    }
+
+    /// Function reads a body framed as `framing`, at most `limit` bytes of it.
+    pub async fn read_body(&mut self, framing: Framing, limit: usize) -> Result<Vec<u8>, Error> {
+        match framing {
+            Framing::Empty => Ok(Vec::new()),
+            Framing::Length(len) if len > limit => Err(Error::BodyTooLarge),
+            Framing::Length(len) => self.read_exact(len).await,
+            Framing::Chunked => self.read_chunked(limit).await,
+            Framing::UntilClose => {
+                loop {
+                    if self.buf.len() > limit {
+                        return Err(Error::BodyTooLarge);
+                    }
+
+                    match self.fill().await {
+                        Ok(()) => {}
+                        Err(Error::Closed) => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                Ok(core::mem::take(&mut self.buf))
+            }
+        }
+    }
+
+    async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Function reads a line ending in a crlf or a bare lf, returns it without the line ending.
+    async fn read_line(&mut self, limit: usize) -> Result<Vec<u8>, Error> {
+        let mut searched = 0;
+
+        loop {
+            if let Some(end) = self.buf[searched..].iter().position(|x| *x == b'\n') {
+                let end = searched + end;
+                let mut line: Vec<u8> = self.buf.drain(..end + 1).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                return Ok(line);
+            }
+
+            if self.buf.len() > limit {
+                return Err(Error::Malformed);
+            }
+
+            searched = self.buf.len();
+            self.fill().await?;
+        }
+    }
+
+    /// Function reads a chunked body (RFC 7230 4.1), trailers are dropped.
+    async fn read_chunked(&mut self, limit: usize) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = self.read_line(MAX_CHUNK_LINE).await?;
+            let line = core::str::from_utf8(&line).map_err(|_| Error::Malformed)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+
+            if size.is_empty() || !size.bytes().all(|x| x.is_ascii_hexdigit()) {
+                return Err(Error::Malformed);
+            }
+
+            let size = usize::from_str_radix(size, 16).map_err(|_| Error::BodyTooLarge)?;
+            if size == 0 {
+                break;
+            }
+
+            if size > limit.saturating_sub(body.len()) {
+                return Err(Error::BodyTooLarge);
+            }
+
+            body.extend(self.read_exact(size).await?);
+            if !self.read_line(2).await?.is_empty() {
+                return Err(Error::Malformed);
+            }
+        }
+
+        // trailers, up to the empty line ending the message.
+        let mut trailers = 0;
+        loop {
+            let line = self.read_line(MAX_CHUNK_LINE).await?;
+            if line.is_empty() {
+                return Ok(body);
+            }
+
+            trailers += line.len();
+            if trailers > limit.max(MAX_CHUNK_LINE) {
+                return Err(Error::HeadersTooLarge);
+            }
+        }
+    }
+}
+
+/// Returns the position of the line feed ending the last header line, searching from `from`.
+/// The empty line after it can end in a crlf or a bare lf.
+fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find(|x| {
+        let rest = &buf[*x..];
+        rest.starts_with(b"\n\n") || rest.starts_with(b"\n\r\n")
+    })
+}
@@ -0,0 +1,526 @@
+//! A small HTTP/1.1 (RFC 7230) framework on top of our tcp streams.
+//!
+//! ```ignore
+//! let router = Router::new()
+//!     .get("/", |_| async { Response::ok().text("hello") })
+//!     .get("/users/:id", |req: Request| async move {
+//!         Response::ok().text(format!("user {}", req.param("id").unwrap()))
+//!     });
+//!
+//! Server::bind(80, router)?.max_connections(64).run().await;
+//!
+//! let client = Client::new();
+//! let response = client.get("http://10.0.0.2/users/1").send().await?;
+//! ```
+/// Http client with connection pooling
+pub mod client;
+mod conn;
+/// Http server and router
+pub mod server;
+/// WebSocket client and server
+pub mod websocket;
+
+pub use client::Client;
+pub use server::Router;
+pub use server::Server;
+pub use websocket::WebSocket;
+
+use crate::net::addr::SocketAddr;
+use crate::net::dns::DnsError;
+use crate::net::tls;
+use crate::prelude::*;
+
+use core::fmt;
+
+/// Errors that can come up while reading or writing a message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The connection closed before the message was complete.
+    Closed,
+    /// The message didnt arrive in time.
+    Timeout,
+    /// The message isnt valid HTTP/1.1.
+    Malformed,
+    /// The start line and headers are bigger than we allow.
+    HeadersTooLarge,
+    /// The body is bigger than we allow.
+    BodyTooLarge,
+    /// The message uses a transfer coding other than chunked.
+    UnsupportedEncoding,
+    /// The url isnt a valid http or https url.
+    InvalidUrl,
+    /// The url is https but the client has no TLS connector.
+    UnsupportedScheme,
+    /// The host of the url couldnt be resolved.
+    Dns(DnsError),
+    /// None of the addresses of the host accepted a connection.
+    Connect,
+    /// The TLS handshake with the host failed.
+    Tls(tls::Error),
+    /// The server redirected us more often than we allow.
+    TooManyRedirects,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection closed"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Malformed => write!(f, "malformed message"),
+            Self::HeadersTooLarge => write!(f, "headers too large"),
+            Self::BodyTooLarge => write!(f, "body too large"),
+            Self::UnsupportedEncoding => write!(f, "unsupported transfer encoding"),
+            Self::InvalidUrl => write!(f, "invalid url"),
+            Self::UnsupportedScheme => write!(f, "https without a tls connector"),
+            Self::Dns(x) => write!(f, "dns lookup failed: {:?}", x),
+            Self::Connect => write!(f, "couldnt connect"),
+            Self::Tls(x) => write!(f, "tls handshake failed: {}", x),
+            Self::TooManyRedirects => write!(f, "too many redirects"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Patch => "PATCH",
+            Self::Options => "OPTIONS",
+            Self::Connect => "CONNECT",
+            Self::Trace => "TRACE",
+            Self::Other(x) => x,
+        }
+    }
+
+    /// Returns whether sending the request twice has the same effect as sending it once
+    /// (RFC 7231 4.2.2).
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Self::Get | Self::Head | Self::Put | Self::Delete | Self::Options | Self::Trace
+        )
+    }
+}
+
+impl From<&str> for Method {
+    fn from(method: &str) -> Self {
+        match method {
+            "GET" => Self::Get,
+            "HEAD" => Self::Head,
+            "POST" => Self::Post,
+            "PUT" => Self::Put,
+            "DELETE" => Self::Delete,
+            "PATCH" => Self::Patch,
+            "OPTIONS" => Self::Options,
+            "CONNECT" => Self::Connect,
+            "TRACE" => Self::Trace,
+            x => Self::Other(x.into()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Header fields of a message in the order they came in, names compare case insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Returns the first value of the header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Returns every value of the header `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, x)| x.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets the header `name` to `value`, replacing any values it had.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a value for the header `name`, keeping the ones it had.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(x, _)| !x.eq_ignore_ascii_case(name));
+    }
+
+    /// Returns whether the comma separated header `name` has `token` in it, like `close` in
+    /// `Connection: keep-alive, close`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|x| x.split(','))
+            .any(|x| x.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(x, y)| (x.as_str(), y.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        for (name, value) in self.0.iter() {
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+/// A request received by a server.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: Method,
+    /// Path of the request target, still percent encoded.
+    pub path: String,
+    /// Query of the request target without the `?`.
+    pub query: Option<String>,
+    /// Minor version of HTTP/1.x.
+    pub version: u8,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Parameters the router matched in the path, percent decoded.
+    pub params: Vec<(String, String)>,
+    pub peer: SocketAddr,
+}
+
+impl Request {
+    /// Returns the first value of the header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Returns the path parameter `name`, `:name` or `*name` in the route.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, x)| x.as_str())
+    }
+
+    /// Returns the percent decoded value of the query parameter `name`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .map(|x| x.splitn(2, '=').collect::<Vec<_>>())
+            .find(|x| percent_decode(x[0], true) == name)
+            .map(|x| {
+                x.get(1)
+                    .map(|x| percent_decode(x, true))
+                    .unwrap_or_default()
+            })
+    }
+
+    /// Returns the body as text, `None` if it isnt utf-8.
+    pub fn text(&self) -> Option<&str> {
+        core::str::from_utf8(&self.body).ok()
+    }
+
+    /// Returns whether the client wants to keep the connection open after this request.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("connection", "close") {
+            return false;
+        }
+
+        self.version >= 1 || self.headers.has_token("connection", "keep-alive")
+    }
+}
+
+/// A response, built with `Response::new(status)` or one of its shortcuts. The client returns
+/// the responses it gets as one too.
+///
+/// ```ignore
+/// Response::new(201).header("location", "/users/1").json("{\"id\":1}")
+/// ```
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::new(200)
+    }
+
+    pub fn no_content() -> Self {
+        Self::new(204)
+    }
+
+    pub fn bad_request() -> Self {
+        Self::new(400)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404)
+    }
+
+    pub fn internal_error() -> Self {
+        Self::new(500)
+    }
+
+    /// Returns a redirect to `location`, `status` should be one of 301, 302, 303, 307 or 308.
+    pub fn redirect(status: u16, location: &str) -> Self {
+        Self::new(status).header("location", location)
+    }
+
+    /// Sets the header `name`, replacing any value it had.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn text(self, body: impl Into<String>) -> Self {
+        self.header("content-type", "text/plain; charset=utf-8")
+            .body(body.into())
+    }
+
+    pub fn html(self, body: impl Into<String>) -> Self {
+        self.header("content-type", "text/html; charset=utf-8")
+            .body(body.into())
+    }
+
+    pub fn json(self, body: impl Into<String>) -> Self {
+        self.header("content-type", "application/json")
+            .body(body.into())
+    }
+
+    /// Returns whether a response with this status never has a body.
+    fn is_bodyless(&self) -> bool {
+        self.status < 200 || self.status == 204 || self.status == 304
+    }
+
+    /// Function serializes the status line and headers, `content-length` is set from the body.
+    fn head(&mut self) -> Vec<u8> {
+        if !self.is_bodyless() {
+            self.headers
+                .insert("content-length", &self.body.len().to_string());
+        }
+
+        let mut buf = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status)).into_bytes();
+        self.headers.write_to(&mut buf);
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+}
+
+/// Returns the reason phrase of `status`.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Returns `x` with its percent escapes decoded, and `+` as a space if `plus` is set. Broken
+/// escapes are kept as they are.
+pub fn percent_decode(x: &str, plus: bool) -> String {
+    let bytes = x.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = |x: Option<&u8>| x.and_then(|x| (*x as char).to_digit(16));
+
+        match bytes[i] {
+            b'%' => match (hex(bytes.get(i + 1)), hex(bytes.get(i + 2))) {
+                (Some(hi), Some(lo)) => {
+                    out.push((hi * 16 + lo) as u8);
+                    i += 3;
+                    continue;
+                }
+                _ => out.push(b'%'),
+            },
+            b'+' if plus => out.push(b' '),
+            x => out.push(x),
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Returns whether `name` is a valid header name (RFC 7230 3.2.6).
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&x))
+}
+
+/// Parses the start line and header fields of a message, `head` doesnt include the empty line
+/// ending it.
+fn parse_head(head: &[u8]) -> Result<(&str, Headers), Error> {
+    let head = core::str::from_utf8(head).map_err(|_| Error::Malformed)?;
+    let mut lines = head.split('\n').map(|x| x.strip_suffix('\r').unwrap_or(x));
+
+    let start = lines.next().ok_or(Error::Malformed)?;
+    let mut headers = Headers::new();
+
+    for line in lines {
+        // obsolete line folding isnt allowed anymore (RFC 7230 3.2.4).
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err(Error::Malformed);
+        }
+
+        let colon = line.find(':').ok_or(Error::Malformed)?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+
+        if !is_token(name) {
+            return Err(Error::Malformed);
+        }
+
+        headers.append(name, value.trim_matches(|x| x == ' ' || x == '\t'));
+    }
+
+    Ok((start, headers))
+}
+
+/// Parses a request head into a request without a body.
+fn parse_request(head: &[u8], peer: SocketAddr) -> Result<Request, Error> {
+    let (start, headers) = parse_head(head)?;
+    let mut parts = start.split(' ');
+
+    let method = parts
+        .next()
+        .filter(|x| is_token(x))
+        .ok_or(Error::Malformed)?;
+    let target = parts.next().ok_or(Error::Malformed)?;
+    let version = match parts.next() {
+        Some("HTTP/1.1") => 1,
+        Some("HTTP/1.0") => 0,
+        _ => return Err(Error::Malformed),
+    };
+
+    if parts.next().is_some() {
+        return Err(Error::Malformed);
+    }
+
+    // absolute form is only meant for proxies, but servers have to accept it (RFC 7230 5.3.2).
+    let target = match target.find("://") {
+        Some(x) => {
+            let rest = &target[x + 3..];
+            rest.find('/').map(|x| &rest[x..]).unwrap_or("/")
+        }
+        None => target,
+    };
+
+    if !target.starts_with('/') && target != "*" {
+        return Err(Error::Malformed);
+    }
+
+    // a http/1.1 request without a host isnt valid (RFC 7230 5.4).
+    if version == 1 && headers.get_all("host").count() != 1 {
+        return Err(Error::Malformed);
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(x) => (&target[..x], Some(target[x + 1..].into())),
+        None => (target, None),
+    };
+
+    Ok(Request {
+        method: Method::from(method),
+        path: path.into(),
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+        params: Vec::new(),
+        peer,
+    })
+}
@@ -0,0 +1,534 @@
+use super::conn::Connection;
+use super::conn::Framing;
+use super::conn::Stream;
+use super::parse_request;
+use super::percent_decode;
+use super::websocket;
+use super::Error;
+use super::Method;
+use super::Request;
+use super::Response;
+use super::WebSocket;
+
+use crate::async_;
+use crate::net::addr::SocketAddr;
+use crate::net::addr::ToSocketAddrs;
+use crate::net::socks::TcpListener;
+use crate::net::socks::TcpStream;
+use crate::net::tls::TlsAcceptor;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::future::Future;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::future::FutureExt;
+
+/// Number of connections past our limit we answer at once, the ones past that are closed
+/// without a answer.
+const MAX_REJECTING: usize = 64;
+
+type Handler = Box<dyn Fn(Request) -> BoxFuture<'static, Response> + Send + Sync>;
+type SocketHandler = Box<dyn Fn(WebSocket, Request) -> BoxFuture<'static, ()> + Send + Sync>;
+
+enum Segment {
+    Exact(String),
+    /// `:name`, matches a single segment.
+    Param(String),
+    /// `*name`, matches the rest of the path.
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+/// A route that upgrades its requests to a websocket.
+struct SocketRoute {
+    segments: Vec<Segment>,
+    handler: SocketHandler,
+}
+
+/// Returns the segments of the route `path`.
+fn parse_route(path: &str) -> Vec<Segment> {
+    path.split('/')
+        .skip(1)
+        .map(|x| {
+            if let Some(name) = x.strip_prefix(':') {
+                Segment::Param(name.into())
+            } else if let Some(name) = x.strip_prefix('*') {
+                Segment::Rest(name.into())
+            } else {
+                Segment::Exact(x.into())
+            }
+        })
+        .collect()
+}
+
+/// Returns the parameters of `path` if it matches the route `segments`.
+fn match_route(segments: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let mut parts = path.split('/').skip(1);
+    let mut params = Vec::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Rest(name) => {
+                let rest = path.splitn(i + 2, '/').nth(i + 1).unwrap_or("");
+                params.push((name.clone(), percent_decode(rest, false)));
+                return Some(params);
+            }
+            Segment::Exact(x) => {
+                if parts.next()? != x {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let part = parts.next().filter(|x| !x.is_empty())?;
+                params.push((name.clone(), percent_decode(part, false)));
+            }
+        }
+    }
+
+    match parts.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+/// Picks the handler of a request by its method and path.
+///
+/// Paths are matched segment by segment, `:name` matches any single segment and `*name` matches
+/// whatever is left of the path, both show up in `Request::params`. Routes are tried in the
+/// order they were added. `HEAD` requests fall back to the `GET` route of their path.
+pub struct Router {
+    routes: Vec<Route>,
+    sockets: Vec<SocketRoute>,
+    fallback: Option<Handler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            sockets: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Adds a route for `method` requests to `path`.
+    pub fn route<F, Fut>(mut self, method: Method, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            segments: parse_route(path),
+            handler: Box::new(move |x| handler(x).boxed()),
+        });
+
+        self
+    }
+
+    pub fn get<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    pub fn put<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Put, path, handler)
+    }
+
+    pub fn delete<F, Fut>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Delete, path, handler)
+    }
+
+    /// Adds a route upgrading `GET` requests to `path` to a websocket, the handler gets the
+    /// socket along with the request that opened it. Websocket routes are tried before the
+    /// others.
+    pub fn websocket<F, Fut>(mut self, path: &str, handler: F) -> Self
+    where
+        F: Fn(WebSocket, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.sockets.push(SocketRoute {
+            segments: parse_route(path),
+            handler: Box::new(move |x, y| handler(x, y).boxed()),
+        });
+
+        self
+    }
+
+    /// Sets the handler of requests no route matches, these get a 404 otherwise.
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        self.fallback = Some(Box::new(move |x| handler(x).boxed()));
+        self
+    }
+
+    /// Function runs the handler of `request`. Paths that only have routes for other methods
+    /// get a 405 listing them.
+    pub async fn handle(&self, mut request: Request) -> Response {
+        let mut allowed: Vec<&str> = Vec::new();
+
+        for method in [request.method.clone(), Method::Get].iter() {
+            for route in self.routes.iter() {
+                let params = match match_route(&route.segments, &request.path) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                if route.method == *method {
+                    request.params = params;
+                    return (route.handler)(request).await;
+                }
+
+                if !allowed.contains(&route.method.as_str()) {
+                    allowed.push(route.method.as_str());
+                }
+            }
+
+            if request.method != Method::Head {
+                break;
+            }
+        }
+
+        if let Some(fallback) = &self.fallback {
+            return fallback(request).await;
+        }
+
+        if allowed.is_empty() {
+            return Response::not_found();
+        }
+
+        Response::new(405).header("allow", &allowed.join(", "))
+    }
+
+    /// Returns the handler of the websocket route `request` matches, if any.
+    fn socket(&self, request: &mut Request) -> Option<&SocketHandler> {
+        if request.method != Method::Get {
+            return None;
+        }
+
+        self.sockets.iter().find_map(|route| {
+            let params = match_route(&route.segments, &request.path)?;
+            request.params = params;
+            Some(&route.handler)
+        })
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Limits a server applies to its connections.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_connections: usize,
+    request_timeout: Duration,
+    keep_alive_timeout: Duration,
+    max_header_size: usize,
+    max_body_size: usize,
+}
+
+/// A http server, every connection is served by its own task.
+///
+/// Requests on a connection are handled one after the other, a connection stays open between
+/// them unless the client asks for it to be closed.
+pub struct Server {
+    listener: TcpListener,
+    router: Arc<Router>,
+    tls: Option<TlsAcceptor>,
+    limits: Limits,
+    active: Arc<AtomicUsize>,
+    /// Connections past our limit that are still being answered.
+    rejecting: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// Function starts listening on `addr` for requests to `router`.
+    pub fn bind<A: ToSocketAddrs>(addr: A, router: Router) -> Result<Self, ()> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            router: Arc::new(router),
+            tls: None,
+            limits: Limits {
+                max_connections: 256,
+                request_timeout: Duration::from_secs(30),
+                keep_alive_timeout: Duration::from_secs(5),
+                max_header_size: 8 * 1024,
+                max_body_size: 1024 * 1024,
+            },
+            active: Arc::new(AtomicUsize::new(0)),
+            rejecting: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Sets how many connections are served at once, clients past that get a 503 unless too
+    /// many are waiting for one already. Defaults to 256.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = max;
+        self
+    }
+
+    /// Sets how long a client gets to send a whole request once it started sending it,
+    /// defaults to 30 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.request_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a idle connection is kept open waiting for the next request, defaults to
+    /// 5 seconds.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Sets the biggest start line and headers we accept, defaults to 8 KiB.
+    pub fn max_header_size(mut self, max: usize) -> Self {
+        self.limits.max_header_size = max;
+        self
+    }
+
+    /// Sets the biggest request body we accept, defaults to 1 MiB.
+    pub fn max_body_size(mut self, max: usize) -> Self {
+        self.limits.max_body_size = max;
+        self
+    }
+
+    /// Serves https with `acceptor` instead of plain http.
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    /// Returns how many connections are being served right now.
+    pub fn connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Function accepts connections and serves them until the listener goes away.
+    pub async fn run(mut self) {
+        while let Some(stream) = self.listener.accept().await {
+            let limits = self.limits;
+
+            if self.active.load(Ordering::Relaxed) >= limits.max_connections {
+                // answering takes a task too, so a flood of clients only gets its connections
+                // closed.
+                if self.rejecting.load(Ordering::Relaxed) < MAX_REJECTING {
+                    let guard = ActiveGuard::new(self.rejecting.clone());
+                    async_::spawn(async move {
+                        let _guard = guard;
+                        reject(stream, limits.request_timeout).await;
+                    });
+                }
+                continue;
+            }
+
+            let guard = ActiveGuard::new(self.active.clone());
+            let router = self.router.clone();
+            let tls = self.tls.clone();
+
+            async_::spawn(async move {
+                let _guard = guard;
+                let peer = stream.peer_addr().await;
+
+                let stream = match tls {
+                    Some(acceptor) => {
+                        match async_::timeout(limits.request_timeout, acceptor.accept(stream)).await
+                        {
+                            Some(Ok(x)) => Stream::Tls(x),
+                            _ => return,
+                        }
+                    }
+                    None => Stream::Tcp(stream),
+                };
+
+                serve(Connection::new(stream), peer, router, limits).await;
+            });
+        }
+    }
+}
+
+/// Counts a connection as active until it is dropped.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self(active)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Function turns away a connection past our limit.
+async fn reject(stream: TcpStream, timeout: Duration) {
+    let mut conn = Connection::new(Stream::Tcp(stream));
+    let mut response = Response::new(503)
+        .header("connection", "close")
+        .header("retry-after", "1");
+    finish(&mut conn, &response.head(), timeout).await;
+}
+
+/// Function writes `buf` to `conn`, giving up once `timeout` passed. Returns whether all of it
+/// got written.
+async fn write(conn: &mut Connection, buf: &[u8], timeout: Duration) -> bool {
+    async_::timeout(timeout, conn.write(buf)).await.is_some()
+}
+
+/// Function writes the last response on `conn` and closes it, so the client sees the end of it
+/// right away instead of whenever the connection gets dropped.
+async fn finish(conn: &mut Connection, buf: &[u8], timeout: Duration) {
+    if write(conn, buf, timeout).await {
+        let _ = async_::timeout(timeout, conn.close()).await;
+    }
+}
+
+/// Function serves the requests of a connection until either side wants it closed.
+async fn serve(mut conn: Connection, peer: SocketAddr, router: Arc<Router>, limits: Limits) {
+    loop {
+        // wait for the next request to start, then give it the request timeout to arrive.
+        if !conn.has_buffered() {
+            match async_::timeout(limits.keep_alive_timeout, conn.fill()).await {
+                Some(Ok(())) => {}
+                _ => return,
+            }
+        }
+
+        let mut request = match async_::timeout(
+            limits.request_timeout,
+            read_request(&mut conn, peer, &limits),
+        )
+        .await
+        {
+            Some(Ok(x)) => x,
+            Some(Err(Error::Closed)) => return,
+            Some(Err(e)) => return send_error(&mut conn, e, limits.request_timeout).await,
+            None => return send_error(&mut conn, Error::Timeout, limits.request_timeout).await,
+        };
+
+        // the connection belongs to the socket after a upgrade, we are done with it either way.
+        if let Some(handler) = router.socket(&mut request) {
+            return match websocket::handshake(&request) {
+                Ok(mut response) => {
+                    if write(&mut conn, &response.head(), limits.request_timeout).await {
+                        handler(WebSocket::spawn_server(conn), request).await;
+                    }
+                }
+                Err(mut response) => {
+                    finish(&mut conn, &response.head(), limits.request_timeout).await
+                }
+            };
+        }
+
+        let keep_alive = request.keep_alive();
+        let head = request.method == Method::Head;
+
+        let mut response = router.handle(request).await;
+        if !keep_alive {
+            response.headers.insert("connection", "close");
+        }
+
+        let keep_alive = keep_alive && !response.headers.has_token("connection", "close");
+        let mut buf = response.head();
+        if !head && !response.is_bodyless() {
+            buf.extend_from_slice(&response.body);
+        }
+
+        if !keep_alive {
+            return finish(&mut conn, &buf, limits.request_timeout).await;
+        }
+
+        // a client that doesnt read its responses would hold on to the connection forever.
+        if !write(&mut conn, &buf, limits.request_timeout).await {
+            return;
+        }
+    }
+}
+
+async fn read_request(
+    conn: &mut Connection,
+    peer: SocketAddr,
+    limits: &Limits,
+) -> Result<Request, Error> {
+    let head = conn.read_head(limits.max_header_size).await?;
+    let mut request = parse_request(&head, peer)?;
+
+    // requests dont have a body unless they say so (RFC 7230 3.3.3).
+    let framing = match Framing::from_headers(&request.headers)? {
+        Framing::UntilClose => Framing::Empty,
+        x => x,
+    };
+
+    if framing == Framing::Empty {
+        return Ok(request);
+    }
+
+    if let Framing::Length(len) = framing {
+        if len > limits.max_body_size {
+            return Err(Error::BodyTooLarge);
+        }
+    }
+
+    if request.version >= 1 && request.headers.has_token("expect", "100-continue") {
+        conn.write(b"HTTP/1.1 100 Continue\r\n\r\n").await;
+    }
+
+    request.body = conn.read_body(framing, limits.max_body_size).await?;
+    Ok(request)
+}
+
+/// Function answers a request we couldnt read with the matching status and gives up on the
+/// connection, we cant tell where the next request would start.
+async fn send_error(conn: &mut Connection, error: Error, timeout: Duration) {
+    let status = match error {
+        Error::Timeout => 408,
+        Error::HeadersTooLarge => 431,
+        Error::BodyTooLarge => 413,
+        Error::UnsupportedEncoding => 501,
+        _ => 400,
+    };
+
+    let mut response = Response::new(status).header("connection", "close");
+    finish(conn, &response.head(), timeout).await;
+}
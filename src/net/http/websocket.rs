@@ -0,0 +1,681 @@
+//! WebSockets (RFC 6455) on top of our http connections.
+//!
+//! Every socket is driven by its own task, which answers pings, puts fragmented messages back
+//! together and runs the closing handshake. Messages come out of the socket as a `Stream` and
+//! go into it through a `Sink` or `send`.
+//!
+//! ```ignore
+//! let router = Router::new().websocket("/echo", |mut ws: WebSocket, _| async move {
+//!     while let Some(Ok(msg)) = ws.recv().await {
+//!         if msg.is_data() {
+//!             let _ = ws.send(msg);
+//!         }
+//!     }
+//! });
+//!
+//! let mut ws = WebSocket::connect("ws://10.0.0.2/echo").await?;
+//! ws.send(Message::Text("hello".into()))?;
+//! ```
+use super::client;
+use super::client::Url;
+use super::conn::Connection;
+use super::conn::Stream as ConnStream;
+use super::parse_head;
+use super::parse_request;
+use super::Request;
+use super::Response;
+
+use crate::arch::random;
+use crate::async_;
+use crate::net::socks::TcpStream;
+use crate::net::tls::TlsConnector;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedReceiver;
+use crate::sync::mpsc::UnboundedSender;
+
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::future;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use sha1::Digest;
+use sha1::Sha1;
+
+/// Appended to the key of a client to get the accept value of the server (RFC 6455 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Biggest payload of a control frame.
+const MAX_CONTROL_LEN: usize = 125;
+/// Messages we send are split into frames of at most this many bytes.
+const MAX_FRAME_LEN: usize = 64 * 1024;
+/// Biggest message we accept, put together from all its frames.
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+/// Biggest handshake we accept.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+/// How long the opening handshake of a client can take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we wait for the close frame of our peer after sending ours.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_POLICY: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The opening handshake failed on the http level.
+    Http(super::Error),
+    /// Our peer didnt accept the opening handshake, or its answer was invalid.
+    Handshake,
+    /// The connection closed without a closing handshake.
+    Closed,
+    /// Our peer broke the protocol, we closed the connection with this close code.
+    Protocol(u16),
+}
+
+impl From<super::Error> for Error {
+    fn from(error: super::Error) -> Self {
+        Self::Http(error)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Pings are answered for us, they only show up for the curious.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The peer closed the connection, `None` if it didnt give a code.
+    Close(Option<CloseFrame>),
+}
+
+impl Message {
+    /// Returns whether this is a text or binary message.
+    pub fn is_data(&self) -> bool {
+        matches!(self, Self::Text(_) | Self::Binary(_))
+    }
+
+    /// Returns the payload of the message, the reason of close messages.
+    pub fn into_data(self) -> Vec<u8> {
+        match self {
+            Self::Text(x) => x.into_bytes(),
+            Self::Binary(x) | Self::Ping(x) | Self::Pong(x) => x,
+            Self::Close(x) => x.map(|x| x.reason.into_bytes()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// A WebSocket connection.
+pub struct WebSocket {
+    tx: UnboundedSender<Message>,
+    rx: UnboundedReceiver<Result<Message, Error>>,
+}
+
+impl WebSocket {
+    /// Function opens a connection to a `ws://` url.
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        Self::connect_with(url, None).await
+    }
+
+    /// Function opens a connection to a `ws://` url, or a `wss://` url if `tls` is given.
+    pub async fn connect_with(url: &str, tls: Option<&TlsConnector>) -> Result<Self, Error> {
+        let url = if let Some(rest) = url.strip_prefix("ws://") {
+            format!("http://{}", rest)
+        } else if let Some(rest) = url.strip_prefix("wss://") {
+            format!("https://{}", rest)
+        } else {
+            return Err(super::Error::InvalidUrl.into());
+        };
+
+        let url = Url::parse(&url)?;
+
+        async_::timeout(HANDSHAKE_TIMEOUT, async {
+            let mut conn = client::open(&url, tls, HANDSHAKE_TIMEOUT).await?;
+            let mut nonce = [0; 16];
+            random::fill(&mut nonce);
+            let key = base64(&nonce);
+
+            let request = format!(
+                "GET {} HTTP/1.1\r\nhost: {}\r\nupgrade: websocket\r\nconnection: Upgrade\r\n\
+                 sec-websocket-key: {}\r\nsec-websocket-version: {}\r\n\r\n",
+                url.target,
+                url.authority(),
+                key,
+                VERSION
+            );
+            conn.write(request.as_bytes()).await;
+
+            let head = conn.read_head(MAX_HEAD_LEN).await?;
+            let (start, headers) = parse_head(&head)?;
+            let (_, status) = client::parse_status(start)?;
+
+            let valid = status == 101
+                && headers.has_token("upgrade", "websocket")
+                && headers.has_token("connection", "upgrade")
+                && headers.get("sec-websocket-accept") == Some(accept_key(&key).as_str())
+                && !headers.contains("sec-websocket-extensions");
+
+            if !valid {
+                return Err(Error::Handshake);
+            }
+
+            Ok(Self::spawn(conn, Role::Client))
+        })
+        .await
+        .unwrap_or(Err(Error::Http(super::Error::Timeout)))
+    }
+
+    /// Function runs the server side of the opening handshake on a fresh connection. Requests
+    /// that arent a valid upgrade are turned away.
+    pub async fn accept(stream: TcpStream) -> Result<(Self, Request), Error> {
+        let peer = stream.peer_addr().await;
+        let mut conn = Connection::new(ConnStream::Tcp(stream));
+
+        let request = async_::timeout(HANDSHAKE_TIMEOUT, async {
+            let head = conn.read_head(MAX_HEAD_LEN).await?;
+            parse_request(&head, peer)
+        })
+        .await
+        .unwrap_or(Err(super::Error::Timeout))?;
+
+        match handshake(&request) {
+            Ok(mut response) => {
+                conn.write(&response.head()).await;
+                Ok((Self::spawn(conn, Role::Server), request))
+            }
+            Err(mut response) => {
+                conn.write(&response.head()).await;
+                Err(Error::Handshake)
+            }
+        }
+    }
+
+    /// Function starts the task driving the socket over `conn`.
+    pub(super) fn spawn_server(conn: Connection) -> Self {
+        Self::spawn(conn, Role::Server)
+    }
+
+    fn spawn(conn: Connection, role: Role) -> Self {
+        let (tx, outgoing) = channel();
+        let (incoming, rx) = channel();
+
+        let driver = Driver {
+            conn,
+            role,
+            incoming,
+            fragment: None,
+            sent_close: false,
+        };
+        async_::spawn(driver.run(outgoing));
+
+        Self { tx, rx }
+    }
+
+    /// Function queues `message` to be sent, fails once the socket is closed.
+    pub fn send(&self, message: Message) -> Result<(), Error> {
+        self.tx.send(message).map_err(|_| Error::Closed)
+    }
+
+    /// Function waits for the next message, returns `None` once the socket is closed.
+    pub async fn recv(&mut self) -> Option<Result<Message, Error>> {
+        self.rx.recv().await
+    }
+
+    /// Function starts the closing handshake, messages our peer sent before it closed its side
+    /// can still be received.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), Error> {
+        self.send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.tx.is_closed() {
+            return Poll::Ready(Err(Error::Closed));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _ = self.close(CLOSE_NORMAL, "");
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Returns the answer to the opening handshake in `request`, a error response if it isnt one
+/// we can accept.
+pub(super) fn handshake(request: &Request) -> Result<Response, Response> {
+    let key = request.header("sec-websocket-key").unwrap_or("");
+
+    let valid = request.method == super::Method::Get
+        && request.version >= 1
+        && request.headers.has_token("upgrade", "websocket")
+        && request.headers.has_token("connection", "upgrade");
+
+    if !valid {
+        return Err(Response::new(426)
+            .header("upgrade", "websocket")
+            .header("connection", "close"));
+    }
+
+    if request.header("sec-websocket-version") != Some(VERSION) {
+        return Err(Response::new(426)
+            .header("sec-websocket-version", VERSION)
+            .header("connection", "close"));
+    }
+
+    // the key is 16 random bytes in base64.
+    if key.len() != 24 || !key.ends_with("==") {
+        return Err(Response::bad_request().header("connection", "close"));
+    }
+
+    Ok(Response::new(101)
+        .header("upgrade", "websocket")
+        .header("connection", "Upgrade")
+        .header("sec-websocket-accept", &accept_key(key)))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    base64(&hasher.finalize())
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+
+    for chunk in data.chunks(3) {
+        let x = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(x >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses the frame at the start of `buf`, `None` if it hasnt arrived in full yet. Frames that
+/// break the protocol return the close code to fail the connection with.
+fn parse_frame(buf: &mut Vec<u8>, masked: bool) -> Result<Option<Frame>, u16> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+
+    // we dont negotiate any extensions, so the reserved bits have to be clear.
+    if buf[0] & 0x70 != 0 || (buf[1] & 0x80 != 0) != masked {
+        return Err(CLOSE_PROTOCOL_ERROR);
+    }
+
+    match opcode {
+        OP_CONTINUATION | OP_TEXT | OP_BINARY => {}
+        OP_CLOSE | OP_PING | OP_PONG if fin && (buf[1] & 0x7f) as usize <= MAX_CONTROL_LEN => {}
+        _ => return Err(CLOSE_PROTOCOL_ERROR),
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        126 | 127 => return Ok(None),
+        x => (x as u64, 2),
+    };
+
+    if len > MAX_MESSAGE_LEN as u64 {
+        return Err(CLOSE_TOO_BIG);
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        if buf.len() < pos + 4 {
+            return Ok(None);
+        }
+
+        mask.copy_from_slice(&buf[pos..pos + 4]);
+        pos += 4;
+    }
+
+    let len = len as usize;
+    if buf.len() < pos + len {
+        return Ok(None);
+    }
+
+    let mut payload: Vec<u8> = buf.drain(..pos + len).skip(pos).collect();
+    if masked {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, x)| *x ^= mask[i % 4]);
+    }
+
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+/// Returns a frame with `payload`, masked with a fresh key if `masked` is set.
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+
+    let mask_bit = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        x if x < 126 => frame.push(mask_bit | x as u8),
+        x if x <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(x as u16).to_be_bytes());
+        }
+        x => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(x as u64).to_be_bytes());
+        }
+    }
+
+    if masked {
+        let mut mask = [0; 4];
+        random::fill(&mut mask);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    frame
+}
+
+/// Returns whether `code` can be sent in a close frame (RFC 6455 7.4).
+fn is_valid_close_code(code: u16) -> bool {
+    match code {
+        1000..=1003 | 1007..=1011 | 3000..=4999 => true,
+        _ => false,
+    }
+}
+
+/// The task behind a socket.
+struct Driver {
+    conn: Connection,
+    role: Role,
+    incoming: UnboundedSender<Result<Message, Error>>,
+    /// Opcode and payload of a fragmented message so far.
+    fragment: Option<(u8, Vec<u8>)>,
+    sent_close: bool,
+}
+
+/// What woke up the driver.
+enum Wakeup {
+    Data(Result<(), super::Error>),
+    Send(Option<Message>),
+}
+
+impl Driver {
+    async fn run(mut self, mut outgoing: UnboundedReceiver<Message>) {
+        loop {
+            loop {
+                match parse_frame(self.conn.buffered(), self.role == Role::Server) {
+                    Ok(Some(frame)) => {
+                        if let Err(code) = self.handle(frame).await {
+                            // a code of 0 means the closing handshake is done.
+                            if code != 0 {
+                                self.fail(code).await;
+                            }
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(code) => return self.fail(code).await,
+                }
+            }
+
+            let wakeup = if self.sent_close {
+                match async_::timeout(CLOSE_TIMEOUT, self.conn.fill()).await {
+                    Some(x) => Wakeup::Data(x),
+                    None => return,
+                }
+            } else {
+                let data = self.conn.fill();
+                let send = outgoing.recv();
+                futures_util::pin_mut!(data, send);
+
+                match future::select(data, send).await {
+                    future::Either::Left((x, _)) => Wakeup::Data(x),
+                    future::Either::Right((x, _)) => Wakeup::Send(x),
+                }
+            };
+
+            match wakeup {
+                Wakeup::Data(Ok(())) => {}
+                Wakeup::Data(Err(_)) => {
+                    let _ = self.incoming.send(Err(Error::Closed));
+                    return;
+                }
+                Wakeup::Send(Some(message)) => self.send(message).await,
+                // the socket was dropped, we dont wait for our peer to answer.
+                Wakeup::Send(None) => {
+                    self.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Function handles a frame of our peer. Returns the code to fail the connection with if
+    /// it broke the protocol, or 0 once the closing handshake is done.
+    async fn handle(&mut self, frame: Frame) -> Result<(), u16> {
+        match frame.opcode {
+            OP_CONTINUATION => {
+                let (opcode, mut payload) = self.fragment.take().ok_or(CLOSE_PROTOCOL_ERROR)?;
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_LEN {
+                    return Err(CLOSE_TOO_BIG);
+                }
+
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.deliver(opcode, payload)?;
+                } else {
+                    self.fragment = Some((opcode, payload));
+                }
+            }
+            OP_TEXT | OP_BINARY => {
+                if self.fragment.is_some() {
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+
+                if frame.fin {
+                    self.deliver(frame.opcode, frame.payload)?;
+                } else {
+                    self.fragment = Some((frame.opcode, frame.payload));
+                }
+            }
+            OP_PING => {
+                if !self.sent_close {
+                    self.write(OP_PONG, &frame.payload).await;
+                }
+
+                let _ = self.incoming.send(Ok(Message::Ping(frame.payload)));
+            }
+            OP_PONG => {
+                let _ = self.incoming.send(Ok(Message::Pong(frame.payload)));
+            }
+            _ => {
+                let close = match frame.payload.len() {
+                    0 => None,
+                    1 => return Err(CLOSE_PROTOCOL_ERROR),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        let reason = String::from_utf8(frame.payload[2..].to_vec())
+                            .map_err(|_| CLOSE_INVALID_PAYLOAD)?;
+
+                        if !is_valid_close_code(code) {
+                            return Err(CLOSE_PROTOCOL_ERROR);
+                        }
+
+                        Some(CloseFrame { code, reason })
+                    }
+                };
+
+                // echo the code back, like most implementations do.
+                if !self.sent_close {
+                    let code = close.as_ref().map(|x| x.code).unwrap_or(CLOSE_NORMAL);
+                    self.write(OP_CLOSE, &code.to_be_bytes()).await;
+                    self.sent_close = true;
+                }
+
+                let _ = self.incoming.send(Ok(Message::Close(close)));
+                return Err(0);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deliver(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), u16> {
+        let message = match opcode {
+            OP_TEXT => {
+                Message::Text(String::from_utf8(payload).map_err(|_| CLOSE_INVALID_PAYLOAD)?)
+            }
+            _ => Message::Binary(payload),
+        };
+
+        let _ = self.incoming.send(Ok(message));
+        Ok(())
+    }
+
+    /// Function sends a message of the user, anything after a close is dropped.
+    async fn send(&mut self, message: Message) {
+        if self.sent_close {
+            return;
+        }
+
+        let (opcode, payload) = match message {
+            Message::Text(x) => (OP_TEXT, x.into_bytes()),
+            Message::Binary(x) => (OP_BINARY, x),
+            // control frames cant be fragmented, so longer payloads are cut short.
+            Message::Ping(mut x) => {
+                x.truncate(MAX_CONTROL_LEN);
+                return self.write(OP_PING, &x).await;
+            }
+            Message::Pong(mut x) => {
+                x.truncate(MAX_CONTROL_LEN);
+                return self.write(OP_PONG, &x).await;
+            }
+            Message::Close(close) => {
+                let mut payload = Vec::new();
+                if let Some(close) = close.filter(|x| is_valid_close_code(x.code)) {
+                    payload.extend_from_slice(&close.code.to_be_bytes());
+                    payload.extend_from_slice(close.reason.as_bytes());
+                    payload.truncate(MAX_CONTROL_LEN);
+                }
+
+                self.sent_close = true;
+                return self.write(OP_CLOSE, &payload).await;
+            }
+        };
+
+        let chunks = payload.chunks(MAX_FRAME_LEN).count().max(1);
+        let mut frames = Vec::with_capacity(payload.len() + chunks * 14);
+
+        for i in 0..chunks {
+            let chunk = payload.get(i * MAX_FRAME_LEN..).unwrap_or(&[]);
+            let chunk = &chunk[..chunk.len().min(MAX_FRAME_LEN)];
+            let opcode = if i == 0 { opcode } else { OP_CONTINUATION };
+
+            frames.extend(encode_frame(
+                i + 1 == chunks,
+                opcode,
+                chunk,
+                self.role == Role::Client,
+            ));
+        }
+
+        self.conn.write(&frames).await;
+    }
+
+    async fn write(&mut self, opcode: u8, payload: &[u8]) {
+        let frame = encode_frame(true, opcode, payload, self.role == Role::Client);
+        self.conn.write(&frame).await;
+    }
+
+    /// Function closes the connection with `code` after our peer broke the protocol.
+    async fn fail(&mut self, code: u16) {
+        if !self.sent_close {
+            self.write(OP_CLOSE, &code.to_be_bytes()).await;
+            self.sent_close = true;
+        }
+
+        let _ = self.incoming.send(Err(Error::Protocol(code)));
+    }
+}
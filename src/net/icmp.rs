@@ -1,23 +1,276 @@
 use super::wire::icmp::Icmp;
+use super::wire::icmp::IcmpCode;
 use super::wire::icmp::IcmpType;
+use super::wire::icmp::TimeExceededCode;
+use super::wire::ipaddr::Ipv4Addr;
 use super::wire::ipv4::Ipv4;
+use super::wire::ipv4::Ipv4Proto;
+use super::wire::Packet;
+use super::stats;
+use super::tcp::ConnectionKey;
+use super::stats::DropReason;
 
-pub struct IcmpLayer;
+use crate::arch::pit::get_milis;
+use crate::async_::Sleep;
+use crate::collections::HashMap;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedSender;
+
+use core::convert::TryInto;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::Ordering::Relaxed;
+use core::time::Duration;
+
+use futures_util::future;
+use futures_util::future::FutureExt;
+use spin::Mutex;
+
+/// Number of bytes of the original datagram we quote after its ip header in error messages.
+const ICMP_ERROR_QUOTE_LEN: usize = 8;
+
+/// Errors that can be returned by `ping`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PingError {
+    /// We have no local ip to send the request from.
+    NoSource,
+    /// No reply arrived before the timeout elapsed.
+    Timeout,
+    /// A router or the host itself told us the destination is unreachable.
+    Unreachable(IcmpCode),
+    /// The request was dropped on its way to the destination.
+    TimeExceeded(TimeExceededCode),
+}
+
+type PingKey = (u16, u16); // identifier, seq
+type PingSender = UnboundedSender<Result<(), PingError>>;
+
+pub struct IcmpLayer {
+    /// Ping requests waiting for a reply.
+    waiters: Mutex<HashMap<PingKey, PingSender>>,
+    /// Last identifier we have used for a echo request.
+    last_ident: AtomicU16,
+}
 
 impl IcmpLayer {
     pub fn new() -> Self {
-        Self
+        Self {
+            waiters: Mutex::new(HashMap::new()),
+            last_ident: AtomicU16::new(0),
+        }
     }
 
     pub async fn handle_packet(&self, packet: Icmp, _: &Ipv4) -> Option<Icmp> {
+        stats::ICMP.rx(packet.as_bytes().len());
+
+        if !packet.verify_checksum() {
+            stats::ICMP.dropped(DropReason::Checksum);
+            return None;
+        }
+
         match packet.packet_type() {
             IcmpType::Echo => {
-                let mut reply = packet.clone();
+                // the request is turned into the reply in place.
+                let mut reply = packet;
                 reply.set_packet_type(IcmpType::EchoReply);
                 reply.set_checksum();
+                stats::ICMP.tx(reply.as_bytes().len());
                 Some(reply)
             }
+            IcmpType::EchoReply => {
+                self.notify((packet.identifier(), packet.seq()), Ok(()));
+                None
+            }
+            IcmpType::DestinationUnreachable => {
+                if packet.code() == IcmpCode::FragNeeded {
+                    self.frag_needed(&packet).await;
+                }
+
+                let key = Self::quoted_echo(&packet)?;
+                self.notify(key, Err(PingError::Unreachable(packet.code())));
+                None
+            }
+            IcmpType::TimeExceeded => {
+                let key = Self::quoted_echo(&packet)?;
+                let code = packet.raw_code().into();
+                self.notify(key, Err(PingError::TimeExceeded(code)));
+                None
+            }
             _ => None,
         }
     }
+
+    /// Function builds a destination unreachable message in response to `original`. Returns
+    /// `None` if we are not allowed to answer `original` with a error.
+    pub fn unreachable(&self, code: IcmpCode, original: &Ipv4) -> Option<Icmp> {
+        self.error(IcmpType::DestinationUnreachable, code.raw(), original)
+    }
+
+    /// Function builds a time exceeded message in response to `original`. Returns `None` if we
+    /// are not allowed to answer `original` with a error.
+    pub fn time_exceeded(&self, code: TimeExceededCode, original: &Ipv4) -> Option<Icmp> {
+        self.error(IcmpType::TimeExceeded, code.raw(), original)
+    }
+
+    fn error(&self, packet_type: IcmpType, code: u8, original: &Ipv4) -> Option<Icmp> {
+        // RFC 1122 3.2.2: never send a error about a error, or about a fragment other than the
+        // first one.
+        if original.offset() != 0 {
+            return None;
+        }
+
+        // nor about a datagram that was sent to a group or broadcast, or from one.
+        if original.dip().is_multicast() || original.dip().is_broadcast() {
+            return None;
+        }
+
+        if original.sip().is_multicast() || original.sip().is_broadcast() {
+            return None;
+        }
+
+        if let Ipv4Proto::ICMP = original.proto() {
+            let original_type: IcmpType = (*original.data().first()?).into();
+            if original_type.is_error() {
+                return None;
+            }
+        }
+
+        let quote_len = original.data().len().min(ICMP_ERROR_QUOTE_LEN);
+
+        let mut packet = Icmp::zeroed();
+        packet.set_packet_type(packet_type);
+        packet.set_raw_code(code);
+        packet.set_data([original.header(), &original.data()[..quote_len]].concat());
+        packet.set_checksum();
+
+        stats::ICMP.tx(packet.as_bytes().len());
+
+        Some(packet)
+    }
+
+    /// Function shrinks the path mtu towards the destination of the datagram quoted in a
+    /// fragmentation needed message. Messages quoting a datagram we didnt send are ignored.
+    async fn frag_needed(&self, packet: &Icmp) {
+        let quoted = match Ipv4::from_bytes(packet.data().to_vec()) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        if !super::ARP_LAYER.is_local(quoted.sip()).await {
+            return;
+        }
+
+        // anyone can forge these, so a quoted tcp segment has to carry data one of our
+        // connections still has in flight (RFC 5927 4.1).
+        if let Ipv4Proto::TCP = quoted.proto() {
+            let in_flight = match Self::quoted_tcp(&quoted) {
+                Some((quad, seq)) => super::TCP_LAYER.in_flight(quad, seq).await,
+                None => false,
+            };
+
+            if !in_flight {
+                return;
+            }
+        }
+
+        super::IP_LAYER.update_pmtu(
+            quoted.dip(),
+            packet.next_hop_mtu() as usize,
+            quoted.len() as usize,
+        );
+
+        super::TCP_LAYER
+            .pmtu_changed(quoted.dip(), quoted.sip())
+            .await;
+    }
+
+    /// Function extracts the connection and sequence number of the tcp segment quoted in a
+    /// error message.
+    fn quoted_tcp(quoted: &Ipv4) -> Option<(ConnectionKey, u32)> {
+        let tcp = quoted.data().get(..8)?;
+
+        let sport = u16::from_be_bytes(tcp[0..2].try_into().ok()?);
+        let dport = u16::from_be_bytes(tcp[2..4].try_into().ok()?);
+        let seq = u32::from_be_bytes(tcp[4..8].try_into().ok()?);
+
+        Some(((quoted.dip(), dport, quoted.sip(), sport), seq))
+    }
+
+    /// Function extracts the identifier and sequence number of the echo request quoted in a
+    /// error message.
+    fn quoted_echo(packet: &Icmp) -> Option<PingKey> {
+        let quoted = Ipv4::from_bytes(packet.data().to_vec()).ok()?;
+        let hdr_len = quoted.hdr_len() as usize;
+
+        if let Ipv4Proto::ICMP = quoted.proto() {
+            let echo = quoted.as_bytes().get(hdr_len..hdr_len + ICMP_ERROR_QUOTE_LEN)?;
+            if IcmpType::from(echo[0]) != IcmpType::Echo {
+                return None;
+            }
+
+            let ident = u16::from_be_bytes(echo[4..6].try_into().ok()?);
+            let seq = u16::from_be_bytes(echo[6..8].try_into().ok()?);
+
+            return Some((ident, seq));
+        }
+
+        None
+    }
+
+    fn notify(&self, key: PingKey, result: Result<(), PingError>) {
+        if let Some(waiter) = self.waiters.lock().remove(&key) {
+            let _ = waiter.send(result);
+        }
+    }
+
+    /// Function sends a echo request to `addr` and waits for the matching reply.
+    pub async fn ping(
+        &self,
+        addr: Ipv4Addr,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Duration, PingError> {
+        let sip = super::ARP_LAYER.local_ip().await.ok_or(PingError::NoSource)?;
+
+        let ident = self.last_ident.fetch_add(1, Relaxed);
+        let seq = 0;
+
+        let (tx, mut rx) = channel();
+        self.waiters.lock().insert((ident, seq), tx);
+
+        let mut request = Icmp::zeroed();
+        request.set_packet_type(IcmpType::Echo);
+        request.set_identifier(ident);
+        request.set_seq(seq);
+        request.set_data(payload);
+        request.set_checksum();
+
+        stats::ICMP.tx(request.as_bytes().len());
+
+        let sent_at = get_milis();
+        super::IP_LAYER
+            .handle_tx(request.into_buf(), Ipv4Proto::ICMP, addr, sip)
+            .await;
+
+        let reply = rx.recv().boxed();
+        let result = match future::select(reply, Sleep::new(timeout)).await {
+            future::Either::Left((Some(result), _)) => result,
+            _ => Err(PingError::Timeout),
+        };
+
+        self.waiters.lock().remove(&(ident, seq));
+
+        result.map(|_| Duration::from_millis(get_milis() - sent_at))
+    }
+}
+
+/// Function sends a echo request to `addr` with `payload` as its data and returns the round trip
+/// time once a reply arrives.
+///
+/// # Arguments
+/// * `addr` - The host to ping.
+/// * `payload` - Data to send along with the request.
+/// * `timeout` - How long to wait for a reply before giving up.
+pub async fn ping(addr: Ipv4Addr, payload: &[u8], timeout: Duration) -> Result<Duration, PingError> {
+    super::ICMP_LAYER.ping(addr, payload, timeout).await
 }
@@ -0,0 +1,328 @@
+use super::stats;
+use super::stats::DropReason;
+use super::wire::igmp::Igmp;
+use super::wire::igmp::IgmpRecordType;
+use super::wire::igmp::IgmpType;
+use super::wire::ipaddr::Ipv4Addr;
+use super::wire::ipv4::Ipv4;
+use super::wire::ipv4::Ipv4Proto;
+use super::wire::ipv4::IPV4_ROUTER_ALERT;
+use super::wire::mac::Mac;
+use super::wire::Packet;
+
+use crate::arch::pit::get_milis;
+use crate::collections::HashMap;
+use crate::prelude::*;
+
+use spin::Mutex;
+
+/// Group every multicast capable host is a member of, general queries are sent here.
+pub const ALL_SYSTEMS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+/// Group IGMPv2 leave messages are sent to.
+pub const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+/// Group IGMPv3 reports are sent to.
+pub const IGMPV3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+/// Number of unsolicited reports we send after joining a group, in case one gets lost.
+const ROBUSTNESS: usize = 2;
+/// Time (ms) between the unsolicited reports sent after joining a group.
+const UNSOLICITED_REPORT_INTERVAL: u64 = 1_000;
+/// Max response time (ms) of IGMPv1 queries, which dont carry one.
+const V1_MAX_RESP_TIME: u64 = 10_000;
+/// Time (ms) after the last query from a older router at which we go back to IGMPv3,
+/// RFC 3376 8.12 with the default query interval and response time.
+const OLDER_VERSION_TIMEOUT: u64 = 260_000;
+
+/// Version of IGMP we speak, we fall back to older versions when a older router is around.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum IgmpVersion {
+    V1,
+    V2,
+    V3,
+}
+
+struct Membership {
+    /// Number of sockets that joined the group.
+    users: usize,
+    /// Local ip and device mac the group was joined on.
+    interface: (Ipv4Addr, Mac),
+    /// Time at which our next report for the group is due.
+    report_at: Option<u64>,
+    /// Unsolicited reports we still have to send after joining.
+    unsolicited: usize,
+}
+
+pub struct IgmpLayer {
+    /// Groups we are a member of.
+    groups: Mutex<HashMap<Ipv4Addr, Membership>>,
+    /// Version we currently speak, along with the time we last saw a query of that version.
+    version: Mutex<(IgmpVersion, u64)>,
+}
+
+impl IgmpLayer {
+    pub fn new() -> Self {
+        Self {
+            groups: Mutex::new(HashMap::new()),
+            version: Mutex::new((IgmpVersion::V3, 0)),
+        }
+    }
+
+    /// Returns the version of IGMP we currently speak.
+    pub fn version(&self) -> IgmpVersion {
+        self.version.lock().0
+    }
+
+    /// Returns whether we are a member of `group`, `ALL_SYSTEMS` always counts.
+    pub fn is_member(&self, group: Ipv4Addr) -> bool {
+        group == ALL_SYSTEMS || self.groups.lock().contains_key(&group)
+    }
+
+    /// Function joins the multicast `group` on the interface with the local ip `interface`, a
+    /// unspecified `interface` picks one of our ips. Joining a group we are already a member of
+    /// only bumps its user count.
+    pub async fn join(&self, group: Ipv4Addr, interface: Ipv4Addr) -> Result<(), ()> {
+        if !group.is_multicast() || group == ALL_SYSTEMS {
+            return Err(());
+        }
+
+        let ip = if interface == Ipv4Addr::new(0, 0, 0, 0) {
+            super::ARP_LAYER.local_ip().await.ok_or(())?
+        } else {
+            interface
+        };
+        let mac = super::ARP_LAYER.resolve_ip_local(ip).await.ok_or(())?;
+
+        {
+            let mut groups = self.groups.lock();
+            if let Some(membership) = groups.get_mut(&group) {
+                membership.users += 1;
+                return Ok(());
+            }
+
+            groups.insert(
+                group,
+                Membership {
+                    users: 1,
+                    interface: (ip, mac),
+                    report_at: Some(get_milis() + UNSOLICITED_REPORT_INTERVAL),
+                    unsolicited: ROBUSTNESS - 1,
+                },
+            );
+        }
+
+        super::ETHERNET_LAYER.join_multicast(mac, Mac::multicast(group));
+
+        // the first report goes out right away, the rest are sent by `poll_timers`.
+        if !ip.is_loopback() {
+            self.report(group, ip, IgmpRecordType::ChangeToExclude)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Function drops one user of `group`, once the last one is gone we leave the group.
+    pub async fn leave(&self, group: Ipv4Addr) {
+        let (ip, mac) = {
+            let mut groups = self.groups.lock();
+            let membership = match groups.get_mut(&group) {
+                Some(x) => x,
+                None => return,
+            };
+
+            membership.users -= 1;
+            if membership.users > 0 {
+                return;
+            }
+
+            let interface = membership.interface;
+            groups.remove(&group);
+
+            // several groups map to the same mac, so we only stop accepting it once none of our
+            // groups on that device do.
+            let mac = Mac::multicast(group);
+            let shared = mac == Mac::multicast(ALL_SYSTEMS)
+                || groups
+                    .iter()
+                    .any(|(g, m)| m.interface.1 == interface.1 && Mac::multicast(*g) == mac);
+            if !shared {
+                super::ETHERNET_LAYER.leave_multicast(interface.1, mac);
+            }
+
+            interface
+        };
+
+        if ip.is_loopback() || mac == Mac::zeroed() {
+            return;
+        }
+
+        match self.version() {
+            // v1 has no leave message, routers just time the group out.
+            IgmpVersion::V1 => {}
+            IgmpVersion::V2 => {
+                let mut packet = Igmp::zeroed();
+                packet.set_packet_type(IgmpType::LeaveGroup);
+                packet.set_group(group);
+                packet.set_checksum();
+                self.send(packet, ALL_ROUTERS, ip).await;
+            }
+            IgmpVersion::V3 => {
+                let packet = Igmp::v3_report(IgmpRecordType::ChangeToInclude, &[group]);
+                self.send(packet, IGMPV3_ROUTERS, ip).await;
+            }
+        }
+    }
+
+    pub async fn handle_packet(&self, packet: Igmp, _: &Ipv4) -> Option<Igmp> {
+        stats::IGMP.rx(packet.as_bytes().len());
+
+        if !packet.verify_checksum() {
+            stats::IGMP.dropped(DropReason::Checksum);
+            return None;
+        }
+
+        let now = get_milis();
+
+        match packet.packet_type() {
+            IgmpType::MembershipQuery => {
+                let (version, max_resp) = if packet.is_v1_query() {
+                    (IgmpVersion::V1, V1_MAX_RESP_TIME)
+                } else if packet.is_v3_query() {
+                    (IgmpVersion::V3, packet.max_resp_time())
+                } else {
+                    (IgmpVersion::V2, packet.max_resp_time())
+                };
+
+                {
+                    let mut current = self.version.lock();
+                    if version <= current.0 {
+                        *current = (version, now);
+                    }
+                }
+
+                // a general query asks about every group, a group specific one only about one.
+                let queried = packet.group();
+                let general = queried == Ipv4Addr::new(0, 0, 0, 0);
+
+                for (group, membership) in self.groups.lock().iter_mut() {
+                    if !general && *group != queried {
+                        continue;
+                    }
+
+                    // answers are spread over the max response time so hosts dont all report
+                    // at once.
+                    let at = now + Self::delay(*group, now, max_resp);
+                    membership.report_at = Some(membership.report_at.map_or(at, |x| x.min(at)));
+                }
+            }
+            // another host already answered for the group, so v1 and v2 hosts keep quiet.
+            IgmpType::V1MembershipReport | IgmpType::V2MembershipReport => {
+                if let Some(membership) = self.groups.lock().get_mut(&packet.group()) {
+                    if membership.unsolicited == 0 {
+                        membership.report_at = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // igmp never answers right away, reports are sent by `poll_timers`.
+        None
+    }
+
+    /// Function announces the groups joined on the device with `device_mac` again, routers may
+    /// have forgotten about them while its link was down.
+    pub fn link_up(&self, device_mac: Mac) {
+        let now = get_milis();
+
+        for membership in self.groups.lock().values_mut() {
+            if membership.interface.1 == device_mac {
+                membership.report_at = Some(now);
+                membership.unsolicited = ROBUSTNESS;
+            }
+        }
+    }
+
+    /// Function sends the reports that are due and falls back to IGMPv3 once older routers have
+    /// been quiet for long enough.
+    pub async fn poll_timers(&self) {
+        let now = get_milis();
+
+        {
+            let mut version = self.version.lock();
+            if version.0 != IgmpVersion::V3 && now - version.1 > OLDER_VERSION_TIMEOUT {
+                *version = (IgmpVersion::V3, now);
+            }
+        }
+
+        let mut due = Vec::new();
+        for (group, membership) in self.groups.lock().iter_mut() {
+            match membership.report_at {
+                Some(x) if x <= now => {}
+                _ => continue,
+            }
+
+            let record_type = if membership.unsolicited > 0 {
+                membership.unsolicited -= 1;
+                IgmpRecordType::ChangeToExclude
+            } else {
+                IgmpRecordType::ModeIsExclude
+            };
+
+            membership.report_at = if membership.unsolicited > 0 {
+                Some(now + UNSOLICITED_REPORT_INTERVAL)
+            } else {
+                None
+            };
+
+            due.push((*group, membership.interface.0, record_type));
+        }
+
+        for (group, ip, record_type) in due {
+            if !ip.is_loopback() {
+                self.report(group, ip, record_type).await;
+            }
+        }
+    }
+
+    /// Function sends a report for `group` from `ip` in the version we currently speak.
+    async fn report(&self, group: Ipv4Addr, ip: Ipv4Addr, record_type: IgmpRecordType) {
+        let packet_type = match self.version() {
+            IgmpVersion::V1 => IgmpType::V1MembershipReport,
+            IgmpVersion::V2 => IgmpType::V2MembershipReport,
+            IgmpVersion::V3 => {
+                let packet = Igmp::v3_report(record_type, &[group]);
+                return self.send(packet, IGMPV3_ROUTERS, ip).await;
+            }
+        };
+
+        let mut packet = Igmp::zeroed();
+        packet.set_packet_type(packet_type);
+        packet.set_group(group);
+        packet.set_checksum();
+
+        // v1 and v2 reports are sent to the group itself.
+        self.send(packet, group, ip).await;
+    }
+
+    /// Function sends `packet` with the router alert option, RFC 3376 4 and RFC 2236 2 want
+    /// routers to look at every igmp message even if it isnt sent to them.
+    async fn send(&self, packet: Igmp, dip: Ipv4Addr, sip: Ipv4Addr) {
+        stats::IGMP.tx(packet.as_bytes().len());
+
+        let packet = packet.into_buf();
+        super::IP_LAYER
+            .handle_tx_with_options(packet, Ipv4Proto::IGMP, dip, sip, &IPV4_ROUTER_ALERT)
+            .await;
+    }
+
+    /// Returns a pseudo random delay in `0..max` (ms), mixed from the group and the time.
+    fn delay(group: Ipv4Addr, now: u64, max: u64) -> u64 {
+        if max == 0 {
+            return 0;
+        }
+
+        let seed = (u32::from_be_bytes(group.octets()) as u64) ^ now;
+        seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) % max
+    }
+}
@@ -3,87 +3,567 @@ use super::wire::ipv4::Ipv4Proto;
 use super::wire::ipaddr::Ipv4Addr;
 use super::wire::eth2::Ether2Frame;
 use super::wire::icmp::Icmp;
+use super::wire::icmp::IcmpCode;
+use super::wire::icmp::TimeExceededCode;
 use super::wire::tcp::Tcp;
+use super::wire::udp::Udp;
+use super::wire::igmp::Igmp;
 use super::wire::Packet;
+use super::wire::PacketBuf;
 use super::wire::eth2::EtherType;
+use super::wire::mac::Mac;
+use super::stats;
+use super::stats::DropReason;
+use super::filter::Action;
+use super::filter::Direction;
 
+use super::wire::buf::DEFAULT_TAILROOM;
+use super::wire::buf::ETH2_HEADER_LEN;
+
+use crate::arch::pit::get_milis;
+use crate::collections::HashMap;
+use crate::prelude::*;
+
+use alloc::collections::BTreeMap;
 use core::sync::atomic::AtomicU16;
 use core::sync::atomic::Ordering::Relaxed;
 
+use spin::Mutex;
+
+/// More fragments flag, as returned by `Ipv4::flags`.
+const IPV4_FLAG_MF: u8 = 0b001;
+/// How long (ms) a learned path mtu is kept before we try bigger packets again (RFC 1191 6.3).
+const PMTU_TIMEOUT: u64 = 600_000;
+/// Common mtus, used when a router asks us to fragment without telling us its mtu (RFC 1191 7.1).
+const PMTU_PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+/// How long (ms) we wait for the rest of a fragmented datagram, like linux `ipfrag_time`.
+const REASSEMBLY_TIMEOUT: u64 = 30_000;
+/// Number of datagrams we reassemble at once, and the bytes all of their fragments take up.
+const REASSEMBLY_MAX_DATAGRAMS: usize = 64;
+const REASSEMBLY_MAX_BYTES: usize = 256 * 1024;
+/// Biggest ipv4 datagram, its header included.
+const IPV4_MAX_LEN: usize = u16::MAX as usize;
+
+/// Fragments of a datagram belong together if they share these (RFC 791): sip, dip, protocol
+/// and id.
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u8, u16);
+
+/// A datagram we are reassembling.
+struct Reassembly {
+    /// The first fragment, its header becomes the header of the datagram.
+    first: Option<Ipv4>,
+    /// Payload of every fragment by its offset in bytes, the first one included.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Length of the payload of the datagram, known once the last fragment is there.
+    len: Option<usize>,
+    /// Bytes the fragments take up.
+    size: usize,
+    expires_at: u64,
+}
+
+impl Reassembly {
+    /// Returns the payload of the datagram once every fragment is there. Overlapping fragments
+    /// are fine, the bytes that arrived first win.
+    fn payload(&self) -> Option<Vec<u8>> {
+        let len = self.len?;
+        self.first.as_ref()?;
+
+        let mut payload = Vec::with_capacity(len);
+        for (offset, data) in self.fragments.iter() {
+            if *offset > payload.len() {
+                return None;
+            }
+
+            let skip = payload.len() - offset;
+            payload.extend_from_slice(data.get(skip..).unwrap_or(&[]));
+        }
+
+        match payload.len() == len {
+            true => Some(payload),
+            false => None,
+        }
+    }
+}
+
 pub struct IpLayer {
     last_ipv4_id: AtomicU16,
+    /// Path mtus we learned from fragmentation needed messages, along with when they expire.
+    pmtu_cache: Mutex<HashMap<Ipv4Addr, (usize, u64)>>,
+    /// Datagrams we got some fragments of.
+    reassembly: Mutex<HashMap<FragmentKey, Reassembly>>,
 }
 
 impl IpLayer {
     pub fn new() -> Self {
         Self {
-            last_ipv4_id: AtomicU16::new(0)
+            last_ipv4_id: AtomicU16::new(0),
+            pmtu_cache: Mutex::new(HashMap::new()),
+            reassembly: Mutex::new(HashMap::new()),
         }
     }
 
-    pub async fn handle_packet(&self, packet: Ipv4, _: &Ether2Frame) -> Option<Ipv4> {
-        // packet is malformed or not intended for us.
-        if super::ARP_LAYER.resolve_ip_local(packet.dip()).await.is_none() {
+    /// Function handles a incoming packet, `loopback` tells whether it was received on the
+    /// loopback device.
+    pub async fn handle_packet(
+        &self,
+        packet: Ipv4,
+        ctx: &Ether2Frame,
+        loopback: bool,
+    ) -> Option<Ipv4> {
+        let reply = self.dispatch(packet, ctx, loopback).await?;
+
+        // replies dont go through `handle_tx`, so they are filtered here.
+        if super::FIREWALL.check_ip(Direction::Egress, &reply) != Action::Accept {
+            stats::IP.dropped(DropReason::Filtered);
             return None;
         }
 
+        Some(reply)
+    }
+
+    async fn dispatch(&self, packet: Ipv4, ctx: &Ether2Frame, loopback: bool) -> Option<Ipv4> {
+        stats::IP.rx(packet.as_bytes().len());
+
+        if !packet.verify_checksum() {
+            stats::IP.dropped(DropReason::Checksum);
+            return None;
+        }
+
+        // packet is malformed or not intended for us, multicast packets are ours if we joined
+        // their group.
+        let multicast = packet.dip().is_multicast();
+        let for_us = if multicast {
+            super::IGMP_LAYER.is_member(packet.dip())
+        } else {
+            super::ARP_LAYER.resolve_ip_local(packet.dip()).await.is_some()
+        };
+
+        if !for_us {
+            stats::IP.dropped(DropReason::NotForUs);
+            return None;
+        }
+
+        // loopback addresses are only valid on the loopback device.
+        if (packet.dip().is_loopback() || packet.sip().is_loopback()) && !loopback {
+            stats::IP.dropped(DropReason::NotForUs);
+            return None;
+        }
+
+        // fragments are held until the whole datagram is there, which then goes through the
+        // firewall and up the stack like any other.
+        let packet = if packet.flags() & IPV4_FLAG_MF != 0 || packet.offset() != 0 {
+            self.reassemble(&packet)?
+        } else {
+            packet
+        };
+
+        match super::FIREWALL.check_ip(Direction::Ingress, &packet) {
+            Action::Accept => {}
+            Action::Drop => {
+                stats::IP.dropped(DropReason::Filtered);
+                return None;
+            }
+            Action::Reject => {
+                stats::IP.dropped(DropReason::Filtered);
+                return self.reject(&packet);
+            }
+        }
+
+        // raw sockets get their own copy, the packet still goes through the stack if we know its
+        // protocol.
+        let raw = super::RAW_LAYER.deliver_ip(&packet);
+
+        match packet.proto() {
+            // only connectionless protocols make sense over multicast.
+            Ipv4Proto::ICMP | Ipv4Proto::TCP if multicast => {
+                stats::IP.dropped(DropReason::NotForUs);
+                return None;
+            }
+            Ipv4Proto::ICMP | Ipv4Proto::TCP | Ipv4Proto::IGMP => {}
+            Ipv4Proto::UDP => {
+                // datagrams sent to a port nobody listens on get a port unreachable, unless they
+                // were sent to a group.
+                let port = packet.data().get(2..4).map(|x| u16::from_be_bytes([x[0], x[1]]));
+                if port.map_or(false, |x| !super::UDP_LAYER.is_bound(packet.dip(), x)) {
+                    stats::UDP.rx(packet.data().len());
+                    stats::UDP.dropped(DropReason::NoSocket);
+
+                    if multicast {
+                        return None;
+                    }
+
+                    let error = super::ICMP_LAYER.unreachable(IcmpCode::PortDown, &packet)?;
+                    return Some(self.reply(&packet, error.into_buf(), Ipv4Proto::ICMP));
+                }
+            }
+            _ if raw => return None,
+            _ => {
+                stats::IP.dropped(DropReason::Unsupported);
+                let error = super::ICMP_LAYER.unreachable(IcmpCode::ProtocolDown, &packet)?;
+                return Some(self.reply(&packet, error.into_buf(), Ipv4Proto::ICMP));
+            }
+        }
+
+        // from here on `packet` only holds the header, the payload moves up without a copy.
+        let (packet, payload) = packet.split();
 
         let (data, packet_type) = match packet.proto() {
             Ipv4Proto::ICMP => {
-                let pkt = Icmp::from_bytes(packet.data().to_vec()).ok()?;
+                let pkt = match Icmp::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::ICMP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
                 (
-                    super::ICMP_LAYER.handle_packet(pkt, &packet).await?.into_bytes(),
+                    super::ICMP_LAYER.handle_packet(pkt, &packet).await?.into_buf(),
                     Ipv4Proto::ICMP,
                 )
             }
-            Ipv4Proto::TCP => {
-                let pkt = Tcp::from_bytes(packet.data().to_vec()).ok()?;
+            Ipv4Proto::UDP => {
+                let pkt = match Udp::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::UDP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
+                (
+                    super::UDP_LAYER.handle_packet(pkt, &packet).await?.into_buf(),
+                    Ipv4Proto::UDP,
+                )
+            }
+            Ipv4Proto::IGMP => {
+                let pkt = match Igmp::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::IGMP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
+                (
+                    super::IGMP_LAYER.handle_packet(pkt, &packet).await?.into_buf(),
+                    Ipv4Proto::IGMP,
+                )
+            }
+            _ => {
+                let pkt = match Tcp::from_buf(payload) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        stats::TCP.dropped(DropReason::Malformed);
+                        return None;
+                    }
+                };
                 (
-                    super::TCP_LAYER.handle_packet(pkt, &packet).await?.into_bytes(),
+                    super::TCP_LAYER.handle_packet(pkt, &packet).await?.into_buf(),
                     Ipv4Proto::TCP,
                 )
             }
-            _ => return None,
         };
 
-        let mut reply = Ipv4::zeroed();
-        reply.set_proto(packet_type);
+        Some(self.reply(&packet, data, packet_type))
+    }
+
+    /// Function holds on to the fragment `packet`, returning the whole datagram once it is the
+    /// last one missing.
+    fn reassemble(&self, packet: &Ipv4) -> Option<Ipv4> {
+        let offset = packet.offset() as usize * 8;
+        let data = packet.data();
+        let last = packet.flags() & IPV4_FLAG_MF == 0;
+
+        // every fragment but the last carries a multiple of 8 bytes.
+        let misaligned = !last && (data.is_empty() || data.len() % 8 != 0);
+        if misaligned || packet.header().len() + offset + data.len() > IPV4_MAX_LEN {
+            stats::IP.dropped(DropReason::Malformed);
+            return None;
+        }
+
+        let key = (packet.sip(), packet.dip(), packet.raw_proto(), packet.id());
+        let mut datagrams = self.reassembly.lock();
+
+        let buffered = datagrams.values().map(|x| x.size).sum::<usize>();
+        let full = datagrams.len() >= REASSEMBLY_MAX_DATAGRAMS;
+        if buffered + data.len() > REASSEMBLY_MAX_BYTES || (full && !datagrams.contains_key(&key)) {
+            stats::IP.dropped(DropReason::Fragment);
+            return None;
+        }
+
+        let datagram = datagrams.entry(key).or_insert_with(|| Reassembly {
+            first: None,
+            fragments: BTreeMap::new(),
+            len: None,
+            size: 0,
+            expires_at: get_milis() + REASSEMBLY_TIMEOUT,
+        });
+
+        if offset == 0 && datagram.first.is_none() {
+            datagram.first = Some(packet.clone());
+        }
+
+        if last {
+            datagram.len = Some(offset + data.len());
+        }
+
+        if !datagram.fragments.contains_key(&offset) {
+            datagram.fragments.insert(offset, data.to_vec());
+            datagram.size += data.len();
+        }
+
+        let payload = datagram.payload()?;
+        let first = datagrams.remove(&key)?.first?;
+
+        // the first fragment may carry more options than the last one accounted for.
+        let hdr_len = first.header().len();
+        if hdr_len + payload.len() > IPV4_MAX_LEN {
+            stats::IP.dropped(DropReason::Malformed);
+            return None;
+        }
+
+        let mut buf = PacketBuf::new(ETH2_HEADER_LEN + hdr_len, 0, DEFAULT_TAILROOM);
+        buf.extend_from_slice(&payload);
+        buf.prepend(hdr_len).copy_from_slice(first.header());
+
+        let mut datagram = Ipv4::from_buf(buf).ok()?;
+        datagram.set_len(payload.len() as u16);
+        datagram.set_offset(0);
+        datagram.set_more_fragments(false);
+        datagram.set_checksum();
+
+        Some(datagram)
+    }
+
+    /// Function answers a packet rejected by the firewall, tcp segments get a RST and everything
+    /// else a administratively prohibited error.
+    fn reject(&self, packet: &Ipv4) -> Option<Ipv4> {
+        if let Ipv4Proto::TCP = packet.proto() {
+            // a copy, but only rejected segments pay for it.
+            let tcp = Tcp::from_bytes(packet.data().to_vec()).ok()?;
+            let reset = super::TCP_LAYER.reset_for(&tcp, packet)?;
+            return Some(self.reply(packet, reset.into_buf(), Ipv4Proto::TCP));
+        }
+
+        let error = super::ICMP_LAYER.unreachable(IcmpCode::AdminProhibited, packet)?;
+        Some(self.reply(packet, error.into_buf(), Ipv4Proto::ICMP))
+    }
+
+    fn reply(&self, packet: &Ipv4, data: PacketBuf, proto: Ipv4Proto) -> Ipv4 {
+        let mut reply = Ipv4::encapsulate(data);
+        reply.set_proto(proto);
         reply.set_sip(packet.dip());
         reply.set_dip(packet.sip());
         reply.set_id(packet.id());
-        reply.set_flags(0x40);
-        reply.set_data(data);
+        reply.set_dont_fragment(proto == Ipv4Proto::TCP);
         reply.set_checksum();
 
-        Some(reply)
+        stats::IP.tx(reply.as_bytes().len());
+
+        reply
+    }
+
+    /// Function sends `packet` to `dip`. The ip and ethernet headers are written into the
+    /// headroom of `packet`, so transport layers should build their packets with enough of it.
+    pub async fn handle_tx(&self, packet: PacketBuf, proto: Ipv4Proto, dip: Ipv4Addr, sip: Ipv4Addr) {
+        self.handle_tx_raw(packet, proto.raw(), dip, sip).await;
+    }
+
+    /// Function sends `packet` to `dip` like `handle_tx`, for protocols `Ipv4Proto` doesnt know
+    /// about.
+    pub async fn handle_tx_raw(&self, packet: PacketBuf, proto: u8, dip: Ipv4Addr, sip: Ipv4Addr) {
+        self.send(Ipv4::encapsulate(packet), proto, dip, sip).await;
+    }
+
+    /// Function sends `packet` to `dip` like `handle_tx`, with `options` in the ip header.
+    pub async fn handle_tx_with_options(
+        &self,
+        packet: PacketBuf,
+        proto: Ipv4Proto,
+        dip: Ipv4Addr,
+        sip: Ipv4Addr,
+        options: &[u8],
+    ) {
+        let ipv4 = Ipv4::encapsulate_with_options(packet, options);
+        self.send(ipv4, proto.raw(), dip, sip).await;
     }
 
-    pub async fn handle_tx(&self, packet: &[u8], proto: Ipv4Proto, dip: Ipv4Addr, sip: Ipv4Addr) {
-        let mut ipv4 = Ipv4::zeroed();
-        ipv4.set_proto(proto);
+    async fn send(&self, mut ipv4: Ipv4, proto: u8, dip: Ipv4Addr, sip: Ipv4Addr) {
+        ipv4.set_raw_proto(proto);
         ipv4.set_dip(dip);
         ipv4.set_sip(sip);
         ipv4.set_id(self.last_ipv4_id.fetch_add(1, Relaxed));
-        ipv4.set_flags(0x40);
-        ipv4.set_data(packet);
+
+        // multicast stays on the local network unless asked otherwise.
+        if dip.is_multicast() {
+            ipv4.set_ttl(1);
+        }
+
+        // tcp sizes its segments to the path mtu, so it wants to hear about it when they dont
+        // fit. Everything else is fine with routers fragmenting it.
+        let tcp = proto == Ipv4Proto::TCP.raw();
+        ipv4.set_dont_fragment(tcp);
         ipv4.set_checksum();
 
-        let dst_mac = match super::ARP_LAYER.resolve_ip(dip).await {
-            Some(x) => x,
-            None => return,
-        };
+        if super::FIREWALL.check_ip(Direction::Egress, &ipv4) != Action::Accept {
+            stats::IP.dropped(DropReason::Filtered);
+            return;
+        }
+
+        let mtu = self.path_mtu(dip, sip).await;
+        if ipv4.as_bytes().len() <= mtu {
+            return self.transmit(ipv4, dip, sip).await;
+        }
+
+        // tcp fits its queued segments to the path mtu as soon as it shrinks, anything still too
+        // big got built before that. It goes out in fragments rather than getting lost.
+        if tcp {
+            ipv4.set_dont_fragment(false);
+            ipv4.set_checksum();
+        }
+
+        for fragment in Self::fragment(&ipv4, mtu) {
+            self.transmit(fragment, dip, sip).await;
+        }
+    }
+
+    /// Function splits `packet` into fragments that fit `mtu`, all of them but the last carry a
+    /// multiple of 8 bytes of the payload.
+    fn fragment(packet: &Ipv4, mtu: usize) -> Vec<Ipv4> {
+        let hdr_len = packet.header().len();
+        let chunk = ((mtu - hdr_len) / 8 * 8).max(8);
+        let chunks = packet.data().chunks(chunk);
+        let count = chunks.len();
+
+        chunks
+            .enumerate()
+            .map(|(i, data)| {
+                let mut buf = PacketBuf::new(ETH2_HEADER_LEN + hdr_len, 0, DEFAULT_TAILROOM);
+                buf.extend_from_slice(data);
+                buf.prepend(hdr_len).copy_from_slice(packet.header());
+
+                let mut fragment = Ipv4::from_buf(buf).expect("net: ip built a short fragment");
+                fragment.set_len(data.len() as u16);
+                fragment.set_more_fragments(i + 1 < count);
+                fragment.set_offset((i * chunk / 8) as u16);
+                fragment.set_checksum();
+                fragment
+            })
+            .collect()
+    }
+
+    /// Function hands `ipv4` to the device it has to go out of.
+    async fn transmit(&self, ipv4: Ipv4, dip: Ipv4Addr, sip: Ipv4Addr) {
+        stats::IP.tx(ipv4.as_bytes().len());
+
+        // traffic to one of our own addresses never hits the wire, we hand it to the loopback
+        // device instead.
+        if super::ARP_LAYER.is_local(dip).await {
+            let mut ether = Ether2Frame::encapsulate(ipv4.into_buf());
+            ether.set_src(Mac::zeroed());
+            ether.set_dst(Mac::zeroed());
+            ether.set_dtype(EtherType::IPv4);
+
+            super::ETHERNET_LAYER.handle_tx(ether).await;
+            return;
+        }
 
         let src_mac = match super::ARP_LAYER.resolve_ip_local(sip).await {
             Some(x) => x,
-            None => return,
+            None => {
+                stats::IP.dropped(DropReason::Unresolved);
+                return;
+            }
         };
 
-        let mut ether = Ether2Frame::zeroed();
-        ether.set_dst(dst_mac);
+        let mut ether = Ether2Frame::encapsulate(ipv4.into_buf());
         ether.set_src(src_mac);
         ether.set_dtype(EtherType::IPv4);
-        ether.set_data(ipv4.into_bytes());
 
-        super::ETHERNET_LAYER.handle_tx(ether).await;
+        // multicast groups map straight to a mac, so there is nothing to resolve.
+        if dip.is_multicast() {
+            ether.set_dst(Mac::multicast(dip));
+            super::ETHERNET_LAYER.handle_tx(ether).await;
+            return;
+        }
+
+        // the destination mac gets filled in by the arp layer once `dip` is resolved.
+        super::ARP_LAYER.handle_tx(ether, dip, sip).await;
+    }
+
+    /// Returns the mtu of the path from `sip` to `dip`, which is the mtu of the device we send
+    /// from unless a router told us about a smaller one.
+    pub async fn path_mtu(&self, dip: Ipv4Addr, sip: Ipv4Addr) -> usize {
+        let device_mac = if super::ARP_LAYER.is_local(dip).await {
+            Some(Mac::zeroed())
+        } else {
+            super::ARP_LAYER.resolve_ip_local(sip).await
+        };
+
+        let link_mtu = match device_mac {
+            Some(mac) => super::ETHERNET_LAYER.mtu(mac),
+            None => super::DEFAULT_MTU,
+        };
+
+        match self.pmtu(dip) {
+            Some(pmtu) => pmtu.min(link_mtu),
+            None => link_mtu,
+        }
+    }
+
+    /// Returns the path mtu we have learned for `dip`, if any.
+    pub fn pmtu(&self, dip: Ipv4Addr) -> Option<usize> {
+        self.pmtu_cache.lock().get(&dip).map(|x| x.0)
+    }
+
+    /// Function records that packets of more than `mtu` bytes dont make it to `dip`, as reported
+    /// by a fragmentation needed message. Routers that dont report their mtu send a zero, we
+    /// then fall back to the next plateau below `original_len` (RFC 1191 7.1).
+    pub fn update_pmtu(&self, dip: Ipv4Addr, mtu: usize, original_len: usize) {
+        let mtu = match mtu {
+            0 => PMTU_PLATEAUS
+                .iter()
+                .cloned()
+                .find(|x| *x < original_len)
+                .unwrap_or(super::MIN_MTU),
+            x => x.max(super::MIN_MTU),
+        };
+
+        let expires_at = get_milis() + PMTU_TIMEOUT;
+        let mut cache = self.pmtu_cache.lock();
+
+        // the path mtu only ever shrinks, it grows back once the entry expires.
+        match cache.get_mut(&dip) {
+            Some(entry) if entry.0 <= mtu => {}
+            Some(entry) => *entry = (mtu, expires_at),
+            None => {
+                cache.insert(dip, (mtu, expires_at));
+            }
+        }
+    }
+
+    /// Function expires path mtus so we notice when a path can carry bigger packets again, and
+    /// gives up on datagrams whose fragments stopped coming in.
+    pub async fn poll_timers(&self) {
+        let now = get_milis();
+        self.pmtu_cache.lock().retain(|_, (_, expires_at)| *expires_at > now);
+
+        let mut expired = Vec::new();
+        self.reassembly.lock().retain(|_, datagram| {
+            if datagram.expires_at > now {
+                return true;
+            }
+
+            stats::IP.dropped(DropReason::Fragment);
+            expired.extend(datagram.first.take());
+            false
+        });
+
+        // the sender only hears about it if we got its first fragment (RFC 792).
+        for first in expired {
+            let code = TimeExceededCode::FragmentReassembly;
+            if let Some(error) = super::ICMP_LAYER.time_exceeded(code, &first) {
+                self.handle_tx(error.into_buf(), Ipv4Proto::ICMP, first.sip(), first.dip())
+                    .await;
+            }
+        }
     }
 }
@@ -12,6 +12,36 @@ pub mod arp;
 pub mod ip;
 /// Icmp layer stuff
 pub mod icmp;
+/// Pcap capture and parsing
+pub mod pcap;
+/// Per-layer packet counters
+pub mod stats;
+/// Packet filter hooks and firewall
+pub mod filter;
+/// Udp layer stuff
+pub mod udp;
+/// Igmp layer, tracks our multicast group memberships
+pub mod igmp;
+/// Raw sockets for protocols the stack doesnt handle
+pub mod raw;
+/// Socket addresses and their parsing
+pub mod addr;
+/// Local port allocation for tcp and udp
+pub mod ports;
+/// Tls 1.3 client and server streams
+pub mod tls;
+/// Dns stub resolver
+pub mod dns;
+/// Http/1.1 server and client
+pub mod http;
+/// Quic transport over udp
+pub mod quic;
+/// Mqtt 3.1.1 and 5 client
+pub mod mqtt;
+/// Syslog sink shipping records to a collector
+pub mod syslog;
+/// Tftp client
+pub mod tftp;
 
 pub use crate::net::wire as frames;
 
@@ -29,11 +59,25 @@ use crate::net::arp::Arp;
 use crate::net::ip::IpLayer;
 use crate::net::icmp::IcmpLayer;
 use crate::net::tcp::TcpLayer;
+use crate::net::filter::Firewall;
+use crate::net::udp::UdpLayer;
+use crate::net::igmp::IgmpLayer;
+use crate::net::raw::RawLayer;
+use crate::net::addr::SocketAddrV4;
+use crate::net::ports::PortTable;
+use crate::net::dns::DnsResolver;
 
+use crate::arch::pit::get_milis;
+use crate::async_::Sleep;
+use crate::driver::LinkState;
+use crate::driver::LinkStatusStream;
 use crate::driver::NetworkDriver;
+use crate::driver::RxMode;
+use crate::driver::RxModeHint;
 use crate::sync::mpsc::*;
 
 use alloc::sync::Arc;
+use core::time::Duration;
 use spin::RwLock;
 
 use hashbrown::HashMap;
@@ -45,8 +89,31 @@ use futures_util::stream::Fuse;
 use futures_util::stream::StreamExt;
 use lazy_static::lazy_static;
 
+/// How often (ms) `NetworkDevice::run_forever` runs the timers of our layers.
+const TIMER_INTERVAL: u64 = 100;
+/// Mtu of a plain ethernet link.
+pub const DEFAULT_MTU: usize = 1500;
+/// Smallest mtu a ipv4 link can have (RFC 791).
+pub const MIN_MTU: usize = 68;
+/// Number of frames `NetworkDevice::run_forever` handles in one go by default.
+pub const DEFAULT_BUDGET: usize = 64;
+
+/// Things that wake up `NetworkDevice::run_forever`.
+enum Event {
+    Rx(Option<Vec<u8>>),
+    Tx(Option<Ether2Frame>),
+    Link(Option<LinkState>),
+    Shutdown,
+    Tick,
+    /// We are polling the driver, nothing in particular woke us up.
+    Poll,
+}
+
 type StreamKey = TcpStream;
-type OpenPorts = Arc<RwLock<HashMap<u16, UnboundedSender<StreamKey>>>>;
+/// Listening sockets, keyed by the local address they are bound to. Several listeners only
+/// share a address if they all set `reuse_port`, each is known by the id of its port
+/// reservation.
+type OpenPorts = Arc<RwLock<HashMap<SocketAddrV4, Vec<(usize, UnboundedSender<StreamKey>)>>>>;
 
 lazy_static! {
     pub static ref ETHERNET_LAYER: Ethernet = Ethernet::new();
@@ -54,6 +121,13 @@ lazy_static! {
     pub static ref IP_LAYER: IpLayer = IpLayer::new();
     pub static ref ICMP_LAYER: IcmpLayer = IcmpLayer::new();
     pub static ref TCP_LAYER: TcpLayer = TcpLayer::new();
+    pub static ref UDP_LAYER: UdpLayer = UdpLayer::new();
+    pub static ref IGMP_LAYER: IgmpLayer = IgmpLayer::new();
+    pub static ref RAW_LAYER: RawLayer = RawLayer::new();
+    pub static ref FIREWALL: Firewall = Firewall::new();
+    pub static ref TCP_PORTS: PortTable = PortTable::new();
+    pub static ref UDP_PORTS: PortTable = PortTable::new();
+    pub static ref DNS_RESOLVER: DnsResolver = DnsResolver::new();
 
     pub static ref OPEN_PORTS: OpenPorts = Arc::new(RwLock::new(HashMap::new()));
 }
@@ -71,6 +145,23 @@ pub struct NetworkDevice<T: NetworkDriver> {
     tx_queue_sender: UnboundedSender<Ether2Frame>,
     /// Device mac
     device_mac: Mac,
+    /// Biggest mtu the driver supports.
+    max_mtu: usize,
+    /// Max number of frames we receive, and send, before going back to sleep.
+    budget: usize,
+    /// Whether this is the loopback device.
+    loopback: bool,
+    /// Hint telling the driver whether we are polling it, if it supports that.
+    rx_mode: Option<RxModeHint>,
+    /// Link state changes reported by the driver, if it can tell.
+    link_stream: Option<Fuse<LinkStatusStream>>,
+    /// Current state of the link.
+    link: LinkState,
+    /// Tasks waiting for link state changes.
+    link_subscribers: Vec<UnboundedSender<LinkState>>,
+    /// Shutdown requests coming from `ShutdownHandle`s.
+    shutdown_rx: UnboundedReceiver<()>,
+    shutdown_tx: UnboundedSender<()>,
 }
 
 impl<T: NetworkDriver> NetworkDevice<T> {
@@ -83,9 +174,14 @@ impl<T: NetworkDriver> NetworkDevice<T> {
         // the network.
         let (tx_queue_sender, tx_queue) = channel();
         let device_mac = device.mac();
+        let max_mtu = device.max_mtu();
+        let (shutdown_tx, shutdown_rx) = channel();
 
         // Register this new network device.
         ETHERNET_LAYER.register_tx(device_mac, tx_queue_sender.clone()).await;
+        // every host is a member of the all systems group, which is where queries end up.
+        ETHERNET_LAYER.join_multicast(device_mac, Mac::multicast(igmp::ALL_SYSTEMS));
+        ETHERNET_LAYER.set_mtu(device_mac, device.mtu().min(max_mtu));
 
         Self {
             rx_sink: rx_sink.fuse(),
@@ -94,51 +190,344 @@ impl<T: NetworkDriver> NetworkDevice<T> {
             tx_queue: Some(tx_queue),
             tx_queue_sender,
             device_mac,
+            max_mtu,
+            budget: DEFAULT_BUDGET,
+            loopback: device.is_loopback(),
+            rx_mode: device.rx_mode_hint(),
+            link_stream: device.link_status().map(|x| x.fuse()),
+            link: LinkState::Up,
+            link_subscribers: Vec::new(),
+            shutdown_rx,
+            shutdown_tx,
+        }
+    }
+
+    /// Returns the current state of the link, devices whose driver cant tell are always up.
+    pub fn link_state(&self) -> LinkState {
+        self.link
+    }
+
+    /// Returns a receiver getting the new state of the link every time it changes, for things
+    /// like renewing leases or announcing ourselves once the link comes back.
+    pub fn link_events(&mut self) -> UnboundedReceiver<LinkState> {
+        let (tx, rx) = channel();
+        self.link_subscribers.push(tx);
+        rx
+    }
+
+    /// Returns a handle that can stop `run_forever` from another task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown_tx.clone())
+    }
+
+    /// Function shuts down a device that isnt running. The device is unregistered from the stack
+    /// and whatever is still queued up is handed to the driver. Running devices are shut down
+    /// through a `ShutdownHandle` instead.
+    pub async fn shutdown(mut self) {
+        if let Some(mut tx_queue) = self.tx_queue.take() {
+            self.finish(&mut tx_queue).await;
+        }
+    }
+
+    async fn finish(&mut self, tx_queue: &mut UnboundedReceiver<Ether2Frame>) {
+        ETHERNET_LAYER.unregister_tx(self.device_mac).await;
+        ARP_LAYER.unregister_local(self.device_mac).await;
+
+        // nothing new can end up in our queue now, whatever is left still goes out.
+        tx_queue.close();
+
+        let mut batch = Vec::new();
+        while let Some(Some(frame)) = tx_queue.recv().now_or_never() {
+            batch.push(frame.into_bytes());
+        }
+
+        self.flush(&mut batch).await;
+
+        self.link = LinkState::Down;
+        for subscriber in self.link_subscribers.drain(..) {
+            let _ = subscriber.send(LinkState::Down);
         }
     }
 
+    /// Function records a new link state and lets everyone who cares know about it.
+    async fn set_link(&mut self, state: LinkState) {
+        if state == self.link {
+            return;
+        }
+
+        self.link = state;
+        self.link_subscribers.retain(|x| x.send(state).is_ok());
+
+        if state == LinkState::Up {
+            // our neighbors may have flushed us from their caches while we were gone.
+            for ip in ARP_LAYER.local_ips(self.device_mac).await {
+                if !ip.is_loopback() {
+                    ARP_LAYER.announce(ip, self.device_mac).await;
+                }
+            }
+
+            IGMP_LAYER.link_up(self.device_mac);
+        }
+    }
+
+    /// Sets how many frames `run_forever` receives, and sends, in one go before it flushes the
+    /// driver and goes back to sleep. Bigger budgets mean less wakeups under load at the cost of
+    /// latency for everything else running.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget.max(1);
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Sets the mtu of this device, anything up to what the driver supports is allowed which
+    /// includes jumbo frames if the driver can do them.
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), ()> {
+        if mtu < MIN_MTU || mtu > self.max_mtu {
+            return Err(());
+        }
+
+        ETHERNET_LAYER.set_mtu(self.device_mac, mtu);
+        Ok(())
+    }
+
+    /// Returns the mtu of this device.
+    pub fn mtu(&self) -> usize {
+        ETHERNET_LAYER.mtu(self.device_mac)
+    }
+
     pub async fn set_ip(&mut self, ip: Ipv4Addr) {
         // Register our ip in the local arp table
         ARP_LAYER.register_local(ip, self.device_mac).await;
+        ETHERNET_LAYER.set_ip_vlan(ip, None);
         self.ip = ip;
+
+        // Let our neighbors know about our new ip.
+        if !ip.is_loopback() {
+            ARP_LAYER.announce(ip, self.device_mac).await;
+        }
+    }
+
+    /// Sets whether this device processes every frame it receives, not only the ones addressed
+    /// to it. Frames meant for other hosts are still handed to the capture tap either way.
+    pub fn set_promiscuous(&self, promiscuous: bool) {
+        ETHERNET_LAYER.set_promiscuous(self.device_mac, promiscuous);
+    }
+
+    /// Makes this device accept frames sent to the multicast `group`.
+    pub fn join_multicast(&self, group: Mac) {
+        ETHERNET_LAYER.join_multicast(self.device_mac, group);
+    }
+
+    /// Stops this device from accepting frames sent to `group`.
+    pub fn leave_multicast(&self, group: Mac) {
+        ETHERNET_LAYER.leave_multicast(self.device_mac, group);
+    }
+
+    /// Creates a sub-interface for the 802.1Q vlan `id` on this device. Frames tagged with any
+    /// vlan that doesnt have a sub-interface are dropped.
+    pub fn add_vlan(&self, id: u16) -> VlanInterface {
+        ETHERNET_LAYER.add_vlan(self.device_mac, id);
+
+        VlanInterface {
+            device_mac: self.device_mac,
+            id,
+        }
     }
 
     pub fn get_sender(&self) -> UnboundedSender<Ether2Frame> {
         self.tx_queue_sender.clone()
     }
 
-    /// Function will run forever grabbing packets from an rx sink and processing them.
+    /// Function will run forever grabbing packets from an rx sink and processing them, until it
+    /// is stopped through a `ShutdownHandle`.
+    ///
+    /// Frames are handled in batches, once something wakes us up we drain up to `budget` frames
+    /// from the driver and our tx queue without sleeping and hand everything we have to send to
+    /// the driver before a single flush.
     pub async fn run_forever(&mut self) {
         let mut tx_queue = self.tx_queue.take().expect("missing tx_queue");
+        let mut next_tick = get_milis() + TIMER_INTERVAL;
+        // frames waiting to be sent, they go to the driver at the end of every batch.
+        let mut batch = Vec::with_capacity(self.budget);
+
         loop {
-            // future that will resolve to a new ether2 frame from the NIC.
-            let rx_item = self.rx_sink.next();
-            // future that will resolve to a new ether2 frame that we need to send to the NIC.
-            let tx_item = tx_queue.recv().boxed().fuse();
-
-            match future::select(rx_item, tx_item).await {
-                future::Either::Left((item, _)) => {
-                    if let Some(frame) = item {
-                        if let Some(frame) = Ether2Frame::from_bytes(frame).ok() {
-                            if let Some(packet) = ETHERNET_LAYER.handle_rx(frame, self.device_mac).await {
-                                let _ = self.tx_sink.send(packet.into_bytes()).await;
-                                let _ = self.tx_sink.flush().await;
-                            }
-                        }
-                    }
+            use future::Either::{Left, Right};
+
+            // the driver doesnt interrupt us while we poll it, so instead of waiting for it we
+            // go straight back to draining it once other tasks had their turn.
+            let event = if self.polling() {
+                crate::async_::yield_now().await;
+
+                if self.shutdown_rx.recv().now_or_never().is_some() {
+                    Event::Shutdown
+                } else if get_milis() >= next_tick {
+                    Event::Tick
+                } else {
+                    Event::Poll
+                }
+            } else {
+                // future that will resolve to a new ether2 frame from the NIC.
+                let rx_item = self.rx_sink.next();
+                // future that will resolve to a new ether2 frame that we need to send to the NIC.
+                let tx_item = tx_queue.recv().boxed().fuse();
+                // future that will resolve once the link goes up or down.
+                let link_item = match self.link_stream.as_mut() {
+                    Some(x) => x.next().left_future(),
+                    None => future::pending().right_future(),
+                };
+                // future that will resolve once someone wants us to stop.
+                let shutdown_item = self.shutdown_rx.recv().boxed();
+                // future that will resolve once its time to run the timers of our layers.
+                let timer =
+                    Sleep::new(Duration::from_millis(next_tick.saturating_sub(get_milis())));
+
+                let frames = future::select(rx_item, tx_item);
+                let control = future::select(link_item, shutdown_item);
+
+                match future::select(future::select(frames, control), timer).await {
+                    Left((Left((Left((frame, _)), _)), _)) => Event::Rx(frame),
+                    Left((Left((Right((frame, _)), _)), _)) => Event::Tx(frame),
+                    Left((Right((Left((state, _)), _)), _)) => Event::Link(state),
+                    Left((Right((Right(_), _)), _)) => Event::Shutdown,
+                    Right(_) => Event::Tick,
                 }
-                future::Either::Right((item, _)) => {
-                    if let Some(frame) = item {
-                        if let Err(tx_send_err) = self.tx_sink.send(frame.into_bytes()).await {
-                            println!("net: tx_send_err {:?}", tx_send_err);
-                        }
-
-                        if let Err(tx_flush_err) = self.tx_sink.flush().await {
-                            println!("net: tx_flush_err {:?}", tx_flush_err);
-                        }
+            };
+
+            // the frame that woke us up is the first of the batch.
+            let mut received = 0;
+            match event {
+                Event::Rx(Some(frame)) => {
+                    received += 1;
+                    self.handle_rx(frame, &mut batch).await;
+                }
+                Event::Tx(Some(frame)) => batch.push(frame.into_bytes()),
+                Event::Link(Some(state)) => self.set_link(state).await,
+                // the driver stopped reporting, the link stays in whatever state it was.
+                Event::Link(None) => self.link_stream = None,
+                Event::Shutdown => {
+                    self.finish(&mut tx_queue).await;
+                    return;
+                }
+                Event::Tick => {
+                    next_tick = get_milis() + TIMER_INTERVAL;
+                    ARP_LAYER.poll_timers().await;
+                    FIREWALL.poll_timers();
+                    IGMP_LAYER.poll_timers().await;
+                    IP_LAYER.poll_timers().await;
+                    TCP_LAYER.poll_timers().await;
+                }
+                Event::Rx(None) | Event::Tx(None) | Event::Poll => {}
+            }
+
+            // everything else that is already waiting gets picked up without going back to sleep.
+            while received < self.budget {
+                match self.rx_sink.next().now_or_never() {
+                    Some(Some(frame)) => {
+                        received += 1;
+                        self.handle_rx(frame, &mut batch).await;
                     }
+                    _ => break,
+                }
+            }
+
+            // this includes whatever the layers above queued up while handling the frames we
+            // just received.
+            for _ in 0..self.budget {
+                match tx_queue.recv().now_or_never() {
+                    Some(Some(frame)) => batch.push(frame.into_bytes()),
+                    _ => break,
+                }
+            }
+
+            self.flush(&mut batch).await;
+
+            // using up the whole budget means frames come in faster than we handle them, so the
+            // driver is better off not interrupting us for every single one.
+            if let Some(hint) = self.rx_mode.as_ref() {
+                if received >= self.budget {
+                    hint.set_mode(RxMode::Polling);
+                } else {
+                    hint.set_mode(RxMode::Interrupt);
                 }
             }
         }
     }
+
+    /// Returns whether we told the driver we are polling it.
+    fn polling(&self) -> bool {
+        self.rx_mode
+            .as_ref()
+            .map_or(false, |x| x.mode() == RxMode::Polling)
+    }
+
+    /// Function hands a received frame to the stack, queueing the reply if there is one.
+    async fn handle_rx(&self, frame: Vec<u8>, batch: &mut Vec<Vec<u8>>) {
+        match Ether2Frame::from_bytes(frame) {
+            Ok(frame) => {
+                let reply = ETHERNET_LAYER.handle_rx(frame, self.device_mac, self.loopback);
+                if let Some(packet) = reply.await {
+                    batch.push(packet.into_bytes());
+                }
+            }
+            Err(_) => stats::ETHERNET.dropped(stats::DropReason::Malformed),
+        }
+    }
+
+    /// Function hands every frame in `batch` to the driver and flushes it once.
+    async fn flush(&mut self, batch: &mut Vec<Vec<u8>>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        for frame in batch.drain(..) {
+            if let Err(tx_send_err) = self.tx_sink.feed(frame).await {
+                println!("net: tx_send_err {:?}", tx_send_err);
+            }
+        }
+
+        if let Err(tx_flush_err) = self.tx_sink.flush().await {
+            println!("net: tx_flush_err {:?}", tx_flush_err);
+        }
+    }
+}
+
+/// Handle that stops `NetworkDevice::run_forever`, the device gets unregistered from the stack
+/// and its queues are drained before `run_forever` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle(UnboundedSender<()>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// A 802.1Q vlan sub-interface of a `NetworkDevice`, created with `NetworkDevice::add_vlan`.
+/// Traffic from and to its ips is tagged with its vlan id, the device itself still does all the
+/// sending and receiving.
+pub struct VlanInterface {
+    device_mac: Mac,
+    id: u16,
+}
+
+impl VlanInterface {
+    /// Returns the vlan id of this interface.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub async fn set_ip(&self, ip: Ipv4Addr) {
+        ARP_LAYER.register_local(ip, self.device_mac).await;
+        ETHERNET_LAYER.set_ip_vlan(ip, Some(self.id));
+
+        // Let our neighbors on this vlan know about our new ip.
+        ARP_LAYER.announce(ip, self.device_mac).await;
+    }
+
+    /// Removes this sub-interface from its device, its ips fall back to the untagged interface.
+    pub fn remove(self) {
+        ETHERNET_LAYER.remove_vlan(self.device_mac, self.id);
+    }
 }
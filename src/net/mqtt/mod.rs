@@ -0,0 +1,831 @@
+//! MQTT 3.1.1 and 5 client on top of our tcp streams.
+//!
+//! Every client is driven by its own task, which keeps the connection alive with pings, sees
+//! publishes of QoS 1 and 2 through their acks and reconnects after the connection drops.
+//! Publishes that werent acked yet are sent again after a reconnect, and subscriptions are
+//! made again if the broker didnt keep our session. Each subscription is a `Stream` of the
+//! publishes matching its filter, dropping it unsubscribes.
+//!
+//! ```ignore
+//! let client = Client::connect("10.0.0.1:1883", Options::new("sensor-1")).await?;
+//! let mut sub = client.subscribe("cmd/sensor-1/#", QoS::AtLeastOnce).await?;
+//! client.publish("telemetry/sensor-1", b"21.5", QoS::AtLeastOnce, true).await?;
+//!
+//! while let Some(msg) = sub.next().await {
+//!     println!("{}: {:?}", msg.topic, msg.payload);
+//! }
+//! ```
+mod packet;
+
+use packet::Packet;
+
+use crate::arch::pit::get_milis;
+use crate::async_;
+use crate::async_::Sleep;
+use crate::collections::BTreeMap;
+use crate::collections::BTreeSet;
+use crate::net::addr::SocketAddr;
+use crate::net::addr::ToSocketAddrs;
+use crate::net::socks::TcpStream;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedReceiver;
+use crate::sync::mpsc::UnboundedSender;
+use crate::sync::Arc;
+
+use core::fmt;
+use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::task::Context;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::future;
+use futures_util::stream::Stream;
+
+/// How long we wait for the broker to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait between reconnect attempts, the delay doubles up to it.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const READ_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Version {
+    Mqtt311,
+    Mqtt5,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+impl QoS {
+    fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            0 => Some(Self::AtMostOnce),
+            1 => Some(Self::AtLeastOnce),
+            2 => Some(Self::ExactlyOnce),
+            _ => None,
+        }
+    }
+}
+
+/// A application message, sent by us or delivered through a subscription.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    /// Whether the broker keeps the message for later subscribers, on delivery whether it is
+    /// such a kept message.
+    pub retain: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The broker couldnt be reached or didnt answer.
+    Connect,
+    /// The broker refused the connection with a return code, a reason code in 5.
+    Refused(u8),
+    /// The broker refused a publish or subscription with a reason code.
+    Rejected(u8),
+    /// The broker broke the protocol.
+    Protocol,
+    /// The topic or filter isnt valid.
+    InvalidTopic,
+    /// The client disconnected and doesnt reconnect.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Connect => write!(f, "couldnt connect to broker"),
+            Self::Refused(x) => write!(f, "connection refused with code {:#x}", x),
+            Self::Rejected(x) => write!(f, "rejected with code {:#x}", x),
+            Self::Protocol => write!(f, "protocol error"),
+            Self::InvalidTopic => write!(f, "invalid topic"),
+            Self::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+/// Settings of a client.
+#[derive(Clone, Debug)]
+pub struct Options {
+    client_id: String,
+    version: Version,
+    keep_alive: Duration,
+    clean_start: bool,
+    username: Option<String>,
+    password: Option<Vec<u8>>,
+    will: Option<Publish>,
+    reconnect: Option<Duration>,
+}
+
+impl Options {
+    /// Returns options for a 3.1.1 client with a clean session, a keep alive of 60 seconds
+    /// and reconnects starting one second after a drop.
+    pub fn new(client_id: &str) -> Self {
+        Self {
+            client_id: client_id.into(),
+            version: Version::Mqtt311,
+            keep_alive: Duration::from_secs(60),
+            clean_start: true,
+            username: None,
+            password: None,
+            will: None,
+            reconnect: Some(Duration::from_secs(1)),
+        }
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Function sets the keep alive, whole seconds up to 65535 of it. 0 turns pings off.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive.min(Duration::from_secs(u16::MAX as u64));
+        self
+    }
+
+    /// Function sets whether the broker drops our session when we connect. Kept sessions keep
+    /// our subscriptions and publishes of QoS 1 and 2 over reconnects.
+    pub fn clean_start(mut self, clean_start: bool) -> Self {
+        self.clean_start = clean_start;
+        self
+    }
+
+    pub fn credentials(mut self, username: &str, password: &[u8]) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.to_vec());
+        self
+    }
+
+    /// Function sets the message the broker publishes for us if we go away without
+    /// disconnecting.
+    pub fn will(mut self, will: Publish) -> Self {
+        self.will = Some(will);
+        self
+    }
+
+    /// Function sets the first delay between reconnect attempts, `None` gives up on the first
+    /// drop.
+    pub fn reconnect(mut self, delay: Option<Duration>) -> Self {
+        self.reconnect = delay;
+        self
+    }
+}
+
+/// What the handles of a client ask of its task.
+enum Request {
+    Publish(Publish, UnboundedSender<Result<(), Error>>),
+    Subscribe(Route, UnboundedSender<Result<(u64, QoS), Error>>),
+    Unsubscribe(u64),
+    Disconnect,
+}
+
+/// A MQTT client. Clones share the connection, it is closed once all of them and their
+/// subscriptions are dropped.
+#[derive(Clone)]
+pub struct Client {
+    tx: UnboundedSender<Request>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Client {
+    /// Function connects to the broker at `addr`, the client reconnects to it on its own
+    /// later on.
+    pub async fn connect<A: ToSocketAddrs>(addr: A, options: Options) -> Result<Self, Error> {
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or(Error::Connect)?;
+
+        let session = open(addr, &options).await?;
+        let (tx, requests) = channel();
+        let connected = Arc::new(AtomicBool::new(true));
+
+        let driver = Driver {
+            addr,
+            keep_alive: session.keep_alive,
+            options,
+            connected: connected.clone(),
+            requests,
+            buf: session.buf,
+            last_sent: get_milis(),
+            ping_sent: None,
+            next_id: 0,
+            outgoing: BTreeMap::new(),
+            incoming: BTreeSet::new(),
+            routes: Vec::new(),
+            next_key: 0,
+            pending: BTreeMap::new(),
+        };
+        async_::spawn(driver.run(session.stream));
+
+        Ok(Self { tx, connected })
+    }
+
+    /// Function publishes `payload` to `topic`, and waits for the broker to ack it at QoS 1 and
+    /// 2. Publishes made while the client reconnects go out once it is back.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), Error> {
+        if topic.is_empty() || topic.contains(|x: char| x == '+' || x == '#') {
+            return Err(Error::InvalidTopic);
+        }
+
+        let publish = Publish {
+            topic: topic.into(),
+            payload: payload.to_vec(),
+            qos,
+            retain,
+        };
+
+        let (tx, mut rx) = channel();
+        self.tx
+            .send(Request::Publish(publish, tx))
+            .map_err(|_| Error::Closed)?;
+
+        rx.recv().await.unwrap_or(Err(Error::Closed))
+    }
+
+    /// Function subscribes to `filter`, and returns the publishes matching it once the broker
+    /// acked the subscription. `Subscription::qos` is the QoS the broker granted.
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<Subscription, Error> {
+        if !is_valid_filter(filter) {
+            return Err(Error::InvalidTopic);
+        }
+
+        let (messages, rx) = channel();
+        let route = Route {
+            key: 0,
+            filter: filter.into(),
+            qos,
+            messages,
+        };
+
+        let (tx, mut reply) = channel();
+        self.tx
+            .send(Request::Subscribe(route, tx))
+            .map_err(|_| Error::Closed)?;
+
+        let (key, qos) = reply.recv().await.unwrap_or(Err(Error::Closed))?;
+        Ok(Subscription {
+            client: self.clone(),
+            key,
+            filter: filter.into(),
+            qos,
+            messages: rx,
+        })
+    }
+
+    /// Function disconnects from the broker, which then drops our will.
+    pub fn disconnect(&self) {
+        let _ = self.tx.send(Request::Disconnect);
+    }
+
+    /// Returns whether the client is connected right now, rather than reconnecting.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// The publishes matching a filter, dropping it unsubscribes from the filter unless another
+/// subscription uses it too.
+pub struct Subscription {
+    client: Client,
+    key: u64,
+    filter: String,
+    qos: QoS,
+    messages: UnboundedReceiver<Publish>,
+}
+
+impl Subscription {
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    /// Returns the QoS the broker granted.
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    /// Function waits for the next publish, `None` once the client is gone.
+    pub async fn recv(&mut self) -> Option<Publish> {
+        self.messages.recv().await
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self.client.tx.send(Request::Unsubscribe(self.key));
+    }
+}
+
+/// Returns whether `filter` is a valid topic filter, wildcards take up a whole level and `#`
+/// can only be the last one.
+fn is_valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, x)| match *x {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            x => !x.contains(|c| c == '+' || c == '#'),
+        })
+}
+
+/// Returns whether `topic` matches `filter`. Topics starting with `$` are only matched by
+/// filters that spell out their first level.
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    let mut first = true;
+
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), Some(x)) => return !(first && x.starts_with('$')),
+            (Some("#"), None) => return true,
+            (Some("+"), Some(x)) if !(first && x.starts_with('$')) => {}
+            (Some(x), Some(y)) if x == y => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+
+        first = false;
+    }
+}
+
+/// A connection the broker accepted.
+struct Session {
+    stream: TcpStream,
+    session_present: bool,
+    /// Keep alive in ms, ours or the one of the broker.
+    keep_alive: u64,
+    /// Bytes that came after the CONNACK.
+    buf: Vec<u8>,
+}
+
+/// Function connects to the broker and waits for it to accept us.
+async fn open(addr: SocketAddr, options: &Options) -> Result<Session, Error> {
+    let mut stream = TcpStream::connect(addr).await.map_err(|_| Error::Connect)?;
+    stream.write(&packet::connect(options)).await;
+
+    let mut buf = Vec::new();
+    let connack = async_::timeout(CONNECT_TIMEOUT, async {
+        let mut chunk = [0; READ_LEN];
+
+        loop {
+            let len = stream.read(&mut chunk).await;
+            if len == 0 {
+                return Err(Error::Connect);
+            }
+
+            buf.extend_from_slice(&chunk[..len]);
+            match packet::parse(&buf, options.version) {
+                Ok(Some((packet, len))) => return Ok((packet, len)),
+                Ok(None) => {}
+                Err(_) => return Err(Error::Protocol),
+            }
+        }
+    })
+    .await
+    .unwrap_or(Err(Error::Connect))?;
+
+    match connack {
+        (
+            Packet::ConnAck {
+                session_present,
+                code: 0,
+                keep_alive,
+            },
+            len,
+        ) => {
+            buf.drain(..len);
+            let keep_alive = keep_alive
+                .map(|x| x as u64 * 1000)
+                .unwrap_or(options.keep_alive.as_secs() * 1000);
+
+            Ok(Session {
+                stream,
+                session_present,
+                keep_alive,
+                buf,
+            })
+        }
+        (Packet::ConnAck { code, .. }, _) => Err(Error::Refused(code)),
+        _ => Err(Error::Protocol),
+    }
+}
+
+/// A publish of QoS 1 or 2 waiting for the broker.
+struct Outgoing {
+    publish: Publish,
+    /// Whether the broker got it at QoS 2 and we sent the PUBREL.
+    released: bool,
+    reply: UnboundedSender<Result<(), Error>>,
+}
+
+/// Where the driver sends the publishes matching a subscription.
+struct Route {
+    key: u64,
+    filter: String,
+    qos: QoS,
+    messages: UnboundedSender<Publish>,
+}
+
+/// A SUBSCRIBE or UNSUBSCRIBE waiting for its ack, subscriptions made again after a reconnect
+/// dont have anyone waiting on them.
+enum Pending {
+    Subscribe(Route, UnboundedSender<Result<(u64, QoS), Error>>),
+    Resubscribe,
+    Unsubscribe,
+}
+
+/// Why a connection ended.
+enum Exit {
+    Lost,
+    Disconnect,
+}
+
+/// What woke up the driver.
+enum Wakeup {
+    Data(usize),
+    Request(Option<Request>),
+    Timer,
+}
+
+/// The task behind a client.
+struct Driver {
+    addr: SocketAddr,
+    options: Options,
+    /// Keep alive of the current connection in ms.
+    keep_alive: u64,
+    connected: Arc<AtomicBool>,
+    requests: UnboundedReceiver<Request>,
+    /// Bytes received that dont make up a whole packet yet.
+    buf: Vec<u8>,
+    last_sent: u64,
+    ping_sent: Option<u64>,
+    next_id: u16,
+    outgoing: BTreeMap<u16, Outgoing>,
+    /// Packet ids of QoS 2 publishes of the broker we delivered and wait on the PUBREL of.
+    incoming: BTreeSet<u16>,
+    routes: Vec<Route>,
+    next_key: u64,
+    pending: BTreeMap<u16, Pending>,
+}
+
+impl Driver {
+    async fn run(mut self, mut stream: TcpStream) {
+        loop {
+            let exit = self.serve(&mut stream).await;
+            self.connected.store(false, Ordering::Relaxed);
+
+            let mut backoff = match (exit, self.options.reconnect) {
+                (Exit::Lost, Some(x)) => x,
+                _ => break,
+            };
+
+            let session = loop {
+                // nobody is left to use the connection.
+                if Arc::strong_count(&self.connected) == 1 {
+                    return self.close();
+                }
+
+                match open(self.addr, &self.options).await {
+                    Ok(x) => break x,
+                    // a broker refusing us wont change its mind.
+                    Err(Error::Refused(_)) => return self.close(),
+                    Err(_) => {
+                        Sleep::new(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            };
+
+            stream = session.stream;
+            self.keep_alive = session.keep_alive;
+            self.buf = session.buf;
+            self.ping_sent = None;
+            self.last_sent = get_milis();
+            self.connected.store(true, Ordering::Relaxed);
+            self.resume(&mut stream, session.session_present).await;
+        }
+
+        self.close();
+    }
+
+    /// Function fails everything waiting on the client, subscriptions end with it.
+    fn close(&mut self) {
+        for (_, x) in core::mem::take(&mut self.outgoing) {
+            let _ = x.reply.send(Err(Error::Closed));
+        }
+
+        for (_, x) in core::mem::take(&mut self.pending) {
+            if let Pending::Subscribe(_, reply) = x {
+                let _ = reply.send(Err(Error::Closed));
+            }
+        }
+
+        self.routes.clear();
+        self.requests.close();
+    }
+
+    /// Function picks up where the last connection left off.
+    async fn resume(&mut self, stream: &mut TcpStream, session_present: bool) {
+        // subscriptions that were waiting on a ack are sent again, and all of them if the
+        // broker forgot our session.
+        for (_, pending) in core::mem::take(&mut self.pending) {
+            if let Pending::Subscribe(route, reply) = pending {
+                self.subscribe(stream, route, reply).await;
+            }
+        }
+
+        if !session_present {
+            let mut filters: Vec<(String, QoS)> = self
+                .routes
+                .iter()
+                .map(|x| (x.filter.clone(), x.qos))
+                .collect();
+            filters.sort();
+            filters.dedup_by(|a, b| a.0 == b.0);
+
+            for (filter, qos) in filters {
+                let id = self.next_id();
+                self.pending.insert(id, Pending::Resubscribe);
+                let packet = packet::subscribe(id, &filter, qos, self.options.version);
+                self.write(stream, &packet).await;
+            }
+        }
+
+        // publishes that werent acked are sent again, marked as duplicates (MQTT 3.1.1 4.4).
+        let resend: Vec<Vec<u8>> = self
+            .outgoing
+            .iter()
+            .map(|(id, x)| match x.released {
+                true => packet::pubrel(*id),
+                false => packet::publish(Some(*id), &x.publish, true, self.options.version),
+            })
+            .collect();
+
+        for packet in resend {
+            self.write(stream, &packet).await;
+        }
+    }
+
+    /// Function runs a connection until it drops or we disconnect.
+    async fn serve(&mut self, stream: &mut TcpStream) -> Exit {
+        let mut chunk = vec![0; READ_LEN];
+
+        loop {
+            loop {
+                match packet::parse(&self.buf, self.options.version) {
+                    Ok(Some((packet, len))) => {
+                        self.buf.drain(..len);
+                        if self.handle(stream, packet).await.is_err() {
+                            return Exit::Lost;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(_) => return Exit::Lost,
+                }
+            }
+
+            // we ping once we were quiet for the keep alive, and give up on a broker that
+            // doesnt answer within another one.
+            let now = get_milis();
+            let deadline = match self.ping_sent {
+                _ if self.keep_alive == 0 => None,
+                Some(x) if now >= x + self.keep_alive => return Exit::Lost,
+                Some(x) => Some(x + self.keep_alive),
+                None if now >= self.last_sent + self.keep_alive => {
+                    self.write(stream, &packet::pingreq()).await;
+                    self.ping_sent = Some(now);
+                    Some(now + self.keep_alive)
+                }
+                None => Some(self.last_sent + self.keep_alive),
+            };
+
+            let wakeup = {
+                let data = stream.read(&mut chunk);
+                let request = self.requests.recv();
+                let period = deadline.map_or(Duration::from_secs(3600), |x| {
+                    Duration::from_millis(x.saturating_sub(now))
+                });
+                let sleep = Sleep::new(period);
+                futures_util::pin_mut!(data, request);
+
+                match future::select(data, future::select(request, sleep)).await {
+                    future::Either::Left((x, _)) => Wakeup::Data(x),
+                    future::Either::Right((future::Either::Left((x, _)), _)) => Wakeup::Request(x),
+                    future::Either::Right(_) => Wakeup::Timer,
+                }
+            };
+
+            match wakeup {
+                Wakeup::Data(0) => return Exit::Lost,
+                Wakeup::Data(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Wakeup::Request(Some(Request::Publish(publish, reply))) => {
+                    self.publish(stream, publish, reply).await
+                }
+                Wakeup::Request(Some(Request::Subscribe(route, reply))) => {
+                    self.subscribe(stream, route, reply).await
+                }
+                Wakeup::Request(Some(Request::Unsubscribe(key))) => {
+                    self.unsubscribe(stream, key).await
+                }
+                // every handle is gone, or one of them asked us to go.
+                Wakeup::Request(Some(Request::Disconnect)) | Wakeup::Request(None) => {
+                    self.write(stream, &packet::disconnect()).await;
+                    return Exit::Disconnect;
+                }
+                Wakeup::Timer => {}
+            }
+        }
+    }
+
+    /// Returns a packet id that isnt in use.
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            let id = self.next_id;
+
+            if id != 0 && !self.outgoing.contains_key(&id) && !self.pending.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    async fn write(&mut self, stream: &mut TcpStream, packet: &[u8]) {
+        stream.write(packet).await;
+        self.last_sent = get_milis();
+    }
+
+    async fn publish(
+        &mut self,
+        stream: &mut TcpStream,
+        publish: Publish,
+        reply: UnboundedSender<Result<(), Error>>,
+    ) {
+        if publish.qos == QoS::AtMostOnce {
+            let packet = packet::publish(None, &publish, false, self.options.version);
+            self.write(stream, &packet).await;
+            let _ = reply.send(Ok(()));
+            return;
+        }
+
+        let id = self.next_id();
+        let packet = packet::publish(Some(id), &publish, false, self.options.version);
+        self.outgoing.insert(
+            id,
+            Outgoing {
+                publish,
+                released: false,
+                reply,
+            },
+        );
+        self.write(stream, &packet).await;
+    }
+
+    async fn subscribe(
+        &mut self,
+        stream: &mut TcpStream,
+        mut route: Route,
+        reply: UnboundedSender<Result<(u64, QoS), Error>>,
+    ) {
+        // routes sent again after a reconnect already have a key.
+        if route.key == 0 {
+            self.next_key += 1;
+            route.key = self.next_key;
+        }
+
+        let id = self.next_id();
+        let packet = packet::subscribe(id, &route.filter, route.qos, self.options.version);
+        self.pending.insert(id, Pending::Subscribe(route, reply));
+        self.write(stream, &packet).await;
+    }
+
+    /// Function forgets a dropped subscription, and unsubscribes from its filter if nobody
+    /// else listens on it.
+    async fn unsubscribe(&mut self, stream: &mut TcpStream, key: u64) {
+        let route = match self.routes.iter().position(|x| x.key == key) {
+            Some(x) => self.routes.remove(x),
+            None => return,
+        };
+
+        if self.routes.iter().any(|x| x.filter == route.filter) {
+            return;
+        }
+
+        let id = self.next_id();
+        self.pending.insert(id, Pending::Unsubscribe);
+        let packet = packet::unsubscribe(id, &route.filter, self.options.version);
+        self.write(stream, &packet).await;
+    }
+
+    /// Function handles a packet of the broker, errors are a broken protocol.
+    async fn handle(&mut self, stream: &mut TcpStream, packet: Packet) -> Result<(), ()> {
+        match packet {
+            Packet::ConnAck { .. } => return Err(()),
+            Packet::Publish { id, publish, .. } => match (publish.qos, id) {
+                (QoS::AtMostOnce, _) => self.deliver(&publish),
+                (QoS::AtLeastOnce, Some(id)) => {
+                    self.deliver(&publish);
+                    self.write(stream, &packet::puback(id)).await;
+                }
+                // a publish of QoS 2 is delivered once, even when the broker sends it again
+                // before our PUBREC got there.
+                (QoS::ExactlyOnce, Some(id)) => {
+                    if self.incoming.insert(id) {
+                        self.deliver(&publish);
+                    }
+                    self.write(stream, &packet::pubrec(id)).await;
+                }
+                _ => return Err(()),
+            },
+            Packet::PubAck(id, code) => {
+                if let Some(x) = self.outgoing.remove(&id) {
+                    let _ = x.reply.send(if code < 0x80 {
+                        Ok(())
+                    } else {
+                        Err(Error::Rejected(code))
+                    });
+                }
+            }
+            Packet::PubRec(id, code) => {
+                if code >= 0x80 {
+                    if let Some(x) = self.outgoing.remove(&id) {
+                        let _ = x.reply.send(Err(Error::Rejected(code)));
+                    }
+                    return Ok(());
+                }
+
+                if let Some(x) = self.outgoing.get_mut(&id) {
+                    x.released = true;
+                }
+                self.write(stream, &packet::pubrel(id)).await;
+            }
+            Packet::PubComp(id) => {
+                if let Some(x) = self.outgoing.remove(&id) {
+                    let _ = x.reply.send(Ok(()));
+                }
+            }
+            Packet::PubRel(id) => {
+                self.incoming.remove(&id);
+                self.write(stream, &packet::pubcomp(id)).await;
+            }
+            Packet::SubAck { id, codes } => {
+                if let Some(Pending::Subscribe(mut route, reply)) = self.pending.remove(&id) {
+                    let code = codes.first().copied().unwrap_or(0x80);
+                    match QoS::from_u8(code) {
+                        Some(qos) => {
+                            route.qos = qos;
+                            let _ = reply.send(Ok((route.key, qos)));
+                            self.routes.push(route);
+                        }
+                        None => {
+                            let _ = reply.send(Err(Error::Rejected(code)));
+                        }
+                    }
+                }
+            }
+            Packet::UnsubAck(id) => {
+                self.pending.remove(&id);
+            }
+            Packet::PingResp => self.ping_sent = None,
+            Packet::Disconnect(_) => return Err(()),
+        }
+
+        Ok(())
+    }
+
+    /// Function hands a publish to every subscription whose filter matches it.
+    fn deliver(&self, publish: &Publish) {
+        for route in self.routes.iter() {
+            if matches(&route.filter, &publish.topic) {
+                let _ = route.messages.send(publish.clone());
+            }
+        }
+    }
+}
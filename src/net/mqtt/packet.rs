@@ -0,0 +1,395 @@
+//! Control packets of MQTT 3.1.1 and 5, the parts a client sends and receives.
+
+use super::Options;
+use super::Publish;
+use super::QoS;
+use super::Version;
+
+use crate::prelude::*;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Properties of MQTT 5 we act on.
+const SESSION_EXPIRY: u8 = 0x11;
+const SERVER_KEEP_ALIVE: u8 = 0x13;
+
+/// Biggest packet we take from the broker.
+pub const MAX_PACKET_LEN: usize = 256 * 1024;
+
+/// Packets the broker sends us.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Packet {
+    ConnAck {
+        session_present: bool,
+        /// Return code in 3.1.1, reason code in 5.
+        code: u8,
+        /// Keep alive the broker wants us to use instead of ours, in seconds.
+        keep_alive: Option<u16>,
+    },
+    Publish {
+        id: Option<u16>,
+        publish: Publish,
+        dup: bool,
+    },
+    /// The acks of publishes with their packet id and reason code, 0 in 3.1.1.
+    PubAck(u16, u8),
+    PubRec(u16, u8),
+    PubRel(u16),
+    PubComp(u16),
+    SubAck {
+        id: u16,
+        codes: Vec<u8>,
+    },
+    UnsubAck(u16),
+    PingResp,
+    /// A broker speaking 5 can disconnect us with a reason code.
+    Disconnect(u8),
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+
+        let (x, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(x)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn varint(&mut self) -> Option<usize> {
+        let mut value = 0;
+
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the keep alive the properties of a CONNACK carry, skipping everything else.
+    fn properties(&mut self) -> Option<Option<u16>> {
+        let len = self.varint()?;
+        let mut r = Reader {
+            buf: self.bytes(len)?,
+        };
+
+        let mut keep_alive = None;
+        while !r.buf.is_empty() {
+            match r.varint()? as u8 {
+                SERVER_KEEP_ALIVE => keep_alive = Some(r.u16()?),
+                0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2a => {
+                    r.u8()?;
+                }
+                0x21 | 0x22 | 0x23 => {
+                    r.u16()?;
+                }
+                0x02 | 0x11 | 0x18 | 0x27 => {
+                    r.bytes(4)?;
+                }
+                0x0b => {
+                    r.varint()?;
+                }
+                0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1a | 0x1c | 0x1f => {
+                    let len = r.u16()? as usize;
+                    r.bytes(len)?;
+                }
+                0x26 => {
+                    r.string()?;
+                    r.string()?;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(keep_alive)
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut x: usize) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+
+        if x == 0 {
+            buf.push(byte);
+            return;
+        }
+
+        buf.push(byte | 0x80);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Returns a packet with its fixed header in front of `body`.
+fn packet(kind: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(body.len() + 5);
+    buf.push(kind << 4 | flags);
+    put_varint(&mut buf, body.len());
+    buf.extend_from_slice(body);
+    buf
+}
+
+pub fn connect(options: &Options) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT");
+    body.push(match options.version {
+        Version::Mqtt311 => 4,
+        Version::Mqtt5 => 5,
+    });
+
+    let mut flags = 0;
+    if options.clean_start {
+        flags |= 0x02;
+    }
+    if let Some(will) = &options.will {
+        flags |= 0x04 | (will.qos as u8) << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+
+    body.push(flags);
+    body.extend_from_slice(&(options.keep_alive.as_secs() as u16).to_be_bytes());
+
+    // a session that outlives the connection needs a expiry in 5, ours never expires.
+    if options.version == Version::Mqtt5 {
+        if options.clean_start {
+            body.push(0);
+        } else {
+            body.extend_from_slice(&[5, SESSION_EXPIRY, 0xff, 0xff, 0xff, 0xff]);
+        }
+    }
+
+    put_bytes(&mut body, options.client_id.as_bytes());
+
+    if let Some(will) = &options.will {
+        if options.version == Version::Mqtt5 {
+            body.push(0);
+        }
+        put_bytes(&mut body, will.topic.as_bytes());
+        put_bytes(&mut body, &will.payload);
+    }
+    if let Some(x) = &options.username {
+        put_bytes(&mut body, x.as_bytes());
+    }
+    if let Some(x) = &options.password {
+        put_bytes(&mut body, x);
+    }
+
+    packet(CONNECT, 0, &body)
+}
+
+pub fn publish(id: Option<u16>, publish: &Publish, dup: bool, version: Version) -> Vec<u8> {
+    let mut body = Vec::with_capacity(publish.topic.len() + publish.payload.len() + 8);
+    put_bytes(&mut body, publish.topic.as_bytes());
+
+    if let Some(id) = id {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    if version == Version::Mqtt5 {
+        body.push(0);
+    }
+    body.extend_from_slice(&publish.payload);
+
+    let mut flags = (publish.qos as u8) << 1;
+    if dup {
+        flags |= 0x08;
+    }
+    if publish.retain {
+        flags |= 0x01;
+    }
+
+    packet(PUBLISH, flags, &body)
+}
+
+pub fn puback(id: u16) -> Vec<u8> {
+    packet(PUBACK, 0, &id.to_be_bytes())
+}
+
+pub fn pubrec(id: u16) -> Vec<u8> {
+    packet(PUBREC, 0, &id.to_be_bytes())
+}
+
+pub fn pubrel(id: u16) -> Vec<u8> {
+    packet(PUBREL, 0x02, &id.to_be_bytes())
+}
+
+pub fn pubcomp(id: u16) -> Vec<u8> {
+    packet(PUBCOMP, 0, &id.to_be_bytes())
+}
+
+pub fn subscribe(id: u16, filter: &str, qos: QoS, version: Version) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    if version == Version::Mqtt5 {
+        body.push(0);
+    }
+
+    put_bytes(&mut body, filter.as_bytes());
+    body.push(qos as u8);
+    packet(SUBSCRIBE, 0x02, &body)
+}
+
+pub fn unsubscribe(id: u16, filter: &str, version: Version) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+    if version == Version::Mqtt5 {
+        body.push(0);
+    }
+
+    put_bytes(&mut body, filter.as_bytes());
+    packet(UNSUBSCRIBE, 0x02, &body)
+}
+
+pub fn pingreq() -> Vec<u8> {
+    packet(PINGREQ, 0, &[])
+}
+
+pub fn disconnect() -> Vec<u8> {
+    packet(DISCONNECT, 0, &[])
+}
+
+/// Parses the first packet in `buf`, returns it along with its length or `None` if it isnt
+/// complete yet. Errors are packets that break the protocol.
+pub fn parse(buf: &[u8], version: Version) -> Result<Option<(Packet, usize)>, ()> {
+    let mut r = Reader { buf };
+    let first = match r.u8() {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    // the length takes at most 4 bytes, anything longer is broken.
+    let len = match r.varint() {
+        Some(x) => x,
+        None if buf.len() >= 5 => return Err(()),
+        None => return Ok(None),
+    };
+
+    if len > MAX_PACKET_LEN {
+        return Err(());
+    }
+
+    let header_len = buf.len() - r.buf.len();
+    let body = match r.bytes(len) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let packet = parse_body(first >> 4, first & 0x0f, body, version).ok_or(())?;
+    Ok(Some((packet, header_len + len)))
+}
+
+fn parse_body(kind: u8, flags: u8, body: &[u8], version: Version) -> Option<Packet> {
+    let v5 = version == Version::Mqtt5;
+    let mut r = Reader { buf: body };
+
+    let packet = match kind {
+        CONNACK => {
+            let session_present = r.u8()? & 0x01 != 0;
+            let code = r.u8()?;
+            let keep_alive = if v5 { r.properties()? } else { None };
+
+            Packet::ConnAck {
+                session_present,
+                code,
+                keep_alive,
+            }
+        }
+        PUBLISH => {
+            let qos = QoS::from_u8((flags >> 1) & 0x03)?;
+            let topic = r.string()?;
+            let id = if qos == QoS::AtMostOnce {
+                None
+            } else {
+                Some(r.u16()?)
+            };
+
+            if v5 {
+                r.properties()?;
+            }
+
+            Packet::Publish {
+                id,
+                dup: flags & 0x08 != 0,
+                publish: Publish {
+                    topic,
+                    payload: r.buf.to_vec(),
+                    qos,
+                    retain: flags & 0x01 != 0,
+                },
+            }
+        }
+        PUBACK | PUBREC | PUBREL | PUBCOMP => {
+            let id = r.u16()?;
+            let code = r.u8().unwrap_or(0);
+
+            match kind {
+                PUBACK => Packet::PubAck(id, code),
+                PUBREC => Packet::PubRec(id, code),
+                PUBREL => Packet::PubRel(id),
+                _ => Packet::PubComp(id),
+            }
+        }
+        SUBACK => {
+            let id = r.u16()?;
+            if v5 {
+                r.properties()?;
+            }
+
+            Packet::SubAck {
+                id,
+                codes: r.buf.to_vec(),
+            }
+        }
+        UNSUBACK => Packet::UnsubAck(r.u16()?),
+        PINGRESP => Packet::PingResp,
+        DISCONNECT if v5 => Packet::Disconnect(r.u8().unwrap_or(0)),
+        _ => return None,
+    };
+
+    Some(packet)
+}
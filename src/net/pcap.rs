@@ -0,0 +1,343 @@
+//! Pcap reading and writing.
+//!
+//! Frames flowing through `Ethernet::handle_rx` and `Ethernet::handle_tx` can be captured into a
+//! in-memory ring buffer or streamed out of the second serial port, either way the output is a
+//! regular pcap file that can be opened with Wireshark. With qemu the serial capture can be
+//! written to a file by adding a second serial device, for example
+//! `-serial stdio -serial file:capture.pcap`.
+//!
+//! Serial captures only queue records, a task of their own writes them out. The port is slow,
+//! so records captured while the queue is full are dropped.
+use crate::arch::pit::get_milis;
+use crate::async_;
+use crate::collections::VecDeque;
+use crate::driver::serial::SERIAL2;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::convert::TryInto;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Magic of pcap files with microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Magic of pcap files with nanosecond timestamps.
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+/// Link type of Ethernet II frames.
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+/// Default number of bytes we keep from each captured frame.
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+/// Bytes a serial capture queues up before dropping records, the port only does about 11KiB a
+/// second.
+const SERIAL_BACKLOG: usize = 1024 * 1024;
+/// Bytes written to the serial port before other tasks get to run.
+const SERIAL_CHUNK: usize = 64;
+
+/// Returns the global header that starts every pcap file we write.
+pub fn file_header(snaplen: u32) -> [u8; PCAP_HEADER_LEN] {
+    let mut header = [0; PCAP_HEADER_LEN];
+    header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+    header[6..8].copy_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+    // thiszone and sigfigs are always 0.
+    header[16..20].copy_from_slice(&snaplen.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+    header
+}
+
+/// Returns a pcap record holding `frame`, truncated to `snaplen` bytes.
+pub fn record(timestamp: Duration, frame: &[u8], snaplen: u32) -> Vec<u8> {
+    let incl_len = frame.len().min(snaplen as usize);
+
+    let mut record = Vec::with_capacity(PCAP_RECORD_HEADER_LEN + incl_len);
+    record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(incl_len as u32).to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(&frame[..incl_len]);
+
+    record
+}
+
+/// A single record read from a pcap file.
+#[derive(Clone, Copy, Debug)]
+pub struct PcapRecord<'a> {
+    /// Time at which the frame was captured.
+    pub timestamp: Duration,
+    /// Length of the frame on the wire, can be bigger than `data` if it was truncated.
+    pub orig_len: u32,
+    /// The captured bytes.
+    pub data: &'a [u8],
+}
+
+/// Iterator over the records of a pcap file.
+#[derive(Clone)]
+pub struct PcapReader<'a> {
+    data: &'a [u8],
+    /// Offset of the next record.
+    offset: usize,
+    /// Whether the file was written with the opposite endianness.
+    swapped: bool,
+    /// Whether the timestamps have nanosecond resolution.
+    nanos: bool,
+}
+
+impl<'a> PcapReader<'a> {
+    /// Function parses the global header of `data`. Only files holding Ethernet frames are
+    /// accepted.
+    pub fn new(data: &'a [u8]) -> Result<Self, ()> {
+        if data.len() < PCAP_HEADER_LEN {
+            return Err(());
+        }
+
+        let magic = u32::from_le_bytes(data[0..4].try_into().map_err(|_| ())?);
+        let (swapped, nanos) = match magic {
+            PCAP_MAGIC => (false, false),
+            PCAP_MAGIC_NANOS => (false, true),
+            x if x.swap_bytes() == PCAP_MAGIC => (true, false),
+            x if x.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+            _ => return Err(()),
+        };
+
+        let this = Self {
+            data,
+            offset: PCAP_HEADER_LEN,
+            swapped,
+            nanos,
+        };
+
+        if this.read_u32(20).ok_or(())? != LINKTYPE_ETHERNET {
+            return Err(());
+        }
+
+        Ok(this)
+    }
+
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let value = u32::from_le_bytes(self.data.get(offset..offset + 4)?.try_into().ok()?);
+
+        Some(if self.swapped {
+            value.swap_bytes()
+        } else {
+            value
+        })
+    }
+}
+
+impl<'a> Iterator for PcapReader<'a> {
+    type Item = PcapRecord<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let secs = self.read_u32(self.offset)? as u64;
+        let frac = self.read_u32(self.offset + 4)?;
+        let incl_len = self.read_u32(self.offset + 8)? as usize;
+        let orig_len = self.read_u32(self.offset + 12)?;
+
+        let start = self.offset + PCAP_RECORD_HEADER_LEN;
+        let data = self.data.get(start..start + incl_len)?;
+        self.offset = start + incl_len;
+
+        let frac = if self.nanos {
+            Duration::from_nanos(frac as u64)
+        } else {
+            Duration::from_micros(frac as u64)
+        };
+
+        Some(PcapRecord {
+            timestamp: Duration::from_secs(secs) + frac,
+            orig_len,
+            data,
+        })
+    }
+}
+
+/// Where captured frames go.
+#[derive(Clone, Copy, Debug)]
+pub enum CaptureTarget {
+    /// Keep the most recent frames in memory, up to the given number of bytes.
+    Ring(usize),
+    /// Stream every frame out of the second serial port.
+    Serial,
+}
+
+#[derive(Default)]
+struct SerialState {
+    /// Records waiting to be written, oldest first.
+    records: VecDeque<Vec<u8>>,
+    /// Total size of `records`.
+    len: usize,
+}
+
+/// Records of a serial capture on their way to the port.
+#[derive(Default)]
+struct SerialQueue {
+    state: Mutex<SerialState>,
+    waker: AtomicWaker,
+}
+
+impl SerialQueue {
+    /// Function queues `record`, returning `false` if the queue is full.
+    fn push(&self, record: Vec<u8>) -> bool {
+        {
+            let mut state = self.state.lock();
+            if state.len + record.len() > SERIAL_BACKLOG {
+                return false;
+            }
+
+            state.len += record.len();
+            state.records.push_back(record);
+        }
+
+        self.waker.wake();
+        true
+    }
+}
+
+/// A running capture, installed on the ethernet layer with `start`.
+pub struct Capture {
+    target: CaptureTarget,
+    snaplen: u32,
+    /// Records kept by a ring capture, oldest first.
+    ring: VecDeque<Vec<u8>>,
+    /// Total size of the records in `ring`.
+    ring_len: usize,
+    /// Records of a serial capture waiting for the port.
+    serial: Option<Arc<SerialQueue>>,
+    /// Records a serial capture dropped because the port couldnt keep up.
+    dropped: usize,
+}
+
+impl Capture {
+    pub fn new(target: CaptureTarget, snaplen: u32) -> Self {
+        let serial = match target {
+            CaptureTarget::Serial => {
+                let queue = Arc::new(SerialQueue::default());
+                queue.push(file_header(snaplen).to_vec());
+                async_::spawn(write_serial(queue.clone()));
+                Some(queue)
+            }
+            CaptureTarget::Ring(_) => None,
+        };
+
+        Self {
+            target,
+            snaplen,
+            ring: VecDeque::new(),
+            ring_len: 0,
+            serial,
+            dropped: 0,
+        }
+    }
+
+    /// Function records a single frame.
+    pub fn push(&mut self, frame: &[u8]) {
+        let record = record(Duration::from_millis(get_milis()), frame, self.snaplen);
+
+        match self.target {
+            CaptureTarget::Serial => {
+                let queued = self.serial.as_ref().map_or(false, |x| x.push(record));
+                if !queued {
+                    self.dropped += 1;
+                }
+            }
+            CaptureTarget::Ring(capacity) => {
+                self.ring_len += record.len();
+                self.ring.push_back(record);
+
+                while self.ring_len > capacity {
+                    match self.ring.pop_front() {
+                        Some(x) => self.ring_len -= x.len(),
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a pcap file holding everything in the ring buffer. Serial captures always return
+    /// a file without any records.
+    pub fn dump(&self) -> Vec<u8> {
+        let mut file = file_header(self.snaplen).to_vec();
+        for record in self.ring.iter() {
+            file.extend_from_slice(record);
+        }
+
+        file
+    }
+
+    /// Returns the number of records a serial capture dropped because the port couldnt keep up.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        // the task writes what is left and exits once it holds the last reference.
+        if let Some(queue) = self.serial.as_ref() {
+            queue.waker.wake();
+        }
+    }
+}
+
+/// Function writes the records of a serial capture to the port until the capture is gone. The
+/// port is only ever used from here, so it is written with interrupts enabled and other tasks
+/// get to run every few bytes.
+async fn write_serial(queue: Arc<SerialQueue>) {
+    loop {
+        let record = poll_fn(|cx| {
+            queue.waker.register(cx.waker());
+
+            let mut state = queue.state.lock();
+            match state.records.pop_front() {
+                Some(x) => {
+                    state.len -= x.len();
+                    Poll::Ready(Some(x))
+                }
+                None if Arc::strong_count(&queue) == 1 => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        let record = match record {
+            Some(x) => x,
+            None => return,
+        };
+
+        for chunk in record.chunks(SERIAL_CHUNK) {
+            {
+                let mut port = SERIAL2.lock();
+                for byte in chunk {
+                    port.send(*byte);
+                }
+            }
+
+            async_::yield_now().await;
+        }
+    }
+}
+
+/// Function starts capturing every frame we send or receive, replacing any running capture.
+pub fn start(target: CaptureTarget) {
+    super::ETHERNET_LAYER.set_capture(Some(Capture::new(target, DEFAULT_SNAPLEN)));
+}
+
+/// Function stops the running capture, returning its contents as a pcap file.
+pub fn stop() -> Option<Vec<u8>> {
+    super::ETHERNET_LAYER.set_capture(None).map(|x| x.dump())
+}
+
+/// Returns the contents of the running capture as a pcap file, without stopping it.
+pub fn dump() -> Option<Vec<u8>> {
+    super::ETHERNET_LAYER.with_capture(|x| x.dump())
+}
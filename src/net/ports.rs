@@ -0,0 +1,203 @@
+//! Bookkeeping of the local ports used by tcp and udp.
+
+use super::addr::SocketAddrV4;
+use super::wire::ipaddr::Ipv4Addr;
+
+use crate::collections::HashMap;
+use crate::prelude::*;
+
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+use spin::Mutex;
+
+/// First port of the range we pick local ports from when none was asked for (RFC 6335).
+pub const EPHEMERAL_START: u16 = 49152;
+/// Last port of the ephemeral range.
+pub const EPHEMERAL_END: u16 = 65535;
+
+/// Options a socket is bound with.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BindOptions {
+    /// Like `SO_REUSEADDR`, the port can be bound while connections still use it, and sockets
+    /// that both set it can bind `0.0.0.0` and a specific address on the same port.
+    pub reuse_addr: bool,
+    /// Like `SO_REUSEPORT`, sockets that all set it can bind the exact same address. Incoming
+    /// connections and datagrams are spread over them.
+    pub reuse_port: bool,
+}
+
+impl BindOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = reuse_addr;
+        self
+    }
+
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    /// A listener or a udp socket.
+    Socket(BindOptions),
+    /// The local end of a tcp connection.
+    Connection,
+}
+
+struct Binding {
+    id: usize,
+    ip: Ipv4Addr,
+    kind: Kind,
+}
+
+impl Binding {
+    /// Returns whether a socket bound to `ip` with `opts` could live next to this binding.
+    fn allows(&self, ip: Ipv4Addr, opts: BindOptions) -> bool {
+        let theirs = match self.kind {
+            // connections dont receive anything through the socket, they only linger.
+            Kind::Connection => return opts.reuse_addr,
+            Kind::Socket(x) => x,
+        };
+
+        if self.ip == ip {
+            return opts.reuse_port && theirs.reuse_port;
+        }
+
+        if self.ip.is_unspecified() || ip.is_unspecified() {
+            return opts.reuse_addr && theirs.reuse_addr;
+        }
+
+        true
+    }
+}
+
+/// The local ports of a single protocol and who uses them.
+pub struct PortTable {
+    ports: Mutex<HashMap<u16, Vec<Binding>>>,
+    /// Offset of the last ephemeral port we handed out.
+    next: AtomicU16,
+    /// Id of the last binding we handed out.
+    last_id: AtomicUsize,
+}
+
+impl PortTable {
+    pub fn new() -> Self {
+        Self {
+            ports: Mutex::new(HashMap::new()),
+            next: AtomicU16::new(0),
+            last_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Function reserves `addr` for a socket bound with `opts`. Port 0 picks a free ephemeral
+    /// port.
+    pub fn bind(&'static self, addr: SocketAddrV4, opts: BindOptions) -> Result<PortGuard, ()> {
+        let mut ports = self.ports.lock();
+        let ip = *addr.ip();
+
+        let port = if addr.port() == 0 {
+            self.free_port(&ports)?
+        } else {
+            addr.port()
+        };
+
+        let bindings = ports.entry(port).or_default();
+        if !bindings.iter().all(|x| x.allows(ip, opts)) {
+            return Err(());
+        }
+
+        Ok(self.insert(bindings, SocketAddrV4::new(ip, port), Kind::Socket(opts)))
+    }
+
+    /// Function reserves the local end of a accepted connection. Connections never conflict
+    /// with anything, they only keep sockets without `reuse_addr` off the port.
+    pub fn connection(&'static self, addr: SocketAddrV4) -> PortGuard {
+        let mut ports = self.ports.lock();
+        let bindings = ports.entry(addr.port()).or_default();
+
+        self.insert(bindings, addr, Kind::Connection)
+    }
+
+    /// Function picks a free ephemeral port for a outgoing connection from `ip`.
+    pub fn ephemeral(&'static self, ip: Ipv4Addr) -> Result<PortGuard, ()> {
+        let mut ports = self.ports.lock();
+        let port = self.free_port(&ports)?;
+        let bindings = ports.entry(port).or_default();
+
+        Ok(self.insert(bindings, SocketAddrV4::new(ip, port), Kind::Connection))
+    }
+
+    fn free_port(&self, ports: &HashMap<u16, Vec<Binding>>) -> Result<u16, ()> {
+        let range = EPHEMERAL_END - EPHEMERAL_START + 1;
+
+        (0..range)
+            .map(|_| EPHEMERAL_START + self.next.fetch_add(1, Relaxed) % range)
+            .find(|x| !ports.contains_key(x))
+            .ok_or(())
+    }
+
+    fn insert(
+        &'static self,
+        bindings: &mut Vec<Binding>,
+        addr: SocketAddrV4,
+        kind: Kind,
+    ) -> PortGuard {
+        let id = self.last_id.fetch_add(1, Relaxed);
+        bindings.push(Binding {
+            id,
+            ip: *addr.ip(),
+            kind,
+        });
+
+        PortGuard {
+            table: self,
+            addr,
+            id,
+        }
+    }
+
+    fn release(&self, port: u16, id: usize) {
+        let mut ports = self.ports.lock();
+
+        if let Some(bindings) = ports.get_mut(&port) {
+            bindings.retain(|x| x.id != id);
+
+            if bindings.is_empty() {
+                ports.remove(&port);
+            }
+        }
+    }
+}
+
+/// A local address reserved in a `PortTable`, released again once dropped.
+pub struct PortGuard {
+    table: &'static PortTable,
+    addr: SocketAddrV4,
+    id: usize,
+}
+
+impl PortGuard {
+    /// Returns the address that was reserved, with the ephemeral port filled in.
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// Returns the id of the reservation, unique within its table.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl Drop for PortGuard {
+    fn drop(&mut self) {
+        self.table.release(self.addr.port(), self.id);
+    }
+}
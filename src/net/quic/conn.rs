@@ -0,0 +1,1639 @@
+//! The state of a single connection (RFC 9000). It doesnt do any io itself, the endpoint
+//! feeds it the datagrams it receives and the timers that fired, and sends what it hands back.
+//!
+//! Times are in ms since boot, like the ones of `arch::pit::get_milis`.
+
+use super::frame;
+use super::frame::Frame;
+use super::frame::MAX_STREAM_OVERHEAD;
+use super::packet;
+use super::packet::Header;
+use super::packet::Keys;
+use super::packet::Kind;
+use super::packet::CID_LEN;
+use super::packet::MAX_CID_LEN;
+use super::packet::MAX_DATAGRAM_LEN;
+use super::packet::TAG_LEN;
+use super::params;
+use super::params::Params;
+use super::recovery::Recovery;
+use super::recovery::SentPacket;
+use super::recovery::Timeout;
+use super::stream::Reassembly;
+use super::stream::RecvStream;
+use super::stream::SendStream;
+use super::stream::FLOW_CONTROL_ERROR;
+use super::Error;
+
+use crate::arch::random;
+use crate::collections::BTreeMap;
+use crate::collections::VecDeque;
+use crate::net::addr::SocketAddr;
+use crate::net::tls;
+use crate::net::tls::ClientConfig;
+use crate::net::tls::Event;
+use crate::net::tls::Handshake;
+use crate::net::tls::Level;
+use crate::net::tls::ServerConfig;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::task::Waker;
+
+/// Transport error codes (RFC 9000 20.1).
+const PROTOCOL_VIOLATION: u64 = 0xa;
+const STREAM_LIMIT_ERROR: u64 = 0x4;
+const STREAM_STATE_ERROR: u64 = 0x5;
+const FRAME_ENCODING_ERROR: u64 = 0x7;
+const TRANSPORT_PARAMETER_ERROR: u64 = 0x8;
+const CONNECTION_ID_LIMIT_ERROR: u64 = 0x9;
+const APPLICATION_ERROR: u64 = 0xc;
+const CRYPTO_BUFFER_EXCEEDED: u64 = 0xd;
+/// TLS alerts are sent as this plus the alert.
+const CRYPTO_ERROR: u64 = 0x100;
+
+const INITIAL: usize = 0;
+const HANDSHAKE: usize = 1;
+const DATA: usize = 2;
+
+/// Idle timeout we ask for, in ms.
+const IDLE_TIMEOUT: u64 = 30_000;
+/// Flow control windows of the connection and of each stream.
+const MAX_DATA: u64 = 1024 * 1024;
+const STREAM_WINDOW: u64 = 256 * 1024;
+/// Streams of each kind our peer can have open at once.
+const MAX_STREAMS: u64 = 100;
+/// Connection ids of our peer we keep around.
+const CID_LIMIT: u64 = 4;
+/// How long we wait before acking application packets, in ms.
+const MAX_ACK_DELAY: u64 = 25;
+const ACK_DELAY_EXPONENT: u64 = 3;
+/// Ranges of packet numbers we remember for acks, older ones are forgotten.
+const MAX_ACK_RANGES: usize = 32;
+/// How far past what tls has consumed we buffer crypto data of our peer (RFC 9000 7.5).
+const MAX_CRYPTO_BUFFER: u64 = 16 * 1024;
+/// Biggest datagram we take from our peer.
+const MAX_UDP_PAYLOAD: u64 = 1472;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Handshake,
+    Established,
+    /// We closed the connection and answer whatever arrives with our close until the time.
+    Closing(u64),
+    /// Our peer closed the connection, we stay quiet until the time.
+    Draining(u64),
+    Closed,
+}
+
+/// A packet number space (RFC 9000 12.3).
+#[derive(Default)]
+struct Space {
+    read: Option<Keys>,
+    write: Option<Keys>,
+    next_pn: u64,
+    largest_rx: Option<u64>,
+    /// Time the largest packet number arrived at, for the delay in our acks.
+    largest_rx_time: u64,
+    /// Ranges of received packet numbers, largest first, the way ack frames carry them.
+    received: Vec<(u64, u64)>,
+    /// Whether we owe our peer a ack, how many ack eliciting packets arrived since the last one
+    /// and until when we can wait with it.
+    ack_pending: bool,
+    unacked: u32,
+    ack_deadline: Option<u64>,
+    crypto_rx: Reassembly,
+    crypto_tx: u64,
+    /// Frames waiting to be sent, retransmissions among them.
+    queue: VecDeque<Frame>,
+    discarded: bool,
+}
+
+impl Space {
+    /// Function records a received packet number, returns false if it is a duplicate.
+    fn on_received(&mut self, pn: u64, now: u64) -> bool {
+        if self.received.iter().any(|x| x.0 <= pn && pn <= x.1) {
+            return false;
+        }
+
+        if self.largest_rx.map_or(true, |x| pn > x) {
+            self.largest_rx = Some(pn);
+            self.largest_rx_time = now;
+        }
+
+        let mut ranges = core::mem::take(&mut self.received);
+        ranges.push((pn, pn));
+        ranges.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+        for (start, end) in ranges {
+            match self.received.last_mut() {
+                Some(last) if end + 1 >= last.0 => last.0 = last.0.min(start),
+                _ => self.received.push((start, end)),
+            }
+        }
+
+        self.received.truncate(MAX_ACK_RANGES);
+        true
+    }
+
+    /// Returns whether the ack we owe has to go out now.
+    fn ack_due(&self, space: usize, now: u64) -> bool {
+        self.ack_pending
+            && (space != DATA || self.unacked >= 2 || self.ack_deadline.map_or(true, |x| x <= now))
+    }
+
+    fn ack_frame(&self, now: u64) -> Frame {
+        let delay = now.saturating_sub(self.largest_rx_time) * 1000;
+
+        Frame::Ack {
+            ranges: self.received.clone(),
+            delay: delay >> ACK_DELAY_EXPONENT,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stream {
+    send: Option<SendStream>,
+    recv: Option<RecvStream>,
+    /// Whether the handle of the application was dropped.
+    detached: bool,
+}
+
+impl Stream {
+    /// Returns whether the stream can be forgotten.
+    fn is_done(&self) -> bool {
+        self.detached
+            && self.send.as_ref().map_or(true, |x| x.is_done())
+            && self
+                .recv
+                .as_ref()
+                .map_or(true, |x| x.is_finished() || x.reset.is_some() || x.stopped)
+    }
+}
+
+/// A packet that was built but isnt protected yet.
+struct Pending {
+    space: usize,
+    payload: Vec<u8>,
+    /// The frames that have to be sent again if the packet gets lost.
+    frames: Vec<Frame>,
+    eliciting: bool,
+}
+
+pub struct Conn {
+    client: bool,
+    state: State,
+    error: Option<Error>,
+    tls: Handshake,
+    spaces: [Space; 3],
+    recovery: Recovery,
+    /// Set for one datagram after a probe timeout, which can go past the congestion window.
+    probe: bool,
+    pub local: SocketAddr,
+    remote: SocketAddr,
+    /// Whether the address of our peer was validated, until then a server sends at most three
+    /// times what it received from it (RFC 9000 8).
+    validated: bool,
+    received_bytes: usize,
+    sent_bytes: usize,
+    /// Data of the PATH_CHALLENGE we are waiting on a answer to.
+    challenge: Option<[u8; 8]>,
+    /// The connection id we sent in our long headers.
+    scid: Vec<u8>,
+    /// Connection ids we issued, with their sequence numbers.
+    local_cids: Vec<(u64, Vec<u8>)>,
+    next_cid_seq: u64,
+    /// Connection ids our peer issued, the first one is the one we send with.
+    remote_cids: Vec<(u64, Vec<u8>)>,
+    retire_prior_to: u64,
+    /// The dcid of the first Initial of the client, and the scid of the first Initial of our
+    /// peer, both checked against the transport parameters.
+    original_dcid: Vec<u8>,
+    peer_scid: Option<Vec<u8>>,
+    peer_params: Params,
+    streams: BTreeMap<u64, Stream>,
+    /// Streams of each kind, bidi first, we opened and how many our peer lets us open.
+    opened: [u64; 2],
+    max_open: [u64; 2],
+    /// Streams of each kind our peer opened and how many we let it open.
+    accepted: [u64; 2],
+    max_accept: [u64; 2],
+    /// Streams our peer opened that the application didnt take yet.
+    incoming: VecDeque<u64>,
+    /// Flow control of the connection, the limit we gave our peer and what it used of it.
+    max_data: u64,
+    data_received: u64,
+    data_read: u64,
+    /// The limit our peer gave us and what we used of it.
+    peer_max_data: u64,
+    data_sent: u64,
+    idle_timeout: u64,
+    last_activity: u64,
+    key_phase: bool,
+    /// Whether the handshake is confirmed (RFC 9001 4.1.2).
+    confirmed: bool,
+    /// The close we send while closing, and whether it has to go out again.
+    close: Option<Frame>,
+    close_pending: bool,
+    /// Whether the endpoint handed a server connection to the application yet.
+    pub announced: bool,
+    wakers: Vec<Waker>,
+}
+
+/// Returns a new random connection id.
+fn new_cid() -> Vec<u8> {
+    let mut cid = vec![0; CID_LEN];
+    random::fill(&mut cid);
+    cid
+}
+
+fn level(space: usize) -> Level {
+    match space {
+        INITIAL => Level::Initial,
+        HANDSHAKE => Level::Handshake,
+        _ => Level::Application,
+    }
+}
+
+fn space(level: Level) -> usize {
+    match level {
+        Level::Initial => INITIAL,
+        Level::Handshake => HANDSHAKE,
+        Level::Application => DATA,
+    }
+}
+
+/// Function splits a crypto or stream frame so its first part fits in `room` bytes, returns
+/// that part and leaves the rest in `frame`.
+fn split(frame: &mut Frame, room: usize) -> Option<Frame> {
+    let max = room.checked_sub(MAX_STREAM_OVERHEAD).filter(|x| *x > 0)?;
+
+    match frame {
+        Frame::Crypto { offset, data } if max < data.len() => {
+            let rest = data.split_off(max);
+            let first = Frame::Crypto {
+                offset: *offset,
+                data: core::mem::replace(data, rest),
+            };
+            *offset += max as u64;
+            Some(first)
+        }
+        Frame::Stream {
+            id, offset, data, ..
+        } if max < data.len() => {
+            let rest = data.split_off(max);
+            let first = Frame::Stream {
+                id: *id,
+                offset: *offset,
+                data: core::mem::replace(data, rest),
+                fin: false,
+            };
+            *offset += max as u64;
+            Some(first)
+        }
+        _ => None,
+    }
+}
+
+impl Conn {
+    fn new(
+        client: bool,
+        tls: Handshake,
+        local: SocketAddr,
+        remote: SocketAddr,
+        original_dcid: Vec<u8>,
+        now: u64,
+    ) -> Self {
+        let scid = new_cid();
+
+        Self {
+            client,
+            state: State::Handshake,
+            error: None,
+            tls,
+            spaces: Default::default(),
+            recovery: Recovery::new(),
+            probe: false,
+            local,
+            remote,
+            validated: client,
+            received_bytes: 0,
+            sent_bytes: 0,
+            challenge: None,
+            local_cids: vec![(0, scid.clone())],
+            scid,
+            next_cid_seq: 1,
+            remote_cids: Vec::new(),
+            retire_prior_to: 0,
+            original_dcid,
+            peer_scid: None,
+            peer_params: Params::default(),
+            streams: BTreeMap::new(),
+            opened: [0; 2],
+            max_open: [0; 2],
+            accepted: [0; 2],
+            max_accept: [MAX_STREAMS; 2],
+            incoming: VecDeque::new(),
+            max_data: MAX_DATA,
+            data_received: 0,
+            data_read: 0,
+            peer_max_data: 0,
+            data_sent: 0,
+            idle_timeout: IDLE_TIMEOUT,
+            last_activity: now,
+            key_phase: false,
+            confirmed: false,
+            close: None,
+            close_pending: false,
+            announced: false,
+            wakers: Vec::new(),
+        }
+    }
+
+    /// Function starts a connection to `remote`, the first datagram is ready to be sent.
+    pub fn client(
+        config: Arc<ClientConfig>,
+        name: &str,
+        local: SocketAddr,
+        remote: SocketAddr,
+        now: u64,
+    ) -> Self {
+        let dcid = new_cid();
+        let mut conn = Self::new(
+            true,
+            Handshake::client(config, name),
+            local,
+            remote,
+            dcid,
+            now,
+        );
+
+        let (read, write) = packet::initial_keys(&conn.original_dcid, true);
+        conn.spaces[INITIAL].read = Some(read);
+        conn.spaces[INITIAL].write = Some(write);
+        conn.remote_cids = vec![(0, conn.original_dcid.clone())];
+
+        let params = conn.params();
+        conn.tls.add_extension(params::EXTENSION, params.encode());
+
+        match conn.tls.start() {
+            Ok(events) => {
+                let _ = conn.apply(events);
+            }
+            Err(alert) => conn.fail(CRYPTO_ERROR + alert.code() as u64, now),
+        }
+
+        conn
+    }
+
+    /// Function accepts a connection from the first Initial packet of a client, the datagram
+    /// it came in still has to be handled.
+    pub fn server(
+        config: Arc<ServerConfig>,
+        header: &Header,
+        local: SocketAddr,
+        remote: SocketAddr,
+        now: u64,
+    ) -> Self {
+        let dcid = header.dcid.clone();
+        let mut conn = Self::new(false, Handshake::server(config), local, remote, dcid, now);
+
+        let (read, write) = packet::initial_keys(&conn.original_dcid, false);
+        conn.spaces[INITIAL].read = Some(read);
+        conn.spaces[INITIAL].write = Some(write);
+        conn.remote_cids = vec![(0, header.scid.clone())];
+        conn.peer_scid = Some(header.scid.clone());
+
+        let mut params = conn.params();
+        params.original_dcid = Some(conn.original_dcid.clone());
+        conn.tls.add_extension(params::EXTENSION, params.encode());
+
+        conn
+    }
+
+    /// Returns the transport parameters we send.
+    fn params(&self) -> Params {
+        Params {
+            original_dcid: None,
+            initial_scid: Some(self.scid.clone()),
+            idle_timeout: IDLE_TIMEOUT,
+            max_udp_payload: MAX_UDP_PAYLOAD,
+            max_data: MAX_DATA,
+            stream_data_bidi_local: STREAM_WINDOW,
+            stream_data_bidi_remote: STREAM_WINDOW,
+            stream_data_uni: STREAM_WINDOW,
+            max_streams_bidi: MAX_STREAMS,
+            max_streams_uni: MAX_STREAMS,
+            ack_delay_exponent: ACK_DELAY_EXPONENT,
+            max_ack_delay: MAX_ACK_DELAY,
+            disable_migration: false,
+            cid_limit: CID_LIMIT,
+        }
+    }
+
+    /// Returns whether packets sent to `dcid` belong to this connection.
+    pub fn owns(&self, dcid: &[u8]) -> bool {
+        self.local_cids.iter().any(|x| x.1 == dcid)
+            || (!self.client && dcid == &self.original_dcid[..])
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == State::Established
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Returns whether the application can still use the connection.
+    pub fn check(&self) -> Result<(), Error> {
+        match self.state {
+            State::Handshake | State::Established => Ok(()),
+            _ => Err(self.error.unwrap_or(Error::Closed)),
+        }
+    }
+
+    pub fn error(&self) -> Option<Error> {
+        self.error
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn alpn(&self) -> Option<Vec<u8>> {
+        self.tls.alpn().map(|x| x.to_vec())
+    }
+
+    /// Returns the smoothed rtt in ms.
+    pub fn rtt(&self) -> u64 {
+        self.recovery.rtt.smoothed
+    }
+
+    /// Function registers a task to wake on the next change of the connection.
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|x| x.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Function handles a datagram our peer sent from `from`.
+    pub fn handle_datagram(&mut self, now: u64, from: SocketAddr, mut data: &mut [u8]) {
+        match self.state {
+            State::Draining(_) | State::Closed => return,
+            // whatever arrives while closing gets our close again (RFC 9000 10.2.1).
+            State::Closing(_) => {
+                self.close_pending = true;
+                return;
+            }
+            _ => {}
+        }
+
+        let len = data.len();
+        if from == self.remote {
+            self.received_bytes += len;
+        }
+
+        while !data.is_empty() {
+            let header = match packet::parse_header(data) {
+                Some(x) => x,
+                None => break,
+            };
+
+            let (packet, rest) = core::mem::take(&mut data).split_at_mut(header.len);
+            data = rest;
+
+            let result = match header.kind {
+                Kind::VersionNegotiation => {
+                    self.version_negotiation(packet, &header);
+                    break;
+                }
+                Kind::Initial | Kind::Handshake | Kind::Short
+                    if header.version == packet::VERSION =>
+                {
+                    self.handle_packet(now, from, len, &header, packet)
+                }
+                _ => Ok(()),
+            };
+
+            if let Err(code) = result {
+                self.fail(code, now);
+                break;
+            }
+
+            if self.state == State::Closed || matches!(self.state, State::Draining(_)) {
+                break;
+            }
+        }
+
+        self.collect();
+        self.wake();
+    }
+
+    /// Function handles a version negotiation packet, which can only end a connection of a
+    /// client before the server answered (RFC 9000 6.2).
+    fn version_negotiation(&mut self, packet: &[u8], header: &Header) {
+        if !self.client || self.peer_scid.is_some() || header.dcid != self.scid {
+            return;
+        }
+
+        if !packet::supported_versions(packet, header).contains(&packet::VERSION) {
+            self.error = Some(Error::VersionMismatch);
+            self.state = State::Closed;
+        }
+    }
+
+    fn handle_packet(
+        &mut self,
+        now: u64,
+        from: SocketAddr,
+        len: usize,
+        header: &Header,
+        packet: &mut [u8],
+    ) -> Result<(), u64> {
+        let space = match header.kind {
+            Kind::Initial => INITIAL,
+            Kind::Handshake => HANDSHAKE,
+            _ => DATA,
+        };
+
+        // packets we cant decrypt are dropped quietly, they could be forged.
+        let (pn, header_len) = {
+            let state = &self.spaces[space];
+            let keys = match &state.read {
+                Some(x) => x,
+                None => return Ok(()),
+            };
+
+            match keys.unprotect(packet, header.pn_offset, state.largest_rx) {
+                Some(x) => x,
+                None => return Ok(()),
+            }
+        };
+
+        let payload = match self.open_packet(space, pn, packet, header_len) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let reserved = if header.is_long() { 0x0c } else { 0x18 };
+        if packet[0] & reserved != 0 {
+            return Err(PROTOCOL_VIOLATION);
+        }
+
+        if !self.spaces[space].on_received(pn, now) {
+            return Ok(());
+        }
+
+        // the first Initial of the server tells us the connection id it picked.
+        if self.client && space == INITIAL && self.peer_scid.is_none() {
+            self.peer_scid = Some(header.scid.clone());
+            self.remote_cids = vec![(0, header.scid.clone())];
+        }
+
+        // a server knows its peer owns the address once it can decrypt a Handshake packet, and
+        // is done with the Initial keys (RFC 9001 4.9.1).
+        if !self.client && space == HANDSHAKE {
+            self.validated = true;
+            self.discard(INITIAL);
+        }
+
+        self.last_activity = now;
+
+        let frames = frame::parse(&payload).ok_or(FRAME_ENCODING_ERROR)?;
+        let eliciting = frames.iter().any(|x| x.is_ack_eliciting());
+        let probing = frames.iter().all(|x| x.is_probing());
+
+        for frame in frames {
+            if space != DATA && !frame.is_allowed_in_handshake() {
+                return Err(PROTOCOL_VIOLATION);
+            }
+
+            self.handle_frame(now, space, frame)?;
+
+            if self.check().is_err() {
+                return Ok(());
+            }
+        }
+
+        let state = &mut self.spaces[space];
+        if eliciting && !state.discarded {
+            state.ack_pending = true;
+            state.unacked += 1;
+            if space == DATA && state.ack_deadline.is_none() {
+                state.ack_deadline = Some(now + MAX_ACK_DELAY);
+            }
+        }
+
+        // a client that sends its newest packet from a new address moved there (RFC 9000 9.3).
+        let newest = state.largest_rx == Some(pn);
+        if !self.client && space == DATA && from != self.remote && !probing && newest {
+            self.remote = from;
+            self.validated = false;
+            self.received_bytes = len;
+            self.sent_bytes = 0;
+            self.validate_path();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the payload of a packet, 1-RTT packets may carry the next key phase of our peer
+    /// (RFC 9001 6).
+    fn open_packet(
+        &mut self,
+        space: usize,
+        pn: u64,
+        packet: &[u8],
+        header_len: usize,
+    ) -> Option<Vec<u8>> {
+        let phase = packet[0] & 0x04 != 0;
+        let keys = self.spaces[space].read.as_ref()?;
+
+        if space != DATA || phase == self.key_phase {
+            return keys.open(pn, packet, header_len);
+        }
+
+        if !self.confirmed {
+            return None;
+        }
+
+        let next = keys.next();
+        let payload = next.open(pn, packet, header_len)?;
+        let write = self.spaces[DATA].write.as_ref()?.next();
+
+        self.spaces[DATA].read = Some(next);
+        self.spaces[DATA].write = Some(write);
+        self.key_phase = phase;
+        Some(payload)
+    }
+
+    fn handle_frame(&mut self, now: u64, space: usize, frame: Frame) -> Result<(), u64> {
+        match frame {
+            Frame::Padding
+            | Frame::Ping
+            | Frame::NewToken
+            | Frame::DataBlocked(_)
+            | Frame::StreamDataBlocked { .. }
+            | Frame::StreamsBlocked { .. } => {}
+            Frame::Ack { ranges, delay } => {
+                if ranges[0].1 >= self.spaces[space].next_pn {
+                    return Err(PROTOCOL_VIOLATION);
+                }
+
+                let delay = delay.saturating_mul(1 << self.peer_params.ack_delay_exponent) / 1000;
+                let lost = self
+                    .recovery
+                    .on_ack(space, &ranges, delay, now, self.confirmed);
+                self.requeue(space, lost);
+            }
+            Frame::Crypto { offset, data } => {
+                let state = &mut self.spaces[space];
+                let end = offset.saturating_add(data.len() as u64);
+                if end > state.crypto_rx.offset() + MAX_CRYPTO_BUFFER {
+                    return Err(CRYPTO_BUFFER_EXCEEDED);
+                }
+
+                state.crypto_rx.insert(offset, &data);
+                let data = state.crypto_rx.pop();
+
+                if !data.is_empty() {
+                    match self.tls.handle(level(space), &data) {
+                        Ok(events) => self.apply(events)?,
+                        Err(alert) => {
+                            self.error = Some(Error::Tls(tls::Error::Alert(alert)));
+                            return Err(CRYPTO_ERROR + alert.code() as u64);
+                        }
+                    }
+                }
+            }
+            Frame::Stream {
+                id,
+                offset,
+                data,
+                fin,
+            } => {
+                let (added, discarded) = match self.stream(id, true)? {
+                    Some(stream) => {
+                        let recv = stream.recv.as_mut().ok_or(STREAM_STATE_ERROR)?;
+                        let added = recv.insert(offset, &data, fin)?;
+                        if recv.stopped {
+                            (added, recv.discard())
+                        } else {
+                            (added, 0)
+                        }
+                    }
+                    None => (0, 0),
+                };
+
+                self.data_read += discarded;
+                self.on_received_data(added)?;
+            }
+            Frame::ResetStream {
+                id,
+                code,
+                final_size,
+            } => {
+                let (added, discarded) = match self.stream(id, true)? {
+                    Some(stream) => {
+                        let recv = stream.recv.as_mut().ok_or(STREAM_STATE_ERROR)?;
+                        let added = recv.on_reset(code, final_size)?;
+                        (added, recv.discard())
+                    }
+                    None => (0, 0),
+                };
+
+                self.data_read += discarded;
+                self.on_received_data(added)?;
+            }
+            Frame::StopSending { id, code } => {
+                let stopped = match self.stream(id, false)? {
+                    Some(stream) => {
+                        let send = stream.send.as_mut().ok_or(STREAM_STATE_ERROR)?;
+                        send.stopped = Some(code);
+                        true
+                    }
+                    None => false,
+                };
+
+                // the stream is reset in turn (RFC 9000 3.5).
+                if stopped {
+                    self.reset(id, code);
+                }
+            }
+            Frame::MaxData(max) => self.peer_max_data = self.peer_max_data.max(max),
+            Frame::MaxStreamData { id, max } => {
+                if let Some(stream) = self.stream(id, false)? {
+                    let send = stream.send.as_mut().ok_or(STREAM_STATE_ERROR)?;
+                    send.max_data = send.max_data.max(max);
+                }
+            }
+            Frame::MaxStreams { bidi, max } => {
+                let kind = if bidi { 0 } else { 1 };
+                self.max_open[kind] = self.max_open[kind].max(max);
+            }
+            Frame::NewConnectionId {
+                seq,
+                retire_prior_to,
+                cid,
+                ..
+            } => self.on_new_cid(seq, retire_prior_to, cid)?,
+            Frame::RetireConnectionId(seq) => {
+                if seq >= self.next_cid_seq {
+                    return Err(PROTOCOL_VIOLATION);
+                }
+
+                self.local_cids.retain(|x| x.0 != seq);
+                self.issue_cids();
+            }
+            Frame::PathChallenge(data) => {
+                self.spaces[DATA].queue.push_back(Frame::PathResponse(data));
+            }
+            Frame::PathResponse(data) => {
+                if self.challenge == Some(data) {
+                    self.challenge = None;
+                    self.validated = true;
+                }
+            }
+            Frame::ConnectionClose {
+                code, frame_type, ..
+            } => {
+                if self.error.is_none() {
+                    self.error = Some(match frame_type {
+                        Some(_) => Error::Transport(code),
+                        None => Error::Application(code),
+                    });
+                }
+
+                let pto = self.recovery.pto(self.peer_params.max_ack_delay);
+                self.state = State::Draining(now + 3 * pto);
+            }
+            Frame::HandshakeDone => {
+                if !self.client {
+                    return Err(PROTOCOL_VIOLATION);
+                }
+
+                if !self.confirmed {
+                    self.confirmed = true;
+                    self.discard(HANDSHAKE);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Function counts stream data our peer sent against the flow control of the connection.
+    fn on_received_data(&mut self, added: u64) -> Result<(), u64> {
+        self.data_received += added;
+
+        if self.data_received > self.max_data {
+            return Err(FLOW_CONTROL_ERROR);
+        }
+
+        self.update_max_data();
+        Ok(())
+    }
+
+    /// Function raises the limit of our peer once it used up half of it.
+    fn update_max_data(&mut self) {
+        if self.max_data - self.data_read <= MAX_DATA / 2 {
+            self.max_data = self.data_read + MAX_DATA;
+            self.spaces[DATA]
+                .queue
+                .push_back(Frame::MaxData(self.max_data));
+        }
+    }
+
+    /// Returns whether we opened the stream `id`.
+    fn is_local(&self, id: u64) -> bool {
+        (id & 1 == 0) == self.client
+    }
+
+    /// Returns the stream a frame of our peer is about, opening the streams of our peer up to
+    /// it. `receiving` is whether the frame is about the half our peer sends on. Streams that
+    /// are already gone give `None`.
+    fn stream(&mut self, id: u64, receiving: bool) -> Result<Option<&mut Stream>, u64> {
+        let kind = ((id >> 1) & 1) as usize;
+        let index = id >> 2;
+
+        if self.is_local(id) {
+            if index >= self.opened[kind] || (kind == 1 && receiving) {
+                return Err(STREAM_STATE_ERROR);
+            }
+        } else {
+            if kind == 1 && !receiving {
+                return Err(STREAM_STATE_ERROR);
+            }
+
+            if index >= self.max_accept[kind] {
+                return Err(STREAM_LIMIT_ERROR);
+            }
+
+            while self.accepted[kind] <= index {
+                let id = self.accepted[kind] << 2 | (kind as u64) << 1 | id & 1;
+                self.accepted[kind] += 1;
+
+                let send = if kind == 0 {
+                    Some(SendStream::new(self.peer_params.stream_data_bidi_local))
+                } else {
+                    None
+                };
+
+                let stream = Stream {
+                    send,
+                    recv: Some(RecvStream::new(STREAM_WINDOW)),
+                    detached: false,
+                };
+
+                self.streams.insert(id, stream);
+                self.incoming.push_back(id);
+            }
+        }
+
+        Ok(self.streams.get_mut(&id))
+    }
+
+    fn on_new_cid(&mut self, seq: u64, retire_prior_to: u64, cid: Vec<u8>) -> Result<(), u64> {
+        if cid.is_empty() || cid.len() > MAX_CID_LEN || retire_prior_to > seq {
+            return Err(FRAME_ENCODING_ERROR);
+        }
+
+        if seq < self.retire_prior_to {
+            self.spaces[DATA]
+                .queue
+                .push_back(Frame::RetireConnectionId(seq));
+            return Ok(());
+        }
+
+        if !self.remote_cids.iter().any(|x| x.0 == seq) {
+            self.remote_cids.push((seq, cid));
+        }
+
+        if retire_prior_to > self.retire_prior_to {
+            self.retire_prior_to = retire_prior_to;
+
+            for (seq, _) in self.remote_cids.iter().filter(|x| x.0 < retire_prior_to) {
+                self.spaces[DATA]
+                    .queue
+                    .push_back(Frame::RetireConnectionId(*seq));
+            }
+
+            self.remote_cids.retain(|x| x.0 >= retire_prior_to);
+        }
+
+        if self.remote_cids.len() > CID_LIMIT as usize {
+            return Err(CONNECTION_ID_LIMIT_ERROR);
+        }
+
+        Ok(())
+    }
+
+    /// Function hands our peer connection ids up to the limit it asked for.
+    fn issue_cids(&mut self) {
+        let limit = self.peer_params.cid_limit.min(CID_LIMIT) as usize;
+
+        while self.local_cids.len() < limit {
+            let cid = new_cid();
+            let seq = self.next_cid_seq;
+            self.next_cid_seq += 1;
+
+            let mut reset_token = [0; 16];
+            random::fill(&mut reset_token);
+
+            self.local_cids.push((seq, cid.clone()));
+            self.spaces[DATA].queue.push_back(Frame::NewConnectionId {
+                seq,
+                retire_prior_to: 0,
+                cid,
+                reset_token,
+            });
+        }
+    }
+
+    /// Function acts on what the TLS handshake asks for.
+    fn apply(&mut self, events: Vec<Event>) -> Result<(), u64> {
+        let suite = self.tls.suite();
+
+        for event in events {
+            match event {
+                Event::Send(level, data) => {
+                    let state = &mut self.spaces[space(level)];
+                    let offset = state.crypto_tx;
+                    state.crypto_tx += data.len() as u64;
+                    state.queue.push_back(Frame::Crypto { offset, data });
+                }
+                Event::ReadSecret(level, secret) => {
+                    self.spaces[space(level)].read = Some(Keys::new(suite, &secret));
+                }
+                Event::WriteSecret(level, secret) => {
+                    self.spaces[space(level)].write = Some(Keys::new(suite, &secret));
+                }
+                Event::Done => self.on_handshake_done()?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_handshake_done(&mut self) -> Result<(), u64> {
+        let params = self
+            .tls
+            .peer_extension(params::EXTENSION)
+            .and_then(|x| Params::decode(x, self.client))
+            .ok_or(TRANSPORT_PARAMETER_ERROR)?;
+
+        // the connection ids in the parameters have to match the ones in the packets, so they
+        // cant have been tampered with (RFC 9000 7.3).
+        if params.initial_scid != self.peer_scid {
+            return Err(TRANSPORT_PARAMETER_ERROR);
+        }
+
+        if self.client && params.original_dcid.as_ref() != Some(&self.original_dcid) {
+            return Err(TRANSPORT_PARAMETER_ERROR);
+        }
+
+        self.max_open = [params.max_streams_bidi, params.max_streams_uni];
+        self.peer_max_data = params.max_data;
+        if params.idle_timeout != 0 {
+            self.idle_timeout = self.idle_timeout.min(params.idle_timeout);
+        }
+
+        self.peer_params = params;
+        self.state = State::Established;
+
+        // a server confirms the handshake right away, and tells its peer (RFC 9001 4.1.2).
+        if !self.client {
+            self.confirmed = true;
+            self.spaces[DATA].queue.push_back(Frame::HandshakeDone);
+            self.discard(HANDSHAKE);
+        }
+
+        self.issue_cids();
+        Ok(())
+    }
+
+    /// Function drops the keys and the state of a packet number space.
+    fn discard(&mut self, space: usize) {
+        let state = &mut self.spaces[space];
+        if state.discarded {
+            return;
+        }
+
+        state.discarded = true;
+        state.read = None;
+        state.write = None;
+        state.queue.clear();
+        state.ack_pending = false;
+        self.recovery.discard(space);
+    }
+
+    /// Function queues the frames of lost packets to be sent again.
+    fn requeue(&mut self, space: usize, frames: Vec<Frame>) {
+        let state = &mut self.spaces[space];
+        if !state.discarded {
+            state.queue.extend(frames);
+        }
+    }
+
+    /// Function starts validating the path to our peer, after either end moved.
+    fn validate_path(&mut self) {
+        let mut challenge = [0; 8];
+        random::fill(&mut challenge);
+
+        self.challenge = Some(challenge);
+        self.spaces[DATA]
+            .queue
+            .push_back(Frame::PathChallenge(challenge));
+        self.recovery.reset_path();
+    }
+
+    /// Returns whether the connection can move to a new local address.
+    pub fn can_migrate(&self) -> bool {
+        self.client
+            && self.confirmed
+            && self.state == State::Established
+            && !self.peer_params.disable_migration
+    }
+
+    /// Function moves the connection to `local`, the endpoint already rebound its socket there
+    /// (RFC 9000 9.2).
+    pub fn migrate(&mut self, local: SocketAddr) {
+        self.local = local;
+
+        // a fresh connection id, so observers cant link the two paths (RFC 9000 9.5).
+        if self.remote_cids.len() > 1 {
+            let (seq, _) = self.remote_cids.remove(0);
+            self.spaces[DATA]
+                .queue
+                .push_back(Frame::RetireConnectionId(seq));
+        }
+
+        self.validate_path();
+    }
+
+    /// Returns the time the next timer of the connection fires at.
+    pub fn timeout(&self) -> Option<u64> {
+        match self.state {
+            State::Closing(x) | State::Draining(x) => return Some(x),
+            State::Closed => return None,
+            _ => {}
+        }
+
+        let idle = self.last_activity + self.idle_timeout;
+        let recovery = self
+            .recovery
+            .timeout(self.peer_params.max_ack_delay, self.confirmed)
+            .map(|x| x.0);
+        let ack = self.spaces[DATA]
+            .ack_deadline
+            .filter(|_| self.spaces[DATA].ack_pending);
+
+        [Some(idle), recovery, ack].iter().filter_map(|x| *x).min()
+    }
+
+    /// Function handles the timers that fired by `now`.
+    pub fn handle_timeout(&mut self, now: u64) {
+        match self.state {
+            State::Closing(x) | State::Draining(x) if x <= now => {
+                self.state = State::Closed;
+                self.wake();
+                return;
+            }
+            State::Closing(_) | State::Draining(_) | State::Closed => return,
+            _ => {}
+        }
+
+        if now >= self.last_activity + self.idle_timeout {
+            if self.error.is_none() {
+                self.error = Some(Error::TimedOut);
+            }
+
+            self.state = State::Closed;
+            self.wake();
+            return;
+        }
+
+        // a ack that is due goes out with the next datagram.
+        let state = &mut self.spaces[DATA];
+        if state.ack_deadline.map_or(false, |x| x <= now) {
+            state.ack_deadline = None;
+            state.unacked = state.unacked.max(2);
+        }
+
+        match self
+            .recovery
+            .on_timeout(now, self.peer_params.max_ack_delay, self.confirmed)
+        {
+            Some(Timeout::Lost(space, frames)) => self.requeue(space, frames),
+            Some(Timeout::Probe(space, frames)) => {
+                if frames.is_empty() {
+                    self.spaces[space].queue.push_back(Frame::Ping);
+                }
+
+                self.requeue(space, frames);
+                self.probe = true;
+            }
+            None => {}
+        }
+    }
+
+    /// Function closes the connection with a error code of the application.
+    pub fn close(&mut self, code: u64, reason: &[u8], now: u64) {
+        if self.check().is_err() {
+            return;
+        }
+
+        if self.error.is_none() {
+            self.error = Some(Error::Closed);
+        }
+
+        self.close = Some(Frame::ConnectionClose {
+            code,
+            frame_type: None,
+            reason: reason.to_vec(),
+        });
+        self.closing(now);
+    }
+
+    /// Function closes the connection with a transport error code.
+    fn fail(&mut self, code: u64, now: u64) {
+        if self.check().is_err() {
+            return;
+        }
+
+        if self.error.is_none() {
+            self.error = Some(Error::Transport(code));
+        }
+
+        self.close = Some(Frame::ConnectionClose {
+            code,
+            frame_type: Some(0),
+            reason: Vec::new(),
+        });
+        self.closing(now);
+    }
+
+    fn closing(&mut self, now: u64) {
+        let pto = self.recovery.pto(self.peer_params.max_ack_delay);
+        self.state = State::Closing(now + 3 * pto);
+        self.close_pending = true;
+        self.wake();
+    }
+
+    /// Returns the next datagram to send along with where to, `None` once there is nothing
+    /// more to send for now.
+    pub fn poll_transmit(&mut self, now: u64) -> Option<(Vec<u8>, SocketAddr)> {
+        let packets = match self.state {
+            State::Draining(_) | State::Closed => return None,
+            State::Closing(_) if !self.close_pending => return None,
+            State::Closing(_) => {
+                self.close_pending = false;
+                self.close_packets()
+            }
+            _ => self.build_packets(now),
+        };
+
+        if packets.is_empty() {
+            return None;
+        }
+
+        let datagram = self.seal(packets, now);
+        self.sent_bytes += datagram.len();
+        self.collect();
+        Some((datagram, self.remote))
+    }
+
+    /// Returns the most we can send in the next datagram.
+    fn send_limit(&self) -> usize {
+        if self.validated {
+            return MAX_DATAGRAM_LEN;
+        }
+
+        let limit = (3 * self.received_bytes).saturating_sub(self.sent_bytes);
+        limit.min(MAX_DATAGRAM_LEN)
+    }
+
+    /// Returns the length of the header and tag of a packet in `space`.
+    fn overhead(&self, space: usize) -> usize {
+        let kind = match space {
+            INITIAL => Kind::Initial,
+            HANDSHAKE => Kind::Handshake,
+            _ => Kind::Short,
+        };
+
+        packet::header_len(kind, &self.remote_cids[0].1, &self.scid) + TAG_LEN
+    }
+
+    fn close_packets(&mut self) -> Vec<Pending> {
+        let close = match &self.close {
+            Some(x) => x.clone(),
+            None => return Vec::new(),
+        };
+
+        let mut packets = Vec::new();
+        for space in 0..3 {
+            if self.spaces[space].write.is_none() {
+                continue;
+            }
+
+            // the reason of a application could tell a peer we didnt authenticate yet too much,
+            // so Initial and Handshake packets carry a plain transport error (RFC 9000 10.2.3).
+            let frame = match &close {
+                Frame::ConnectionClose {
+                    frame_type: None, ..
+                } if space != DATA => Frame::ConnectionClose {
+                    code: APPLICATION_ERROR,
+                    frame_type: Some(0),
+                    reason: Vec::new(),
+                },
+                _ => close.clone(),
+            };
+
+            let mut payload = Vec::new();
+            frame.encode(&mut payload);
+            packets.push(Pending {
+                space,
+                payload,
+                frames: Vec::new(),
+                eliciting: false,
+            });
+        }
+
+        packets
+    }
+
+    fn build_packets(&mut self, now: u64) -> Vec<Pending> {
+        let limit = self.send_limit();
+        let mut used = 0;
+        let mut packets = Vec::new();
+
+        for space in 0..3 {
+            if self.spaces[space].write.is_none() {
+                continue;
+            }
+
+            let overhead = self.overhead(space);
+            let room = match limit.checked_sub(used + overhead) {
+                Some(x) if x > 0 => x,
+                _ => break,
+            };
+
+            if let Some(packet) = self.build_payload(space, room, now) {
+                used += overhead + packet.payload.len();
+                packets.push(packet);
+            }
+        }
+
+        // datagrams of a client with a Initial, and ack eliciting ones of a server, are padded
+        // to the full size (RFC 9000 14.1).
+        let pad = packets
+            .iter()
+            .any(|x| x.space == INITIAL && (self.client || x.eliciting));
+
+        if let Some(last) = packets.last_mut().filter(|_| pad) {
+            let len = last.payload.len() + limit.saturating_sub(used);
+            last.payload.resize(len, 0);
+        }
+
+        packets
+    }
+
+    /// Returns the payload of the next packet in `space`, at most `room` bytes long.
+    fn build_payload(&mut self, space: usize, room: usize, now: u64) -> Option<Pending> {
+        let congested = !self.probe && !self.recovery.congestion.can_send(MAX_DATAGRAM_LEN);
+        let state = &mut self.spaces[space];
+
+        // acks go first, and dont count against the congestion window.
+        let mut ack = Vec::new();
+        if state.ack_pending {
+            state.ack_frame(now).encode(&mut ack);
+        }
+
+        let ack_due = state.ack_due(space, now);
+        let room = room.checked_sub(ack.len())?;
+        let mut payload = Vec::new();
+        let mut frames = Vec::new();
+
+        while !congested {
+            let mut frame = match state.queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+
+            if payload.len() + buf.len() > room {
+                if let Some(first) = split(&mut frame, room - payload.len()) {
+                    first.encode(&mut payload);
+                    frames.push(first);
+                }
+
+                state.queue.push_front(frame);
+                break;
+            }
+
+            payload.extend_from_slice(&buf);
+            frames.push(frame);
+        }
+
+        if space == DATA && self.state == State::Established && !congested {
+            self.send_streams(room, &mut payload, &mut frames);
+        }
+
+        let eliciting = frames.iter().any(|x| x.is_ack_eliciting());
+        if !eliciting && !ack_due {
+            return None;
+        }
+
+        let state = &mut self.spaces[space];
+        if !ack.is_empty() {
+            state.ack_pending = false;
+            state.unacked = 0;
+            state.ack_deadline = None;
+            ack.extend_from_slice(&payload);
+            payload = ack;
+        }
+
+        frames.retain(|x| x.is_retransmittable());
+
+        Some(Pending {
+            space,
+            payload,
+            frames,
+            eliciting,
+        })
+    }
+
+    /// Function fills the rest of a 1-RTT packet with stream data.
+    fn send_streams(&mut self, room: usize, payload: &mut Vec<u8>, frames: &mut Vec<Frame>) {
+        let ids: Vec<u64> = self
+            .streams
+            .iter()
+            .filter(|x| x.1.send.as_ref().map_or(false, |x| x.is_sendable()))
+            .map(|x| *x.0)
+            .collect();
+
+        let mut sent = false;
+        for id in ids {
+            let left = room.saturating_sub(payload.len());
+            if left <= MAX_STREAM_OVERHEAD {
+                break;
+            }
+
+            let credit = self.peer_max_data.saturating_sub(self.data_sent) as usize;
+            let send = match self.streams.get_mut(&id).and_then(|x| x.send.as_mut()) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let (offset, data, fin) = send.take((left - MAX_STREAM_OVERHEAD).min(credit));
+            if data.is_empty() && !fin {
+                continue;
+            }
+
+            self.data_sent += data.len() as u64;
+            sent = true;
+
+            let frame = Frame::Stream {
+                id,
+                offset,
+                data,
+                fin,
+            };
+            frame.encode(payload);
+            frames.push(frame);
+        }
+
+        // writers waiting on a full buffer can go on.
+        if sent {
+            self.wake();
+        }
+    }
+
+    /// Function protects `packets` and puts them in one datagram.
+    fn seal(&mut self, packets: Vec<Pending>, now: u64) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(MAX_DATAGRAM_LEN);
+        let dcid = self.remote_cids[0].1.clone();
+
+        for pending in packets {
+            let space = pending.space;
+            let kind = match space {
+                INITIAL => Kind::Initial,
+                HANDSHAKE => Kind::Handshake,
+                _ => Kind::Short,
+            };
+
+            let pn = self.spaces[space].next_pn;
+            self.spaces[space].next_pn += 1;
+
+            let mut packet = Vec::with_capacity(MAX_DATAGRAM_LEN);
+            let pn_offset = packet::put_header(
+                &mut packet,
+                kind,
+                &dcid,
+                &self.scid,
+                self.key_phase,
+                pn,
+                pending.payload.len(),
+            );
+            packet.extend_from_slice(&pending.payload);
+
+            if let Some(keys) = &self.spaces[space].write {
+                keys.seal(pn, pn_offset, &mut packet);
+            }
+
+            if pending.eliciting {
+                let sent = SentPacket {
+                    time: now,
+                    size: packet.len(),
+                    frames: pending.frames,
+                };
+                self.recovery.on_sent(space, pn, sent);
+                self.probe = false;
+            }
+
+            datagram.extend_from_slice(&packet);
+
+            // a client is done with the Initial keys once it sends a Handshake packet (RFC 9001
+            // 4.9.1).
+            if self.client && space == HANDSHAKE {
+                self.discard(INITIAL);
+            }
+        }
+
+        datagram
+    }
+
+    /// Function forgets the streams that are done on both ends, and lets our peer open new
+    /// ones in place of its own.
+    fn collect(&mut self) {
+        let done: Vec<u64> = self
+            .streams
+            .iter()
+            .filter(|x| x.1.is_done())
+            .map(|x| *x.0)
+            .collect();
+
+        for id in done {
+            self.streams.remove(&id);
+
+            if !self.is_local(id) {
+                let kind = ((id >> 1) & 1) as usize;
+                self.max_accept[kind] += 1;
+                self.spaces[DATA].queue.push_back(Frame::MaxStreams {
+                    bidi: kind == 0,
+                    max: self.max_accept[kind],
+                });
+            }
+        }
+    }
+
+    /// Function opens a stream, returns `None` while our peer doesnt let us open more.
+    pub fn open(&mut self, bidi: bool) -> Result<Option<u64>, Error> {
+        self.check()?;
+
+        let kind = if bidi { 0 } else { 1 };
+        if self.state != State::Established || self.opened[kind] >= self.max_open[kind] {
+            return Ok(None);
+        }
+
+        let id = self.opened[kind] << 2 | (kind as u64) << 1 | (if self.client { 0 } else { 1 });
+        self.opened[kind] += 1;
+
+        let stream = if bidi {
+            Stream {
+                send: Some(SendStream::new(self.peer_params.stream_data_bidi_remote)),
+                recv: Some(RecvStream::new(STREAM_WINDOW)),
+                detached: false,
+            }
+        } else {
+            Stream {
+                send: Some(SendStream::new(self.peer_params.stream_data_uni)),
+                ..Default::default()
+            }
+        };
+
+        self.streams.insert(id, stream);
+        Ok(Some(id))
+    }
+
+    /// Returns the next stream our peer opened, `None` while there is none.
+    pub fn accept(&mut self) -> Result<Option<u64>, Error> {
+        match self.incoming.pop_front() {
+            Some(id) => Ok(Some(id)),
+            None => self.check().map(|_| None),
+        }
+    }
+
+    /// Function reads from stream `id` into `buf`, returns `None` while there is nothing to
+    /// read and 0 once the stream ended.
+    pub fn read(&mut self, id: u64, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let recv = match self.streams.get_mut(&id).and_then(|x| x.recv.as_mut()) {
+            Some(x) => x,
+            None => return Ok(Some(0)),
+        };
+
+        if let Some(code) = recv.reset {
+            return Err(Error::Reset(code));
+        }
+
+        if recv.has_data() {
+            let len = recv.read(buf);
+            if let Some(max) = recv.window_update() {
+                self.spaces[DATA]
+                    .queue
+                    .push_back(Frame::MaxStreamData { id, max });
+            }
+
+            self.data_read += len as u64;
+            self.update_max_data();
+            self.collect();
+            return Ok(Some(len));
+        }
+
+        if recv.is_finished() {
+            return Ok(Some(0));
+        }
+
+        self.check().map(|_| None)
+    }
+
+    /// Function buffers data to send on stream `id`, returns how much fit.
+    pub fn write(&mut self, id: u64, data: &[u8]) -> Result<usize, Error> {
+        self.check()?;
+
+        let send = self
+            .streams
+            .get_mut(&id)
+            .and_then(|x| x.send.as_mut())
+            .ok_or(Error::Closed)?;
+
+        if let Some(code) = send.stopped {
+            return Err(Error::Stopped(code));
+        }
+
+        if send.reset.is_some() || send.is_finished() {
+            return Err(Error::Closed);
+        }
+
+        Ok(send.write(data))
+    }
+
+    /// Function ends stream `id` once the data written so far was sent.
+    pub fn finish(&mut self, id: u64) {
+        if let Some(send) = self.streams.get_mut(&id).and_then(|x| x.send.as_mut()) {
+            send.finish();
+        }
+    }
+
+    /// Function abandons sending on stream `id`.
+    pub fn reset(&mut self, id: u64, code: u64) {
+        if let Some(send) = self.streams.get_mut(&id).and_then(|x| x.send.as_mut()) {
+            if !send.is_done() {
+                send.reset = Some(code);
+                let final_size = send.offset();
+                self.spaces[DATA].queue.push_back(Frame::ResetStream {
+                    id,
+                    code,
+                    final_size,
+                });
+            }
+        }
+    }
+
+    /// Function asks our peer to stop sending on stream `id`.
+    pub fn stop(&mut self, id: u64, code: u64) {
+        if let Some(recv) = self.streams.get_mut(&id).and_then(|x| x.recv.as_mut()) {
+            if !recv.stopped && recv.reset.is_none() && !recv.is_finished() {
+                recv.stopped = true;
+                self.data_read += recv.discard();
+                self.spaces[DATA]
+                    .queue
+                    .push_back(Frame::StopSending { id, code });
+                self.update_max_data();
+            }
+        }
+    }
+
+    /// Function lets go of stream `id` after its handle was dropped, what was written is still
+    /// sent.
+    pub fn detach(&mut self, id: u64) {
+        self.finish(id);
+        self.stop(id, 0);
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.detached = true;
+        }
+
+        self.collect();
+    }
+}
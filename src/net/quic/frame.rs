@@ -0,0 +1,464 @@
+//! Frames, the contents of packets (RFC 9000 19).
+
+use super::packet::put_varint;
+use super::packet::Reader;
+
+use crate::prelude::*;
+
+const PADDING: u64 = 0x00;
+const PING: u64 = 0x01;
+const ACK: u64 = 0x02;
+const ACK_ECN: u64 = 0x03;
+const RESET_STREAM: u64 = 0x04;
+const STOP_SENDING: u64 = 0x05;
+const CRYPTO: u64 = 0x06;
+const NEW_TOKEN: u64 = 0x07;
+const STREAM: u64 = 0x08;
+const STREAM_LAST: u64 = 0x0f;
+const MAX_DATA: u64 = 0x10;
+const MAX_STREAM_DATA: u64 = 0x11;
+const MAX_STREAMS_BIDI: u64 = 0x12;
+const MAX_STREAMS_UNI: u64 = 0x13;
+const DATA_BLOCKED: u64 = 0x14;
+const STREAM_DATA_BLOCKED: u64 = 0x15;
+const STREAMS_BLOCKED_BIDI: u64 = 0x16;
+const STREAMS_BLOCKED_UNI: u64 = 0x17;
+const NEW_CONNECTION_ID: u64 = 0x18;
+const RETIRE_CONNECTION_ID: u64 = 0x19;
+const PATH_CHALLENGE: u64 = 0x1a;
+const PATH_RESPONSE: u64 = 0x1b;
+const CONNECTION_CLOSE: u64 = 0x1c;
+const APPLICATION_CLOSE: u64 = 0x1d;
+const HANDSHAKE_DONE: u64 = 0x1e;
+
+/// Flags in the type of stream frames.
+const STREAM_FIN: u64 = 0x01;
+const STREAM_LEN: u64 = 0x02;
+const STREAM_OFF: u64 = 0x04;
+
+/// Most bytes a stream or crypto frame adds on top of its data.
+pub const MAX_STREAM_OVERHEAD: usize = 1 + 8 + 8 + 8;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Frame {
+    Padding,
+    Ping,
+    /// Ranges of packet numbers, largest first, as inclusive `(smallest, largest)` pairs. The
+    /// delay is in microseconds, scaled down by the ack delay exponent.
+    Ack {
+        ranges: Vec<(u64, u64)>,
+        delay: u64,
+    },
+    ResetStream {
+        id: u64,
+        code: u64,
+        final_size: u64,
+    },
+    StopSending {
+        id: u64,
+        code: u64,
+    },
+    Crypto {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    NewToken,
+    Stream {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+        fin: bool,
+    },
+    MaxData(u64),
+    MaxStreamData {
+        id: u64,
+        max: u64,
+    },
+    MaxStreams {
+        bidi: bool,
+        max: u64,
+    },
+    DataBlocked(u64),
+    StreamDataBlocked {
+        id: u64,
+        max: u64,
+    },
+    StreamsBlocked {
+        bidi: bool,
+        max: u64,
+    },
+    NewConnectionId {
+        seq: u64,
+        retire_prior_to: u64,
+        cid: Vec<u8>,
+        reset_token: [u8; 16],
+    },
+    RetireConnectionId(u64),
+    PathChallenge([u8; 8]),
+    PathResponse([u8; 8]),
+    /// `frame_type` is `None` for a close by the application.
+    ConnectionClose {
+        code: u64,
+        frame_type: Option<u64>,
+        reason: Vec<u8>,
+    },
+    HandshakeDone,
+}
+
+impl Frame {
+    /// Returns whether a packet with this frame has to be acknowledged.
+    pub fn is_ack_eliciting(&self) -> bool {
+        match self {
+            Self::Padding | Self::Ack { .. } | Self::ConnectionClose { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Returns whether a packet with this frame can also be sent on a path that is only being
+    /// probed (RFC 9000 9.1).
+    pub fn is_probing(&self) -> bool {
+        match self {
+            Self::Padding
+            | Self::PathChallenge(_)
+            | Self::PathResponse(_)
+            | Self::NewConnectionId { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether this frame can be sent in Initial and Handshake packets (RFC 9000 12.4).
+    pub fn is_allowed_in_handshake(&self) -> bool {
+        match self {
+            Self::Padding | Self::Ping | Self::Ack { .. } | Self::Crypto { .. } => true,
+            Self::ConnectionClose { frame_type, .. } => frame_type.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether this frame has to be sent again if the packet it was in got lost. Acks
+    /// and pings are sent again whenever they are needed.
+    pub fn is_retransmittable(&self) -> bool {
+        match self {
+            Self::Padding
+            | Self::Ping
+            | Self::Ack { .. }
+            | Self::PathChallenge(_)
+            | Self::PathResponse(_)
+            | Self::ConnectionClose { .. } => false,
+            _ => true,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Padding => buf.push(0),
+            Self::Ping => put_varint(buf, PING),
+            Self::Ack { ranges, delay } => {
+                put_varint(buf, ACK);
+                put_varint(buf, ranges[0].1);
+                put_varint(buf, *delay);
+                put_varint(buf, ranges.len() as u64 - 1);
+                put_varint(buf, ranges[0].1 - ranges[0].0);
+
+                for pair in ranges.windows(2) {
+                    put_varint(buf, pair[0].0 - pair[1].1 - 2);
+                    put_varint(buf, pair[1].1 - pair[1].0);
+                }
+            }
+            Self::ResetStream {
+                id,
+                code,
+                final_size,
+            } => {
+                put_varint(buf, RESET_STREAM);
+                put_varint(buf, *id);
+                put_varint(buf, *code);
+                put_varint(buf, *final_size);
+            }
+            Self::StopSending { id, code } => {
+                put_varint(buf, STOP_SENDING);
+                put_varint(buf, *id);
+                put_varint(buf, *code);
+            }
+            Self::Crypto { offset, data } => {
+                put_varint(buf, CRYPTO);
+                put_varint(buf, *offset);
+                put_varint(buf, data.len() as u64);
+                buf.extend_from_slice(data);
+            }
+            // we never hand out tokens.
+            Self::NewToken => {}
+            Self::Stream {
+                id,
+                offset,
+                data,
+                fin,
+            } => {
+                let mut kind = STREAM | STREAM_LEN;
+                if *offset != 0 {
+                    kind |= STREAM_OFF;
+                }
+                if *fin {
+                    kind |= STREAM_FIN;
+                }
+
+                put_varint(buf, kind);
+                put_varint(buf, *id);
+                if *offset != 0 {
+                    put_varint(buf, *offset);
+                }
+                put_varint(buf, data.len() as u64);
+                buf.extend_from_slice(data);
+            }
+            Self::MaxData(max) => {
+                put_varint(buf, MAX_DATA);
+                put_varint(buf, *max);
+            }
+            Self::MaxStreamData { id, max } => {
+                put_varint(buf, MAX_STREAM_DATA);
+                put_varint(buf, *id);
+                put_varint(buf, *max);
+            }
+            Self::MaxStreams { bidi, max } => {
+                let kind = if *bidi {
+                    MAX_STREAMS_BIDI
+                } else {
+                    MAX_STREAMS_UNI
+                };
+
+                put_varint(buf, kind);
+                put_varint(buf, *max);
+            }
+            Self::DataBlocked(max) => {
+                put_varint(buf, DATA_BLOCKED);
+                put_varint(buf, *max);
+            }
+            Self::StreamDataBlocked { id, max } => {
+                put_varint(buf, STREAM_DATA_BLOCKED);
+                put_varint(buf, *id);
+                put_varint(buf, *max);
+            }
+            Self::StreamsBlocked { bidi, max } => {
+                let kind = if *bidi {
+                    STREAMS_BLOCKED_BIDI
+                } else {
+                    STREAMS_BLOCKED_UNI
+                };
+
+                put_varint(buf, kind);
+                put_varint(buf, *max);
+            }
+            Self::NewConnectionId {
+                seq,
+                retire_prior_to,
+                cid,
+                reset_token,
+            } => {
+                put_varint(buf, NEW_CONNECTION_ID);
+                put_varint(buf, *seq);
+                put_varint(buf, *retire_prior_to);
+                buf.push(cid.len() as u8);
+                buf.extend_from_slice(cid);
+                buf.extend_from_slice(reset_token);
+            }
+            Self::RetireConnectionId(seq) => {
+                put_varint(buf, RETIRE_CONNECTION_ID);
+                put_varint(buf, *seq);
+            }
+            Self::PathChallenge(data) => {
+                put_varint(buf, PATH_CHALLENGE);
+                buf.extend_from_slice(data);
+            }
+            Self::PathResponse(data) => {
+                put_varint(buf, PATH_RESPONSE);
+                buf.extend_from_slice(data);
+            }
+            Self::ConnectionClose {
+                code,
+                frame_type,
+                reason,
+            } => {
+                match frame_type {
+                    Some(x) => {
+                        put_varint(buf, CONNECTION_CLOSE);
+                        put_varint(buf, *code);
+                        put_varint(buf, *x);
+                    }
+                    None => {
+                        put_varint(buf, APPLICATION_CLOSE);
+                        put_varint(buf, *code);
+                    }
+                }
+
+                put_varint(buf, reason.len() as u64);
+                buf.extend_from_slice(reason);
+            }
+            Self::HandshakeDone => put_varint(buf, HANDSHAKE_DONE),
+        }
+    }
+
+    /// Parses the next frame, `None` if it is malformed or of a type we dont know.
+    fn parse(r: &mut Reader) -> Option<Self> {
+        let kind = r.varint()?;
+
+        let frame = match kind {
+            PADDING => Self::Padding,
+            PING => Self::Ping,
+            ACK | ACK_ECN => {
+                let largest = r.varint()?;
+                let delay = r.varint()?;
+                let count = r.varint()?;
+                let first = r.varint()?;
+
+                let mut smallest = largest.checked_sub(first)?;
+                let mut ranges = vec![(smallest, largest)];
+
+                for _ in 0..count {
+                    let gap = r.varint()?;
+                    let len = r.varint()?;
+                    let largest = smallest.checked_sub(gap + 2)?;
+                    smallest = largest.checked_sub(len)?;
+                    ranges.push((smallest, largest));
+                }
+
+                // we dont use ecn, the counts are skipped.
+                if kind == ACK_ECN {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+
+                Self::Ack { ranges, delay }
+            }
+            RESET_STREAM => Self::ResetStream {
+                id: r.varint()?,
+                code: r.varint()?,
+                final_size: r.varint()?,
+            },
+            STOP_SENDING => Self::StopSending {
+                id: r.varint()?,
+                code: r.varint()?,
+            },
+            CRYPTO => {
+                let offset = r.varint()?;
+                let len = r.varint()? as usize;
+                Self::Crypto {
+                    offset,
+                    data: r.bytes(len)?.to_vec(),
+                }
+            }
+            NEW_TOKEN => {
+                let len = r.varint()? as usize;
+                r.bytes(len)?;
+                Self::NewToken
+            }
+            STREAM..=STREAM_LAST => {
+                let id = r.varint()?;
+                let offset = if kind & STREAM_OFF != 0 {
+                    r.varint()?
+                } else {
+                    0
+                };
+
+                let data = if kind & STREAM_LEN != 0 {
+                    let len = r.varint()? as usize;
+                    r.bytes(len)?
+                } else {
+                    let rest = r.remaining();
+                    r.bytes(rest)?
+                };
+
+                Self::Stream {
+                    id,
+                    offset,
+                    data: data.to_vec(),
+                    fin: kind & STREAM_FIN != 0,
+                }
+            }
+            MAX_DATA => Self::MaxData(r.varint()?),
+            MAX_STREAM_DATA => Self::MaxStreamData {
+                id: r.varint()?,
+                max: r.varint()?,
+            },
+            MAX_STREAMS_BIDI | MAX_STREAMS_UNI => Self::MaxStreams {
+                bidi: kind == MAX_STREAMS_BIDI,
+                max: r.varint()?,
+            },
+            DATA_BLOCKED => Self::DataBlocked(r.varint()?),
+            STREAM_DATA_BLOCKED => Self::StreamDataBlocked {
+                id: r.varint()?,
+                max: r.varint()?,
+            },
+            STREAMS_BLOCKED_BIDI | STREAMS_BLOCKED_UNI => Self::StreamsBlocked {
+                bidi: kind == STREAMS_BLOCKED_BIDI,
+                max: r.varint()?,
+            },
+            NEW_CONNECTION_ID => {
+                let seq = r.varint()?;
+                let retire_prior_to = r.varint()?;
+                let cid = r.vec8()?.to_vec();
+                let mut reset_token = [0; 16];
+                reset_token.copy_from_slice(r.bytes(16)?);
+
+                Self::NewConnectionId {
+                    seq,
+                    retire_prior_to,
+                    cid,
+                    reset_token,
+                }
+            }
+            RETIRE_CONNECTION_ID => Self::RetireConnectionId(r.varint()?),
+            PATH_CHALLENGE | PATH_RESPONSE => {
+                let mut data = [0; 8];
+                data.copy_from_slice(r.bytes(8)?);
+
+                if kind == PATH_CHALLENGE {
+                    Self::PathChallenge(data)
+                } else {
+                    Self::PathResponse(data)
+                }
+            }
+            CONNECTION_CLOSE | APPLICATION_CLOSE => {
+                let code = r.varint()?;
+                let frame_type = if kind == CONNECTION_CLOSE {
+                    Some(r.varint()?)
+                } else {
+                    None
+                };
+                let len = r.varint()? as usize;
+
+                Self::ConnectionClose {
+                    code,
+                    frame_type,
+                    reason: r.bytes(len)?.to_vec(),
+                }
+            }
+            HANDSHAKE_DONE => Self::HandshakeDone,
+            _ => return None,
+        };
+
+        Some(frame)
+    }
+}
+
+/// Parses the frames in the payload of a packet. Runs of padding come out as a single frame.
+pub fn parse(payload: &[u8]) -> Option<Vec<Frame>> {
+    let mut r = Reader::new(payload);
+    let mut frames = Vec::new();
+
+    // a packet without frames is a protocol violation.
+    if payload.is_empty() {
+        return None;
+    }
+
+    while !r.is_empty() {
+        let frame = Frame::parse(&mut r)?;
+        if frame == Frame::Padding && frames.last() == Some(&Frame::Padding) {
+            continue;
+        }
+
+        frames.push(frame);
+    }
+
+    Some(frames)
+}
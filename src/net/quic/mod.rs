@@ -0,0 +1,576 @@
+//! QUIC (RFC 9000) on top of our udp sockets, secured with our TLS 1.3 handshake (RFC 9001).
+//!
+//! Every endpoint is driven by its own task, which owns the udp socket, sends whatever its
+//! connections have to send and runs their timers. A connection carries any number of
+//! bidirectional and unidirectional streams, which are read and written much like a TcpStream.
+//! Lost packets are sent again, congestion control is NewReno, and connections survive their
+//! client moving to a new address. There is no 0-RTT, Retry, stateless reset or path mtu
+//! discovery.
+//!
+//! ```ignore
+//! let config = ClientConfig::new().add_root_certificates(ROOT_PEM)?.with_alpn(&[b"hq-interop"]);
+//! let conn = QuicConnector::new(config).connect("10.0.0.1:4433", "example.com").await?;
+//! let mut stream = conn.open_bi().await?;
+//! stream.write(b"hello").await?;
+//! stream.finish();
+//! ```
+mod conn;
+mod frame;
+mod packet;
+mod params;
+mod recovery;
+mod stream;
+
+use conn::Conn;
+use packet::Kind;
+use packet::CID_LEN;
+use packet::MAX_DATAGRAM_LEN;
+
+use crate::arch::pit::get_milis;
+use crate::async_;
+use crate::async_::Sleep;
+use crate::net::addr::SocketAddr;
+use crate::net::addr::ToSocketAddrs;
+use crate::net::socks::UdpSocket;
+use crate::net::tls;
+use crate::net::tls::ClientConfig;
+use crate::net::tls::ServerConfig;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedReceiver;
+use crate::sync::mpsc::UnboundedSender;
+use crate::sync::Arc;
+
+use core::fmt;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::future;
+use futures_util::pin_mut;
+
+/// Biggest datagram we read off the socket.
+const MAX_UDP_LEN: usize = 2048;
+/// How long a endpoint without timers sleeps before looking again, in ms.
+const IDLE_POLL: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The connection or stream was closed on our end.
+    Closed,
+    /// Our peer stopped answering.
+    TimedOut,
+    /// The TLS handshake failed.
+    Tls(tls::Error),
+    /// The connection was closed with a transport error code.
+    Transport(u64),
+    /// Our peer closed the connection with a error code of its application.
+    Application(u64),
+    /// Our peer reset the stream with a error code.
+    Reset(u64),
+    /// Our peer asked us to stop sending on the stream with a error code.
+    Stopped(u64),
+    /// The server doesnt speak QUIC version 1.
+    VersionMismatch,
+    /// The connection cant move to a new address, either end doesnt allow it yet.
+    Migration,
+    /// The address couldnt be resolved or bound.
+    Bind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "closed"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::Tls(x) => write!(f, "tls: {}", x),
+            Self::Transport(x) => write!(f, "transport error {:#x}", x),
+            Self::Application(x) => write!(f, "closed by peer with code {}", x),
+            Self::Reset(x) => write!(f, "stream reset with code {}", x),
+            Self::Stopped(x) => write!(f, "stream stopped with code {}", x),
+            Self::VersionMismatch => write!(f, "no common quic version"),
+            Self::Migration => write!(f, "migration not possible"),
+            Self::Bind => write!(f, "couldnt bind address"),
+        }
+    }
+}
+
+/// What the handles of a connection ask of its endpoint.
+enum Command {
+    /// The connection has something new to send.
+    Wake,
+    /// Move the connection of a client to a new port.
+    Migrate(UnboundedSender<Result<(), Error>>),
+}
+
+/// A connection, shared between its endpoint and its handles.
+struct Shared {
+    conn: spin::Mutex<Conn>,
+    endpoint: UnboundedSender<Command>,
+}
+
+impl Shared {
+    /// Function runs `f` on the connection and lets the endpoint send what it queued.
+    fn with<R>(&self, f: impl FnOnce(&mut Conn) -> R) -> R {
+        let result = f(&mut self.conn.lock());
+        let _ = self.endpoint.send(Command::Wake);
+        result
+    }
+}
+
+/// Closes the connection once the last handle is gone.
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let now = get_milis();
+        self.shared.with(|x| x.close(0, b"", now));
+    }
+}
+
+/// A QUIC connection. Clones share the connection, it is closed once all of them and their
+/// streams are dropped.
+#[derive(Clone)]
+pub struct Connection {
+    handle: Arc<Handle>,
+}
+
+impl Connection {
+    fn new(shared: Arc<Shared>) -> Self {
+        Self {
+            handle: Arc::new(Handle { shared }),
+        }
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.handle.shared
+    }
+
+    /// Function waits until `f` returns something, it is called again whenever the connection
+    /// changes.
+    async fn poll<T>(
+        &self,
+        mut f: impl FnMut(&mut Conn) -> Result<Option<T>, Error>,
+    ) -> Result<T, Error> {
+        let shared = self.shared();
+
+        future::poll_fn(|cx| {
+            let mut conn = shared.conn.lock();
+
+            match f(&mut conn) {
+                Ok(Some(x)) => {
+                    drop(conn);
+                    let _ = shared.endpoint.send(Command::Wake);
+                    Poll::Ready(Ok(x))
+                }
+                Ok(None) => {
+                    conn.register(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            }
+        })
+        .await
+    }
+
+    /// Function opens a bidirectional stream, waiting while our peer doesnt allow more.
+    pub async fn open_bi(&self) -> Result<QuicStream, Error> {
+        let id = self.poll(|x| x.open(true)).await?;
+        Ok(QuicStream::new(id, self.clone()))
+    }
+
+    /// Function opens a stream we only send on.
+    pub async fn open_uni(&self) -> Result<QuicStream, Error> {
+        let id = self.poll(|x| x.open(false)).await?;
+        Ok(QuicStream::new(id, self.clone()))
+    }
+
+    /// Function waits for the next stream our peer opens.
+    pub async fn accept(&self) -> Result<QuicStream, Error> {
+        let id = self.poll(|x| x.accept()).await?;
+        Ok(QuicStream::new(id, self.clone()))
+    }
+
+    /// Function closes the connection with a error code and reason for our peer, streams fail
+    /// from then on.
+    pub fn close(&self, code: u64, reason: &[u8]) {
+        let now = get_milis();
+        self.shared().with(|x| x.close(code, reason, now));
+    }
+
+    /// Function moves the connection of a client to a new local port, our peer keeps it going
+    /// on the new path (RFC 9000 9).
+    pub async fn migrate(&self) -> Result<(), Error> {
+        let (tx, mut rx) = channel();
+        self.shared()
+            .endpoint
+            .send(Command::Migrate(tx))
+            .map_err(|_| Error::Closed)?;
+
+        rx.recv().await.unwrap_or(Err(Error::Closed))
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.shared().conn.lock().remote()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared().conn.lock().local
+    }
+
+    /// Returns the protocol agreed on through ALPN.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.shared().conn.lock().alpn()
+    }
+
+    /// Returns our estimate of the round trip time.
+    pub fn rtt(&self) -> Duration {
+        Duration::from_millis(self.shared().conn.lock().rtt())
+    }
+
+    /// Returns why the connection was closed.
+    pub fn error(&self) -> Option<Error> {
+        self.shared().conn.lock().error()
+    }
+}
+
+/// A stream of a QUIC connection. Dropping it finishes what we send and stops what we receive.
+pub struct QuicStream {
+    id: u64,
+    conn: Connection,
+}
+
+impl QuicStream {
+    fn new(id: u64, conn: Connection) -> Self {
+        Self { id, conn }
+    }
+
+    /// Returns the id of the stream, its lowest bits tell who opened it and whether it is
+    /// unidirectional.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Function reads into `buf`, returns how many bytes were read and 0 once the stream ended.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let id = self.id;
+        self.conn.poll(|x| x.read(id, buf)).await
+    }
+
+    /// Function writes all of `data`, waiting while the send buffer is full.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        let id = self.id;
+
+        while !data.is_empty() {
+            let len = self
+                .conn
+                .poll(|x| x.write(id, data).map(|x| Some(x).filter(|x| *x > 0)))
+                .await?;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Function ends the stream once what was written is sent.
+    pub fn finish(&mut self) {
+        let id = self.id;
+        self.conn.shared().with(|x| x.finish(id));
+    }
+
+    /// Function abandons what we send with a error code for our peer.
+    pub fn reset(&mut self, code: u64) {
+        let id = self.id;
+        self.conn.shared().with(|x| x.reset(id, code));
+    }
+
+    /// Function asks our peer to stop sending with a error code.
+    pub fn stop(&mut self, code: u64) {
+        let id = self.id;
+        self.conn.shared().with(|x| x.stop(id, code));
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.conn.shared().with(|x| x.detach(id));
+    }
+}
+
+/// Accepts QUIC connections on a udp port.
+pub struct QuicListener {
+    local: SocketAddr,
+    incoming: UnboundedReceiver<Connection>,
+}
+
+impl QuicListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: ServerConfig) -> Result<Self, ()> {
+        let socket = UdpSocket::bind(addr)?;
+        let local = socket.local_addr();
+        let (tx, rx) = channel();
+
+        let server = Server {
+            config: Arc::new(config),
+            incoming: tx,
+        };
+
+        async_::spawn(Endpoint::new(socket, Some(server)).run());
+
+        Ok(Self {
+            local,
+            incoming: rx,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Function waits for the next connection that finished its handshake.
+    pub async fn accept(&mut self) -> Option<Connection> {
+        self.incoming.recv().await
+    }
+}
+
+/// Opens QUIC connections with a shared config, each on its own udp port.
+#[derive(Clone)]
+pub struct QuicConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl QuicConnector {
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Function connects to `addr` and waits for the handshake. `server_name` is sent through
+    /// SNI and has to be one of the names of the server certificate.
+    pub async fn connect<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        server_name: &str,
+    ) -> Result<Connection, Error> {
+        let remote = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or(Error::Bind)?;
+        let socket = UdpSocket::bind(0).map_err(|_| Error::Bind)?;
+
+        let conn = Conn::client(
+            self.config.clone(),
+            server_name,
+            socket.local_addr(),
+            remote,
+            get_milis(),
+        );
+
+        let mut endpoint = Endpoint::new(socket, None);
+        let shared = endpoint.add(conn);
+        async_::spawn(endpoint.run());
+
+        let conn = Connection::new(shared);
+        conn.poll(|x| x.check().map(|_| Some(()).filter(|_| x.is_established())))
+            .await?;
+
+        Ok(conn)
+    }
+}
+
+/// What a endpoint of a listener needs to accept connections.
+struct Server {
+    config: Arc<ServerConfig>,
+    incoming: UnboundedSender<Connection>,
+}
+
+/// Owns a udp socket and drives the connections on it.
+struct Endpoint {
+    socket: UdpSocket,
+    conns: Vec<Arc<Shared>>,
+    commands: UnboundedReceiver<Command>,
+    sender: UnboundedSender<Command>,
+    server: Option<Server>,
+}
+
+/// What woke up a endpoint.
+enum Wakeup {
+    Datagram(usize, SocketAddr),
+    Command(Option<Command>),
+    Timer,
+}
+
+impl Endpoint {
+    fn new(socket: UdpSocket, server: Option<Server>) -> Self {
+        let (sender, commands) = channel();
+
+        Self {
+            socket,
+            conns: Vec::new(),
+            commands,
+            sender,
+            server,
+        }
+    }
+
+    fn add(&mut self, conn: Conn) -> Arc<Shared> {
+        let shared = Arc::new(Shared {
+            conn: spin::Mutex::new(conn),
+            endpoint: self.sender.clone(),
+        });
+
+        self.conns.push(shared.clone());
+        shared
+    }
+
+    async fn run(mut self) {
+        let mut buf = vec![0; MAX_UDP_LEN];
+
+        loop {
+            let now = get_milis();
+
+            for shared in self.conns.iter() {
+                let mut conn = shared.conn.lock();
+                if conn.timeout().map_or(false, |x| x <= now) {
+                    conn.handle_timeout(now);
+                }
+            }
+
+            self.flush(now).await;
+            self.conns.retain(|x| !x.conn.lock().is_closed());
+            self.announce();
+
+            // a endpoint stops once its connections are gone and no listener takes new ones.
+            let listening = self
+                .server
+                .as_ref()
+                .map_or(false, |x| !x.incoming.is_closed());
+            if self.conns.is_empty() && !listening {
+                return;
+            }
+
+            let next = self
+                .conns
+                .iter()
+                .filter_map(|x| x.conn.lock().timeout())
+                .min();
+            let period = next.map_or(IDLE_POLL, |x| x.saturating_sub(now));
+
+            let wakeup = {
+                let recv = self.socket.recv_from(&mut buf);
+                let command = self.commands.recv();
+                let sleep = Sleep::new(Duration::from_millis(period));
+                pin_mut!(recv, command);
+
+                match future::select(recv, future::select(command, sleep)).await {
+                    future::Either::Left((Ok((len, from)), _)) => Wakeup::Datagram(len, from),
+                    future::Either::Left((Err(_), _)) => return,
+                    future::Either::Right((future::Either::Left((x, _)), _)) => Wakeup::Command(x),
+                    future::Either::Right(_) => Wakeup::Timer,
+                }
+            };
+
+            match wakeup {
+                Wakeup::Datagram(len, from) => self.on_datagram(from, &mut buf[..len]).await,
+                Wakeup::Command(Some(Command::Migrate(reply))) => {
+                    let _ = reply.send(self.migrate());
+                }
+                Wakeup::Command(_) | Wakeup::Timer => {}
+            }
+        }
+    }
+
+    /// Function sends everything the connections have to send.
+    async fn flush(&self, now: u64) {
+        for shared in self.conns.iter() {
+            loop {
+                let next = shared.conn.lock().poll_transmit(now);
+                match next {
+                    Some((datagram, to)) => {
+                        let _ = self.socket.send_to(&datagram, to).await;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Function hands the server connections that finished their handshake to the listener.
+    fn announce(&self) {
+        let server = match &self.server {
+            Some(x) => x,
+            None => return,
+        };
+
+        let ready: Vec<Arc<Shared>> = self
+            .conns
+            .iter()
+            .filter(|x| {
+                let mut conn = x.conn.lock();
+                let ready = !conn.announced && conn.is_established();
+                conn.announced |= ready;
+                ready
+            })
+            .cloned()
+            .collect();
+
+        for shared in ready {
+            let _ = server.incoming.send(Connection::new(shared));
+        }
+    }
+
+    async fn on_datagram(&mut self, from: SocketAddr, data: &mut [u8]) {
+        let now = get_milis();
+        let header = match packet::parse_header(data) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Some(shared) = self.conns.iter().find(|x| x.conn.lock().owns(&header.dcid)) {
+            shared.conn.lock().handle_datagram(now, from, data);
+            return;
+        }
+
+        // only a listener starts connections, for Initials big enough to show the client can
+        // take our answer (RFC 9000 14.1).
+        let config = match &self.server {
+            Some(x) if data.len() >= MAX_DATAGRAM_LEN && header.is_long() => x.config.clone(),
+            _ => return,
+        };
+
+        if header.version != packet::VERSION && header.kind != Kind::VersionNegotiation {
+            let answer = packet::version_negotiation(&header.scid, &header.dcid);
+            let _ = self.socket.send_to(&answer, from).await;
+            return;
+        }
+
+        if header.kind != Kind::Initial || header.dcid.len() < CID_LEN {
+            return;
+        }
+
+        let local = self.socket.local_addr();
+        let mut conn = Conn::server(config, &header, local, from, now);
+        conn.handle_datagram(now, from, data);
+        self.add(conn);
+    }
+
+    /// Function moves the connection of a client endpoint to a new port.
+    fn migrate(&mut self) -> Result<(), Error> {
+        let shared = match &self.conns[..] {
+            [x] if self.server.is_none() => x,
+            _ => return Err(Error::Migration),
+        };
+
+        if !shared.conn.lock().can_migrate() {
+            return Err(Error::Migration);
+        }
+
+        let socket = UdpSocket::bind(0).map_err(|_| Error::Bind)?;
+        shared.conn.lock().migrate(socket.local_addr());
+        self.socket = socket;
+        Ok(())
+    }
+}
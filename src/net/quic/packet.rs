@@ -0,0 +1,434 @@
+//! Packet headers (RFC 9000 17) and packet protection (RFC 9001 5).
+
+use crate::arch::random;
+use crate::net::tls;
+use crate::net::tls::Aead;
+use crate::net::tls::CipherSuite;
+use crate::net::tls::Hash;
+use crate::prelude::*;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::Aes128;
+use aes::BlockEncrypt;
+use aes::NewBlockCipher;
+use chacha20::cipher::NewCipher;
+use chacha20::cipher::StreamCipher;
+use chacha20::cipher::StreamCipherSeek;
+use chacha20::ChaCha20;
+
+/// QUIC version 1, the only one we speak.
+pub const VERSION: u32 = 1;
+/// Length of the connection ids we pick for ourselves, short headers dont carry it.
+pub const CID_LEN: usize = 8;
+pub const MAX_CID_LEN: usize = 20;
+/// We never send datagrams bigger than the smallest size every path has to carry (RFC 9000
+/// 14), so we dont have to discover the mtu of a path.
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+pub const TAG_LEN: usize = 16;
+/// We always send 4 byte packet numbers, which also leaves room for the sample of the header
+/// protection without padding.
+pub const PN_LEN: usize = 4;
+
+const NONCE_LEN: usize = 12;
+const SAMPLE_LEN: usize = 16;
+
+/// Salt of the Initial secrets of version 1 (RFC 9001 5.2).
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// Appends `x` as a variable length integer (RFC 9000 16).
+pub fn put_varint(buf: &mut Vec<u8>, x: u64) {
+    if x < 1 << 6 {
+        buf.push(x as u8);
+    } else if x < 1 << 14 {
+        buf.extend_from_slice(&(x as u16 | 0x4000).to_be_bytes());
+    } else if x < 1 << 30 {
+        buf.extend_from_slice(&(x as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&(x | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+/// Reads the fields of packets, frames and transport parameters.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Returns how many bytes are left.
+    pub fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let x = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(x)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|x| x[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    /// Reads bytes prefixed with a 1 byte length.
+    pub fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn varint(&mut self) -> Option<u64> {
+        let first = *self.buf.get(self.pos)?;
+        let len = 1 << (first >> 6);
+        let bytes = self.bytes(len)?;
+
+        Some(
+            bytes[1..]
+                .iter()
+                .fold((first & 0x3f) as u64, |x, y| x << 8 | *y as u64),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+    VersionNegotiation,
+    /// 1-RTT packets, the only ones with a short header.
+    Short,
+}
+
+pub struct Header {
+    pub kind: Kind,
+    pub version: u32,
+    pub dcid: Vec<u8>,
+    pub scid: Vec<u8>,
+    /// Offset of the packet number.
+    pub pn_offset: usize,
+    /// Length of the whole packet, what follows it in the datagram are coalesced packets.
+    pub len: usize,
+}
+
+impl Header {
+    /// Returns whether this is a long header packet.
+    pub fn is_long(&self) -> bool {
+        self.kind != Kind::Short
+    }
+}
+
+/// Parses the header of the first packet in `buf`. Packets of other versions only have their
+/// version and connection ids parsed, their length is the rest of the datagram.
+pub fn parse_header(buf: &[u8]) -> Option<Header> {
+    let first = *buf.first()?;
+
+    if first & 0x80 == 0 {
+        return Some(Header {
+            kind: Kind::Short,
+            version: VERSION,
+            dcid: buf.get(1..1 + CID_LEN)?.to_vec(),
+            scid: Vec::new(),
+            pn_offset: 1 + CID_LEN,
+            len: buf.len(),
+        });
+    }
+
+    let mut r = Reader::new(buf);
+    r.u8()?;
+    let version = r.u32()?;
+    let dcid = r.vec8()?.to_vec();
+    let scid = r.vec8()?.to_vec();
+
+    let kind = match (version, (first >> 4) & 3) {
+        (0, _) => Kind::VersionNegotiation,
+        (_, 0) => Kind::Initial,
+        (_, 1) => Kind::ZeroRtt,
+        (_, 2) => Kind::Handshake,
+        _ => Kind::Retry,
+    };
+
+    let mut header = Header {
+        kind,
+        version,
+        dcid,
+        scid,
+        pn_offset: r.pos(),
+        len: buf.len(),
+    };
+
+    if version != VERSION || kind == Kind::Retry {
+        return Some(header);
+    }
+
+    if header.dcid.len() > MAX_CID_LEN || header.scid.len() > MAX_CID_LEN {
+        return None;
+    }
+
+    // we never hand out tokens, so the tokens of Initial packets are skipped.
+    if kind == Kind::Initial {
+        let len = r.varint()? as usize;
+        r.bytes(len)?;
+    }
+
+    let len = r.varint()? as usize;
+    header.pn_offset = r.pos();
+    header.len = header.pn_offset.checked_add(len)?;
+
+    if header.len > buf.len() {
+        return None;
+    }
+
+    Some(header)
+}
+
+/// Function writes the header of a packet up to and including its packet number, returns the
+/// offset of the packet number. `len` is the length of the payload, without the tag.
+pub fn put_header(
+    buf: &mut Vec<u8>,
+    kind: Kind,
+    dcid: &[u8],
+    scid: &[u8],
+    key_phase: bool,
+    pn: u64,
+    len: usize,
+) -> usize {
+    let pn_bits = (PN_LEN - 1) as u8;
+
+    if kind == Kind::Short {
+        buf.push(0x40 | (if key_phase { 0x04 } else { 0 }) | pn_bits);
+        buf.extend_from_slice(dcid);
+    } else {
+        let kind = if kind == Kind::Initial { 0 } else { 2 };
+        buf.push(0xc0 | kind << 4 | pn_bits);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        buf.push(dcid.len() as u8);
+        buf.extend_from_slice(dcid);
+        buf.push(scid.len() as u8);
+        buf.extend_from_slice(scid);
+
+        if kind == 0 {
+            put_varint(buf, 0);
+        }
+
+        // always 2 bytes, so the header length doesnt depend on the payload.
+        let len = (PN_LEN + len + TAG_LEN) as u16;
+        buf.extend_from_slice(&(len | 0x4000).to_be_bytes());
+    }
+
+    let pn_offset = buf.len();
+    buf.extend_from_slice(&(pn as u32).to_be_bytes());
+    pn_offset
+}
+
+/// Returns the length of the header `put_header` writes.
+pub fn header_len(kind: Kind, dcid: &[u8], scid: &[u8]) -> usize {
+    match kind {
+        Kind::Short => 1 + dcid.len() + PN_LEN,
+        Kind::Initial => 7 + dcid.len() + scid.len() + 1 + 2 + PN_LEN,
+        _ => 7 + dcid.len() + scid.len() + 2 + PN_LEN,
+    }
+}
+
+/// Returns a Version Negotiation packet answering a packet with the connection ids `dcid` and
+/// `scid` (RFC 9000 17.2.1).
+pub fn version_negotiation(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0x80 | random::u64() as u8];
+    buf.extend_from_slice(&0u32.to_be_bytes());
+    buf.push(scid.len() as u8);
+    buf.extend_from_slice(scid);
+    buf.push(dcid.len() as u8);
+    buf.extend_from_slice(dcid);
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf
+}
+
+/// Returns the versions listed in a Version Negotiation packet.
+pub fn supported_versions(buf: &[u8], header: &Header) -> Vec<u32> {
+    let mut r = Reader::new(&buf[header.pn_offset..]);
+    let mut versions = Vec::new();
+
+    while let Some(x) = r.u32() {
+        versions.push(x);
+    }
+
+    versions
+}
+
+/// Returns the full packet number closest to the next one we expect (RFC 9000 A.3).
+fn decode_pn(largest: Option<u64>, truncated: u64, bits: u32) -> u64 {
+    let expected = largest.map_or(0, |x| x + 1);
+    let window = 1u64 << bits;
+    let half = window / 2;
+    let candidate = (expected & !(window - 1)) | truncated;
+
+    if candidate + half <= expected && candidate < (1 << 62) - window {
+        candidate + window
+    } else if candidate > expected + half && candidate >= window {
+        candidate - window
+    } else {
+        candidate
+    }
+}
+
+/// The keys protecting the packets one side sends at a encryption level.
+pub struct Keys {
+    suite: CipherSuite,
+    secret: Hash,
+    aead: Aead,
+    iv: [u8; NONCE_LEN],
+    /// Key of the header protection, it survives key updates.
+    hp: Vec<u8>,
+}
+
+impl Keys {
+    /// Derives the keys of the traffic `secret` (RFC 9001 5.1).
+    pub fn new(suite: CipherSuite, secret: &Hash) -> Self {
+        let key = tls::hkdf_expand_label(secret, b"quic key", &[], suite.key_len());
+        let mut iv = [0; NONCE_LEN];
+        iv.copy_from_slice(&tls::hkdf_expand_label(secret, b"quic iv", &[], NONCE_LEN));
+
+        Self {
+            suite,
+            secret: *secret,
+            aead: Aead::new(suite, &key),
+            iv,
+            hp: tls::hkdf_expand_label(secret, b"quic hp", &[], suite.key_len()),
+        }
+    }
+
+    /// Returns the keys of the next key phase (RFC 9001 6).
+    pub fn next(&self) -> Self {
+        let mut secret = Hash::default();
+        secret.copy_from_slice(&tls::hkdf_expand_label(
+            &self.secret,
+            b"quic ku",
+            &[],
+            secret.len(),
+        ));
+
+        let mut keys = Self::new(self.suite, &secret);
+        keys.hp = self.hp.clone();
+        keys
+    }
+
+    fn nonce(&self, pn: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (x, y) in nonce[NONCE_LEN - 8..].iter_mut().zip(&pn.to_be_bytes()) {
+            *x ^= y;
+        }
+        nonce
+    }
+
+    /// Returns the header protection mask for `sample`.
+    fn mask(&self, sample: &[u8]) -> [u8; 5] {
+        let mut mask = [0; 5];
+
+        match self.suite {
+            CipherSuite::Aes128GcmSha256 => {
+                let cipher = Aes128::new(GenericArray::from_slice(&self.hp));
+                let mut block = GenericArray::clone_from_slice(sample);
+                cipher.encrypt_block(&mut block);
+                mask.copy_from_slice(&block[..5]);
+            }
+            CipherSuite::ChaCha20Poly1305Sha256 => {
+                let counter = u32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+                let mut cipher = ChaCha20::new(
+                    chacha20::Key::from_slice(&self.hp),
+                    chacha20::Nonce::from_slice(&sample[4..]),
+                );
+                cipher.seek(counter as u64 * 64);
+                cipher.apply_keystream(&mut mask);
+            }
+        }
+
+        mask
+    }
+
+    /// Function protects the packet in `packet` in place. It holds a header written by
+    /// `put_header` followed by the payload.
+    pub fn seal(&self, pn: u64, pn_offset: usize, packet: &mut Vec<u8>) {
+        let header_len = pn_offset + PN_LEN;
+        let mut payload = packet.split_off(header_len);
+        let _ = self.aead.seal(&self.nonce(pn), packet, &mut payload);
+        packet.extend_from_slice(&payload);
+
+        let mask = self.mask(&packet[header_len..header_len + SAMPLE_LEN]);
+        packet[0] ^= mask[0] & if packet[0] & 0x80 != 0 { 0x0f } else { 0x1f };
+        for i in 0..PN_LEN {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+    }
+
+    /// Function removes the header protection of `packet` in place, returns its packet number
+    /// and the length of its header. `largest` is the largest packet number received so far.
+    pub fn unprotect(
+        &self,
+        packet: &mut [u8],
+        pn_offset: usize,
+        largest: Option<u64>,
+    ) -> Option<(u64, usize)> {
+        let sample = packet.get(pn_offset + 4..pn_offset + 4 + SAMPLE_LEN)?;
+        let mask = self.mask(sample);
+
+        packet[0] ^= mask[0] & if packet[0] & 0x80 != 0 { 0x0f } else { 0x1f };
+        let pn_len = (packet[0] & 0x03) as usize + 1;
+
+        let mut truncated = 0;
+        for i in 0..pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+            truncated = truncated << 8 | packet[pn_offset + i] as u64;
+        }
+
+        let pn = decode_pn(largest, truncated, pn_len as u32 * 8);
+        Some((pn, pn_offset + pn_len))
+    }
+
+    /// Returns the payload of a packet whose header protection was removed, `None` if it
+    /// wasnt protected with these keys.
+    pub fn open(&self, pn: u64, packet: &[u8], header_len: usize) -> Option<Vec<u8>> {
+        let mut payload = packet[header_len..].to_vec();
+        self.aead
+            .open(&self.nonce(pn), &packet[..header_len], &mut payload)
+            .ok()?;
+        Some(payload)
+    }
+}
+
+/// Returns the keys protecting the Initial packets of a connection whose client picked `dcid`
+/// (RFC 9001 5.2), the ones we read with and the ones we write with.
+pub fn initial_keys(dcid: &[u8], client: bool) -> (Keys, Keys) {
+    let secret = tls::hkdf_extract(&INITIAL_SALT, dcid);
+    let keys = |label: &[u8]| {
+        let mut x = Hash::default();
+        x.copy_from_slice(&tls::hkdf_expand_label(&secret, label, &[], x.len()));
+        Keys::new(CipherSuite::Aes128GcmSha256, &x)
+    };
+
+    let (client_keys, server_keys) = (keys(b"client in"), keys(b"server in"));
+    if client {
+        (server_keys, client_keys)
+    } else {
+        (client_keys, server_keys)
+    }
+}
@@ -0,0 +1,189 @@
+//! Transport parameters (RFC 9000 18), carried in the quic_transport_parameters extension of
+//! the TLS handshake.
+
+use super::packet::put_varint;
+use super::packet::Reader;
+use super::packet::MAX_CID_LEN;
+
+use crate::prelude::*;
+
+/// Id of the TLS extension carrying the parameters.
+pub const EXTENSION: u16 = 0x39;
+
+const ORIGINAL_DCID: u64 = 0x00;
+const IDLE_TIMEOUT: u64 = 0x01;
+const STATELESS_RESET_TOKEN: u64 = 0x02;
+const MAX_UDP_PAYLOAD: u64 = 0x03;
+const MAX_DATA: u64 = 0x04;
+const STREAM_DATA_BIDI_LOCAL: u64 = 0x05;
+const STREAM_DATA_BIDI_REMOTE: u64 = 0x06;
+const STREAM_DATA_UNI: u64 = 0x07;
+const MAX_STREAMS_BIDI: u64 = 0x08;
+const MAX_STREAMS_UNI: u64 = 0x09;
+const ACK_DELAY_EXPONENT: u64 = 0x0a;
+const MAX_ACK_DELAY: u64 = 0x0b;
+const DISABLE_MIGRATION: u64 = 0x0c;
+const PREFERRED_ADDRESS: u64 = 0x0d;
+const CID_LIMIT: u64 = 0x0e;
+const INITIAL_SCID: u64 = 0x0f;
+const RETRY_SCID: u64 = 0x10;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Params {
+    /// The dcid of the first Initial of the client, sent by servers.
+    pub original_dcid: Option<Vec<u8>>,
+    /// The scid of the first Initial packet of the sender.
+    pub initial_scid: Option<Vec<u8>>,
+    /// Idle timeout in ms, 0 for none.
+    pub idle_timeout: u64,
+    pub max_udp_payload: u64,
+    pub max_data: u64,
+    /// Limit on the streams opened by the sender of the parameters.
+    pub stream_data_bidi_local: u64,
+    /// Limit on the streams opened by the receiver of the parameters.
+    pub stream_data_bidi_remote: u64,
+    pub stream_data_uni: u64,
+    pub max_streams_bidi: u64,
+    pub max_streams_uni: u64,
+    pub ack_delay_exponent: u64,
+    /// Max ack delay in ms.
+    pub max_ack_delay: u64,
+    pub disable_migration: bool,
+    pub cid_limit: u64,
+}
+
+impl Default for Params {
+    /// Returns the values parameters take when they arent sent.
+    fn default() -> Self {
+        Self {
+            original_dcid: None,
+            initial_scid: None,
+            idle_timeout: 0,
+            max_udp_payload: 65527,
+            max_data: 0,
+            stream_data_bidi_local: 0,
+            stream_data_bidi_remote: 0,
+            stream_data_uni: 0,
+            max_streams_bidi: 0,
+            max_streams_uni: 0,
+            ack_delay_exponent: 3,
+            max_ack_delay: 25,
+            disable_migration: false,
+            cid_limit: 2,
+        }
+    }
+}
+
+impl Params {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut put = |id: u64, value: &[u8]| {
+            put_varint(&mut buf, id);
+            put_varint(&mut buf, value.len() as u64);
+            buf.extend_from_slice(value);
+        };
+
+        let varint = |x: u64| {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, x);
+            buf
+        };
+
+        if let Some(x) = &self.original_dcid {
+            put(ORIGINAL_DCID, x);
+        }
+        if let Some(x) = &self.initial_scid {
+            put(INITIAL_SCID, x);
+        }
+
+        put(IDLE_TIMEOUT, &varint(self.idle_timeout));
+        put(MAX_UDP_PAYLOAD, &varint(self.max_udp_payload));
+        put(MAX_DATA, &varint(self.max_data));
+        put(STREAM_DATA_BIDI_LOCAL, &varint(self.stream_data_bidi_local));
+        put(
+            STREAM_DATA_BIDI_REMOTE,
+            &varint(self.stream_data_bidi_remote),
+        );
+        put(STREAM_DATA_UNI, &varint(self.stream_data_uni));
+        put(MAX_STREAMS_BIDI, &varint(self.max_streams_bidi));
+        put(MAX_STREAMS_UNI, &varint(self.max_streams_uni));
+        put(ACK_DELAY_EXPONENT, &varint(self.ack_delay_exponent));
+        put(MAX_ACK_DELAY, &varint(self.max_ack_delay));
+        put(CID_LIMIT, &varint(self.cid_limit));
+
+        if self.disable_migration {
+            put(DISABLE_MIGRATION, &[]);
+        }
+
+        buf
+    }
+
+    /// Parses the parameters our peer sent, `server` is whether it is a server.
+    pub fn decode(buf: &[u8], server: bool) -> Option<Self> {
+        let mut params = Self::default();
+        let mut r = Reader::new(buf);
+        let mut seen = Vec::new();
+
+        while !r.is_empty() {
+            let id = r.varint()?;
+            let len = r.varint()? as usize;
+            let value = r.bytes(len)?;
+
+            // every parameter can only be sent once (RFC 9000 7.4).
+            if seen.contains(&id) {
+                return None;
+            }
+            seen.push(id);
+
+            let mut v = Reader::new(value);
+            let mut varint = || {
+                let x = v.varint()?;
+                if v.is_empty() {
+                    Some(x)
+                } else {
+                    None
+                }
+            };
+
+            match id {
+                ORIGINAL_DCID | STATELESS_RESET_TOKEN | PREFERRED_ADDRESS | RETRY_SCID
+                    if !server =>
+                {
+                    return None
+                }
+                ORIGINAL_DCID if len <= MAX_CID_LEN => params.original_dcid = Some(value.to_vec()),
+                INITIAL_SCID if len <= MAX_CID_LEN => params.initial_scid = Some(value.to_vec()),
+                ORIGINAL_DCID | INITIAL_SCID => return None,
+                IDLE_TIMEOUT => params.idle_timeout = varint()?,
+                MAX_UDP_PAYLOAD => params.max_udp_payload = varint()?,
+                MAX_DATA => params.max_data = varint()?,
+                STREAM_DATA_BIDI_LOCAL => params.stream_data_bidi_local = varint()?,
+                STREAM_DATA_BIDI_REMOTE => params.stream_data_bidi_remote = varint()?,
+                STREAM_DATA_UNI => params.stream_data_uni = varint()?,
+                MAX_STREAMS_BIDI => params.max_streams_bidi = varint()?,
+                MAX_STREAMS_UNI => params.max_streams_uni = varint()?,
+                ACK_DELAY_EXPONENT => params.ack_delay_exponent = varint()?,
+                MAX_ACK_DELAY => params.max_ack_delay = varint()?,
+                CID_LIMIT => params.cid_limit = varint()?,
+                DISABLE_MIGRATION if len == 0 => params.disable_migration = true,
+                DISABLE_MIGRATION => return None,
+                // parameters we dont know, or dont act on, are ignored.
+                _ => {}
+            }
+        }
+
+        // values that are out of range are a error as well (RFC 9000 18.2).
+        let valid = params.max_udp_payload >= 1200
+            && params.ack_delay_exponent <= 20
+            && params.max_ack_delay < 1 << 14
+            && params.cid_limit >= 2
+            && params.max_streams_bidi <= 1 << 60
+            && params.max_streams_uni <= 1 << 60;
+
+        if !valid || params.initial_scid.is_none() {
+            return None;
+        }
+
+        Some(params)
+    }
+}
@@ -0,0 +1,344 @@
+//! Loss detection and congestion control (RFC 9002).
+//!
+//! Times are in ms, like the rest of our timers. Congestion control is NewReno, without ecn
+//! and without persistent congestion.
+
+use super::frame::Frame;
+use super::packet::MAX_DATAGRAM_LEN;
+
+use crate::collections::BTreeMap;
+use crate::prelude::*;
+
+/// Packets this far below the largest acknowledged one are lost (RFC 9002 6.1.1).
+const PACKET_THRESHOLD: u64 = 3;
+const INITIAL_RTT: u64 = 333;
+const GRANULARITY: u64 = 1;
+const INITIAL_WINDOW: usize = 10 * MAX_DATAGRAM_LEN;
+const MINIMUM_WINDOW: usize = 2 * MAX_DATAGRAM_LEN;
+
+pub struct SentPacket {
+    pub time: u64,
+    pub size: usize,
+    /// Frames that have to be sent again if the packet is lost.
+    pub frames: Vec<Frame>,
+}
+
+/// Round trip time estimates (RFC 9002 5).
+pub struct Rtt {
+    pub latest: u64,
+    pub smoothed: u64,
+    pub var: u64,
+    pub min: u64,
+    has_sample: bool,
+}
+
+impl Rtt {
+    fn new() -> Self {
+        Self {
+            latest: 0,
+            smoothed: INITIAL_RTT,
+            var: INITIAL_RTT / 2,
+            min: 0,
+            has_sample: false,
+        }
+    }
+
+    fn update(&mut self, sample: u64, ack_delay: u64, confirmed: bool) {
+        self.latest = sample;
+
+        if !self.has_sample {
+            self.has_sample = true;
+            self.min = sample;
+            self.smoothed = sample;
+            self.var = sample / 2;
+            return;
+        }
+
+        self.min = self.min.min(sample);
+
+        // the ack delay of our peer only counts once the handshake is confirmed, and never
+        // below the smallest rtt we saw.
+        let ack_delay = if confirmed { ack_delay } else { 0 };
+        let adjusted = if sample >= self.min + ack_delay {
+            sample - ack_delay
+        } else {
+            sample
+        };
+
+        let diff = if self.smoothed > adjusted {
+            self.smoothed - adjusted
+        } else {
+            adjusted - self.smoothed
+        };
+
+        self.var = (3 * self.var + diff) / 4;
+        self.smoothed = (7 * self.smoothed + adjusted) / 8;
+    }
+
+    /// Returns the probe timeout without the max ack delay of our peer and without backoff.
+    pub fn pto(&self) -> u64 {
+        self.smoothed + (4 * self.var).max(GRANULARITY)
+    }
+
+    fn loss_delay(&self) -> u64 {
+        (9 * self.latest.max(self.smoothed) / 8).max(GRANULARITY)
+    }
+}
+
+/// NewReno congestion control (RFC 9002 7).
+pub struct Congestion {
+    pub window: usize,
+    ssthresh: usize,
+    pub in_flight: usize,
+    /// Time the current recovery period started at.
+    recovery_start: Option<u64>,
+}
+
+impl Congestion {
+    fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            ssthresh: usize::MAX,
+            in_flight: 0,
+            recovery_start: None,
+        }
+    }
+
+    /// Returns whether a packet of `size` bytes fits in the window.
+    pub fn can_send(&self, size: usize) -> bool {
+        self.in_flight + size <= self.window
+    }
+
+    fn in_recovery(&self, sent: u64) -> bool {
+        self.recovery_start.map_or(false, |x| sent <= x)
+    }
+
+    fn on_acked(&mut self, packet: &SentPacket) {
+        self.in_flight = self.in_flight.saturating_sub(packet.size);
+
+        if self.in_recovery(packet.time) {
+            return;
+        }
+
+        if self.window < self.ssthresh {
+            self.window += packet.size;
+        } else {
+            self.window += MAX_DATAGRAM_LEN * packet.size / self.window;
+        }
+    }
+
+    fn on_lost(&mut self, packets: &[SentPacket], now: u64) {
+        let mut latest = None;
+        for packet in packets {
+            self.in_flight = self.in_flight.saturating_sub(packet.size);
+            latest = latest.max(Some(packet.time));
+        }
+
+        match latest {
+            Some(x) if !self.in_recovery(x) => {
+                self.recovery_start = Some(now);
+                self.ssthresh = (self.window / 2).max(MINIMUM_WINDOW);
+                self.window = self.ssthresh;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What has to happen after a recovery timer fired.
+pub enum Timeout {
+    /// Packets of a space were declared lost, their frames have to be sent again.
+    Lost(usize, Vec<Frame>),
+    /// A probe has to be sent in a space, with the frames of its oldest packet in flight.
+    Probe(usize, Vec<Frame>),
+}
+
+#[derive(Default)]
+struct Space {
+    sent: BTreeMap<u64, SentPacket>,
+    largest_acked: Option<u64>,
+    /// Time the next packet of this space is declared lost at, by the time threshold.
+    loss_time: Option<u64>,
+    last_sent: Option<u64>,
+}
+
+/// The recovery state of a connection, the packet spaces are indexed the same way as in the
+/// connection.
+pub struct Recovery {
+    pub rtt: Rtt,
+    pub congestion: Congestion,
+    spaces: [Space; 3],
+    /// How many probe timeouts fired in a row.
+    pto_count: u32,
+}
+
+impl Recovery {
+    pub fn new() -> Self {
+        Self {
+            rtt: Rtt::new(),
+            congestion: Congestion::new(),
+            spaces: Default::default(),
+            pto_count: 0,
+        }
+    }
+
+    /// Function records a ack eliciting packet we sent. Packets that only carry acks dont
+    /// count against the congestion window and arent tracked.
+    pub fn on_sent(&mut self, space: usize, pn: u64, packet: SentPacket) {
+        self.congestion.in_flight += packet.size;
+        self.spaces[space].last_sent = Some(packet.time);
+        self.spaces[space].sent.insert(pn, packet);
+    }
+
+    /// Function handles a ack of our peer, returns the frames of the packets it made us
+    /// declare lost. `ack_delay` is in ms.
+    pub fn on_ack(
+        &mut self,
+        space: usize,
+        ranges: &[(u64, u64)],
+        ack_delay: u64,
+        now: u64,
+        confirmed: bool,
+    ) -> Vec<Frame> {
+        let largest = ranges[0].1;
+        let state = &mut self.spaces[space];
+        state.largest_acked = state.largest_acked.max(Some(largest));
+
+        let mut acked = Vec::new();
+        for (smallest, largest) in ranges.iter() {
+            let pns: Vec<u64> = state
+                .sent
+                .range(*smallest..=*largest)
+                .map(|x| *x.0)
+                .collect();
+            for pn in pns {
+                if let Some(packet) = state.sent.remove(&pn) {
+                    acked.push((pn, packet));
+                }
+            }
+        }
+
+        if acked.is_empty() {
+            return Vec::new();
+        }
+
+        // only the largest packet of a ack gives a rtt sample (RFC 9002 5.1).
+        if let Some((_, packet)) = acked.iter().find(|x| x.0 == largest) {
+            let sample = now.saturating_sub(packet.time);
+            self.rtt.update(sample, ack_delay, confirmed);
+        }
+
+        for (_, packet) in acked.iter() {
+            self.congestion.on_acked(packet);
+        }
+
+        self.pto_count = 0;
+        self.detect_lost(space, now)
+    }
+
+    /// Function declares packets of `space` lost by the packet and time thresholds, returns
+    /// their frames.
+    fn detect_lost(&mut self, space: usize, now: u64) -> Vec<Frame> {
+        let delay = self.rtt.loss_delay();
+        let state = &mut self.spaces[space];
+        let largest = match state.largest_acked {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        state.loss_time = None;
+        let mut lost = Vec::new();
+
+        for (pn, packet) in state.sent.range(..=largest) {
+            if packet.time + delay <= now || *pn + PACKET_THRESHOLD <= largest {
+                lost.push(*pn);
+            } else {
+                let time = packet.time + delay;
+                state.loss_time = Some(state.loss_time.map_or(time, |x| x.min(time)));
+            }
+        }
+
+        let lost: Vec<SentPacket> = lost
+            .into_iter()
+            .filter_map(|x| state.sent.remove(&x))
+            .collect();
+
+        self.congestion.on_lost(&lost, now);
+        lost.into_iter().flat_map(|x| x.frames).collect()
+    }
+
+    /// Returns the time the next recovery timer fires at, either to declare packets lost or to
+    /// send a probe. `max_ack_delay` only applies to the application space, which doesnt get a
+    /// probe timer until the handshake is confirmed.
+    pub fn timeout(&self, max_ack_delay: u64, confirmed: bool) -> Option<(u64, usize)> {
+        let loss = (0..3)
+            .filter_map(|x| self.spaces[x].loss_time.map(|y| (y, x)))
+            .min();
+
+        if loss.is_some() {
+            return loss;
+        }
+
+        let backoff = 1 << self.pto_count.min(16);
+        (0..3)
+            .filter(|x| !self.spaces[*x].sent.is_empty())
+            .filter(|x| *x != 2 || confirmed)
+            .filter_map(|x| {
+                let mut pto = self.rtt.pto();
+                if x == 2 {
+                    pto += max_ack_delay;
+                }
+
+                self.spaces[x].last_sent.map(|y| (y + pto * backoff, x))
+            })
+            .min()
+    }
+
+    /// Function handles a recovery timer that fired.
+    pub fn on_timeout(&mut self, now: u64, max_ack_delay: u64, confirmed: bool) -> Option<Timeout> {
+        let (time, space) = self.timeout(max_ack_delay, confirmed)?;
+        if time > now {
+            return None;
+        }
+
+        if self.spaces[space].loss_time.is_some() {
+            return Some(Timeout::Lost(space, self.detect_lost(space, now)));
+        }
+
+        self.pto_count += 1;
+        let frames = self.spaces[space]
+            .sent
+            .values()
+            .next()
+            .map(|x| x.frames.clone())
+            .unwrap_or_default();
+
+        // the probe is sent right away, so the timer starts over from it.
+        self.spaces[space].last_sent = Some(now);
+        Some(Timeout::Probe(space, frames))
+    }
+
+    /// Function forgets the packets of a space whose keys were discarded.
+    pub fn discard(&mut self, space: usize) {
+        let state = core::mem::take(&mut self.spaces[space]);
+        for packet in state.sent.values() {
+            self.congestion.in_flight = self.congestion.in_flight.saturating_sub(packet.size);
+        }
+
+        self.pto_count = 0;
+    }
+
+    /// Function starts over the rtt estimate and congestion window after our peer moved to a
+    /// new path (RFC 9000 9.4).
+    pub fn reset_path(&mut self) {
+        let in_flight = self.congestion.in_flight;
+        self.rtt = Rtt::new();
+        self.congestion = Congestion::new();
+        self.congestion.in_flight = in_flight;
+    }
+
+    /// Returns the probe timeout including backoff, for the timers that are a multiple of it.
+    pub fn pto(&self, max_ack_delay: u64) -> u64 {
+        (self.rtt.pto() + max_ack_delay) << self.pto_count.min(16)
+    }
+}
@@ -0,0 +1,279 @@
+//! Send and receive buffers of streams, along with their flow control (RFC 9000 2-4).
+
+use crate::collections::BTreeMap;
+use crate::collections::VecDeque;
+use crate::prelude::*;
+
+/// Bytes a stream buffers that havent been sent yet, writes wait once it is full.
+pub const SEND_BUFFER: usize = 64 * 1024;
+
+/// Error codes of the transport (RFC 9000 20.1).
+pub const FLOW_CONTROL_ERROR: u64 = 0x3;
+pub const FINAL_SIZE_ERROR: u64 = 0x6;
+
+/// Puts data that arrives out of order, and maybe more than once, back together.
+#[derive(Default)]
+pub struct Reassembly {
+    chunks: BTreeMap<u64, Vec<u8>>,
+    /// Offset of the first byte we dont have yet.
+    offset: u64,
+}
+
+impl Reassembly {
+    /// Function stores `data` found at `offset`.
+    pub fn insert(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        if end <= self.offset {
+            return;
+        }
+
+        let skip = self.offset.saturating_sub(offset) as usize;
+        let offset = offset.max(self.offset);
+        let data = &data[skip..];
+
+        match self.chunks.get(&offset) {
+            Some(x) if x.len() >= data.len() => {}
+            _ => {
+                self.chunks.insert(offset, data.to_vec());
+            }
+        }
+    }
+
+    /// Returns the offset of the first byte we dont have yet.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the bytes that follow what was popped so far without a gap.
+    pub fn pop(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        loop {
+            let first = match self.chunks.keys().next() {
+                Some(x) if *x <= self.offset => *x,
+                _ => return out,
+            };
+
+            let chunk = self.chunks.remove(&first).unwrap_or_default();
+            let skip = (self.offset - first) as usize;
+
+            if skip < chunk.len() {
+                out.extend_from_slice(&chunk[skip..]);
+                self.offset += (chunk.len() - skip) as u64;
+            }
+        }
+    }
+}
+
+pub struct SendStream {
+    /// Bytes written that havent been sent yet.
+    pending: VecDeque<u8>,
+    /// Offset of the first pending byte.
+    offset: u64,
+    /// Limit of our peer on the offsets we send.
+    pub max_data: u64,
+    /// Whether the application finished the stream, and whether we sent the fin.
+    fin: bool,
+    fin_sent: bool,
+    /// Set once we reset the stream, or our peer asked us to stop with a code.
+    pub reset: Option<u64>,
+    pub stopped: Option<u64>,
+}
+
+impl SendStream {
+    pub fn new(max_data: u64) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            offset: 0,
+            max_data,
+            fin: false,
+            fin_sent: false,
+            reset: None,
+            stopped: None,
+        }
+    }
+
+    /// Function buffers as much of `data` as fits, returns how much that was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(SEND_BUFFER - self.pending.len());
+        self.pending.extend(data[..len].iter());
+        len
+    }
+
+    pub fn finish(&mut self) {
+        self.fin = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.fin
+    }
+
+    /// Returns the offset of the next byte to send, the final size if the stream is reset.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns whether everything was sent, or the stream was reset.
+    pub fn is_done(&self) -> bool {
+        self.reset.is_some() || self.fin_sent
+    }
+
+    /// Returns whether there is data, or a fin, that can be sent within the limit of our peer.
+    pub fn is_sendable(&self) -> bool {
+        if self.is_done() {
+            return false;
+        }
+
+        (!self.pending.is_empty() && self.offset < self.max_data)
+            || (self.fin && self.pending.is_empty())
+    }
+
+    /// Returns the next data to send, at most `len` bytes of it, along with its offset and
+    /// whether it ends the stream.
+    pub fn take(&mut self, len: usize) -> (u64, Vec<u8>, bool) {
+        let credit = self.max_data.saturating_sub(self.offset) as usize;
+        let len = len.min(credit).min(self.pending.len());
+        let offset = self.offset;
+
+        let data: Vec<u8> = self.pending.drain(..len).collect();
+        self.offset += len as u64;
+
+        let fin = self.fin && self.pending.is_empty();
+        self.fin_sent |= fin;
+        (offset, data, fin)
+    }
+
+    /// Returns how many bytes `take` can hand out right now, the fin aside.
+    pub fn available(&self) -> usize {
+        let credit = self.max_data.saturating_sub(self.offset) as usize;
+        credit.min(self.pending.len())
+    }
+}
+
+pub struct RecvStream {
+    data: Reassembly,
+    /// Bytes ready to be read.
+    readable: VecDeque<u8>,
+    /// Bytes the application read so far.
+    read: u64,
+    /// Largest offset we saw, what counts against flow control.
+    highest: u64,
+    pub final_size: Option<u64>,
+    /// Set once our peer reset the stream, with its code.
+    pub reset: Option<u64>,
+    /// Limit we gave our peer, and the size of the window we keep open.
+    max_data: u64,
+    window: u64,
+    /// Whether we asked our peer to stop sending.
+    pub stopped: bool,
+}
+
+impl RecvStream {
+    pub fn new(window: u64) -> Self {
+        Self {
+            data: Reassembly::default(),
+            readable: VecDeque::new(),
+            read: 0,
+            highest: 0,
+            final_size: None,
+            reset: None,
+            max_data: window,
+            window,
+            stopped: false,
+        }
+    }
+
+    /// Function stores data our peer sent, returns how many bytes it added to what counts
+    /// against the flow control of the connection. Errors are transport error codes.
+    pub fn insert(&mut self, offset: u64, data: &[u8], fin: bool) -> Result<u64, u64> {
+        let end = offset + data.len() as u64;
+
+        if end > self.max_data {
+            return Err(FLOW_CONTROL_ERROR);
+        }
+
+        if let Some(x) = self.final_size {
+            if end > x || (fin && end != x) {
+                return Err(FINAL_SIZE_ERROR);
+            }
+        }
+
+        if fin {
+            if end < self.highest {
+                return Err(FINAL_SIZE_ERROR);
+            }
+            self.final_size = Some(end);
+        }
+
+        let added = end.saturating_sub(self.highest);
+        self.highest = self.highest.max(end);
+
+        if self.reset.is_none() && !self.stopped {
+            self.data.insert(offset, data);
+            self.readable.extend(self.data.pop());
+        }
+
+        Ok(added)
+    }
+
+    /// Function handles a reset of our peer, returns how many bytes it added to what counts
+    /// against the flow control of the connection.
+    pub fn on_reset(&mut self, code: u64, final_size: u64) -> Result<u64, u64> {
+        if self.final_size.map_or(false, |x| x != final_size) || final_size < self.highest {
+            return Err(FINAL_SIZE_ERROR);
+        }
+
+        if final_size > self.max_data {
+            return Err(FLOW_CONTROL_ERROR);
+        }
+
+        let added = final_size - self.highest;
+        self.highest = final_size;
+        self.final_size = Some(final_size);
+
+        if self.reset.is_none() {
+            self.reset = Some(code);
+        }
+
+        Ok(added)
+    }
+
+    /// Function copies readable bytes into `buf`, returns how many.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.readable.len());
+        for (x, y) in buf.iter_mut().zip(self.readable.drain(..len)) {
+            *x = y;
+        }
+
+        self.read += len as u64;
+        len
+    }
+
+    /// Function gives up on the bytes that havent been read, after a reset or once we asked
+    /// our peer to stop. Returns how many bytes that was, they count as read for flow control.
+    pub fn discard(&mut self) -> u64 {
+        self.readable.clear();
+        let len = self.highest - self.read;
+        self.read = self.highest;
+        len
+    }
+
+    pub fn has_data(&self) -> bool {
+        !self.readable.is_empty()
+    }
+
+    /// Returns whether every byte up to the fin was read.
+    pub fn is_finished(&self) -> bool {
+        self.final_size == Some(self.read)
+    }
+
+    /// Returns a new limit for our peer once the application read half of the window.
+    pub fn window_update(&mut self) -> Option<u64> {
+        if self.final_size.is_some() || self.max_data - self.read > self.window / 2 {
+            return None;
+        }
+
+        self.max_data = self.read + self.window;
+        Some(self.max_data)
+    }
+}
@@ -0,0 +1,113 @@
+use super::wire::eth2::Ether2Frame;
+use super::wire::ipv4::Ipv4;
+
+use crate::collections::HashMap;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedReceiver;
+use crate::sync::mpsc::UnboundedSender;
+
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+use spin::RwLock;
+
+/// Sockets registered for a single ethertype or protocol, each has its own id so it can be
+/// removed again.
+type Sockets<T> = Vec<(usize, UnboundedSender<T>)>;
+
+/// Keeps track of raw sockets and hands them a copy of every frame or packet they asked for.
+pub struct RawLayer {
+    /// Raw ethernet sockets, keyed by ethertype.
+    ethernet: RwLock<HashMap<u16, Sockets<Ether2Frame>>>,
+    /// Raw ip sockets, keyed by protocol number.
+    ip: RwLock<HashMap<u8, Sockets<Ipv4>>>,
+    /// Id of the last socket we registered.
+    last_id: AtomicUsize,
+}
+
+impl RawLayer {
+    pub fn new() -> Self {
+        Self {
+            ethernet: RwLock::new(HashMap::new()),
+            ip: RwLock::new(HashMap::new()),
+            last_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Function registers a socket for frames of `ethertype`, returning its id along with the
+    /// queue the frames end up in.
+    pub fn register_ethernet(&self, ethertype: u16) -> (usize, UnboundedReceiver<Ether2Frame>) {
+        let (tx, rx) = channel();
+        let id = self.last_id.fetch_add(1, Relaxed);
+
+        self.ethernet
+            .write()
+            .entry(ethertype)
+            .or_default()
+            .push((id, tx));
+
+        (id, rx)
+    }
+
+    /// Function registers a socket for ip packets carrying `proto`, returning its id along with
+    /// the queue the packets end up in.
+    pub fn register_ip(&self, proto: u8) -> (usize, UnboundedReceiver<Ipv4>) {
+        let (tx, rx) = channel();
+        let id = self.last_id.fetch_add(1, Relaxed);
+
+        self.ip.write().entry(proto).or_default().push((id, tx));
+
+        (id, rx)
+    }
+
+    pub fn unregister_ethernet(&self, ethertype: u16, id: usize) {
+        Self::unregister(&mut self.ethernet.write(), ethertype, id);
+    }
+
+    pub fn unregister_ip(&self, proto: u8, id: usize) {
+        Self::unregister(&mut self.ip.write(), proto, id);
+    }
+
+    fn unregister<K: Eq + core::hash::Hash, T>(
+        map: &mut HashMap<K, Sockets<T>>,
+        key: K,
+        id: usize,
+    ) {
+        if let Some(sockets) = map.get_mut(&key) {
+            sockets.retain(|(x, _)| *x != id);
+
+            if sockets.is_empty() {
+                map.remove(&key);
+            }
+        }
+    }
+
+    /// Function hands a copy of `frame` to every socket registered for its ethertype. Returns
+    /// whether there was any.
+    pub fn deliver_ethernet(&self, frame: &Ether2Frame) -> bool {
+        match self.ethernet.read().get(&frame.raw_dtype()) {
+            Some(sockets) => {
+                for (_, socket) in sockets {
+                    let _ = socket.send(frame.clone());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Function hands a copy of `packet` to every socket registered for its protocol. Returns
+    /// whether there was any.
+    pub fn deliver_ip(&self, packet: &Ipv4) -> bool {
+        match self.ip.read().get(&packet.raw_proto()) {
+            Some(sockets) => {
+                for (_, socket) in sockets {
+                    let _ = socket.send(packet.clone());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
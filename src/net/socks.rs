@@ -1,5 +1,17 @@
 use super::StreamKey;
 use super::OPEN_PORTS;
+use super::addr::first_v4;
+use super::addr::SocketAddr;
+use super::addr::ToSocketAddrs;
+use super::ports::BindOptions;
+use super::ports::PortGuard;
+use super::udp::Datagram;
+use super::wire::buf::DEFAULT_TAILROOM;
+use super::wire::buf::TRANSPORT_HEADROOM;
+use super::wire::eth2::Ether2Frame;
+use super::wire::ipaddr::Ipv4Addr;
+use super::wire::ipv4::Ipv4;
+use super::wire::PacketBuf;
 use crate::prelude::*;
 use crate::sync::mpsc::*;
 use crate::sync::Arc;
@@ -10,24 +22,40 @@ use core::pin::Pin;
 use core::task::Context;
 use core::task::Poll;
 
+use futures_util::future::poll_fn;
+use futures_util::stream::Stream;
+
 pub struct TcpListener {
+    port: PortGuard,
     rx: UnboundedReceiver<StreamKey>,
 }
 
 impl TcpListener {
-    pub fn bind(port: u16) -> Result<Self, ()> {
+    /// Function starts listening on `addr`, which can be a `SocketAddr`, a `(ip, port)` tuple, a
+    /// string like `"10.0.0.1:80"` or a bare port, which listens on all of our addresses. Port
+    /// 0 picks a free ephemeral port.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, ()> {
+        Self::bind_with(addr, BindOptions::default())
+    }
+
+    /// Function starts listening on `addr` like `bind`, with `opts` deciding whether the port
+    /// can be shared.
+    pub fn bind_with<A: ToSocketAddrs>(addr: A, opts: BindOptions) -> Result<Self, ()> {
+        let port = super::TCP_PORTS.bind(first_v4(addr)?, opts)?;
         let (tx, rx) = channel();
-        {
-            let mut ports = OPEN_PORTS.write();
 
-            if ports.contains_key(&port) {
-                return Err(());
-            }
+        OPEN_PORTS
+            .write()
+            .entry(port.addr())
+            .or_default()
+            .push((port.id(), tx));
 
-            ports.insert(port, tx);
-        }
+        Ok(Self { port, rx })
+    }
 
-        Ok(Self { rx })
+    /// Returns the address this listener is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.port.addr().into()
     }
 
     pub async fn accept(&mut self) -> Option<TcpStream> {
@@ -35,11 +63,45 @@ impl TcpListener {
     }
 }
 
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut ports = OPEN_PORTS.write();
+        let addr = self.port.addr();
+
+        if let Some(listeners) = ports.get_mut(&addr) {
+            listeners.retain(|(x, _)| *x != self.port.id());
+
+            if listeners.is_empty() {
+                ports.remove(&addr);
+            }
+        }
+    }
+}
+
 pub struct TcpStream {
     pub(crate) raw: Arc<Mutex<super::TcpConnection>>,
 }
 
 impl TcpStream {
+    /// Function opens a connection to `addr`. Connections to `127.0.0.0/8` or to one of our
+    /// own addresses go over the loopback device, which has to be running.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ()> {
+        let addr = first_v4(addr)?;
+        let raw = super::TCP_LAYER.connect(*addr.ip(), addr.port()).await?;
+
+        Ok(Self { raw })
+    }
+
+    /// Returns the address of our end of the connection.
+    pub async fn local_addr(&self) -> SocketAddr {
+        self.raw.lock().await.local_addr().into()
+    }
+
+    /// Returns the address of the remote end of the connection.
+    pub async fn peer_addr(&self) -> SocketAddr {
+        self.raw.lock().await.peer_addr().into()
+    }
+
     pub async fn read(&mut self, buffer: &mut [u8]) -> usize {
         struct ReadFuture<'a> {
             inner: &'a TcpStream,
@@ -52,6 +114,10 @@ impl TcpStream {
             fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
                 match self.inner.raw.try_lock() {
                     Some(mut guard) => {
+                        if !guard.has_data() && guard.is_eof() {
+                            return Poll::Ready(0);
+                        }
+
                         if !guard.has_data() {
                             guard.register_waker(cx.waker().clone());
                             return Poll::Pending;
@@ -74,7 +140,267 @@ impl TcpStream {
         .await
     }
 
-    pub async fn write(&mut self, item: &[u8]) {
-        self.raw.lock().await.write(item).await;
+    /// Function sends `item` to our peer, waiting for room in its window when it has no more.
+    /// Whatever is left once the connection is closed for sending is dropped.
+    pub async fn write(&mut self, mut item: &[u8]) {
+        while !item.is_empty() {
+            let writable = poll_fn(|cx| match self.raw.try_lock() {
+                Some(mut guard) => guard.poll_writable(cx),
+                None => {
+                    self.raw.register_waker(cx);
+                    Poll::Pending
+                }
+            });
+
+            if !writable.await {
+                return;
+            }
+
+            let len = self.raw.lock().await.write(item).await;
+            item = &item[len..];
+        }
+    }
+
+    /// Function closes our side of the connection by sending a FIN, after which writes are
+    /// dropped. Reads keep returning what our peer sends until it closes its side too.
+    pub async fn shutdown(&mut self) {
+        close(&self.raw).await;
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // sending the FIN needs the lock, which we cant wait for in here. The connection lingers
+        // until our peer closes too, its port is released once it is gone.
+        let raw = self.raw.clone();
+        crate::async_::spawn(async move {
+            close(&raw).await;
+            raw.lock().await.orphan();
+        });
+    }
+}
+
+/// Function closes our side of `raw`, sending the FIN if there is one to send.
+async fn close(raw: &Mutex<super::TcpConnection>) {
+    let mut conn = raw.lock().await;
+    let quad = conn.quad();
+
+    if let Some(fin) = conn.close() {
+        super::TCP_LAYER.handle_tx(fin, quad.2, quad.0).await;
+    }
+}
+
+pub struct UdpSocket {
+    port: PortGuard,
+    rx: UnboundedReceiver<Datagram>,
+    /// Multicast groups this socket joined, left again once it is dropped.
+    groups: Vec<Ipv4Addr>,
+}
+
+impl UdpSocket {
+    /// Function binds a socket to `addr`, binding port 0 picks a free ephemeral port and a bare
+    /// port binds to all of our addresses.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, ()> {
+        Self::bind_with(addr, BindOptions::default())
+    }
+
+    /// Function binds a socket to `addr` like `bind`, with `opts` deciding whether the port can
+    /// be shared.
+    pub fn bind_with<A: ToSocketAddrs>(addr: A, opts: BindOptions) -> Result<Self, ()> {
+        let (port, rx) = super::UDP_LAYER.bind(first_v4(addr)?, opts)?;
+
+        Ok(Self {
+            port,
+            rx,
+            groups: Vec::new(),
+        })
+    }
+
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.port.addr().into()
+    }
+
+    /// Function sends `buf` to `addr`, returning the number of bytes sent.
+    pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize, ()> {
+        let addr = first_v4(addr)?;
+        let local = self.port.addr();
+        let dip = *addr.ip();
+
+        // sockets bound to a address send from it, otherwise datagrams to our own addresses go
+        // out from them and everything else from our main ip.
+        let sip = if !local.ip().is_unspecified() {
+            *local.ip()
+        } else if super::ARP_LAYER.is_local(dip).await {
+            dip
+        } else {
+            super::ARP_LAYER.local_ip().await.ok_or(())?
+        };
+
+        super::UDP_LAYER
+            .handle_tx(buf, local.port(), sip, addr.port(), dip)
+            .await;
+
+        Ok(buf.len())
+    }
+
+    /// Function waits for a datagram and copies it into `buf`, returning the number of bytes
+    /// copied along with the address it came from. Bytes that dont fit in `buf` are discarded.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), ()> {
+        let datagram = self.rx.recv().await.ok_or(())?;
+
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+
+        Ok((len, datagram.src.into()))
+    }
+
+    /// Function joins the multicast group `multiaddr` on the interface with the local ip
+    /// `interface`, a unspecified `interface` picks one of our ips.
+    pub async fn join_multicast_v4(&mut self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<(), ()> {
+        if self.groups.contains(&multiaddr) {
+            return Err(());
+        }
+
+        super::IGMP_LAYER.join(multiaddr, interface).await?;
+        self.groups.push(multiaddr);
+
+        Ok(())
+    }
+
+    /// Function leaves the multicast group `multiaddr`, joined earlier with `join_multicast_v4`.
+    pub async fn leave_multicast_v4(&mut self, multiaddr: Ipv4Addr, _interface: Ipv4Addr) -> Result<(), ()> {
+        if !self.groups.contains(&multiaddr) {
+            return Err(());
+        }
+
+        self.groups.retain(|x| *x != multiaddr);
+        super::IGMP_LAYER.leave(multiaddr).await;
+
+        Ok(())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        super::UDP_LAYER.unbind(&self.port);
+
+        // leaving a group may have to send a message, which we cant wait for in here.
+        let groups = core::mem::take(&mut self.groups);
+        if !groups.is_empty() {
+            crate::async_::spawn(async move {
+                for group in groups {
+                    super::IGMP_LAYER.leave(group).await;
+                }
+            });
+        }
+    }
+}
+
+/// A socket receiving every ethernet frame of a single ethertype, frames are handed out as a
+/// stream. Frames of types the stack knows about still go through the stack as well.
+pub struct RawSocket {
+    ethertype: u16,
+    id: usize,
+    rx: UnboundedReceiver<Ether2Frame>,
+}
+
+impl RawSocket {
+    /// Function registers a socket for frames of `ethertype`, several sockets can share one.
+    pub fn bind(ethertype: u16) -> Self {
+        let (id, rx) = super::RAW_LAYER.register_ethernet(ethertype);
+
+        Self { ethertype, id, rx }
+    }
+
+    /// Returns the ethertype this socket is registered for.
+    pub fn ethertype(&self) -> u16 {
+        self.ethertype
+    }
+
+    /// Function waits for the next frame, returns `None` once the stack is gone.
+    pub async fn recv(&mut self) -> Option<Ether2Frame> {
+        self.rx.recv().await
+    }
+
+    /// Function sends `frame` out of the device whose mac is its source, the ethertype of the
+    /// frame is set to the one of this socket.
+    pub async fn send(&self, mut frame: Ether2Frame) {
+        frame.set_raw_dtype(self.ethertype);
+        super::ETHERNET_LAYER.handle_tx(frame).await;
+    }
+}
+
+impl Stream for RawSocket {
+    type Item = Ether2Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        super::RAW_LAYER.unregister_ethernet(self.ethertype, self.id);
+    }
+}
+
+/// A socket receiving every ip packet carrying a single protocol, packets are handed out as a
+/// stream with their ip header. Packets of protocols the stack knows about still go through
+/// the stack as well.
+pub struct RawIpSocket {
+    proto: u8,
+    id: usize,
+    rx: UnboundedReceiver<Ipv4>,
+}
+
+impl RawIpSocket {
+    /// Function registers a socket for packets carrying `proto`, several sockets can share one.
+    pub fn bind(proto: u8) -> Self {
+        let (id, rx) = super::RAW_LAYER.register_ip(proto);
+
+        Self { proto, id, rx }
+    }
+
+    /// Returns the protocol number this socket is registered for.
+    pub fn proto(&self) -> u8 {
+        self.proto
+    }
+
+    /// Function waits for the next packet, returns `None` once the stack is gone.
+    pub async fn recv(&mut self) -> Option<Ipv4> {
+        self.rx.recv().await
+    }
+
+    /// Function sends `data` to `addr`, the ip header is filled in for us.
+    pub async fn send_to(&self, data: &[u8], addr: Ipv4Addr) -> Result<usize, ()> {
+        let sip = if super::ARP_LAYER.is_local(addr).await {
+            addr
+        } else {
+            super::ARP_LAYER.local_ip().await.ok_or(())?
+        };
+
+        let mut packet = PacketBuf::new(TRANSPORT_HEADROOM, data.len(), DEFAULT_TAILROOM);
+        packet.copy_from_slice(data);
+
+        super::IP_LAYER
+            .handle_tx_raw(packet, self.proto, addr, sip)
+            .await;
+
+        Ok(data.len())
+    }
+}
+
+impl Stream for RawIpSocket {
+    type Item = Ipv4;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for RawIpSocket {
+    fn drop(&mut self) {
+        super::RAW_LAYER.unregister_ip(self.proto, self.id);
     }
 }
@@ -0,0 +1,343 @@
+//! Per-layer counters of our network stack.
+//!
+//! Every layer bumps its counters as packets go through it, `snapshot` returns a copy of all of
+//! them at once for monitoring. Counters are plain relaxed atomics, so a snapshot taken while
+//! packets are flowing isnt guaranteed to be consistent across layers.
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+
+/// Why a packet was dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DropReason {
+    /// Packet was too short or otherwise couldnt be parsed.
+    Malformed,
+    /// Packet is addressed to a ip or mac that isnt ours.
+    NotForUs,
+    /// Packet carries a protocol or ethertype we dont handle.
+    Unsupported,
+    /// Packet failed its checksum.
+    Checksum,
+    /// Packet is a fragment we couldnt reassemble, we ran out of room or the rest never came.
+    Fragment,
+    /// Outgoing packet couldnt be sent because its destination didnt resolve.
+    Unresolved,
+    /// No socket or connection wanted the packet.
+    NoSocket,
+    /// Packet was dropped or rejected by the firewall.
+    Filtered,
+    /// Outgoing packet doesnt fit the mtu of the link or path it has to go over.
+    TooBig,
+}
+
+/// Counters shared by every layer.
+pub(crate) struct LayerCounters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    malformed: AtomicU64,
+    not_for_us: AtomicU64,
+    unsupported: AtomicU64,
+    checksum: AtomicU64,
+    fragment: AtomicU64,
+    unresolved: AtomicU64,
+    no_socket: AtomicU64,
+    filtered: AtomicU64,
+    too_big: AtomicU64,
+}
+
+impl LayerCounters {
+    pub const fn new() -> Self {
+        Self {
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            not_for_us: AtomicU64::new(0),
+            unsupported: AtomicU64::new(0),
+            checksum: AtomicU64::new(0),
+            fragment: AtomicU64::new(0),
+            unresolved: AtomicU64::new(0),
+            no_socket: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            too_big: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a received packet of `len` bytes.
+    pub fn rx(&self, len: usize) {
+        self.rx_packets.fetch_add(1, Relaxed);
+        self.rx_bytes.fetch_add(len as u64, Relaxed);
+    }
+
+    /// Records a sent packet of `len` bytes.
+    pub fn tx(&self, len: usize) {
+        self.tx_packets.fetch_add(1, Relaxed);
+        self.tx_bytes.fetch_add(len as u64, Relaxed);
+    }
+
+    /// Records a dropped packet.
+    pub fn dropped(&self, reason: DropReason) {
+        self.counter(reason).fetch_add(1, Relaxed);
+    }
+
+    fn counter(&self, reason: DropReason) -> &AtomicU64 {
+        match reason {
+            DropReason::Malformed => &self.malformed,
+            DropReason::NotForUs => &self.not_for_us,
+            DropReason::Unsupported => &self.unsupported,
+            DropReason::Checksum => &self.checksum,
+            DropReason::Fragment => &self.fragment,
+            DropReason::Unresolved => &self.unresolved,
+            DropReason::NoSocket => &self.no_socket,
+            DropReason::Filtered => &self.filtered,
+            DropReason::TooBig => &self.too_big,
+        }
+    }
+
+    pub fn snapshot(&self) -> LayerStats {
+        LayerStats {
+            rx_packets: self.rx_packets.load(Relaxed),
+            rx_bytes: self.rx_bytes.load(Relaxed),
+            tx_packets: self.tx_packets.load(Relaxed),
+            tx_bytes: self.tx_bytes.load(Relaxed),
+            drops: DropStats {
+                malformed: self.malformed.load(Relaxed),
+                not_for_us: self.not_for_us.load(Relaxed),
+                unsupported: self.unsupported.load(Relaxed),
+                checksum: self.checksum.load(Relaxed),
+                fragment: self.fragment.load(Relaxed),
+                unresolved: self.unresolved.load(Relaxed),
+                no_socket: self.no_socket.load(Relaxed),
+                filtered: self.filtered.load(Relaxed),
+                too_big: self.too_big.load(Relaxed),
+            },
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.rx_packets,
+            &self.rx_bytes,
+            &self.tx_packets,
+            &self.tx_bytes,
+            &self.malformed,
+            &self.not_for_us,
+            &self.unsupported,
+            &self.checksum,
+            &self.fragment,
+            &self.unresolved,
+            &self.no_socket,
+            &self.filtered,
+            &self.too_big,
+        ]
+        .iter()
+        {
+            counter.store(0, Relaxed);
+        }
+    }
+}
+
+/// Counters only the arp layer has.
+pub(crate) struct ArpCounters {
+    /// Outgoing packets whose destination wasnt in the cache yet.
+    misses: AtomicU64,
+    /// Entries that gave up resolving.
+    failures: AtomicU64,
+}
+
+impl ArpCounters {
+    pub const fn new() -> Self {
+        Self {
+            misses: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Relaxed);
+    }
+
+    pub fn failure(&self) {
+        self.failures.fetch_add(1, Relaxed);
+    }
+}
+
+/// Counters only the tcp layer has.
+pub(crate) struct TcpCounters {
+    retransmits: AtomicU64,
+    resets_sent: AtomicU64,
+    resets_received: AtomicU64,
+}
+
+impl TcpCounters {
+    pub const fn new() -> Self {
+        Self {
+            retransmits: AtomicU64::new(0),
+            resets_sent: AtomicU64::new(0),
+            resets_received: AtomicU64::new(0),
+        }
+    }
+
+    pub fn retransmit(&self) {
+        self.retransmits.fetch_add(1, Relaxed);
+    }
+
+    pub fn reset_sent(&self) {
+        self.resets_sent.fetch_add(1, Relaxed);
+    }
+
+    pub fn reset_received(&self) {
+        self.resets_received.fetch_add(1, Relaxed);
+    }
+}
+
+/// Counters of the connection tracking table of the firewall.
+pub(crate) struct ConntrackCounters {
+    /// Flows pushed out of a full table.
+    evictions: AtomicU64,
+}
+
+impl ConntrackCounters {
+    pub const fn new() -> Self {
+        Self {
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn evict(&self) {
+        self.evictions.fetch_add(1, Relaxed);
+    }
+}
+
+pub(crate) static ETHERNET: LayerCounters = LayerCounters::new();
+pub(crate) static ARP: LayerCounters = LayerCounters::new();
+pub(crate) static ARP_CACHE: ArpCounters = ArpCounters::new();
+pub(crate) static IP: LayerCounters = LayerCounters::new();
+pub(crate) static ICMP: LayerCounters = LayerCounters::new();
+pub(crate) static TCP: LayerCounters = LayerCounters::new();
+pub(crate) static TCP_CONN: TcpCounters = TcpCounters::new();
+pub(crate) static UDP: LayerCounters = LayerCounters::new();
+pub(crate) static IGMP: LayerCounters = LayerCounters::new();
+pub(crate) static CONNTRACK: ConntrackCounters = ConntrackCounters::new();
+
+/// Dropped packets of a layer, by reason.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DropStats {
+    pub malformed: u64,
+    pub not_for_us: u64,
+    pub unsupported: u64,
+    pub checksum: u64,
+    pub fragment: u64,
+    pub unresolved: u64,
+    pub no_socket: u64,
+    pub filtered: u64,
+    pub too_big: u64,
+}
+
+impl DropStats {
+    /// Returns the number of dropped packets regardless of the reason.
+    pub fn total(&self) -> u64 {
+        self.malformed
+            + self.not_for_us
+            + self.unsupported
+            + self.checksum
+            + self.fragment
+            + self.unresolved
+            + self.no_socket
+            + self.filtered
+            + self.too_big
+    }
+}
+
+/// Snapshot of the counters of a single layer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LayerStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub drops: DropStats,
+}
+
+/// Snapshot of the arp layer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ArpStats {
+    pub layer: LayerStats,
+    pub misses: u64,
+    pub failures: u64,
+}
+
+/// Snapshot of the tcp layer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TcpStats {
+    pub layer: LayerStats,
+    pub retransmits: u64,
+    pub resets_sent: u64,
+    pub resets_received: u64,
+}
+
+/// Snapshot of the connection tracking table.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConntrackStats {
+    pub evictions: u64,
+}
+
+/// Snapshot of the whole stack.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NetStats {
+    pub ethernet: LayerStats,
+    pub arp: ArpStats,
+    pub ip: LayerStats,
+    pub icmp: LayerStats,
+    pub tcp: TcpStats,
+    pub udp: LayerStats,
+    pub igmp: LayerStats,
+    pub conntrack: ConntrackStats,
+}
+
+/// Returns the current value of every counter.
+pub fn snapshot() -> NetStats {
+    NetStats {
+        ethernet: ETHERNET.snapshot(),
+        arp: ArpStats {
+            layer: ARP.snapshot(),
+            misses: ARP_CACHE.misses.load(Relaxed),
+            failures: ARP_CACHE.failures.load(Relaxed),
+        },
+        ip: IP.snapshot(),
+        icmp: ICMP.snapshot(),
+        tcp: TcpStats {
+            layer: TCP.snapshot(),
+            retransmits: TCP_CONN.retransmits.load(Relaxed),
+            resets_sent: TCP_CONN.resets_sent.load(Relaxed),
+            resets_received: TCP_CONN.resets_received.load(Relaxed),
+        },
+        udp: UDP.snapshot(),
+        igmp: IGMP.snapshot(),
+        conntrack: ConntrackStats {
+            evictions: CONNTRACK.evictions.load(Relaxed),
+        },
+    }
+}
+
+/// Resets every counter back to zero.
+pub fn reset() {
+    for layer in [&ETHERNET, &ARP, &IP, &ICMP, &TCP, &UDP, &IGMP].iter() {
+        layer.reset();
+    }
+
+    for counter in [
+        &ARP_CACHE.misses,
+        &ARP_CACHE.failures,
+        &TCP_CONN.retransmits,
+        &TCP_CONN.resets_sent,
+        &TCP_CONN.resets_received,
+        &CONNTRACK.evictions,
+    ]
+    .iter()
+    {
+        counter.store(0, Relaxed);
+    }
+}
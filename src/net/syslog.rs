@@ -0,0 +1,551 @@
+//! Syslog (RFC 5424) sink.
+//!
+//! Records are formatted when they are logged and queued, a task ships them to the collector
+//! over udp (RFC 5426) or over tcp with octet counting (RFC 6587). While the collector cant be
+//! reached records stay queued, those that dont fit anymore are counted as dropped and written
+//! to the serial port instead. Every record carries a `meta` element with a sequence id, which
+//! lets the collector spot gaps, and once the collector is back it is told how many records it
+//! missed.
+//!
+//! ```ignore
+//! let log = Syslog::new("10.0.0.1:514", Config::new("sensor").transport(Transport::Tcp))?;
+//! log.log(Severity::Info, format_args!("booted"));
+//! log.record(Severity::Warning)
+//!     .msg_id("TEMP")
+//!     .param("temp@32473", "celsius", "81")
+//!     .send(format_args!("running hot"));
+//!
+//! // `syslog!` goes through the sink installed here, or to serial without one.
+//! syslog::init(log);
+//! syslog!(Severity::Error, "disk {} failed", 1);
+//! ```
+use crate::arch::pit::get_milis;
+use crate::async_;
+use crate::async_::Sleep;
+use crate::collections::VecDeque;
+use crate::net::addr::SocketAddr;
+use crate::net::addr::ToSocketAddrs;
+use crate::net::socks::TcpStream;
+use crate::net::socks::UdpSocket;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::fmt;
+use core::fmt::Write;
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+/// Longest wait between attempts to reach the collector, the delay doubles up to it.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Biggest datagram we send, bigger records are cut so they dont need fragmenting.
+const MAX_UDP_LEN: usize = 1472;
+const BOM: &str = "\u{feff}";
+
+/// The sink installed with `init`, which `syslog!` logs through.
+static SINK: Mutex<Option<Syslog>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let x = match self {
+            Self::Emergency => "emerg",
+            Self::Alert => "alert",
+            Self::Critical => "crit",
+            Self::Error => "err",
+            Self::Warning => "warning",
+            Self::Notice => "notice",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        };
+
+        write!(f, "{}", x)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Facility {
+    Kernel = 0,
+    User = 1,
+    Daemon = 3,
+    Auth = 4,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// Records go out as datagrams, which the collector might never get.
+    Udp,
+    /// Records go out over a connection, which is opened again when it drops.
+    Tcp,
+}
+
+/// Settings of a sink.
+#[derive(Clone, Debug)]
+pub struct Config {
+    app_name: String,
+    hostname: Option<String>,
+    facility: Facility,
+    transport: Transport,
+    level: Severity,
+    capacity: usize,
+    fallback: bool,
+}
+
+impl Config {
+    /// Returns settings for a sink sending `user` records of every severity over udp, queueing
+    /// up to 1024 records and falling back to serial.
+    pub fn new(app_name: &str) -> Self {
+        Self {
+            app_name: app_name.into(),
+            hostname: None,
+            facility: Facility::User,
+            transport: Transport::Udp,
+            level: Severity::Debug,
+            capacity: 1024,
+            fallback: true,
+        }
+    }
+
+    /// Function sets the hostname records carry, without one they carry our ip.
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    pub fn facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Function sets the least severe records we keep, the rest is discarded right away.
+    pub fn level(mut self, level: Severity) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Function sets how many records are queued while the collector cant be reached.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Function sets whether records we have to drop are written to the serial port.
+    pub fn fallback(mut self, fallback: bool) -> Self {
+        self.fallback = fallback;
+        self
+    }
+}
+
+/// Counters of a sink.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Records handed to the network.
+    pub sent: u64,
+    /// Records that never reached the network because the queue was full.
+    pub dropped: u64,
+    /// Dropped records that went to the serial port instead.
+    pub fallback: u64,
+    /// Records waiting in the queue right now.
+    pub queued: usize,
+    /// Whether the collector was reachable the last time we tried.
+    pub connected: bool,
+}
+
+struct State {
+    queue: VecDeque<Vec<u8>>,
+    stats: Stats,
+    /// Records dropped since the collector was last told about drops.
+    missed: u64,
+    sequence: u32,
+    hostname: String,
+}
+
+struct Inner {
+    config: Config,
+    state: Mutex<State>,
+    waker: AtomicWaker,
+}
+
+/// A syslog sink, clones share the queue. Its task sends what is left in the queue and exits
+/// once every clone is dropped.
+#[derive(Clone)]
+pub struct Syslog {
+    inner: Arc<Inner>,
+}
+
+impl Syslog {
+    /// Function creates a sink shipping records to the collector at `addr`, only failing if
+    /// `addr` isnt a address.
+    pub fn new<A: ToSocketAddrs>(addr: A, config: Config) -> Result<Self, ()> {
+        let addr = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or(())?;
+
+        let hostname = config
+            .hostname
+            .as_deref()
+            .map(header_field)
+            .unwrap_or_default();
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(config.capacity.min(1024)),
+                stats: Stats::default(),
+                missed: 0,
+                sequence: 0,
+                hostname,
+            }),
+            config,
+            waker: AtomicWaker::new(),
+        });
+
+        let driver = Driver {
+            inner: inner.clone(),
+            addr,
+            udp: None,
+            tcp: None,
+        };
+        async_::spawn(driver.run());
+
+        Ok(Self { inner })
+    }
+
+    /// Function logs a record without a message id or structured data.
+    pub fn log(&self, severity: Severity, args: fmt::Arguments) {
+        self.record(severity).send(args)
+    }
+
+    /// Returns a record to fill in, it is logged by `Record::send`.
+    pub fn record(&self, severity: Severity) -> Record<'_> {
+        Record {
+            sink: self,
+            severity,
+            msg_id: None,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.inner.state.lock();
+        Stats {
+            queued: state.queue.len(),
+            ..state.stats
+        }
+    }
+
+    /// Function formats a record and queues it, pushing out the oldest one if the queue is full.
+    fn push(&self, severity: Severity, msg_id: Option<&str>, data: &str, args: fmt::Arguments) {
+        let config = &self.inner.config;
+        if severity > config.level {
+            return;
+        }
+
+        let mut state = self.inner.state.lock();
+        state.sequence = state.sequence % (i32::MAX as u32) + 1;
+
+        let mut record = String::new();
+        let _ = write!(
+            record,
+            "<{}>1 - {} {} - {} [meta sequenceId=\"{}\" sysUpTime=\"{}\"]{} {}{}",
+            config.facility as u8 * 8 + severity as u8,
+            or_nil(&state.hostname),
+            or_nil(&header_field(&config.app_name)),
+            or_nil(&header_field(msg_id.unwrap_or(""))),
+            state.sequence,
+            get_milis() / 10,
+            data,
+            BOM,
+            args
+        );
+
+        if state.queue.len() >= config.capacity {
+            if let Some(x) = state.queue.pop_front() {
+                state.stats.dropped += 1;
+                state.missed += 1;
+
+                if config.fallback {
+                    state.stats.fallback += 1;
+                    println!("syslog: {}", String::from_utf8_lossy(&x));
+                }
+            }
+        }
+
+        state.queue.push_back(record.into_bytes());
+        drop(state);
+        self.inner.waker.wake();
+    }
+}
+
+impl Drop for Syslog {
+    fn drop(&mut self) {
+        // the task checks whether it was the last clone.
+        self.inner.waker.wake();
+    }
+}
+
+/// A record being filled in.
+pub struct Record<'a> {
+    sink: &'a Syslog,
+    severity: Severity,
+    msg_id: Option<String>,
+    /// Structured data elements, with their id and params.
+    data: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl<'a> Record<'a> {
+    /// Function sets the message id, which tells the collector what kind of record this is.
+    pub fn msg_id(mut self, msg_id: &str) -> Self {
+        self.msg_id = Some(msg_id.into());
+        self
+    }
+
+    /// Function adds a param to the structured data element `id`, ids of our own should look
+    /// like `name@private-enterprise-number`.
+    pub fn param(mut self, id: &str, name: &str, value: &str) -> Self {
+        let id = sd_name(id);
+        let param = (sd_name(name), value.into());
+
+        match self.data.iter_mut().find(|x| x.0 == id) {
+            Some(x) => x.1.push(param),
+            None => self.data.push((id, vec![param])),
+        }
+
+        self
+    }
+
+    /// Function logs the record with the message `args`.
+    pub fn send(self, args: fmt::Arguments) {
+        let mut data = String::new();
+        for (id, params) in self.data.iter() {
+            let _ = write!(data, "[{}", id);
+            for (name, value) in params {
+                let _ = write!(data, " {}=\"{}\"", name, escape(value));
+            }
+            data.push(']');
+        }
+
+        self.sink
+            .push(self.severity, self.msg_id.as_deref(), &data, args);
+    }
+}
+
+/// Returns `x` cut down to the printable ascii RFC 5424 allows in header fields.
+fn header_field(x: &str) -> String {
+    x.chars()
+        .filter(|x| ('!'..='~').contains(x))
+        .take(48)
+        .collect()
+}
+
+/// Returns `x` cut down to a valid structured data name.
+fn sd_name(x: &str) -> String {
+    x.chars()
+        .filter(|x| ('!'..='~').contains(x) && !matches!(*x, '=' | ']' | '"'))
+        .take(32)
+        .collect()
+}
+
+/// Returns `x` with the characters param values cant hold escaped.
+fn escape(x: &str) -> String {
+    let mut escaped = String::with_capacity(x.len());
+    for c in x.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn or_nil(x: &str) -> &str {
+    if x.is_empty() {
+        "-"
+    } else {
+        x
+    }
+}
+
+/// Function installs the sink `syslog!` logs through, replacing the one installed before.
+pub fn init(sink: Syslog) {
+    *SINK.lock() = Some(sink);
+}
+
+/// Function removes the installed sink, returning it.
+pub fn take() -> Option<Syslog> {
+    SINK.lock().take()
+}
+
+#[doc(hidden)]
+pub fn _log(severity: Severity, args: fmt::Arguments) {
+    // a clone so the lock isnt held while the record is formatted.
+    let sink = SINK.lock().clone();
+
+    match sink {
+        Some(x) => x.log(severity, args),
+        None => println!("{}: {}", severity, args),
+    }
+}
+
+/// Logs a record through the sink installed with `syslog::init`, or prints it to serial if
+/// there is none.
+#[macro_export]
+macro_rules! syslog {
+    ($severity:expr, $($arg:tt)*) => {
+        $crate::net::syslog::_log($severity, format_args!($($arg)*))
+    };
+}
+
+/// The task behind a sink.
+struct Driver {
+    inner: Arc<Inner>,
+    addr: SocketAddr,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+}
+
+impl Driver {
+    async fn run(mut self) {
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            let record = poll_fn(|cx| {
+                self.inner.waker.register(cx.waker());
+
+                let mut state = self.inner.state.lock();
+                match state.queue.pop_front() {
+                    Some(x) => Poll::Ready(Some(x)),
+                    None if Arc::strong_count(&self.inner) == 1 => Poll::Ready(None),
+                    None => Poll::Pending,
+                }
+            })
+            .await;
+
+            let record = match record {
+                Some(x) => x,
+                None => return,
+            };
+
+            if self.send(&record).await.is_ok() {
+                backoff = Duration::from_secs(1);
+                self.sent();
+                continue;
+            }
+
+            // the record goes back in front and waits for the collector, unless newer records
+            // took its place.
+            {
+                let mut state = self.inner.state.lock();
+                state.stats.connected = false;
+
+                if state.queue.len() < self.inner.config.capacity {
+                    state.queue.push_front(record);
+                } else {
+                    state.stats.dropped += 1;
+                    state.missed += 1;
+                }
+            }
+
+            // without clones left nobody can see the records anymore.
+            if Arc::strong_count(&self.inner) == 1 {
+                return;
+            }
+
+            Sleep::new(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Function counts a record that went out, telling the collector about the records it
+    /// missed if it was out of reach before.
+    fn sent(&mut self) {
+        let missed = {
+            let mut state = self.inner.state.lock();
+            state.stats.sent += 1;
+            state.stats.connected = true;
+            core::mem::take(&mut state.missed)
+        };
+
+        if missed > 0 {
+            let sink = Syslog {
+                inner: self.inner.clone(),
+            };
+
+            sink.record(Severity::Warning)
+                .msg_id("DROPPED")
+                .param("meta", "dropped", &missed.to_string())
+                .send(format_args!(
+                    "{} records were dropped while the collector was unreachable",
+                    missed
+                ));
+        }
+    }
+
+    /// Function sends a record to the collector, errors mean it cant be reached right now.
+    async fn send(&mut self, record: &[u8]) -> Result<(), ()> {
+        let hostname_missing = self.inner.state.lock().hostname.is_empty();
+        if hostname_missing {
+            if let Some(ip) = super::ARP_LAYER.local_ip().await {
+                self.inner.state.lock().hostname = ip.to_string();
+            }
+        }
+
+        match self.inner.config.transport {
+            Transport::Udp => {
+                if self.udp.is_none() {
+                    self.udp = Some(UdpSocket::bind(0)?);
+                }
+
+                let socket = self.udp.as_ref().ok_or(())?;
+                let len = record.len().min(MAX_UDP_LEN);
+                socket.send_to(&record[..len], self.addr).await?;
+            }
+            Transport::Tcp => {
+                if let Some(stream) = self.tcp.as_ref() {
+                    if stream.raw.lock().await.is_eof() {
+                        self.tcp = None;
+                    }
+                }
+
+                if self.tcp.is_none() {
+                    self.tcp = Some(TcpStream::connect(self.addr).await?);
+                }
+
+                let stream = self.tcp.as_mut().ok_or(())?;
+                let mut frame = format!("{} ", record.len()).into_bytes();
+                frame.extend_from_slice(record);
+                stream.write(&frame).await;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
+use super::addr::SocketAddrV4;
+use super::ports::PortGuard;
 use super::wire::eth2::Ether2Frame;
 use super::wire::eth2::EtherType;
 use super::wire::ipaddr::Ipv4Addr;
 use super::wire::ipv4::Ipv4;
 use super::wire::ipv4::Ipv4Proto;
-use super::wire::mac::Mac;
 use super::wire::tcp::Tcp;
 use super::wire::tcp::TcpFlag;
 use super::wire::tcp::TcpStates;
 use super::wire::Packet;
+use super::wire::buf::IPV4_HEADER_LEN;
+use super::stats;
+use super::stats::DropReason;
+use super::filter::Action;
+use super::filter::Direction;
+use super::filter::Flow;
 
+use crate::arch::pit::get_milis;
 use crate::prelude::*;
 use crate::sync::mpsc::UnboundedReceiver;
 use crate::sync::mpsc::UnboundedSender;
@@ -16,8 +24,17 @@ use crate::sync::RwLock;
 use crate::net::socks::TcpStream;
 use crate::sync::Mutex;
 
+use crate::async_::Sleep;
+
+use core::task::Context;
+use core::task::Poll;
 use core::task::Waker;
+use core::time::Duration;
+
+use futures_util::future;
+use futures_util::future::poll_fn;
 
+use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use hashbrown::HashMap;
 use hashbrown::hash_map::Entry;
@@ -25,6 +42,25 @@ use hashbrown::hash_map::Entry;
 pub type ConnectionKey = (Ipv4Addr, u16, Ipv4Addr, u16); // sip, sport, dip, dport
 pub type ConnectionMap = HashMap<ConnectionKey, Arc<Mutex<TcpConnection>>>;
 
+/// Number of SYNs we send before giving up on a active open.
+const TCP_SYN_RETRIES: usize = 3;
+/// How long we wait for a answer to our SYN before retransmitting it.
+const TCP_SYN_TIMEOUT: Duration = Duration::from_secs(1);
+/// Size of a tcp header without options.
+const TCP_HEADER_LEN: usize = 20;
+/// Mss we assume when the peer doesnt send the option (RFC 1122 4.2.2.6).
+const TCP_DEFAULT_MSS: u16 = 536;
+/// How long (ms) connections stay in TIME-WAIT, 2 MSL with the 30s MSL linux uses.
+const TCP_TIME_WAIT: u64 = 60_000;
+/// How long (ms) orphaned connections wait in FIN-WAIT-2 for our peer to close, like linux
+/// `tcp_fin_timeout`.
+const TCP_FIN_TIMEOUT: u64 = 60_000;
+/// Retransmission timeout (ms) we start out with, and the one it backs off to (RFC 6298).
+const TCP_RTO_INITIAL: u64 = 1000;
+const TCP_RTO_MAX: u64 = 60_000;
+/// Number of times in a row a segment is retransmitted before we give up on the connection.
+const TCP_RETRIES: usize = 8;
+
 pub struct TcpLayer {
     connections: RwLock<ConnectionMap>,
 }
@@ -37,49 +73,251 @@ impl TcpLayer {
     }
 
     pub async fn handle_packet(&self, packet: Tcp, ctx: &Ipv4) -> Option<Tcp> {
+        stats::TCP.rx(packet.len());
+
+        if !packet.verify_checksum(ctx.sip(), ctx.dip()) {
+            stats::TCP.dropped(DropReason::Checksum);
+            return None;
+        }
+
+        if packet.is_rst() {
+            stats::TCP_CONN.reset_received();
+        }
+
+        let flow = Flow::from_tcp(&packet, ctx.sip(), ctx.dip());
+        let reply = match super::FIREWALL.check_tcp(Direction::Ingress, &flow, &packet) {
+            Action::Accept => self.dispatch(packet, ctx).await?,
+            Action::Drop => {
+                stats::TCP.dropped(DropReason::Filtered);
+                return None;
+            }
+            Action::Reject => {
+                stats::TCP.dropped(DropReason::Filtered);
+                self.reset_for(&packet, ctx)?
+            }
+        };
+
+        let flow = Flow::from_tcp(&reply, ctx.dip(), ctx.sip());
+        if super::FIREWALL.check_tcp(Direction::Egress, &flow, &reply) != Action::Accept {
+            stats::TCP.dropped(DropReason::Filtered);
+            return None;
+        }
+
+        stats::TCP.tx(reply.len());
+        if reply.is_rst() {
+            stats::TCP_CONN.reset_sent();
+        }
+
+        Some(reply)
+    }
+
+    /// Function hands `packet` to the connection it belongs to, or to a listener if it opens a
+    /// new one.
+    async fn dispatch(&self, packet: Tcp, ctx: &Ipv4) -> Option<Tcp> {
         let conn_key = (ctx.sip(), packet.src(), ctx.dip(), packet.dst());
 
-        let local_mac = super::ARP_LAYER.resolve_ip_local(ctx.dip()).await.expect("failed to get local mac");
-        let mac = super::ARP_LAYER.resolve_ip(ctx.sip()).await.expect("failed to resolve remote ip");
+        // the mss we advertise in our SYN-ACK depends on the path, which is only worth looking up
+        // for a SYN.
+        let mss = if packet.is_syn() && !packet.is_ack() {
+            self.local_mss(ctx.sip(), ctx.dip()).await
+        } else {
+            TCP_DEFAULT_MSS
+        };
 
         match self.connections.write().await.entry(conn_key) {
             Entry::Occupied(mut entry) => {
-                let mut lock = entry.get_mut().lock().await;
-                return lock.handle_packet(packet, ctx);
+                let conn = entry.get_mut().clone();
+                let mut lock = conn.lock().await;
+                let reply = lock.handle_packet(packet, ctx);
+
+                // the listener only gets the stream once the handshake is done, so whatever is
+                // written to it right away doesnt get lost.
+                if let Some(listener) = lock.take_listener() {
+                    let _ = listener.send(TcpStream { raw: conn.clone() });
+                }
+
+                return reply;
             }
             Entry::Vacant(entry) => {
-                let key = packet.dst();
                 let lock = super::OPEN_PORTS.read();
 
+                // listeners bound to the exact address win over the ones bound to all of them.
+                let listeners = lock
+                    .get(&SocketAddrV4::new(ctx.dip(), packet.dst()))
+                    .or_else(|| lock.get(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, packet.dst())));
+
+                // listeners sharing a address get the connections of a peer by turns, each peer
+                // always ends up at the same one.
+                let listener = listeners.and_then(|x| {
+                    let hash = u32::from(ctx.sip()) as usize + packet.src() as usize;
+                    x.get(hash % x.len().max(1))
+                });
+
                 // we are listening on dst port
-                if let Some(listener) = lock.get(&key) {
-                    match TcpConnection::accept(
-                        packet,
-                        ctx,
-                        local_mac,
-                        mac,
-                    ) {
-                        Ok((conn, out)) => {
-                            let conn = Arc::new(crate::sync::Mutex::new(conn));
-                            let stream = TcpStream { raw: conn.clone() };
-
-                            listener
-                                .send(stream)
-                                .expect("failed to send key to listener");
-                            entry.insert(conn);
+                if let Some((_, listener)) = listener {
+                    match TcpConnection::accept(packet, ctx, mss) {
+                        Ok((mut conn, out)) => {
+                            conn.listener = Some(listener.clone());
+                            entry.insert(Arc::new(crate::sync::Mutex::new(conn)));
 
                             return Some(out);
                         }
                         Err(e) => return e,
                     }
                 }
+
+                stats::TCP.dropped(DropReason::NoSocket);
                 return None;
             }
         }
     }
 
+    /// Function actively opens a connection to `dip:dport`, retransmitting our SYN until we
+    /// either get a answer or run out of retries.
+    pub async fn connect(&self, dip: Ipv4Addr, dport: u16) -> Result<Arc<Mutex<TcpConnection>>, ()> {
+        let sip = if super::ARP_LAYER.is_local(dip).await {
+            dip
+        } else {
+            super::ARP_LAYER.local_ip().await.ok_or(())?
+        };
+        let mss = self.local_mss(dip, sip).await;
+
+        // the port is released again once the connection is gone.
+        let port = super::TCP_PORTS.ephemeral(sip)?;
+        let quad = (dip, dport, sip, port.addr().port());
+
+        let conn = Arc::new(Mutex::new(TcpConnection::connect(quad, mss, port)));
+        self.connections.write().await.insert(quad, conn.clone());
+
+        for attempt in 0..TCP_SYN_RETRIES {
+            if attempt > 0 {
+                stats::TCP_CONN.retransmit();
+            }
+
+            let syn = conn.lock().await.syn();
+            self.handle_tx(syn, sip, dip).await;
+
+            let established = poll_fn(|cx| match conn.try_lock() {
+                Some(mut guard) => guard.poll_established(cx),
+                None => {
+                    conn.register_waker(cx);
+                    Poll::Pending
+                }
+            });
+
+            match future::select(established, Sleep::new(TCP_SYN_TIMEOUT)).await {
+                future::Either::Left((true, _)) => return Ok(conn),
+                future::Either::Left((false, _)) => break,
+                future::Either::Right(_) => continue,
+            }
+        }
+
+        self.connections.write().await.remove(&quad);
+        Err(())
+    }
+
+    /// Returns the mss we advertise to `remote`, so our peer's segments fit the path mtu.
+    async fn local_mss(&self, remote: Ipv4Addr, local: Ipv4Addr) -> u16 {
+        let mtu = super::IP_LAYER.path_mtu(remote, local).await;
+        (mtu - IPV4_HEADER_LEN - TCP_HEADER_LEN).min(u16::MAX as usize) as u16
+    }
+
+    /// Returns whether `seq` is in flight on the connection `quad`, meaning we sent it and our
+    /// peer didnt ack it yet.
+    pub async fn in_flight(&self, quad: ConnectionKey, seq: u32) -> bool {
+        let conn = match self.connections.read().await.get(&quad) {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+
+        let conn = conn.lock().await;
+        conn.in_flight(seq)
+    }
+
+    /// Function fits the retransmission queues of our connections from `sip` to `dip` to the
+    /// path mtu after it shrunk. Their oldest segment is sent again right away, it most likely
+    /// got dropped for being too big.
+    pub async fn pmtu_changed(&self, dip: Ipv4Addr, sip: Ipv4Addr) {
+        let mtu = super::IP_LAYER.path_mtu(dip, sip).await;
+        let conns = self
+            .connections
+            .read()
+            .await
+            .iter()
+            .filter(|(quad, _)| quad.0 == dip && quad.2 == sip)
+            .map(|(_, conn)| conn.clone())
+            .collect::<Vec<_>>();
+
+        for conn in conns {
+            let packet = conn.lock().await.shrink_mtu(mtu);
+            if let Some(packet) = packet {
+                self.handle_tx(packet, sip, dip).await;
+            }
+        }
+    }
+
+    /// Function runs the timers of every connection, sending out their retransmissions, and
+    /// forgets the ones that are closed. Their local port was released when they closed, streams
+    /// still holding on to one keep seeing it as closed.
+    pub async fn poll_timers(&self) {
+        let now = get_milis();
+        let mut retransmits = Vec::new();
+
+        self.connections.write().await.retain(|_, conn| {
+            conn.try_lock().map_or(true, |mut x| {
+                if let Some(packet) = x.poll_timers(now) {
+                    let quad = x.quad();
+                    retransmits.push((packet, quad.2, quad.0));
+                }
+
+                !x.is_closed()
+            })
+        });
+
+        for (packet, sip, dip) in retransmits {
+            self.handle_tx(packet, sip, dip).await;
+        }
+    }
+
+    /// Function builds the RST answering `packet`, as described on p.36 of RFC793. Returns
+    /// `None` if `packet` is a RST itself.
+    pub fn reset_for(&self, packet: &Tcp, ctx: &Ipv4) -> Option<Tcp> {
+        if packet.is_rst() {
+            return None;
+        }
+
+        let mut reset = Tcp::zeroed();
+        reset.set_src(packet.dst());
+        reset.set_dst(packet.src());
+        reset.set_hlen(20);
+
+        if packet.is_ack() {
+            reset.set_flags(&[TcpFlag::RST]);
+            reset.set_seq(packet.ack());
+        } else {
+            let len = packet.dlen() as u32 + packet.is_syn() as u32 + packet.is_fin() as u32;
+            reset.set_flags(&[TcpFlag::RST, TcpFlag::ACK]);
+            reset.set_ack(packet.seq().wrapping_add(len));
+        }
+
+        reset.set_checksum(ctx.sip(), ctx.dip());
+
+        Some(reset)
+    }
+
     pub async fn handle_tx(&self, packet: Tcp, sip: Ipv4Addr, dip: Ipv4Addr) {
-        super::IP_LAYER.handle_tx(packet.as_bytes(), Ipv4Proto::TCP, dip, sip).await;
+        let flow = Flow::from_tcp(&packet, sip, dip);
+        if super::FIREWALL.check_tcp(Direction::Egress, &flow, &packet) != Action::Accept {
+            stats::TCP.dropped(DropReason::Filtered);
+            return;
+        }
+
+        stats::TCP.tx(packet.len());
+        if packet.is_rst() {
+            stats::TCP_CONN.reset_sent();
+        }
+
+        super::IP_LAYER.handle_tx(packet.into_buf(), Ipv4Proto::TCP, dip, sip).await;
     }
 }
 
@@ -114,21 +352,61 @@ pub struct TcpConnection {
     data: Vec<u8>,
     /// Waker for task waiting on data.
     waker: Option<Waker>,
+    /// Waker for the task waiting on room in the window of our peer.
+    write_waker: Option<Waker>,
     /// Last ipv4 packet id
     last_ipv4_id: u16,
-    /// Mac of this device.
-    mac: Mac,
-    /// Mac of the outbound device,
-    dst_mac: Mac,
+    /// Biggest segment our peer wants to receive.
+    snd_mss: u16,
+    /// Mss we advertised to our peer.
+    rcv_mss: u16,
+    /// Time (ms) at which we entered TIME-WAIT.
+    time_wait: Option<u64>,
+    /// Segments we sent that our peer didnt ack yet, oldest first.
+    unacked: VecDeque<Unacked>,
+    /// Current retransmission timeout (ms).
+    rto: u64,
+    /// Time (ms) at which the oldest unacked segment gets retransmitted.
+    rto_at: Option<u64>,
+    /// Number of times in a row the oldest unacked segment got retransmitted.
+    retries: usize,
+    /// Time (ms) at which we entered FIN-WAIT-2.
+    fin_wait_2: Option<u64>,
+    /// Whether the stream of this connection got dropped, nobody reads from it anymore.
+    orphaned: bool,
+    /// Listener waiting for the stream of this connection, until the handshake is done.
+    listener: Option<UnboundedSender<TcpStream>>,
+    /// Reservation of our local port, keeps it from being reused while we are around. Released
+    /// once the connection is closed.
+    port: Option<PortGuard>,
+}
+
+/// A segment waiting on the retransmission queue.
+struct Unacked {
+    seq: u32,
+    data: Vec<u8>,
+    fin: bool,
+}
+
+/// Returns whether sequence number `a` comes before `b`, in the modulo 2^32 space of RFC793 3.3.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Returns a initial send sequence number, random so that our sequence numbers cant be guessed
+/// by someone off path (RFC 6528).
+fn initial_seq() -> u32 {
+    crate::arch::random::u64() as u32
 }
 
 impl TcpConnection {
-    pub fn accept(
-        tcp: Tcp,
-        ip: &Ipv4,
-        mac: Mac,
-        dst_mac: Mac,
-    ) -> Result<(Self, Tcp), Option<Tcp>> {
+    /// Function answers a SYN opening a new connection, advertising `mss` as the biggest
+    /// segment we want to receive.
+    pub fn accept(tcp: Tcp, ip: &Ipv4, mss: u16) -> Result<(Self, Tcp), Option<Tcp>> {
         // First check for a RST
         if tcp.is_rst() {
             return Err(None);
@@ -152,90 +430,140 @@ impl TcpConnection {
             return Err(None);
         }
 
+        let iss = initial_seq();
         let this = Self {
             state: TcpStates::TCP_SYN_RECEIVED,
-            snd_iss: 0,
-            snd_una: 0,
-            snd_nxt: 1,
-            snd_wnd: 1024,
+            snd_iss: iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: tcp.window() as u32,
             snd_up: false,
             snd_wl1: 0,
             snd_wl2: 0,
             rcv_irs: tcp.seq(),
-            rcv_nxt: tcp.seq() + 1,
+            rcv_nxt: tcp.seq().wrapping_add(1),
             rcv_wnd: tcp.window(),
             rcv_up: false,
             quad: (ip.sip(), tcp.src(), ip.dip(), tcp.dst()),
             data: Vec::new(),
             waker: None,
+            write_waker: None,
             last_ipv4_id: ip.id(),
-            mac,
-            dst_mac,
+            snd_mss: tcp.mss().unwrap_or(TCP_DEFAULT_MSS),
+            rcv_mss: mss,
+            time_wait: None,
+            unacked: VecDeque::new(),
+            rto: TCP_RTO_INITIAL,
+            // the SYN-ACK is retransmitted like everything else, a handshake that never
+            // completes times out.
+            rto_at: Some(get_milis() + TCP_RTO_INITIAL),
+            retries: 0,
+            fin_wait_2: None,
+            orphaned: false,
+            listener: None,
+            port: Some(super::TCP_PORTS.connection(SocketAddrV4::new(ip.dip(), tcp.dst()))),
         };
 
-        let mut packet = Tcp::zeroed();
-
-        packet.set_dst(this.quad.1);
-        packet.set_src(this.quad.3);
-        packet.set_flags(&[TcpFlag::SYN, TcpFlag::ACK]);
-        packet.set_seq(this.snd_iss); //replace this with a random num at runtime
-        packet.set_ack(this.rcv_nxt);
-        packet.set_hlen(20);
-        packet.set_checksum(ip.sip(), ip.dip());
+        let packet = this.syn_ack();
 
         Ok((this, packet))
     }
 
+    /// Function creates a connection in the SYN-SENT state, the SYN itself is built with
+    /// `syn`. `mss` is the biggest segment we want to receive and `port` the reservation of our
+    /// local port.
+    pub fn connect(quad: ConnectionKey, mss: u16, port: PortGuard) -> Self {
+        let iss = initial_seq();
+
+        Self {
+            state: TcpStates::TCP_SYNSENT,
+            snd_iss: iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 1024,
+            snd_up: false,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            rcv_irs: 0,
+            rcv_nxt: 0,
+            rcv_wnd: u16::MAX,
+            rcv_up: false,
+            quad,
+            data: Vec::new(),
+            waker: None,
+            write_waker: None,
+            last_ipv4_id: 0,
+            snd_mss: TCP_DEFAULT_MSS,
+            rcv_mss: mss,
+            time_wait: None,
+            unacked: VecDeque::new(),
+            rto: TCP_RTO_INITIAL,
+            rto_at: None,
+            retries: 0,
+            fin_wait_2: None,
+            orphaned: false,
+            listener: None,
+            port: Some(port),
+        }
+    }
+
     pub fn handle_packet(&mut self, tcp: Tcp, ip: &Ipv4) -> Option<Tcp> {
-        // handle keep_alives
+        // handle keep_alives, these carry a sequence number one below what we expect next.
         if let TcpStates::TCP_ESTABLISHED = self.state {
-            if tcp.is_ack() && !tcp.is_psh() {
+            if tcp.is_ack() && tcp.dlen() <= 1 && tcp.seq() == self.rcv_nxt.wrapping_sub(1) {
                 return Some(self.ack(tcp, ip));
             }
         }
 
-        // SYN-SENT state
+        // SYN-SENT state p.66
         if let TcpStates::TCP_SYNSENT = self.state {
-            if tcp.is_ack() {
-                if tcp.ack() <= self.snd_iss || self.snd_nxt < tcp.ack() {
-                    // return Some(self.reset(tcp, ip)); // <SEQ=SEG.ACK>
-                    return None;
-                }
+            let acceptable =
+                tcp.is_ack() && seq_lt(self.snd_iss, tcp.ack()) && seq_le(tcp.ack(), self.snd_nxt);
 
-                // use wrapping comparations
-                if self.snd_una <= tcp.ack() && tcp.ack() <= self.snd_nxt {
-                    if tcp.is_rst() {
-                        // TODO: Drop segment and close connection
-                        return None;
-                    }
+            if tcp.is_ack() && !acceptable {
+                return None;
+            }
+
+            if tcp.is_rst() {
+                // connection refused.
+                if acceptable {
+                    self.set_closed();
                 }
+                return None;
             }
 
-            // 4th step, check the syn bit
-            if tcp.is_syn() {
-                self.rcv_nxt = tcp.seq() + 1;
+            // 4th step, check the syn bit. A SYN without a ACK would be a simultaneous open,
+            // which we dont do.
+            if tcp.is_syn() && acceptable {
                 self.rcv_irs = tcp.seq();
+                self.rcv_nxt = tcp.seq().wrapping_add(1);
+                self.snd_mss = tcp.mss().unwrap_or(TCP_DEFAULT_MSS);
+                self.snd_una = tcp.ack();
+                self.snd_wnd = tcp.window() as u32;
+                self.snd_wl1 = tcp.seq();
+                self.snd_wl2 = tcp.ack();
 
-                // TODO: SND.UNA should be advanced to equal SEG.ACK (if there
-                // is an ACK), and any segments on the retransmission queue which
-                // are thereby acknowledged should be removed
-                if tcp.is_ack() {
-                    self.snd_una += 1
-                }
-
-                // our SYN has been ack'd
-                if self.snd_una > self.snd_iss {
-                    self.state = TcpStates::TCP_ESTABLISHED;
-                    return Some(self.ack(tcp, ip)); // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-                }
+                self.state = TcpStates::TCP_ESTABLISHED;
+                self.wake();
+                return Some(self.ack(tcp, ip)); // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
             }
 
-            if !tcp.is_syn() || !tcp.is_rst() {
+            return None;
+        }
+
+        // the only thing that can arrive in TIME-WAIT is a retransmission of the FIN of our peer,
+        // which means our ack got lost. Acknowledge it again and restart the 2 MSL timeout.
+        if let TcpStates::TCP_TIME_WAIT = self.state {
+            if tcp.is_rst() {
+                self.set_closed();
                 return None;
             }
+
+            if tcp.is_fin() {
+                self.time_wait = Some(get_milis());
+            }
+            return Some(self.ack(tcp, ip));
         }
-        // TODO: p.69 check the seq number again??
-        // if invalid <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
 
         // second check the rst bit p.70 RFC793
         if tcp.is_rst() {
@@ -250,9 +578,7 @@ impl TcpConnection {
                     // on the retransmission queue should be removed.  And in the
                     // active OPEN case, enter the CLOSED state and delete the TCB,
                     // and return.
-
-                    // TODO: Remove this TCP connection from the tcp stack as it is marked CLOSED.
-                    self.state = TcpStates::TCP_CLOSE;
+                    self.set_closed();
                 }
                 TcpStates::TCP_ESTABLISHED
                 | TcpStates::TCP_FIN_WAIT_1
@@ -263,163 +589,212 @@ impl TcpConnection {
                     // flushed.  Users should also receive an unsolicited general
                     // "connection reset" signal.  Enter the CLOSED state, delete the
                     // TCB, and return.
-                    self.state = TcpStates::TCP_CLOSE;
+                    self.set_closed();
                 }
                 TcpStates::TCP_CLOSING | TcpStates::TCP_LAST_ACK | TcpStates::TCP_TIME_WAIT => {
                     // If the RST bit is set then, enter the CLOSED state, delete the
                     // TCB, and return.
-                    self.state = TcpStates::TCP_CLOSE;
-                }
-                TcpStates::TCP_SYNSENT | TcpStates::TCP_LISTEN | TcpStates::TCP_CLOSE => {
-                    println!(
-                        "tcp: attempted to process packet when socket is SYNSENT | LISTEN | CLOSE"
-                    );
+                    self.set_closed();
                 }
+                TcpStates::TCP_SYNSENT | TcpStates::TCP_LISTEN | TcpStates::TCP_CLOSE => {}
             }
+
+            self.wake();
             return None;
         }
 
         // fourth check syn bit p.71
+        if tcp.is_syn() && tcp.seq() == self.rcv_irs {
+            // a retransmission of the SYN we already got, meaning our answer got lost.
+            if let TcpStates::TCP_SYN_RECEIVED = self.state {
+                return Some(self.syn_ack());
+            }
+            return Some(self.ack(tcp, ip));
+        }
+
         if tcp.is_syn() {
             // If the SYN is in the window it is an error, send a reset, any
             // outstanding RECEIVEs and SEND should receive "reset" responses,
             // all segment queues should be flushed, the user should also
             // receive an unsolicited general "connection reset" signal, enter
             // the CLOSED state, delete the TCB, and return.
-
-            // If the SYN is not in the window this step would not be reached
-            // and an ack would have been sent in the first step (sequence
-            // number check).
             //
             // NOTE: I think its safe to assume that we can just reset any connection if
             // this branch is reached.
-
-            self.state = TcpStates::TCP_CLOSE;
+            self.set_closed();
             return Some(self.reset(tcp, ip));
         }
 
-        // fifth check the ack field
-        if tcp.is_ack() {
-            match self.state {
-                TcpStates::TCP_SYN_RECEIVED => {
-                    self.state = TcpStates::TCP_ESTABLISHED;
+        // fifth check the ack field, segments without one are dropped.
+        if !tcp.is_ack() {
+            return None;
+        }
+
+        match self.state {
+            TcpStates::TCP_SYN_RECEIVED => {
+                if !seq_le(self.snd_una, tcp.ack()) || !seq_le(tcp.ack(), self.snd_nxt) {
+                    return None;
                 }
-                TcpStates::TCP_ESTABLISHED
-                | TcpStates::TCP_FIN_WAIT_1
-                | TcpStates::TCP_FIN_WAIT_2
-                | TcpStates::TCP_CLOSE_WAIT
-                | TcpStates::TCP_CLOSING
-                | TcpStates::TCP_LAST_ACK => {
-                    if self.snd_una < tcp.ack() && tcp.ack() <= self.snd_nxt {
-                        self.snd_una = tcp.ack();
-                        // TODO: clean retransmission queue here and send acks to our clients
-                        // waiting for confirmation of send's
-
-                        if self.snd_wl1 < tcp.seq()
-                            || (self.snd_wl1 == tcp.seq() && self.snd_wl2 <= tcp.ack())
-                        {
-                            self.snd_wnd = tcp.window() as u32;
-                            self.snd_wl1 = tcp.seq();
-                            self.snd_wl2 = tcp.ack();
-                        }
 
-                        // FIN-WAIT-1 STATE
-                        if let TcpStates::TCP_FIN_WAIT_1 = self.state {
-                            // NOTE: Do we have to do extra checking of the packet to ensure that
-                            // this ack ack's our FIN?
-                            self.state = TcpStates::TCP_FIN_WAIT_2;
-                        }
+                self.snd_una = tcp.ack();
+                self.snd_wnd = tcp.window() as u32;
+                self.snd_wl1 = tcp.seq();
+                self.snd_wl2 = tcp.ack();
+                self.state = TcpStates::TCP_ESTABLISHED;
+                self.acked();
+            }
+            TcpStates::TCP_ESTABLISHED
+            | TcpStates::TCP_FIN_WAIT_1
+            | TcpStates::TCP_FIN_WAIT_2
+            | TcpStates::TCP_CLOSE_WAIT
+            | TcpStates::TCP_CLOSING
+            | TcpStates::TCP_LAST_ACK => {
+                // a ack for something we didnt send yet gets a ack back and is dropped.
+                if seq_lt(self.snd_nxt, tcp.ack()) {
+                    return Some(self.ack(tcp, ip));
+                }
 
-                        // FIN-WAIT-2 STATE
-                        if let TcpStates::TCP_FIN_WAIT_2 = self.state {
-                            // If the retransmission queue is empty the users CLOSE can be ok'd
-                            // without deleting the TCB.
-                        }
+                if seq_lt(self.snd_una, tcp.ack()) {
+                    self.snd_una = tcp.ack();
+                    self.acked();
+                }
 
-                        // CLOSING STATE
-                        if let TcpStates::TCP_CLOSING = self.state {
-                            // In addition to the processing for the ESTABLISHED state, if
-                            // the ACK acknowledges our FIN then enter the TIME-WAIT state,
-                            // otherwise ignore the segment.
-                        }
+                if seq_lt(self.snd_wl1, tcp.seq())
+                    || (self.snd_wl1 == tcp.seq() && seq_le(self.snd_wl2, tcp.ack()))
+                {
+                    self.snd_wnd = tcp.window() as u32;
+                    self.snd_wl1 = tcp.seq();
+                    self.snd_wl2 = tcp.ack();
+                }
 
-                        // LAST-ACK STATE
-                        if let TcpStates::TCP_LAST_ACK = self.state {
-                            // The only thing that can arrive in this state is an
-                            // acknowledgment of our FIN.  If our FIN is now acknowledged,
-                            // delete the TCB, enter the CLOSED state, and return.
-                        }
+                // acks and window updates make room for whoever is waiting to write.
+                self.wake_writer();
 
-                        // TIME-WAIT STATE
-                        if let TcpStates::TCP_TIME_WAIT = self.state {
-                            // The only thing that can arrive in this state is a
-                            // retransmission of the remote FIN.  Acknowledge it, and restart
-                            // the 2 MSL timeout.
+                // in the closing states our FIN is the last thing we sent, so everything being
+                // acked means the FIN is too.
+                if self.snd_una == self.snd_nxt {
+                    match self.state {
+                        TcpStates::TCP_FIN_WAIT_1 => {
+                            self.state = TcpStates::TCP_FIN_WAIT_2;
+                            self.fin_wait_2 = Some(get_milis());
+                        }
+                        TcpStates::TCP_CLOSING => self.enter_time_wait(),
+                        TcpStates::TCP_LAST_ACK => {
+                            self.set_closed();
+                            return None;
                         }
+                        _ => {}
                     }
                 }
-                _ => {}
             }
+            _ => return None,
         }
 
-        // sixth, check the urg bit.
-        if tcp.is_urg() {
-            unimplemented!("Fuck you, this rfc is deprecated");
+        // sixth, the urg bit. Urgent data is long deprecated (RFC 6093), it is read inline like
+        // everything else.
+
+        // seventh process segment text, and eighth check the fin bit.
+        let len = tcp.dlen() as u32;
+        if len == 0 && !tcp.is_fin() {
+            return None;
         }
 
-        // seventh process segment text.
-        if tcp.data().len() > 0 {
-            if let TcpStates::TCP_ESTABLISHED
-            | TcpStates::TCP_FIN_WAIT_1
-            | TcpStates::TCP_FIN_WAIT_2 = self.state
-            {
-                if tcp.seq() == self.rcv_nxt {
-                    // Once the TCP takes responsibility for the data it advances
-                    // RCV.NXT over the data accepted, and adjusts RCV.WND as
-                    // apporopriate to the current buffer availability.  The total of
-                    // RCV.NXT and RCV.WND should not be reduced.
-                    self.data.extend_from_slice(tcp.data());
-
-                    self.rcv_nxt += tcp.dlen() as u32;
-
-                    // wake the async read task.
-                    if let Some(waker) = self.waker.take() {
-                        waker.wake();
-                    }
+        // how far into the segment the next byte we expect sits, the FIN counts as the byte
+        // after the data.
+        let offset = self.rcv_nxt.wrapping_sub(tcp.seq());
 
-                    return Some(self.ack(tcp, ip)); // send our ack
-                } else {
-                    // TODO: Move this segment into a queue for later processing as it is within
-                    // the window of data to receive but it is not the left most segment.
-                    unimplemented!()
-                }
-            }
+        // segments starting past what we expect are out of order, they are dropped and our
+        // duplicate ack gets the missing data retransmitted. Segments we already have get acked
+        // again, since our last ack might have been lost.
+        if (offset as i32) < 0 || offset > len || (offset == len && !tcp.is_fin()) {
+            return Some(self.ack(tcp, ip));
+        }
+
+        if let TcpStates::TCP_ESTABLISHED | TcpStates::TCP_FIN_WAIT_1 | TcpStates::TCP_FIN_WAIT_2 =
+            self.state
+        {
+            // Once the TCP takes responsibility for the data it advances
+            // RCV.NXT over the data accepted, and adjusts RCV.WND as
+            // apporopriate to the current buffer availability.  The total of
+            // RCV.NXT and RCV.WND should not be reduced.
+            self.data.extend_from_slice(&tcp.data()[offset as usize..]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len - offset);
+        } else {
+            // our peer already closed its side, anything past its FIN is bogus.
+            return Some(self.ack(tcp, ip));
         }
 
-        // eighth check the fin bit.
         if tcp.is_fin() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+
             match self.state {
-                TcpStates::TCP_CLOSE | TcpStates::TCP_LISTEN | TcpStates::TCP_SYNSENT => {
-                    // dont progress segment
-                    return None;
-                }
-                TcpStates::TCP_SYN_RECEIVED | TcpStates::TCP_ESTABLISHED => {
-                    self.state = TcpStates::TCP_CLOSE_WAIT;
-                }
-                TcpStates::TCP_FIN_WAIT_1 => {
-                    // If our FIN has been ACKed (perhaps in this segment), then
-                    // enter TIME-WAIT, start the time-wait timer, turn off the other
-                    // timers; otherwise enter the CLOSING state.
-                    self.state = TcpStates::TCP_FIN_WAIT_2;
-                }
-                TcpStates::TCP_TIME_WAIT => {
-                    // TODO: Restart 2msl time wait timeout.
-                }
+                TcpStates::TCP_ESTABLISHED => self.state = TcpStates::TCP_CLOSE_WAIT,
+                // our FIN is still on its way, both sides are closing at once.
+                TcpStates::TCP_FIN_WAIT_1 => self.state = TcpStates::TCP_CLOSING,
+                TcpStates::TCP_FIN_WAIT_2 => self.enter_time_wait(),
                 _ => {}
             }
         }
 
-        None
+        // wake the async read task.
+        self.wake();
+
+        Some(self.ack(tcp, ip)) // send our ack
+    }
+
+    /// Function drops everything our peer acked from the retransmission queue and restarts the
+    /// retransmission timer.
+    fn acked(&mut self) {
+        while let Some(front) = self.unacked.front_mut() {
+            let len = front.data.len() as u32 + front.fin as u32;
+            if seq_le(front.seq.wrapping_add(len), self.snd_una) {
+                self.unacked.pop_front();
+                continue;
+            }
+
+            // only part of the segment got acked.
+            if seq_lt(front.seq, self.snd_una) {
+                let len = self.snd_una.wrapping_sub(front.seq) as usize;
+                front.data.drain(..len);
+                front.seq = self.snd_una;
+            }
+            break;
+        }
+
+        self.rto = TCP_RTO_INITIAL;
+        self.retries = 0;
+        self.rto_at = match self.unacked.is_empty() {
+            true => None,
+            false => Some(get_milis() + self.rto),
+        };
+    }
+
+    /// Function puts a segment we just sent on the retransmission queue.
+    fn push_unacked(&mut self, seq: u32, data: Vec<u8>, fin: bool) {
+        self.unacked.push_back(Unacked { seq, data, fin });
+
+        if self.rto_at.is_none() {
+            self.rto_at = Some(get_milis() + self.rto);
+        }
+    }
+
+    /// Function moves the connection to CLOSED, dropping whatever it still had queued and
+    /// releasing its local port.
+    fn set_closed(&mut self) {
+        self.state = TcpStates::TCP_CLOSE;
+        self.unacked.clear();
+        self.rto_at = None;
+        self.time_wait = None;
+        self.fin_wait_2 = None;
+        self.port = None;
+        self.wake();
+        self.wake_writer();
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = TcpStates::TCP_TIME_WAIT;
+        self.time_wait = Some(get_milis());
+        self.fin_wait_2 = None;
     }
 
     fn ack(&mut self, tcp: Tcp, ip: &Ipv4) -> Tcp {
@@ -449,23 +824,311 @@ impl TcpConnection {
         packet
     }
 
-    pub async fn write(&mut self, item: &[u8]) {
-        self.last_ipv4_id += 1;
+    /// Function builds the SYN-ACK answering the SYN of our peer.
+    fn syn_ack(&self) -> Tcp {
+        let mut packet = Tcp::zeroed();
+        packet.set_dst(self.quad.1);
+        packet.set_src(self.quad.3);
+        packet.set_flags(&[TcpFlag::SYN, TcpFlag::ACK]);
+        packet.set_seq(self.snd_iss);
+        packet.set_ack(self.rcv_nxt);
+        packet.set_hlen(20);
+        packet.set_mss(self.rcv_mss);
+        packet.set_checksum(self.quad.0, self.quad.2);
+
+        packet
+    }
 
+    /// Returns a segment from us to our peer starting at `seq`.
+    fn segment(&self, flags: &[TcpFlag], seq: u32, data: &[u8]) -> Tcp {
         let mut packet = Tcp::zeroed();
         packet.set_dst(self.quad.1);
         packet.set_src(self.quad.3);
-        packet.set_flags(&[TcpFlag::PSH, TcpFlag::ACK]);
-        packet.set_seq(self.snd_nxt);
+        packet.set_flags(flags);
+        packet.set_seq(seq);
         packet.set_ack(self.rcv_nxt);
         packet.set_window(self.rcv_wnd);
         packet.set_hlen(20);
-        packet.set_data(item.to_vec());
+        packet.set_data(data.to_vec());
+        packet.set_checksum(self.quad.0, self.quad.2);
+
+        packet
+    }
+
+    /// Function sends as much of `item` to our peer as its window has room for, split into
+    /// segments that fit both the mss of our peer and the current path mtu. Returns how much got
+    /// sent, nothing once we, or our peer, closed the connection for sending.
+    pub async fn write(&mut self, item: &[u8]) -> usize {
+        if !self.is_writable() {
+            return 0;
+        }
+
+        let path_mtu = super::IP_LAYER.path_mtu(self.quad.0, self.quad.2).await;
+        let mss = self.segment_size(path_mtu);
+        let len = item.len().min(self.send_room());
+
+        for chunk in item[..len].chunks(mss) {
+            self.last_ipv4_id = self.last_ipv4_id.wrapping_add(1);
+
+            let packet = self.segment(&[TcpFlag::PSH, TcpFlag::ACK], self.snd_nxt, chunk);
+            self.push_unacked(self.snd_nxt, chunk.to_vec(), false);
+            self.snd_nxt = self.snd_nxt.wrapping_add(chunk.len() as u32);
+
+            super::TCP_LAYER.handle_tx(packet, self.quad.2, self.quad.0).await;
+        }
+
+        len
+    }
+
+    fn is_writable(&self) -> bool {
+        match self.state {
+            TcpStates::TCP_ESTABLISHED | TcpStates::TCP_CLOSE_WAIT => true,
+            _ => false,
+        }
+    }
+
+    /// Returns how many more bytes the window of our peer has room for. With nothing in flight
+    /// a byte always goes out, so we notice a zero window opening up again (RFC 1122 4.2.2.17).
+    fn send_room(&self) -> usize {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        match (self.snd_wnd as usize).saturating_sub(in_flight) {
+            0 if in_flight == 0 => 1,
+            x => x,
+        }
+    }
+
+    /// Function resolves once the window of our peer has room for more data, returning `false`
+    /// if the connection got closed for sending instead.
+    pub fn poll_writable(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if !self.is_writable() {
+            return Poll::Ready(false);
+        }
+
+        if self.send_room() > 0 {
+            return Poll::Ready(true);
+        }
+
+        self.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Returns how much data fits a segment on a path with a mtu of `path_mtu`.
+    fn segment_size(&self, path_mtu: usize) -> usize {
+        (self.snd_mss as usize)
+            .min(path_mtu.saturating_sub(IPV4_HEADER_LEN + TCP_HEADER_LEN))
+            .max(1)
+    }
+
+    /// Function splits the segments on the retransmission queue that dont fit a path with a
+    /// mtu of `path_mtu`. Returns the oldest segment if any had to be split, since the
+    /// original didnt make it.
+    pub fn shrink_mtu(&mut self, path_mtu: usize) -> Option<Tcp> {
+        let mss = self.segment_size(path_mtu);
+        if self.unacked.iter().all(|x| x.data.len() <= mss) {
+            return None;
+        }
+
+        let mut unacked = VecDeque::with_capacity(self.unacked.len());
+        for segment in self.unacked.drain(..) {
+            if segment.data.len() <= mss {
+                unacked.push_back(segment);
+                continue;
+            }
+
+            // a FIN goes along with the last piece.
+            let count = (segment.data.len() + mss - 1) / mss;
+            let mut seq = segment.seq;
+            for (i, chunk) in segment.data.chunks(mss).enumerate() {
+                unacked.push_back(Unacked {
+                    seq,
+                    data: chunk.to_vec(),
+                    fin: segment.fin && i + 1 == count,
+                });
+                seq = seq.wrapping_add(chunk.len() as u32);
+            }
+        }
+        self.unacked = unacked;
+
+        self.retransmission()
+    }
+
+    /// Returns the oldest segment on the retransmission queue, built again.
+    fn retransmission(&self) -> Option<Tcp> {
+        // the SYN-ACK isnt on the queue, it is all state.
+        if let TcpStates::TCP_SYN_RECEIVED = self.state {
+            return Some(self.syn_ack());
+        }
+
+        let front = self.unacked.front()?;
+        let flags: &[TcpFlag] = match front.fin {
+            true => &[TcpFlag::FIN, TcpFlag::ACK],
+            false => &[TcpFlag::PSH, TcpFlag::ACK],
+        };
+
+        Some(self.segment(flags, front.seq, &front.data))
+    }
+
+    /// Returns whether `seq` is in flight, meaning we sent it and our peer didnt ack it yet.
+    pub fn in_flight(&self, seq: u32) -> bool {
+        seq_le(self.snd_una, seq) && seq_lt(seq, self.snd_nxt)
+    }
+
+    /// Function closes our side of the connection, returning the FIN that tells our peer if
+    /// there is one to send. Data our peer still sends can be read until it closes too.
+    pub fn close(&mut self) -> Option<Tcp> {
+        match self.state {
+            TcpStates::TCP_SYN_RECEIVED | TcpStates::TCP_ESTABLISHED => {
+                self.state = TcpStates::TCP_FIN_WAIT_1;
+            }
+            TcpStates::TCP_CLOSE_WAIT => self.state = TcpStates::TCP_LAST_ACK,
+            // nobody knows about us yet.
+            TcpStates::TCP_SYNSENT | TcpStates::TCP_LISTEN => {
+                self.set_closed();
+                return None;
+            }
+            _ => return None,
+        }
+
+        let fin = self.segment(&[TcpFlag::FIN, TcpFlag::ACK], self.snd_nxt, &[]);
+        self.push_unacked(self.snd_nxt, Vec::new(), true);
+        self.snd_nxt = self.snd_nxt.wrapping_add(1);
+
+        Some(fin)
+    }
+
+    /// Returns the listener waiting for this connection once the handshake is done, it is only
+    /// handed out once. Connections that never got that far are forgotten by their listener.
+    pub fn take_listener(&mut self) -> Option<UnboundedSender<TcpStream>> {
+        match self.state {
+            TcpStates::TCP_SYN_RECEIVED => None,
+            TcpStates::TCP_CLOSE => {
+                self.listener = None;
+                None
+            }
+            _ => self.listener.take(),
+        }
+    }
+
+    /// Function marks the connection as orphaned, its stream got dropped.
+    pub fn orphan(&mut self) {
+        self.orphaned = true;
+    }
+
+    /// Function runs the timers of this connection, moving it out of TIME-WAIT once 2 MSL have
+    /// passed, and out of FIN-WAIT-2 once a orphaned connection waited long enough. Returns the oldest unacked segment if its time to retransmit it.
+    pub fn poll_timers(&mut self, now: u64) -> Option<Tcp> {
+        if let Some(since) = self.time_wait {
+            if now.saturating_sub(since) >= TCP_TIME_WAIT {
+                self.set_closed();
+            }
+        }
+
+        // nobody reads from a orphaned connection, so we dont wait forever for our peer to close
+        // its side too.
+        if let (true, Some(since)) = (self.orphaned, self.fin_wait_2) {
+            if now.saturating_sub(since) >= TCP_FIN_TIMEOUT {
+                self.set_closed();
+            }
+        }
+
+        match self.rto_at {
+            Some(x) if x <= now => {}
+            _ => return None,
+        }
+
+        // our peer is gone.
+        if self.retries >= TCP_RETRIES || self.is_closed() {
+            self.set_closed();
+            return None;
+        }
+
+        // back off until our peer answers again (RFC 6298 5.5).
+        self.retries += 1;
+        self.rto = (self.rto * 2).min(TCP_RTO_MAX);
+        self.rto_at = Some(now + self.rto);
+        stats::TCP_CONN.retransmit();
+
+        self.retransmission()
+    }
+
+    /// Returns the biggest segment we send to our peer.
+    pub fn mss(&self) -> u16 {
+        self.snd_mss
+    }
+
+    /// Returns the address of our end of the connection.
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.quad.2, self.quad.3)
+    }
+
+    /// Returns the address of our peer.
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.quad.0, self.quad.1)
+    }
+
+    /// Returns the addresses and ports of both ends, the key of this connection.
+    pub fn quad(&self) -> ConnectionKey {
+        self.quad
+    }
+
+    /// Function builds the SYN we send out when actively opening a connection.
+    pub fn syn(&self) -> Tcp {
+        let mut packet = Tcp::zeroed();
+        packet.set_dst(self.quad.1);
+        packet.set_src(self.quad.3);
+        packet.set_flags(&[TcpFlag::SYN]);
+        packet.set_seq(self.snd_iss);
+        packet.set_window(self.rcv_wnd);
+        packet.set_hlen(20);
+        packet.set_mss(self.rcv_mss);
         packet.set_checksum(self.quad.0, self.quad.2);
 
-        self.snd_nxt += item.len() as u32;
+        packet
+    }
 
-        super::TCP_LAYER.handle_tx(packet, self.quad.2, self.quad.0).await;
+    /// Function resolves once the handshake has completed, returning `false` if the connection
+    /// got closed instead.
+    pub fn poll_established(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        match self.state {
+            TcpStates::TCP_SYNSENT | TcpStates::TCP_SYN_RECEIVED => {
+                self.register_waker(cx.waker().clone());
+                Poll::Pending
+            }
+            TcpStates::TCP_ESTABLISHED => Poll::Ready(true),
+            _ => Poll::Ready(false),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self.state {
+            TcpStates::TCP_CLOSE => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether our peer wont send anything anymore, because it closed its side or the
+    /// connection is gone.
+    pub fn is_eof(&self) -> bool {
+        match self.state {
+            TcpStates::TCP_CLOSE
+            | TcpStates::TCP_CLOSE_WAIT
+            | TcpStates::TCP_CLOSING
+            | TcpStates::TCP_LAST_ACK
+            | TcpStates::TCP_TIME_WAIT => true,
+            _ => false,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
     }
 
     pub fn has_data(&self) -> bool {
@@ -484,3 +1147,332 @@ impl TcpConnection {
         min_len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_::executor::Executor;
+    use crate::driver::testing::Fate;
+    use crate::driver::testing::PipeNic;
+    use crate::net::addr::SocketAddr;
+    use crate::net::socks::TcpListener;
+    use crate::net::wire::arp::ArpOpcode;
+    use crate::net::wire::arp::ArpPacket;
+    use crate::net::wire::mac::Mac;
+    use crate::net::wire::PacketBuf;
+    use crate::net::NetworkDevice;
+
+    use core::future::Future;
+
+    const PORT: u16 = 7000;
+    const PEER_PORT: u16 = 40000;
+    /// Sequence number our peer starts out with.
+    const PEER_ISS: u32 = 1000;
+
+    /// The other end of the link, driven by hand.
+    struct Peer {
+        nic: PipeNic,
+        /// End of the link our stack runs on.
+        stack: PipeNic,
+        mac: Mac,
+        ip: Ipv4Addr,
+        stack_mac: Mac,
+        stack_ip: Ipv4Addr,
+    }
+
+    impl Peer {
+        fn send(&self, flags: &[TcpFlag], seq: u32, ack: u32, data: &[u8]) {
+            self.send_window(flags, seq, ack, u16::MAX, data);
+        }
+
+        fn send_window(&self, flags: &[TcpFlag], seq: u32, ack: u32, window: u16, data: &[u8]) {
+            let mut tcp = Tcp::zeroed();
+            tcp.set_src(PEER_PORT);
+            tcp.set_dst(PORT);
+            tcp.set_flags(flags);
+            tcp.set_seq(seq);
+            tcp.set_ack(ack);
+            tcp.set_window(window);
+            tcp.set_hlen(20);
+            tcp.set_data(data.to_vec());
+            tcp.set_checksum(self.ip, self.stack_ip);
+
+            let mut ip = Ipv4::encapsulate(tcp.into_buf());
+            ip.set_proto(Ipv4Proto::TCP);
+            ip.set_sip(self.ip);
+            ip.set_dip(self.stack_ip);
+            ip.set_checksum();
+
+            self.frame(EtherType::IPv4, ip.into_buf());
+        }
+
+        fn frame(&self, dtype: EtherType, data: PacketBuf) {
+            let mut frame = Ether2Frame::encapsulate(data);
+            frame.set_src(self.mac);
+            frame.set_dst(self.stack_mac);
+            frame.set_dtype(dtype);
+
+            self.nic.send(frame.into_bytes()).unwrap();
+        }
+
+        /// Function waits up to `ms` for the next segment our stack sends, skipping everything
+        /// else.
+        async fn recv(&mut self, ms: u64) -> Option<Tcp> {
+            let deadline = get_milis() + ms;
+
+            loop {
+                let left = Duration::from_millis(deadline.saturating_sub(get_milis()));
+                let frame = crate::async_::timeout(left, self.nic.recv()).await??;
+                let (frame, payload) = Ether2Frame::from_bytes(frame).ok()?.split();
+                if frame.dtype() != EtherType::IPv4 {
+                    continue;
+                }
+
+                let (ip, payload) = Ipv4::from_buf(payload).ok()?.split();
+                if ip.proto() == Ipv4Proto::TCP {
+                    return Tcp::from_buf(payload).ok();
+                }
+            }
+        }
+
+        /// Function opens a connection to the stack, returning the first sequence number of the
+        /// stack and the stream it accepted.
+        async fn handshake(&mut self, listener: &mut TcpListener) -> (u32, TcpStream) {
+            self.send(&[TcpFlag::SYN], PEER_ISS, 0, &[]);
+
+            let syn_ack = self.recv(500).await.expect("no SYN-ACK");
+            assert!(syn_ack.is_syn() && syn_ack.is_ack());
+            assert_eq!(syn_ack.ack(), PEER_ISS + 1);
+
+            let iss = syn_ack.seq().wrapping_add(1);
+            self.send(&[TcpFlag::ACK], PEER_ISS + 1, iss, &[]);
+
+            (iss, listener.accept().await.unwrap())
+        }
+    }
+
+    /// Function runs `test` against our stack running on one end of a `PipeNic`, `n` keeps the
+    /// macs and ips of every test apart since the layers of the stack are global.
+    fn run<F, Fut>(n: u8, test: F)
+    where
+        F: FnOnce(Peer) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        Executor::new().block_on(async move {
+            let stack_mac = Mac::from([0x02, 0, 0, 0, n, 1]);
+            let mac = Mac::from([0x02, 0, 0, 0, n, 2]);
+            let (mut nic, peer) = PipeNic::pair(stack_mac, mac);
+
+            let mut netdev = NetworkDevice::new(&mut nic).await;
+            let stack_ip = Ipv4Addr::new(10, 0, n, 1);
+            netdev.set_ip(stack_ip).await;
+            let shutdown = netdev.shutdown_handle();
+
+            let mut peer = Peer {
+                nic: peer,
+                stack: nic,
+                mac,
+                ip: Ipv4Addr::new(10, 0, n, 2),
+                stack_mac,
+                stack_ip,
+            };
+
+            let test = async move {
+                // let the stack know our mac.
+                let mut request = ArpPacket::zeroed();
+                request.set_opcode(ArpOpcode::ArpRequest);
+                request.set_smac(peer.mac);
+                request.set_sip(peer.ip);
+                request.set_tip(peer.stack_ip);
+                peer.frame(EtherType::ARP, request.into_buf());
+
+                loop {
+                    let frame = peer.nic.recv().await.unwrap();
+                    let (frame, payload) = Ether2Frame::from_bytes(frame).unwrap().split();
+                    if frame.dtype() != EtherType::ARP {
+                        continue;
+                    }
+
+                    let reply = ArpPacket::from_buf(payload).unwrap();
+                    if reply.opcode() == ArpOpcode::ArpReply && reply.tip() == peer.ip {
+                        break;
+                    }
+                }
+
+                test(peer).await;
+                shutdown.shutdown();
+            };
+
+            future::join(netdev.run_forever(), test).await;
+        });
+    }
+
+    #[unittest]
+    fn handshake() {
+        run(1, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+            let (iss, mut stream) = peer.handshake(&mut listener).await;
+
+            let addr = SocketAddr::from(SocketAddrV4::new(peer.ip, PEER_PORT));
+            assert_eq!(stream.peer_addr().await, addr);
+
+            peer.send(&[TcpFlag::PSH, TcpFlag::ACK], PEER_ISS + 1, iss, b"ping");
+            let mut buf = [0; 16];
+            let len = stream.read(&mut buf).await;
+            assert_eq!(&buf[..len], b"ping");
+
+            let ack = peer.recv(500).await.expect("data wasnt acked");
+            assert_eq!(ack.ack(), PEER_ISS + 5);
+
+            stream.write(b"pong").await;
+            let data = peer.recv(500).await.expect("no data");
+            assert_eq!(data.seq(), iss);
+            assert_eq!(data.data(), b"pong");
+        });
+    }
+
+    #[unittest]
+    fn greeting() {
+        run(5, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+
+            // the stream only shows up once the handshake is done.
+            peer.send(&[TcpFlag::SYN], PEER_ISS, 0, &[]);
+            let syn_ack = peer.recv(500).await.expect("no SYN-ACK");
+            let wait = Duration::from_millis(100);
+            let early = crate::async_::timeout(wait, listener.accept()).await;
+            assert!(early.is_none());
+
+            let iss = syn_ack.seq().wrapping_add(1);
+            peer.send(&[TcpFlag::ACK], PEER_ISS + 1, iss, &[]);
+            let mut stream = listener.accept().await.unwrap();
+
+            // so a server writing first doesnt lose what it wrote.
+            stream.write(b"hello").await;
+            let data = peer.recv(500).await.expect("greeting got lost");
+            assert_eq!(data.seq(), iss);
+            assert_eq!(data.data(), b"hello");
+        });
+    }
+
+    #[unittest]
+    fn loss() {
+        run(2, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+
+            // our SYN-ACK gets lost, the retransmitted SYN gets it again.
+            peer.stack.drop_next(1);
+            peer.send(&[TcpFlag::SYN], PEER_ISS, 0, &[]);
+            assert!(peer.recv(200).await.is_none());
+
+            let (iss, mut stream) = peer.handshake(&mut listener).await;
+
+            // the first copy of our data gets lost, it is sent again once the rto expires.
+            peer.stack.drop_next(1);
+            stream.write(b"hello").await;
+            assert!(peer.recv(200).await.is_none());
+
+            let data = peer.recv(2000).await.expect("data wasnt retransmitted");
+            assert_eq!(data.seq(), iss);
+            assert_eq!(data.data(), b"hello");
+
+            let captured = peer.nic.captured();
+            let dropped = captured.iter().filter(|x| x.fate == Fate::Dropped);
+            assert_eq!(dropped.count(), 2);
+
+            // once acked nothing gets retransmitted anymore.
+            peer.send(&[TcpFlag::ACK], PEER_ISS + 1, iss + 5, &[]);
+            assert!(peer.recv(2500).await.is_none());
+        });
+    }
+
+    #[unittest]
+    fn syn_ack_retransmit() {
+        run(6, |mut peer| async move {
+            let _listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+
+            // our peer never finishes the handshake, so our SYN-ACK is sent again.
+            peer.send(&[TcpFlag::SYN], PEER_ISS, 0, &[]);
+            let syn_ack = peer.recv(500).await.expect("no SYN-ACK");
+
+            let again = peer.recv(1500).await.expect("SYN-ACK wasnt retransmitted");
+            assert!(again.is_syn() && again.is_ack());
+            assert_eq!(again.seq(), syn_ack.seq());
+            assert_eq!(again.ack(), PEER_ISS + 1);
+        });
+    }
+
+    #[unittest]
+    fn window() {
+        run(7, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+            let (iss, mut stream) = peer.handshake(&mut listener).await;
+
+            // our peer only has room for 4 bytes.
+            peer.send_window(&[TcpFlag::ACK], PEER_ISS + 1, iss, 4, &[]);
+
+            let write = stream.write(b"hello world");
+            let check = async {
+                let data = peer.recv(500).await.expect("no data");
+                assert_eq!(data.data(), b"hell");
+                assert!(peer.recv(200).await.is_none());
+
+                // acking it opens the window for the rest.
+                peer.send_window(&[TcpFlag::ACK], PEER_ISS + 1, iss + 4, 100, &[]);
+                let data = peer.recv(500).await.expect("rest wasnt sent");
+                assert_eq!(data.seq(), iss + 4);
+                assert_eq!(data.data(), b"o world");
+            };
+
+            future::join(write, check).await;
+        });
+    }
+
+    #[unittest]
+    fn teardown() {
+        run(3, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+
+            // our peer closes first, reads return eof once its FIN is acked.
+            let (iss, mut stream) = peer.handshake(&mut listener).await;
+            peer.send(&[TcpFlag::FIN, TcpFlag::ACK], PEER_ISS + 1, iss, &[]);
+
+            let ack = peer.recv(500).await.expect("FIN wasnt acked");
+            assert!(ack.is_ack() && !ack.is_fin());
+            assert_eq!(ack.ack(), PEER_ISS + 2);
+            assert_eq!(stream.read(&mut [0; 16]).await, 0);
+
+            // dropping the stream sends our FIN.
+            drop(stream);
+            let fin = peer.recv(500).await.expect("no FIN on drop");
+            assert!(fin.is_fin());
+            assert_eq!(fin.seq(), iss);
+
+            peer.send(&[TcpFlag::ACK], PEER_ISS + 2, iss + 1, &[]);
+            assert!(peer.recv(1500).await.is_none());
+        });
+
+        run(4, |mut peer| async move {
+            let mut listener = TcpListener::bind((peer.stack_ip, PORT)).unwrap();
+
+            // we close first and read until our peer closes too.
+            let (iss, mut stream) = peer.handshake(&mut listener).await;
+            stream.shutdown().await;
+
+            let fin = peer.recv(500).await.expect("no FIN on shutdown");
+            assert!(fin.is_fin());
+            assert_eq!(fin.seq(), iss);
+
+            peer.send(&[TcpFlag::PSH, TcpFlag::ACK], PEER_ISS + 1, iss + 1, b"bye");
+            let mut buf = [0; 16];
+            let len = stream.read(&mut buf).await;
+            assert_eq!(&buf[..len], b"bye");
+            peer.recv(500).await.expect("data wasnt acked");
+
+            peer.send(&[TcpFlag::FIN, TcpFlag::ACK], PEER_ISS + 4, iss + 1, &[]);
+            let ack = peer.recv(500).await.expect("FIN wasnt acked");
+            assert_eq!(ack.ack(), PEER_ISS + 5);
+            assert_eq!(stream.read(&mut buf).await, 0);
+        });
+    }
+}
@@ -0,0 +1,314 @@
+//! TFTP (RFC 1350) client, asking for the blksize (RFC 2348) and tsize (RFC 2349) options.
+//!
+//! Files are fetched in octet mode straight into memory, which makes it a good fit for pulling
+//! per-instance configuration from a boot server before anything else runs.
+//!
+//! ```ignore
+//! let config = Tftp::new("10.0.0.1:69")?
+//!     .timeout(Duration::from_secs(2))
+//!     .get("sensor-1.toml")
+//!     .await?;
+//! ```
+use crate::async_;
+use crate::net::addr::SocketAddr;
+use crate::net::addr::ToSocketAddrs;
+use crate::net::socks::UdpSocket;
+use crate::prelude::*;
+
+use core::fmt;
+use core::str;
+use core::time::Duration;
+
+const RRQ: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+/// Error codes we send.
+const ERR_DISK_FULL: u16 = 3;
+const ERR_ILLEGAL: u16 = 4;
+const ERR_UNKNOWN_TID: u16 = 5;
+/// Error code of servers refusing our options (RFC 2347).
+const ERR_OPTIONS: u16 = 8;
+
+/// Block size servers use when they dont take the blksize option.
+const DEFAULT_BLOCK_SIZE: u16 = 512;
+/// Block size we ask for, so a block fits in a 1500 byte frame along with its headers.
+const BLOCK_SIZE: u16 = 1468;
+const MIN_BLOCK_SIZE: u16 = 8;
+const MAX_BLOCK_SIZE: u16 = 65464;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The server address isnt a address, or we cant send to it.
+    Address,
+    /// The server stopped answering.
+    TimedOut,
+    /// The server sent a error with this code.
+    Remote(u16),
+    /// The server broke the protocol.
+    Protocol,
+    /// The file is bigger than we take.
+    TooBig,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Address => write!(f, "server cant be reached"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::Remote(x) => write!(f, "server error {}", x),
+            Self::Protocol => write!(f, "protocol error"),
+            Self::TooBig => write!(f, "file too big"),
+        }
+    }
+}
+
+/// A TFTP client for a single server.
+#[derive(Clone, Debug)]
+pub struct Tftp {
+    server: SocketAddr,
+    block_size: u16,
+    timeout: Duration,
+    retries: usize,
+    max_size: usize,
+}
+
+impl Tftp {
+    /// Function creates a client for the server at `addr`. By default it asks for 1468 byte
+    /// blocks, waits a second for each packet, tries 5 more times before giving up and takes
+    /// files up to 16MiB.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let server = addr
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or(Error::Address)?;
+
+        Ok(Self {
+            server,
+            block_size: BLOCK_SIZE,
+            timeout: Duration::from_secs(1),
+            retries: 5,
+            max_size: 16 * 1024 * 1024,
+        })
+    }
+
+    /// Function sets the block size we ask the server for, 512 doesnt ask for a option at all.
+    pub fn block_size(mut self, block_size: u16) -> Self {
+        self.block_size = block_size.max(MIN_BLOCK_SIZE).min(MAX_BLOCK_SIZE);
+        self
+    }
+
+    /// Function sets how long we wait for each packet before sending our last one again.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Function sets how many times our last packet is sent again before we give up.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Function sets the size of the biggest file we take.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Function fetches `file` from the server.
+    pub async fn get(&self, file: &str) -> Result<Vec<u8>, Error> {
+        match self.fetch(file, true).await {
+            // servers should ignore options they dont know, some refuse them instead.
+            Err(Error::Remote(ERR_OPTIONS)) => self.fetch(file, false).await,
+            x => x,
+        }
+    }
+
+    async fn fetch(&self, file: &str, options: bool) -> Result<Vec<u8>, Error> {
+        let mut socket = UdpSocket::bind(0).map_err(|_| Error::Address)?;
+        let mut buf = vec![0; self.block_size.max(DEFAULT_BLOCK_SIZE) as usize + 4];
+
+        // the server answers from a port of its own, which is the one we talk to from then on.
+        let mut peer = None;
+        let mut last = request(file, options, self.block_size);
+        let mut block_size = DEFAULT_BLOCK_SIZE as usize;
+        let mut block = 0u16;
+        let mut data = Vec::new();
+        let mut attempts = 0;
+
+        socket
+            .send_to(&last, self.server)
+            .await
+            .map_err(|_| Error::Address)?;
+
+        loop {
+            let (len, from) = match async_::timeout(self.timeout, socket.recv_from(&mut buf)).await
+            {
+                Some(Ok(x)) => x,
+                Some(Err(_)) => return Err(Error::Address),
+                None if attempts < self.retries => {
+                    attempts += 1;
+                    let dest = peer.unwrap_or(self.server);
+                    let _ = socket.send_to(&last, dest).await;
+                    continue;
+                }
+                None => return Err(Error::TimedOut),
+            };
+
+            match peer {
+                Some(x) if x != from => {
+                    let packet = error(ERR_UNKNOWN_TID, "unknown transfer id");
+                    let _ = socket.send_to(&packet, from).await;
+                    continue;
+                }
+                None if from.ip() != self.server.ip() => continue,
+                _ => peer = Some(from),
+            }
+
+            match parse(&buf[..len]) {
+                Some(Packet::OAck(acked)) if options && block == 0 && data.is_empty() => {
+                    for (name, value) in acked {
+                        let value: usize = value.parse().map_err(|_| Error::Protocol)?;
+
+                        if name.eq_ignore_ascii_case("blksize") {
+                            let range = MIN_BLOCK_SIZE as usize..=self.block_size as usize;
+                            if !range.contains(&value) {
+                                return Err(Error::Protocol);
+                            }
+                            block_size = value;
+                        } else if name.eq_ignore_ascii_case("tsize") {
+                            if value > self.max_size {
+                                let packet = error(ERR_DISK_FULL, "file too big");
+                                let _ = socket.send_to(&packet, from).await;
+                                return Err(Error::TooBig);
+                            }
+                            data.reserve(value);
+                        }
+                    }
+
+                    last = ack(0);
+                }
+                Some(Packet::Data(n, payload)) if n == block.wrapping_add(1) => {
+                    if payload.len() > block_size {
+                        return Err(Error::Protocol);
+                    }
+
+                    if data.len() + payload.len() > self.max_size {
+                        let packet = error(ERR_DISK_FULL, "file too big");
+                        let _ = socket.send_to(&packet, from).await;
+                        return Err(Error::TooBig);
+                    }
+
+                    data.extend_from_slice(payload);
+                    block = n;
+                    last = ack(n);
+
+                    // a short block is the last one.
+                    if payload.len() < block_size {
+                        let _ = socket.send_to(&last, from).await;
+                        return Ok(data);
+                    }
+                }
+                // our last ack got lost and the server sent the block again, it gets the ack
+                // again below.
+                Some(Packet::Data(n, _)) if n == block => {}
+                Some(Packet::Error(code)) => return Err(Error::Remote(code)),
+                _ => {
+                    let packet = error(ERR_ILLEGAL, "unexpected packet");
+                    let _ = socket.send_to(&packet, from).await;
+                    return Err(Error::Protocol);
+                }
+            }
+
+            attempts = 0;
+            socket
+                .send_to(&last, from)
+                .await
+                .map_err(|_| Error::Address)?;
+        }
+    }
+}
+
+/// Packets the server sends us.
+enum Packet<'a> {
+    Data(u16, &'a [u8]),
+    /// The options the server took, with their values.
+    OAck(Vec<(&'a str, &'a str)>),
+    Error(u16),
+}
+
+fn parse(buf: &[u8]) -> Option<Packet<'_>> {
+    if buf.len() < 4 {
+        return None;
+    }
+
+    let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+    let value = u16::from_be_bytes([buf[2], buf[3]]);
+
+    let packet = match opcode {
+        DATA => Packet::Data(value, &buf[4..]),
+        ERROR => Packet::Error(value),
+        OACK => {
+            let strings = buf[2..]
+                .split(|x| *x == 0)
+                .map(str::from_utf8)
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+
+            // every string ends in a nul, which leaves a empty one at the end.
+            let strings = strings.split_last()?.1;
+            if strings.len() % 2 != 0 {
+                return None;
+            }
+
+            Packet::OAck(strings.chunks(2).map(|x| (x[0], x[1])).collect())
+        }
+        _ => return None,
+    };
+
+    Some(packet)
+}
+
+/// Returns a read request for `file`, asking for the size of it and for blocks of
+/// `block_size` if `options` is set.
+fn request(file: &str, options: bool, block_size: u16) -> Vec<u8> {
+    let mut packet = RRQ.to_be_bytes().to_vec();
+    for x in [file, "octet"].iter() {
+        packet.extend_from_slice(x.as_bytes());
+        packet.push(0);
+    }
+
+    if options {
+        let mut put = |x: &str| {
+            packet.extend_from_slice(x.as_bytes());
+            packet.push(0);
+        };
+
+        if block_size != DEFAULT_BLOCK_SIZE {
+            put("blksize");
+            put(&block_size.to_string());
+        }
+        put("tsize");
+        put("0");
+    }
+
+    packet
+}
+
+fn ack(block: u16) -> Vec<u8> {
+    let mut packet = ACK.to_be_bytes().to_vec();
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet
+}
+
+fn error(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    packet
+}
@@ -0,0 +1,106 @@
+//! Helpers for the length prefixed vectors TLS messages are made of.
+
+use crate::prelude::*;
+
+/// Cursor over a received message, every read returns `None` if the message is too short.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let data = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(data)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        let x = self.bytes(2)?;
+        Some(u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    pub fn u24(&mut self) -> Option<usize> {
+        let x = self.bytes(3)?;
+        Some((x[0] as usize) << 16 | (x[1] as usize) << 8 | x[2] as usize)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        let x = self.bytes(4)?;
+        Some(u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    /// Reads a vector with a one byte length.
+    pub fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads a vector with a two byte length.
+    pub fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads a vector with a three byte length.
+    pub fn vec24(&mut self) -> Option<&'a [u8]> {
+        let len = self.u24()?;
+        self.bytes(len)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let data = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        data
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+pub fn put_u8(buf: &mut Vec<u8>, x: u8) {
+    buf.push(x);
+}
+
+pub fn put_u16(buf: &mut Vec<u8>, x: u16) {
+    buf.extend_from_slice(&x.to_be_bytes());
+}
+
+pub fn put_u24(buf: &mut Vec<u8>, x: usize) {
+    buf.extend_from_slice(&(x as u32).to_be_bytes()[1..]);
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&x.to_be_bytes());
+}
+
+/// Writes whatever `f` writes, prefixed with its length in `len_bytes` bytes.
+fn put_vec(buf: &mut Vec<u8>, len_bytes: usize, f: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.resize(start + len_bytes, 0);
+    f(buf);
+
+    let len = (buf.len() - start - len_bytes).to_be_bytes();
+    buf[start..start + len_bytes].copy_from_slice(&len[len.len() - len_bytes..]);
+}
+
+pub fn put_vec8(buf: &mut Vec<u8>, f: impl FnOnce(&mut Vec<u8>)) {
+    put_vec(buf, 1, f)
+}
+
+pub fn put_vec16(buf: &mut Vec<u8>, f: impl FnOnce(&mut Vec<u8>)) {
+    put_vec(buf, 2, f)
+}
+
+pub fn put_vec24(buf: &mut Vec<u8>, f: impl FnOnce(&mut Vec<u8>)) {
+    put_vec(buf, 3, f)
+}
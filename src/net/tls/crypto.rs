@@ -0,0 +1,363 @@
+//! The cryptography TLS 1.3 is built on, hashing and the key schedule (RFC 8446 7.1), record
+//! protection, key exchange and signatures.
+
+use super::codec;
+
+use crate::arch::random;
+use crate::prelude::*;
+
+use core::convert::TryFrom;
+
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use sha2::{Digest, Sha256};
+
+/// Size of the output of the hash of every cipher suite we support.
+pub const HASH_LEN: usize = 32;
+/// Size of the authentication tag of our aeads.
+pub const TAG_LEN: usize = 16;
+/// Size of the nonce of our aeads.
+pub const NONCE_LEN: usize = 12;
+
+pub type Hash = [u8; HASH_LEN];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+/// Running hash of every handshake message sent and received so far.
+#[derive(Clone)]
+pub struct Transcript(Sha256);
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self(Sha256::new())
+    }
+
+    pub fn update(&mut self, message: &[u8]) {
+        self.0.update(message);
+    }
+
+    /// Returns the hash of the messages so far, more can still be added afterwards.
+    pub fn hash(&self) -> Hash {
+        self.0.clone().finalize().into()
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104) of the concatenation of `data`.
+pub fn hmac(key: &[u8], data: &[&[u8]]) -> Hash {
+    const BLOCK_LEN: usize = 64;
+
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..HASH_LEN].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut ipad = block;
+    let mut opad = block;
+    for (i, o) in ipad.iter_mut().zip(opad.iter_mut()) {
+        *i ^= 0x36;
+        *o ^= 0x5c;
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&ipad);
+    for x in data {
+        inner.update(x);
+    }
+
+    let mut outer = Sha256::new();
+    outer.update(&opad);
+    outer.update(&inner.finalize());
+    outer.finalize().into()
+}
+
+/// HKDF-Extract (RFC 5869).
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Hash {
+    hmac(salt, &[ikm])
+}
+
+/// HKDF-Expand (RFC 5869).
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let mut okm = Vec::with_capacity(len);
+    let mut block: Vec<u8> = Vec::new();
+
+    for counter in 1..=255u8 {
+        if okm.len() >= len {
+            break;
+        }
+
+        block = hmac(prk, &[&block, info, &[counter]]).to_vec();
+        okm.extend_from_slice(&block);
+    }
+
+    okm.truncate(len);
+    okm
+}
+
+/// HKDF-Expand-Label, every secret and key of a connection is derived with it.
+pub fn hkdf_expand_label(secret: &[u8], label: &[u8], context: &[u8], len: usize) -> Vec<u8> {
+    let mut info = Vec::new();
+    codec::put_u16(&mut info, len as u16);
+    codec::put_vec8(&mut info, |x| {
+        x.extend_from_slice(b"tls13 ");
+        x.extend_from_slice(label);
+    });
+    codec::put_vec8(&mut info, |x| x.extend_from_slice(context));
+
+    hkdf_expand(secret, &info, len)
+}
+
+/// Derive-Secret, `hash` is the transcript hash of the messages the secret depends on.
+pub fn derive_secret(secret: &[u8], label: &[u8], hash: &Hash) -> Hash {
+    let mut out = [0; HASH_LEN];
+    out.copy_from_slice(&hkdf_expand_label(secret, label, hash, HASH_LEN));
+    out
+}
+
+/// Returns the secret that replaces `secret` after a key update.
+pub fn next_secret(secret: &Hash) -> Hash {
+    let mut out = [0; HASH_LEN];
+    out.copy_from_slice(&hkdf_expand_label(secret, b"traffic upd", &[], HASH_LEN));
+    out
+}
+
+/// Returns the verify data of a Finished message sent by the owner of `secret`.
+pub fn finished(secret: &Hash, transcript: &Hash) -> Hash {
+    let key = hkdf_expand_label(secret, b"finished", &[], HASH_LEN);
+    hmac(&key, &[transcript])
+}
+
+/// The cipher suites we support, both use SHA-256.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CipherSuite {
+    Aes128GcmSha256,
+    ChaCha20Poly1305Sha256,
+}
+
+impl CipherSuite {
+    /// Every suite we support, in the order we prefer them.
+    pub const ALL: [Self; 2] = [Self::Aes128GcmSha256, Self::ChaCha20Poly1305Sha256];
+
+    pub fn id(self) -> u16 {
+        match self {
+            Self::Aes128GcmSha256 => 0x1301,
+            Self::ChaCha20Poly1305Sha256 => 0x1303,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Option<Self> {
+        Self::ALL.iter().cloned().find(|x| x.id() == id)
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128GcmSha256 => 16,
+            Self::ChaCha20Poly1305Sha256 => 32,
+        }
+    }
+}
+
+/// A aead keyed for one of our cipher suites.
+pub enum Aead {
+    Aes(Aes128Gcm),
+    ChaCha(ChaCha20Poly1305),
+}
+
+impl Aead {
+    pub fn new(suite: CipherSuite, key: &[u8]) -> Self {
+        match suite {
+            CipherSuite::Aes128GcmSha256 => {
+                Self::Aes(Aes128Gcm::new(aes_gcm::Key::from_slice(key)))
+            }
+            CipherSuite::ChaCha20Poly1305Sha256 => Self::ChaCha(ChaCha20Poly1305::new(
+                chacha20poly1305::Key::from_slice(key),
+            )),
+        }
+    }
+
+    /// Function encrypts `buf` in place and appends the tag.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], buf: &mut Vec<u8>) -> Result<(), ()> {
+        match self {
+            Self::Aes(x) => x.encrypt_in_place(aes_gcm::Nonce::from_slice(nonce), aad, buf),
+            Self::ChaCha(x) => {
+                x.encrypt_in_place(chacha20poly1305::Nonce::from_slice(nonce), aad, buf)
+            }
+        }
+        .map_err(|_| ())
+    }
+
+    /// Function checks the tag at the end of `buf` and decrypts it in place, the tag is removed.
+    pub fn open(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], buf: &mut Vec<u8>) -> Result<(), ()> {
+        match self {
+            Self::Aes(x) => x.decrypt_in_place(aes_gcm::Nonce::from_slice(nonce), aad, buf),
+            Self::ChaCha(x) => {
+                x.decrypt_in_place(chacha20poly1305::Nonce::from_slice(nonce), aad, buf)
+            }
+        }
+        .map_err(|_| ())
+    }
+}
+
+/// The key and iv protecting one direction of a connection, along with its sequence number.
+pub struct TrafficKeys {
+    aead: Aead,
+    iv: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl TrafficKeys {
+    /// Derives the keys of the traffic `secret` (RFC 8446 7.3).
+    pub fn new(suite: CipherSuite, secret: &[u8]) -> Self {
+        let key = hkdf_expand_label(secret, b"key", &[], suite.key_len());
+        let mut iv = [0; NONCE_LEN];
+        iv.copy_from_slice(&hkdf_expand_label(secret, b"iv", &[], NONCE_LEN));
+
+        Self {
+            aead: Aead::new(suite, &key),
+            iv,
+            seq: 0,
+        }
+    }
+
+    /// Returns the nonce of the next record, the iv xored with the sequence number.
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = self.iv;
+        for (x, y) in nonce[NONCE_LEN - 8..]
+            .iter_mut()
+            .zip(&self.seq.to_be_bytes())
+        {
+            *x ^= y;
+        }
+        nonce
+    }
+
+    pub fn seal(&mut self, aad: &[u8], buf: &mut Vec<u8>) -> Result<(), ()> {
+        let nonce = self.nonce();
+        self.seq += 1;
+        self.aead.seal(&nonce, aad, buf)
+    }
+
+    pub fn open(&mut self, aad: &[u8], buf: &mut Vec<u8>) -> Result<(), ()> {
+        let nonce = self.nonce();
+        self.aead.open(&nonce, aad, buf)?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// Named group of x25519, the only key exchange we do.
+pub const X25519: u16 = 0x001d;
+
+/// A ephemeral x25519 key pair.
+pub struct KeyShare(x25519_dalek::StaticSecret);
+
+impl KeyShare {
+    pub fn generate() -> Self {
+        let mut secret = [0; 32];
+        random::fill(&mut secret);
+        Self(secret.into())
+    }
+
+    pub fn public(&self) -> [u8; 32] {
+        x25519_dalek::PublicKey::from(&self.0).to_bytes()
+    }
+
+    /// Function computes the shared secret with the share of our peer.
+    pub fn agree(&self, peer: &[u8]) -> Result<[u8; 32], ()> {
+        let peer = <[u8; 32]>::try_from(peer).map_err(|_| ())?;
+        let shared = self.0.diffie_hellman(&peer.into()).to_bytes();
+
+        // a all zero secret means the peer sent a low order point (RFC 7748 6.1).
+        if shared == [0; 32] {
+            return Err(());
+        }
+
+        Ok(shared)
+    }
+}
+
+/// Signature schemes we can sign and verify with.
+pub const ECDSA_P256_SHA256: u16 = 0x0403;
+pub const ED25519: u16 = 0x0807;
+/// Every scheme we support, in the order we prefer them.
+pub const SIGNATURE_SCHEMES: [u16; 2] = [ED25519, ECDSA_P256_SHA256];
+
+/// A private key a server proves its identity with.
+pub enum SigningKey {
+    Ed25519(ed25519_dalek::Keypair),
+    EcdsaP256(p256::ecdsa::SigningKey),
+}
+
+impl SigningKey {
+    /// Function loads a ed25519 key from its 32 byte seed.
+    pub fn ed25519(seed: &[u8]) -> Result<Self, ()> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(seed).map_err(|_| ())?;
+        let public = ed25519_dalek::PublicKey::from(&secret);
+
+        Ok(Self::Ed25519(ed25519_dalek::Keypair { secret, public }))
+    }
+
+    /// Function loads a P-256 key from its 32 byte scalar.
+    pub fn ecdsa_p256(scalar: &[u8]) -> Result<Self, ()> {
+        Ok(Self::EcdsaP256(
+            p256::ecdsa::SigningKey::from_bytes(scalar).map_err(|_| ())?,
+        ))
+    }
+
+    pub fn scheme(&self) -> u16 {
+        match self {
+            Self::Ed25519(_) => ED25519,
+            Self::EcdsaP256(_) => ECDSA_P256_SHA256,
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        use ed25519_dalek::Signer;
+        use p256::ecdsa::signature::Signer as _;
+
+        match self {
+            Self::Ed25519(x) => x.sign(message).to_bytes().to_vec(),
+            Self::EcdsaP256(x) => {
+                let signature: p256::ecdsa::Signature = x.sign(message);
+                signature.to_der().as_bytes().to_vec()
+            }
+        }
+    }
+}
+
+/// The public key of a certificate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PublicKey {
+    Ed25519([u8; 32]),
+    /// A uncompressed or compressed P-256 point.
+    EcdsaP256(Vec<u8>),
+    /// A key of a algorithm we dont support, like RSA.
+    Unsupported,
+}
+
+impl PublicKey {
+    /// Function checks that `signature` of `message` was made with `scheme` by the owner of
+    /// this key.
+    pub fn verify(&self, scheme: u16, message: &[u8], signature: &[u8]) -> Result<(), ()> {
+        use ed25519_dalek::Verifier;
+        use p256::ecdsa::signature::Verifier as _;
+
+        match (self, scheme) {
+            (Self::Ed25519(key), ED25519) => {
+                let key = ed25519_dalek::PublicKey::from_bytes(key).map_err(|_| ())?;
+                let signature = ed25519_dalek::Signature::try_from(signature).map_err(|_| ())?;
+                key.verify(message, &signature).map_err(|_| ())
+            }
+            (Self::EcdsaP256(key), ECDSA_P256_SHA256) => {
+                let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(key).map_err(|_| ())?;
+                let signature = p256::ecdsa::Signature::from_der(signature).map_err(|_| ())?;
+                key.verify(message, &signature).map_err(|_| ())
+            }
+            _ => Err(()),
+        }
+    }
+}
@@ -0,0 +1,809 @@
+//! The TLS 1.3 handshake (RFC 8446 4) as a state machine that doesnt do any io itself.
+//!
+//! Handshake messages go in along with the level they were received at, and what comes out are
+//! messages to send and the secrets protecting each level. The record layer of TLS and the
+//! crypto frames of QUIC both drive it.
+
+use super::codec;
+use super::codec::Reader;
+use super::crypto;
+use super::crypto::CipherSuite;
+use super::crypto::Hash;
+use super::crypto::KeyShare;
+use super::crypto::Transcript;
+use super::crypto::HASH_LEN;
+use super::x509;
+use super::x509::Certificate;
+use super::Alert;
+use super::ClientConfig;
+use super::ServerConfig;
+
+use crate::arch::random;
+use crate::net::wire::ipaddr::Ipv4Addr;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const NEW_SESSION_TICKET: u8 = 4;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const CERTIFICATE: u8 = 11;
+const CERTIFICATE_REQUEST: u8 = 13;
+const CERTIFICATE_VERIFY: u8 = 15;
+const FINISHED: u8 = 20;
+/// Key updates are handled by the record layer, QUIC doesnt have them.
+pub const KEY_UPDATE: u8 = 24;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+
+const TLS12: u16 = 0x0303;
+const TLS13: u16 = 0x0304;
+
+/// Biggest handshake message we are willing to buffer.
+const MAX_MESSAGE_LEN: usize = 1 << 16;
+
+/// Random of a ServerHello that is really a HelloRetryRequest.
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// The encryption levels handshake messages are sent at.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    /// Plaintext, the hellos.
+    Initial,
+    /// Protected with the handshake secrets.
+    Handshake,
+    /// Protected with the application secrets.
+    Application,
+}
+
+/// Things the owner of a handshake has to act on, in the order they come out.
+pub enum Event {
+    /// Handshake messages to send at a level.
+    Send(Level, Vec<u8>),
+    /// Secret protecting what we receive at a level from now on.
+    ReadSecret(Level, Hash),
+    /// Secret protecting what we send at a level from now on.
+    WriteSecret(Level, Hash),
+    /// The handshake is complete.
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    ClientStart,
+    WaitServerHello,
+    WaitEncryptedExtensions,
+    WaitCertificate,
+    WaitCertificateVerify,
+    WaitFinished,
+    WaitClientHello,
+    WaitClientFinished,
+    Done,
+}
+
+impl State {
+    /// Returns the level the next message has to arrive at.
+    fn level(self) -> Level {
+        match self {
+            Self::ClientStart | Self::WaitServerHello | Self::WaitClientHello => Level::Initial,
+            Self::Done => Level::Application,
+            _ => Level::Handshake,
+        }
+    }
+}
+
+enum Role {
+    /// A client connecting to `name`.
+    Client(Arc<ClientConfig>, String),
+    Server(Arc<ServerConfig>),
+}
+
+pub struct Handshake {
+    role: Role,
+    state: State,
+    transcript: Transcript,
+    suite: CipherSuite,
+    key_share: Option<KeyShare>,
+    /// Traffic secrets of the handshake level.
+    client_hs: Hash,
+    server_hs: Hash,
+    master: Hash,
+    /// Bytes of a message that hasnt been received in full yet.
+    pending: Vec<u8>,
+    /// Extensions we send on top of our own, in the ClientHello or the EncryptedExtensions.
+    extensions: Vec<(u16, Vec<u8>)>,
+    /// Extensions our peer sent that we dont handle ourselves.
+    peer_extensions: Vec<(u16, Vec<u8>)>,
+    alpn: Option<Vec<u8>>,
+    server_name: Option<String>,
+    peer_certs: Vec<Certificate>,
+    /// Index of the certificate we present, servers only.
+    cert: usize,
+    /// Context of a CertificateRequest, clients only.
+    cert_request: Option<Vec<u8>>,
+}
+
+impl Handshake {
+    fn new(role: Role, state: State) -> Self {
+        Self {
+            role,
+            state,
+            transcript: Transcript::new(),
+            suite: CipherSuite::Aes128GcmSha256,
+            key_share: None,
+            client_hs: [0; HASH_LEN],
+            server_hs: [0; HASH_LEN],
+            master: [0; HASH_LEN],
+            pending: Vec::new(),
+            extensions: Vec::new(),
+            peer_extensions: Vec::new(),
+            alpn: None,
+            server_name: None,
+            peer_certs: Vec::new(),
+            cert: 0,
+            cert_request: None,
+        }
+    }
+
+    /// Creates the handshake of a client connecting to `name`, a domain or a ip literal.
+    pub fn client(config: Arc<ClientConfig>, name: &str) -> Self {
+        Self::new(Role::Client(config, name.into()), State::ClientStart)
+    }
+
+    pub fn server(config: Arc<ServerConfig>) -> Self {
+        Self::new(Role::Server(config), State::WaitClientHello)
+    }
+
+    /// Adds a extension to our ClientHello, or to our EncryptedExtensions as a server.
+    pub fn add_extension(&mut self, id: u16, data: Vec<u8>) {
+        self.extensions.push((id, data));
+    }
+
+    /// Returns a extension our peer sent, for extensions the handshake doesnt know about.
+    pub fn peer_extension(&self, id: u16) -> Option<&[u8]> {
+        self.peer_extensions
+            .iter()
+            .find(|(x, _)| *x == id)
+            .map(|(_, x)| x.as_slice())
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Returns the protocol agreed on through ALPN.
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    /// Returns the name the client asked for through SNI, servers only.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Returns the certificate chain our peer presented, leaf first.
+    pub fn peer_certificates(&self) -> &[Certificate] {
+        &self.peer_certs
+    }
+
+    /// Function starts the handshake, clients send their hello.
+    pub fn start(&mut self) -> Result<Vec<Event>, Alert> {
+        if self.state != State::ClientStart {
+            return Ok(Vec::new());
+        }
+
+        let hello = self.client_hello();
+        self.transcript.update(&hello);
+        self.state = State::WaitServerHello;
+
+        Ok(vec![Event::Send(Level::Initial, hello)])
+    }
+
+    /// Function handles handshake bytes received at `level`, which may hold several messages
+    /// or only part of one.
+    pub fn handle(&mut self, level: Level, data: &[u8]) -> Result<Vec<Event>, Alert> {
+        if level != self.state.level() {
+            return Err(Alert::UnexpectedMessage);
+        }
+
+        self.pending.extend_from_slice(data);
+        let mut events = Vec::new();
+
+        while self.pending.len() >= 4 {
+            let len = Reader::new(&self.pending[1..4]).u24().unwrap_or(0);
+            if len > MAX_MESSAGE_LEN {
+                return Err(Alert::DecodeError);
+            }
+
+            if self.pending.len() < 4 + len {
+                break;
+            }
+
+            let message: Vec<u8> = self.pending.drain(..4 + len).collect();
+
+            // messages of a level never continue at the next one.
+            if self.state.level() != level {
+                return Err(Alert::UnexpectedMessage);
+            }
+
+            events.extend(self.message(message[0], &message)?);
+        }
+
+        Ok(events)
+    }
+
+    /// Function handles a single complete message, `message` includes its header.
+    fn message(&mut self, kind: u8, message: &[u8]) -> Result<Vec<Event>, Alert> {
+        let body = &message[4..];
+
+        match (self.state, kind) {
+            (State::WaitServerHello, SERVER_HELLO) => self.server_hello(message, body),
+            (State::WaitEncryptedExtensions, ENCRYPTED_EXTENSIONS) => {
+                self.encrypted_extensions(message, body)
+            }
+            (State::WaitCertificate, CERTIFICATE_REQUEST) if self.cert_request.is_none() => {
+                let mut r = Reader::new(body);
+                self.cert_request = Some(r.vec8().ok_or(Alert::DecodeError)?.to_vec());
+                self.transcript.update(message);
+                Ok(Vec::new())
+            }
+            (State::WaitCertificate, CERTIFICATE) => self.certificate(message, body),
+            (State::WaitCertificateVerify, CERTIFICATE_VERIFY) => {
+                self.certificate_verify(message, body)
+            }
+            (State::WaitFinished, FINISHED) => self.server_finished(message, body),
+            (State::WaitClientHello, CLIENT_HELLO) => self.client_hello_received(message, body),
+            (State::WaitClientFinished, FINISHED) => self.client_finished(message, body),
+            // we dont resume sessions, so tickets are of no use to us.
+            (State::Done, NEW_SESSION_TICKET) if self.is_client() => Ok(Vec::new()),
+            _ => Err(Alert::UnexpectedMessage),
+        }
+    }
+
+    fn is_client(&self) -> bool {
+        match self.role {
+            Role::Client(..) => true,
+            Role::Server(_) => false,
+        }
+    }
+
+    fn client_hello(&mut self) -> Vec<u8> {
+        let (config, name) = match &self.role {
+            Role::Client(config, name) => (config.clone(), name.clone()),
+            Role::Server(_) => unreachable!(),
+        };
+
+        let key_share = KeyShare::generate();
+        let mut client_random = [0; 32];
+        random::fill(&mut client_random);
+
+        let mut body = Vec::new();
+        codec::put_u16(&mut body, TLS12);
+        body.extend_from_slice(&client_random);
+        codec::put_vec8(&mut body, |_| {});
+        codec::put_vec16(&mut body, |x| {
+            for suite in CipherSuite::ALL.iter() {
+                codec::put_u16(x, suite.id());
+            }
+        });
+        codec::put_vec8(&mut body, |x| x.push(0));
+
+        codec::put_vec16(&mut body, |x| {
+            extension(x, EXT_SUPPORTED_VERSIONS, |x| {
+                codec::put_vec8(x, |x| codec::put_u16(x, TLS13))
+            });
+            extension(x, EXT_SUPPORTED_GROUPS, |x| {
+                codec::put_vec16(x, |x| codec::put_u16(x, crypto::X25519))
+            });
+            extension(x, EXT_SIGNATURE_ALGORITHMS, |x| {
+                codec::put_vec16(x, |x| {
+                    for scheme in crypto::SIGNATURE_SCHEMES.iter() {
+                        codec::put_u16(x, *scheme);
+                    }
+                })
+            });
+            extension(x, EXT_KEY_SHARE, |x| {
+                codec::put_vec16(x, |x| {
+                    codec::put_u16(x, crypto::X25519);
+                    codec::put_vec16(x, |x| x.extend_from_slice(&key_share.public()));
+                })
+            });
+
+            // ip literals arent allowed in SNI (RFC 6066 3).
+            if name.parse::<Ipv4Addr>().is_err() {
+                extension(x, EXT_SERVER_NAME, |x| {
+                    codec::put_vec16(x, |x| {
+                        codec::put_u8(x, 0);
+                        codec::put_vec16(x, |x| x.extend_from_slice(name.as_bytes()));
+                    })
+                });
+            }
+
+            if !config.alpn.is_empty() {
+                extension(x, EXT_ALPN, |x| alpn_list(x, &config.alpn));
+            }
+
+            for (id, data) in self.extensions.iter() {
+                extension(x, *id, |x| x.extend_from_slice(data));
+            }
+        });
+
+        self.key_share = Some(key_share);
+        encode(CLIENT_HELLO, &body)
+    }
+
+    fn server_hello(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let mut r = Reader::new(body);
+        r.u16().ok_or(Alert::DecodeError)?;
+        let server_random = r.bytes(32).ok_or(Alert::DecodeError)?;
+        r.vec8().ok_or(Alert::DecodeError)?;
+        let suite = r.u16().ok_or(Alert::DecodeError)?;
+        r.u8().ok_or(Alert::DecodeError)?;
+        let extensions = parse_extensions(r.vec16().ok_or(Alert::DecodeError)?)?;
+
+        // we only ever offer x25519, so a retry could only ask for something we dont have.
+        if server_random == HELLO_RETRY_RANDOM {
+            return Err(Alert::HandshakeFailure);
+        }
+
+        let version = find(&extensions, EXT_SUPPORTED_VERSIONS).ok_or(Alert::ProtocolVersion)?;
+        if version != TLS13.to_be_bytes() {
+            return Err(Alert::ProtocolVersion);
+        }
+
+        self.suite = CipherSuite::from_id(suite).ok_or(Alert::IllegalParameter)?;
+
+        let mut share =
+            Reader::new(find(&extensions, EXT_KEY_SHARE).ok_or(Alert::MissingExtension)?);
+        if share.u16() != Some(crypto::X25519) {
+            return Err(Alert::IllegalParameter);
+        }
+
+        let peer = share.vec16().ok_or(Alert::DecodeError)?;
+        let shared = self
+            .key_share
+            .take()
+            .ok_or(Alert::InternalError)?
+            .agree(peer)
+            .map_err(|_| Alert::IllegalParameter)?;
+
+        self.transcript.update(message);
+        self.handshake_secrets(&shared);
+        self.state = State::WaitEncryptedExtensions;
+
+        Ok(vec![
+            Event::ReadSecret(Level::Handshake, self.server_hs),
+            Event::WriteSecret(Level::Handshake, self.client_hs),
+        ])
+    }
+
+    fn encrypted_extensions(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let mut r = Reader::new(body);
+        let extensions = parse_extensions(r.vec16().ok_or(Alert::DecodeError)?)?;
+
+        if let Some(data) = find(&extensions, EXT_ALPN) {
+            let protocol = Reader::new(data)
+                .vec16()
+                .and_then(|x| Reader::new(x).vec8())
+                .ok_or(Alert::DecodeError)?;
+
+            let offered = match &self.role {
+                Role::Client(config, _) => config.alpn.iter().any(|x| x.as_slice() == protocol),
+                Role::Server(_) => false,
+            };
+
+            if !offered {
+                return Err(Alert::IllegalParameter);
+            }
+
+            self.alpn = Some(protocol.to_vec());
+        }
+
+        self.keep_extensions(extensions);
+        self.transcript.update(message);
+        self.state = State::WaitCertificate;
+
+        Ok(Vec::new())
+    }
+
+    fn certificate(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let mut r = Reader::new(body);
+        r.vec8().ok_or(Alert::DecodeError)?;
+        let mut list = Reader::new(r.vec24().ok_or(Alert::DecodeError)?);
+
+        let mut certs = Vec::new();
+        while !list.is_empty() {
+            let der = list.vec24().ok_or(Alert::DecodeError)?;
+            list.vec16().ok_or(Alert::DecodeError)?;
+            certs.push(Certificate::from_der(der).map_err(|_| Alert::BadCertificate)?);
+        }
+
+        if certs.is_empty() {
+            return Err(Alert::CertificateRequired);
+        }
+
+        if let Role::Client(config, name) = &self.role {
+            if config.verify && x509::verify_chain(&certs, &config.roots, name).is_err() {
+                return Err(Alert::BadCertificate);
+            }
+        }
+
+        self.peer_certs = certs;
+        self.transcript.update(message);
+        self.state = State::WaitCertificateVerify;
+
+        Ok(Vec::new())
+    }
+
+    fn certificate_verify(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let mut r = Reader::new(body);
+        let scheme = r.u16().ok_or(Alert::DecodeError)?;
+        let signature = r.vec16().ok_or(Alert::DecodeError)?;
+
+        if !crypto::SIGNATURE_SCHEMES.contains(&scheme) {
+            return Err(Alert::IllegalParameter);
+        }
+
+        let content = verify_content(
+            b"TLS 1.3, server CertificateVerify",
+            &self.transcript.hash(),
+        );
+        self.peer_certs[0]
+            .public_key()
+            .verify(scheme, &content, signature)
+            .map_err(|_| Alert::DecryptError)?;
+
+        self.transcript.update(message);
+        self.state = State::WaitFinished;
+
+        Ok(Vec::new())
+    }
+
+    fn server_finished(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let expected = crypto::finished(&self.server_hs, &self.transcript.hash());
+        if !constant_time_eq(&expected, body) {
+            return Err(Alert::DecryptError);
+        }
+
+        self.transcript.update(message);
+        let (client_ap, server_ap) = self.application_secrets();
+
+        // we dont have client certificates, a empty one tells the server so.
+        let mut flight = Vec::new();
+        if let Some(context) = self.cert_request.take() {
+            let mut body = Vec::new();
+            codec::put_vec8(&mut body, |x| x.extend_from_slice(&context));
+            codec::put_vec24(&mut body, |_| {});
+
+            let certificate = encode(CERTIFICATE, &body);
+            self.transcript.update(&certificate);
+            flight.extend_from_slice(&certificate);
+        }
+
+        let finished = encode(
+            FINISHED,
+            &crypto::finished(&self.client_hs, &self.transcript.hash()),
+        );
+        self.transcript.update(&finished);
+        flight.extend_from_slice(&finished);
+
+        self.state = State::Done;
+
+        Ok(vec![
+            Event::ReadSecret(Level::Application, server_ap),
+            Event::Send(Level::Handshake, flight),
+            Event::WriteSecret(Level::Application, client_ap),
+            Event::Done,
+        ])
+    }
+
+    fn client_hello_received(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let config = match &self.role {
+            Role::Server(config) => config.clone(),
+            Role::Client(..) => return Err(Alert::UnexpectedMessage),
+        };
+
+        let mut r = Reader::new(body);
+        r.u16().ok_or(Alert::DecodeError)?;
+        r.bytes(32).ok_or(Alert::DecodeError)?;
+        let session_id = r.vec8().ok_or(Alert::DecodeError)?;
+        let suites = r.vec16().ok_or(Alert::DecodeError)?;
+        r.vec8().ok_or(Alert::DecodeError)?;
+        let extensions = parse_extensions(r.vec16().ok_or(Alert::DecodeError)?)?;
+
+        let versions = find(&extensions, EXT_SUPPORTED_VERSIONS).ok_or(Alert::ProtocolVersion)?;
+        if !u16_list(Reader::new(versions).vec8()).contains(&TLS13) {
+            return Err(Alert::ProtocolVersion);
+        }
+
+        let suites = u16_list(Some(suites));
+        self.suite = CipherSuite::ALL
+            .iter()
+            .cloned()
+            .find(|x| suites.contains(&x.id()))
+            .ok_or(Alert::HandshakeFailure)?;
+
+        // clients that dont send a x25519 share would need a HelloRetryRequest, which we dont do.
+        let peer_share = {
+            let data = find(&extensions, EXT_KEY_SHARE).ok_or(Alert::MissingExtension)?;
+            let mut shares = Reader::new(Reader::new(data).vec16().ok_or(Alert::DecodeError)?);
+            let mut found = None;
+
+            while !shares.is_empty() {
+                let group = shares.u16().ok_or(Alert::DecodeError)?;
+                let key = shares.vec16().ok_or(Alert::DecodeError)?;
+                if group == crypto::X25519 {
+                    found = Some(key);
+                }
+            }
+
+            found.ok_or(Alert::HandshakeFailure)?
+        };
+
+        if let Some(data) = find(&extensions, EXT_SERVER_NAME) {
+            let mut names = Reader::new(Reader::new(data).vec16().ok_or(Alert::DecodeError)?);
+            while !names.is_empty() {
+                let kind = names.u8().ok_or(Alert::DecodeError)?;
+                let name = names.vec16().ok_or(Alert::DecodeError)?;
+                if kind == 0 {
+                    let name = core::str::from_utf8(name).map_err(|_| Alert::DecodeError)?;
+                    self.server_name = Some(name.into());
+                }
+            }
+        }
+
+        let schemes = u16_list(
+            find(&extensions, EXT_SIGNATURE_ALGORITHMS).and_then(|x| Reader::new(x).vec16()),
+        );
+        self.cert = config
+            .select(self.server_name.as_deref(), &schemes)
+            .ok_or(Alert::HandshakeFailure)?;
+
+        if let Some(data) = find(&extensions, EXT_ALPN) {
+            if !config.alpn.is_empty() {
+                let mut offered = Reader::new(Reader::new(data).vec16().ok_or(Alert::DecodeError)?);
+                let mut protocols = Vec::new();
+                while !offered.is_empty() {
+                    protocols.push(offered.vec8().ok_or(Alert::DecodeError)?);
+                }
+
+                let chosen = config
+                    .alpn
+                    .iter()
+                    .find(|x| protocols.contains(&x.as_slice()))
+                    .ok_or(Alert::NoApplicationProtocol)?;
+                self.alpn = Some(chosen.clone());
+            }
+        }
+
+        let key_share = KeyShare::generate();
+        let shared = key_share
+            .agree(peer_share)
+            .map_err(|_| Alert::IllegalParameter)?;
+
+        self.keep_extensions(extensions);
+        self.transcript.update(message);
+
+        let mut server_random = [0; 32];
+        random::fill(&mut server_random);
+
+        let mut body = Vec::new();
+        codec::put_u16(&mut body, TLS12);
+        body.extend_from_slice(&server_random);
+        codec::put_vec8(&mut body, |x| x.extend_from_slice(session_id));
+        codec::put_u16(&mut body, self.suite.id());
+        codec::put_u8(&mut body, 0);
+        codec::put_vec16(&mut body, |x| {
+            extension(x, EXT_SUPPORTED_VERSIONS, |x| codec::put_u16(x, TLS13));
+            extension(x, EXT_KEY_SHARE, |x| {
+                codec::put_u16(x, crypto::X25519);
+                codec::put_vec16(x, |x| x.extend_from_slice(&key_share.public()));
+            });
+        });
+
+        let hello = encode(SERVER_HELLO, &body);
+        self.transcript.update(&hello);
+        self.handshake_secrets(&shared);
+
+        let flight = self.server_flight(&config);
+        let (_, server_ap) = self.application_secrets();
+        self.state = State::WaitClientFinished;
+
+        Ok(vec![
+            Event::Send(Level::Initial, hello),
+            Event::WriteSecret(Level::Handshake, self.server_hs),
+            Event::ReadSecret(Level::Handshake, self.client_hs),
+            Event::Send(Level::Handshake, flight),
+            Event::WriteSecret(Level::Application, server_ap),
+        ])
+    }
+
+    /// Returns the EncryptedExtensions, Certificate, CertificateVerify and Finished of a server.
+    fn server_flight(&mut self, config: &ServerConfig) -> Vec<u8> {
+        let (chain, key) = config.certificate(self.cert);
+        let mut flight = Vec::new();
+
+        let mut body = Vec::new();
+        codec::put_vec16(&mut body, |x| {
+            if let Some(protocol) = &self.alpn {
+                extension(x, EXT_ALPN, |x| {
+                    alpn_list(x, core::slice::from_ref(protocol))
+                });
+            }
+
+            for (id, data) in self.extensions.iter() {
+                extension(x, *id, |x| x.extend_from_slice(data));
+            }
+        });
+        flight.extend_from_slice(&encode(ENCRYPTED_EXTENSIONS, &body));
+
+        let mut body = Vec::new();
+        codec::put_vec8(&mut body, |_| {});
+        codec::put_vec24(&mut body, |x| {
+            for cert in chain {
+                codec::put_vec24(x, |x| x.extend_from_slice(cert.der()));
+                codec::put_vec16(x, |_| {});
+            }
+        });
+        flight.extend_from_slice(&encode(CERTIFICATE, &body));
+        self.transcript.update(&flight);
+
+        let content = verify_content(
+            b"TLS 1.3, server CertificateVerify",
+            &self.transcript.hash(),
+        );
+        let mut body = Vec::new();
+        codec::put_u16(&mut body, key.scheme());
+        codec::put_vec16(&mut body, |x| x.extend_from_slice(&key.sign(&content)));
+        let verify = encode(CERTIFICATE_VERIFY, &body);
+        self.transcript.update(&verify);
+        flight.extend_from_slice(&verify);
+
+        let finished = encode(
+            FINISHED,
+            &crypto::finished(&self.server_hs, &self.transcript.hash()),
+        );
+        self.transcript.update(&finished);
+        flight.extend_from_slice(&finished);
+
+        flight
+    }
+
+    fn client_finished(&mut self, message: &[u8], body: &[u8]) -> Result<Vec<Event>, Alert> {
+        let expected = crypto::finished(&self.client_hs, &self.transcript.hash());
+        if !constant_time_eq(&expected, body) {
+            return Err(Alert::DecryptError);
+        }
+
+        // the application secrets only cover the transcript up to the Finished of the server.
+        let (client_ap, _) = self.application_secrets();
+        self.transcript.update(message);
+        self.state = State::Done;
+
+        Ok(vec![
+            Event::ReadSecret(Level::Application, client_ap),
+            Event::Done,
+        ])
+    }
+
+    fn handshake_secrets(&mut self, shared: &[u8]) {
+        let empty = crypto::sha256(&[]);
+        let early = crypto::hkdf_extract(&[0; HASH_LEN], &[0; HASH_LEN]);
+        let derived = crypto::derive_secret(&early, b"derived", &empty);
+        let handshake = crypto::hkdf_extract(&derived, shared);
+
+        let hash = self.transcript.hash();
+        self.client_hs = crypto::derive_secret(&handshake, b"c hs traffic", &hash);
+        self.server_hs = crypto::derive_secret(&handshake, b"s hs traffic", &hash);
+
+        let derived = crypto::derive_secret(&handshake, b"derived", &empty);
+        self.master = crypto::hkdf_extract(&derived, &[0; HASH_LEN]);
+    }
+
+    /// Returns the client and server application secrets, the transcript has to end with the
+    /// Finished of the server.
+    fn application_secrets(&self) -> (Hash, Hash) {
+        let hash = self.transcript.hash();
+        (
+            crypto::derive_secret(&self.master, b"c ap traffic", &hash),
+            crypto::derive_secret(&self.master, b"s ap traffic", &hash),
+        )
+    }
+
+    /// Keeps the extensions we dont handle ourselves around for `peer_extension`.
+    fn keep_extensions(&mut self, extensions: Vec<(u16, &[u8])>) {
+        const HANDLED: [u16; 6] = [
+            EXT_SERVER_NAME,
+            EXT_SUPPORTED_GROUPS,
+            EXT_SIGNATURE_ALGORITHMS,
+            EXT_ALPN,
+            EXT_SUPPORTED_VERSIONS,
+            EXT_KEY_SHARE,
+        ];
+
+        self.peer_extensions = extensions
+            .into_iter()
+            .filter(|(id, _)| !HANDLED.contains(id))
+            .map(|(id, data)| (id, data.to_vec()))
+            .collect();
+    }
+}
+
+/// Returns a handshake message of `kind` with `body`.
+fn encode(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    codec::put_u8(&mut message, kind);
+    codec::put_vec24(&mut message, |x| x.extend_from_slice(body));
+    message
+}
+
+fn extension(buf: &mut Vec<u8>, id: u16, f: impl FnOnce(&mut Vec<u8>)) {
+    codec::put_u16(buf, id);
+    codec::put_vec16(buf, f);
+}
+
+fn alpn_list(buf: &mut Vec<u8>, protocols: &[Vec<u8>]) {
+    codec::put_vec16(buf, |x| {
+        for protocol in protocols {
+            codec::put_vec8(x, |x| x.extend_from_slice(protocol));
+        }
+    });
+}
+
+fn parse_extensions(data: &[u8]) -> Result<Vec<(u16, &[u8])>, Alert> {
+    let mut r = Reader::new(data);
+    let mut extensions: Vec<(u16, &[u8])> = Vec::new();
+
+    while !r.is_empty() {
+        let id = r.u16().ok_or(Alert::DecodeError)?;
+        let data = r.vec16().ok_or(Alert::DecodeError)?;
+
+        if extensions.iter().any(|(x, _)| *x == id) {
+            return Err(Alert::IllegalParameter);
+        }
+        extensions.push((id, data));
+    }
+
+    Ok(extensions)
+}
+
+fn find<'a>(extensions: &[(u16, &'a [u8])], id: u16) -> Option<&'a [u8]> {
+    extensions.iter().find(|(x, _)| *x == id).map(|(_, x)| *x)
+}
+
+/// Parses a list of u16s, a missing or broken list is a empty one.
+fn u16_list(data: Option<&[u8]>) -> Vec<u16> {
+    let mut r = Reader::new(data.unwrap_or(&[]));
+    let mut list = Vec::new();
+
+    while let Some(x) = r.u16() {
+        list.push(x);
+    }
+
+    list
+}
+
+/// Returns what the signature of a CertificateVerify covers (RFC 8446 4.4.3).
+fn verify_content(context: &[u8], hash: &Hash) -> Vec<u8> {
+    let mut content = vec![0x20; 64];
+    content.extend_from_slice(context);
+    content.push(0);
+    content.extend_from_slice(hash);
+    content
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
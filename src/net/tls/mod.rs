@@ -0,0 +1,645 @@
+//! TLS 1.3 (RFC 8446) on top of our tcp streams.
+//!
+//! Only the parts of the protocol most peers need are here. Keys are exchanged with x25519,
+//! records are protected with AES-128-GCM or ChaCha20-Poly1305, and certificates are signed with
+//! Ed25519 or ECDSA P-256. There is no session resumption, early data, HelloRetryRequest or
+//! client authentication. A server asking for a client certificate gets an empty one.
+//!
+//! ```ignore
+//! let config = ClientConfig::new().add_root_certificates(ROOT_PEM)?;
+//! let tcp = TcpStream::connect("10.0.0.1:443").await?;
+//! let mut tls = TlsConnector::new(config).connect("example.com", tcp).await?;
+//! tls.write(b"hello").await;
+//! ```
+mod codec;
+mod crypto;
+mod handshake;
+/// Certificate and key loading
+pub mod x509;
+
+pub(crate) use crypto::hkdf_expand_label;
+pub(crate) use crypto::hkdf_extract;
+pub(crate) use crypto::Aead;
+pub(crate) use crypto::CipherSuite;
+pub(crate) use crypto::Hash;
+pub(crate) use handshake::Event;
+pub(crate) use handshake::Handshake;
+pub(crate) use handshake::Level;
+
+pub use x509::Certificate;
+
+use crypto::SigningKey;
+use crypto::TrafficKeys;
+use crypto::TAG_LEN;
+
+use crate::net::addr::SocketAddr;
+use crate::net::socks::TcpStream;
+use crate::prelude::*;
+use crate::sync::Arc;
+
+use core::fmt;
+
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+const HEADER_LEN: usize = 5;
+/// Biggest plaintext a record can carry.
+const MAX_FRAGMENT_LEN: usize = 1 << 14;
+/// Biggest protected record, the plaintext plus its content type, padding and tag.
+const MAX_CIPHERTEXT_LEN: usize = MAX_FRAGMENT_LEN + 256;
+
+const ALERT_WARNING: u8 = 1;
+const ALERT_FATAL: u8 = 2;
+
+/// Alert descriptions (RFC 8446 6).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Alert {
+    CloseNotify,
+    UnexpectedMessage,
+    BadRecordMac,
+    RecordOverflow,
+    HandshakeFailure,
+    BadCertificate,
+    UnsupportedCertificate,
+    CertificateUnknown,
+    IllegalParameter,
+    DecodeError,
+    DecryptError,
+    ProtocolVersion,
+    InternalError,
+    MissingExtension,
+    UnrecognizedName,
+    CertificateRequired,
+    NoApplicationProtocol,
+    Other(u8),
+}
+
+impl Alert {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::CloseNotify => 0,
+            Self::UnexpectedMessage => 10,
+            Self::BadRecordMac => 20,
+            Self::RecordOverflow => 22,
+            Self::HandshakeFailure => 40,
+            Self::BadCertificate => 42,
+            Self::UnsupportedCertificate => 43,
+            Self::CertificateUnknown => 46,
+            Self::IllegalParameter => 47,
+            Self::DecodeError => 50,
+            Self::DecryptError => 51,
+            Self::ProtocolVersion => 70,
+            Self::InternalError => 80,
+            Self::MissingExtension => 109,
+            Self::UnrecognizedName => 112,
+            Self::CertificateRequired => 116,
+            Self::NoApplicationProtocol => 120,
+            Self::Other(x) => x,
+        }
+    }
+}
+
+impl From<u8> for Alert {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => Self::CloseNotify,
+            10 => Self::UnexpectedMessage,
+            20 => Self::BadRecordMac,
+            22 => Self::RecordOverflow,
+            40 => Self::HandshakeFailure,
+            42 => Self::BadCertificate,
+            43 => Self::UnsupportedCertificate,
+            46 => Self::CertificateUnknown,
+            47 => Self::IllegalParameter,
+            50 => Self::DecodeError,
+            51 => Self::DecryptError,
+            70 => Self::ProtocolVersion,
+            80 => Self::InternalError,
+            109 => Self::MissingExtension,
+            112 => Self::UnrecognizedName,
+            116 => Self::CertificateRequired,
+            120 => Self::NoApplicationProtocol,
+            x => Self::Other(x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The connection was closed before the handshake finished.
+    Closed,
+    /// We gave up on the connection and told our peer why with this alert.
+    Alert(Alert),
+    /// Our peer gave up on the connection with this alert.
+    PeerAlert(Alert),
+    /// A certificate or key couldnt be loaded.
+    InvalidCertificate,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection closed"),
+            Self::Alert(x) => write!(f, "sent alert {:?}", x),
+            Self::PeerAlert(x) => write!(f, "received alert {:?}", x),
+            Self::InvalidCertificate => write!(f, "invalid certificate or key"),
+        }
+    }
+}
+
+impl From<Alert> for Error {
+    fn from(alert: Alert) -> Self {
+        Self::Alert(alert)
+    }
+}
+
+/// Certificates, keys and protocols of a server.
+pub struct ServerConfig {
+    /// Certificate chains and their keys, the first one is presented when SNI doesnt match any.
+    certs: Vec<(Vec<Certificate>, SigningKey)>,
+    pub(crate) alpn: Vec<Vec<u8>>,
+}
+
+impl ServerConfig {
+    /// Creates a config presenting the certificate chain in `chain`, leaf first, signed with
+    /// `key`. Both can be PEM or DER, keys as PKCS#8 or SEC1.
+    pub fn new(chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        Self {
+            certs: Vec::new(),
+            alpn: Vec::new(),
+        }
+        .add_certificate(chain, key)
+    }
+
+    /// Adds another certificate chain, presented to clients asking for one of its names
+    /// through SNI.
+    pub fn add_certificate(mut self, chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let chain = x509::load_certs(chain).map_err(|_| Error::InvalidCertificate)?;
+        let key = x509::load_key(key).map_err(|_| Error::InvalidCertificate)?;
+
+        if chain.is_empty() {
+            return Err(Error::InvalidCertificate);
+        }
+
+        self.certs.push((chain, key));
+        Ok(self)
+    }
+
+    /// Sets the protocols we speak through ALPN, most preferred first. Clients offering none of
+    /// them are turned away.
+    pub fn with_alpn(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn = protocols.iter().map(|x| x.to_vec()).collect();
+        self
+    }
+
+    /// Returns the certificate to present to a client asking for `name`, which can verify the
+    /// signature `schemes`.
+    pub(crate) fn select(&self, name: Option<&str>, schemes: &[u16]) -> Option<usize> {
+        let usable = |i: &usize| schemes.contains(&self.certs[*i].1.scheme());
+
+        name.and_then(|name| {
+            (0..self.certs.len())
+                .filter(usable)
+                .find(|i| self.certs[*i].0[0].matches_name(name))
+        })
+        .or_else(|| (0..self.certs.len()).find(usable))
+    }
+
+    pub(crate) fn certificate(&self, index: usize) -> (&[Certificate], &SigningKey) {
+        let (chain, key) = &self.certs[index];
+        (chain, key)
+    }
+}
+
+/// Trusted roots and protocols of a client.
+pub struct ClientConfig {
+    pub(crate) roots: Vec<Certificate>,
+    pub(crate) alpn: Vec<Vec<u8>>,
+    pub(crate) verify: bool,
+}
+
+impl ClientConfig {
+    /// Creates a config without any trusted roots, add some with `add_root_certificates`.
+    pub fn new() -> Self {
+        Self {
+            roots: Vec::new(),
+            alpn: Vec::new(),
+            verify: true,
+        }
+    }
+
+    /// Trusts the certificates in `data`, PEM or a single DER certificate.
+    pub fn add_root_certificates(mut self, data: &[u8]) -> Result<Self, Error> {
+        let certs = x509::load_certs(data).map_err(|_| Error::InvalidCertificate)?;
+        self.roots.extend(certs);
+        Ok(self)
+    }
+
+    /// Sets the protocols we offer through ALPN, most preferred first.
+    pub fn with_alpn(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn = protocols.iter().map(|x| x.to_vec()).collect();
+        self
+    }
+
+    /// Accepts any certificate the server presents. Only the signature proving the server owns
+    /// the certificate is still checked, so anyone in the path can read the connection.
+    pub fn dangerous_disable_verification(mut self) -> Self {
+        self.verify = false;
+        self
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accepts TLS connections on tcp streams with a shared config.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Function runs the server side of the handshake on `stream`.
+    pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream, Error> {
+        let mut stream = TlsStream::new(stream, Handshake::server(self.config.clone()));
+        stream.handshake().await?;
+        Ok(stream)
+    }
+}
+
+/// Opens TLS connections on tcp streams with a shared config.
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Function runs the client side of the handshake on `stream`. `domain` is sent through SNI
+    /// and has to be one of the names of the server certificate, it can also be a ip address.
+    pub async fn connect(&self, domain: &str, stream: TcpStream) -> Result<TlsStream, Error> {
+        let mut stream = TlsStream::new(stream, Handshake::client(self.config.clone(), domain));
+        stream.handshake().await?;
+        Ok(stream)
+    }
+}
+
+/// A TLS connection over a tcp stream.
+pub struct TlsStream {
+    tcp: TcpStream,
+    handshake: Handshake,
+    read_level: Level,
+    read_keys: Option<TrafficKeys>,
+    write_keys: Option<TrafficKeys>,
+    read_secret: Hash,
+    write_secret: Hash,
+    /// Bytes received that dont make up a whole record yet.
+    incoming: Vec<u8>,
+    /// Application data that hasnt been read yet.
+    plaintext: Vec<u8>,
+    /// Post handshake messages that dont make up a whole message yet.
+    messages: Vec<u8>,
+    /// Whether the connection is done receiving, after a close_notify or a error.
+    closed: bool,
+    /// Whether we sent a close_notify or a fatal alert.
+    sent_close: bool,
+    error: Option<Error>,
+}
+
+impl TlsStream {
+    fn new(tcp: TcpStream, handshake: Handshake) -> Self {
+        Self {
+            tcp,
+            handshake,
+            read_level: Level::Initial,
+            read_keys: None,
+            write_keys: None,
+            read_secret: [0; crypto::HASH_LEN],
+            write_secret: [0; crypto::HASH_LEN],
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            messages: Vec::new(),
+            closed: false,
+            sent_close: false,
+            error: None,
+        }
+    }
+
+    /// Returns the protocol agreed on through ALPN.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.handshake.alpn()
+    }
+
+    /// Returns the name the client asked for through SNI, on the server side.
+    pub fn server_name(&self) -> Option<&str> {
+        self.handshake.server_name()
+    }
+
+    /// Returns the certificate chain of the server, on the client side.
+    pub fn peer_certificates(&self) -> &[Certificate] {
+        self.handshake.peer_certificates()
+    }
+
+    /// Returns the error that ended the connection, if it didnt end cleanly.
+    pub fn error(&self) -> Option<Error> {
+        self.error
+    }
+
+    pub async fn local_addr(&self) -> SocketAddr {
+        self.tcp.local_addr().await
+    }
+
+    pub async fn peer_addr(&self) -> SocketAddr {
+        self.tcp.peer_addr().await
+    }
+
+    /// Function reads application data into `buffer`, returns 0 once the connection is closed.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> usize {
+        loop {
+            if !self.plaintext.is_empty() {
+                let len = buffer.len().min(self.plaintext.len());
+                buffer[..len].copy_from_slice(&self.plaintext[..len]);
+                self.plaintext.drain(..len);
+                return len;
+            }
+
+            if self.closed {
+                return 0;
+            }
+
+            let result = match self.read_record().await {
+                Ok(Some((APPLICATION_DATA, data))) if self.handshake.is_done() => {
+                    self.plaintext = data;
+                    Ok(())
+                }
+                Ok(Some((HANDSHAKE, data))) => self.post_handshake(&data).await,
+                Ok(Some((ALERT, data))) => Self::alert_received(&data),
+                Ok(Some(_)) => Err(Alert::UnexpectedMessage.into()),
+                Ok(None) => Err(Error::Closed),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                self.fail(e).await;
+            }
+        }
+    }
+
+    /// Function writes `item` as application data.
+    pub async fn write(&mut self, item: &[u8]) {
+        if self.sent_close {
+            return;
+        }
+
+        self.write_record(APPLICATION_DATA, item).await;
+    }
+
+    /// Function tells our peer we wont send anything else, and closes our side of the tcp
+    /// stream. We can still read what it sends until it closes its side.
+    pub async fn close(&mut self) {
+        if !self.sent_close {
+            self.send_alert(ALERT_WARNING, Alert::CloseNotify).await;
+        }
+
+        self.tcp.shutdown().await;
+    }
+
+    async fn handshake(&mut self) -> Result<(), Error> {
+        if let Err(e) = self.drive().await {
+            self.fail(e).await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn drive(&mut self) -> Result<(), Error> {
+        let events = self.handshake.start()?;
+        self.apply(events).await;
+
+        while !self.handshake.is_done() {
+            let (kind, data) = self.read_record().await?.ok_or(Error::Closed)?;
+
+            match kind {
+                HANDSHAKE => {
+                    let events = self.handshake.handle(self.read_level, &data)?;
+                    self.apply(events).await;
+                }
+                ALERT => Self::alert_received(&data)?,
+                _ => return Err(Alert::UnexpectedMessage.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn apply(&mut self, events: Vec<Event>) {
+        let suite = self.handshake.suite();
+
+        for event in events {
+            match event {
+                Event::Send(_, data) => self.write_record(HANDSHAKE, &data).await,
+                Event::ReadSecret(level, secret) => {
+                    self.read_level = level;
+                    self.read_keys = Some(TrafficKeys::new(suite, &secret));
+                    self.read_secret = secret;
+                }
+                Event::WriteSecret(_, secret) => {
+                    self.write_keys = Some(TrafficKeys::new(suite, &secret));
+                    self.write_secret = secret;
+                }
+                Event::Done => {}
+            }
+        }
+    }
+
+    /// Function handles handshake messages after the handshake, key updates and tickets.
+    async fn post_handshake(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.messages.extend_from_slice(data);
+
+        while self.messages.len() >= 4 {
+            let len = codec::Reader::new(&self.messages[1..4]).u24().unwrap_or(0);
+            if self.messages.len() < 4 + len {
+                break;
+            }
+
+            let message: Vec<u8> = self.messages.drain(..4 + len).collect();
+            if message[0] != handshake::KEY_UPDATE {
+                self.handshake.handle(Level::Application, &message)?;
+                continue;
+            }
+
+            let requested = match &message[4..] {
+                [0] => false,
+                [1] => true,
+                _ => return Err(Alert::DecodeError.into()),
+            };
+
+            let suite = self.handshake.suite();
+            self.read_secret = crypto::next_secret(&self.read_secret);
+            self.read_keys = Some(TrafficKeys::new(suite, &self.read_secret));
+
+            if requested && !self.sent_close {
+                self.write_record(HANDSHAKE, &[handshake::KEY_UPDATE, 0, 0, 1, 0])
+                    .await;
+                self.write_secret = crypto::next_secret(&self.write_secret);
+                self.write_keys = Some(TrafficKeys::new(suite, &self.write_secret));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the error a received alert ends the connection with, close_notify ends it
+    /// cleanly.
+    fn alert_received(data: &[u8]) -> Result<(), Error> {
+        match data {
+            [_, 0] => Err(Error::Closed),
+            [_, code] => Err(Error::PeerAlert(Alert::from(*code))),
+            _ => Err(Alert::DecodeError.into()),
+        }
+    }
+
+    /// Function ends the connection because of `error`, telling our peer if it was our call.
+    async fn fail(&mut self, error: Error) {
+        self.closed = true;
+
+        match error {
+            Error::Closed => {}
+            Error::Alert(alert) => {
+                self.error = Some(error);
+                if !self.sent_close {
+                    self.send_alert(ALERT_FATAL, alert).await;
+                }
+            }
+            _ => self.error = Some(error),
+        }
+    }
+
+    async fn send_alert(&mut self, level: u8, alert: Alert) {
+        self.write_record(ALERT, &[level, alert.code()]).await;
+        self.sent_close = true;
+    }
+
+    /// Function reads the next record that isnt a change_cipher_spec, returns its content type
+    /// and plaintext, or `None` if the tcp stream closed.
+    async fn read_record(&mut self) -> Result<Option<(u8, Vec<u8>)>, Error> {
+        loop {
+            while self.incoming.len() < HEADER_LEN
+                || self.incoming.len() < HEADER_LEN + self.record_len()
+            {
+                if self.incoming.len() >= HEADER_LEN && self.record_len() > MAX_CIPHERTEXT_LEN {
+                    return Err(Alert::RecordOverflow.into());
+                }
+
+                let mut buf = [0; 4096];
+                let len = self.tcp.read(&mut buf).await;
+                if len == 0 {
+                    return Ok(None);
+                }
+
+                self.incoming.extend_from_slice(&buf[..len]);
+            }
+
+            let len = self.record_len();
+            let record: Vec<u8> = self.incoming.drain(..HEADER_LEN + len).collect();
+            let (header, body) = record.split_at(HEADER_LEN);
+
+            // middlebox compatibility records, they dont mean anything in TLS 1.3.
+            if header[0] == CHANGE_CIPHER_SPEC && !self.handshake.is_done() {
+                continue;
+            }
+
+            let keys = match self.read_keys.as_mut() {
+                Some(keys) => keys,
+                None if header[0] == HANDSHAKE || header[0] == ALERT => {
+                    return Ok(Some((header[0], body.to_vec())));
+                }
+                None => return Err(Alert::UnexpectedMessage.into()),
+            };
+
+            if header[0] != APPLICATION_DATA {
+                return Err(Alert::UnexpectedMessage.into());
+            }
+
+            let mut data = body.to_vec();
+            keys.open(header, &mut data)
+                .map_err(|_| Error::Alert(Alert::BadRecordMac))?;
+
+            // the content type is the last byte that isnt padding.
+            let kind = loop {
+                match data.pop() {
+                    Some(0) => continue,
+                    Some(kind) => break kind,
+                    None => return Err(Alert::UnexpectedMessage.into()),
+                }
+            };
+
+            if data.len() > MAX_FRAGMENT_LEN {
+                return Err(Alert::RecordOverflow.into());
+            }
+
+            // empty records are only allowed for application data.
+            if data.is_empty() && kind != APPLICATION_DATA {
+                return Err(Alert::UnexpectedMessage.into());
+            }
+
+            if !data.is_empty() || kind != APPLICATION_DATA {
+                return Ok(Some((kind, data)));
+            }
+        }
+    }
+
+    /// Returns the length of the record at the start of `incoming`.
+    fn record_len(&self) -> usize {
+        u16::from_be_bytes([self.incoming[3], self.incoming[4]]) as usize
+    }
+
+    /// Function sends `data` as records of `kind`, protected once we have keys.
+    async fn write_record(&mut self, kind: u8, data: &[u8]) {
+        for chunk in data.chunks(MAX_FRAGMENT_LEN) {
+            let mut record = Vec::with_capacity(HEADER_LEN + chunk.len() + 1 + TAG_LEN);
+
+            match self.write_keys.as_mut() {
+                Some(keys) => {
+                    let len = (chunk.len() + 1 + TAG_LEN) as u16;
+                    let header = [APPLICATION_DATA, 3, 3, (len >> 8) as u8, len as u8];
+
+                    let mut inner = Vec::with_capacity(chunk.len() + 1 + TAG_LEN);
+                    inner.extend_from_slice(chunk);
+                    inner.push(kind);
+
+                    if keys.seal(&header, &mut inner).is_err() {
+                        return;
+                    }
+
+                    record.extend_from_slice(&header);
+                    record.extend_from_slice(&inner);
+                }
+                None => {
+                    let len = chunk.len() as u16;
+                    record.extend_from_slice(&[kind, 3, 3, (len >> 8) as u8, len as u8]);
+                    record.extend_from_slice(chunk);
+                }
+            }
+
+            self.tcp.write(&record).await;
+        }
+    }
+}
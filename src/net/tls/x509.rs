@@ -0,0 +1,412 @@
+//! Just enough DER, PEM and X.509 to load our own certificates and keys and to check the chain
+//! a server presents.
+//!
+//! We have no wall clock yet, so the validity period of certificates isnt checked.
+
+use super::crypto::PublicKey;
+use super::crypto::SigningKey;
+use super::crypto::ECDSA_P256_SHA256;
+use super::crypto::ED25519;
+
+use crate::net::wire::ipaddr::Ipv4Addr;
+use crate::prelude::*;
+
+use core::str;
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+/// `[0]` and `[3]` of the tbs certificate and `[0]` of a ec private key.
+const TAG_EXPLICIT_0: u8 = 0xa0;
+const TAG_EXPLICIT_3: u8 = 0xa3;
+/// `dNSName` and `iPAddress` of a subject alternative name.
+const TAG_DNS_NAME: u8 = 0x82;
+const TAG_IP_ADDRESS: u8 = 0x87;
+
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+/// Longest chain we follow from a leaf to a root.
+const MAX_CHAIN_DEPTH: usize = 8;
+
+/// Cursor over a series of DER encoded values.
+struct Der<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Reads the next value, returning its tag, its contents and the whole encoding.
+    fn read(&mut self) -> Option<(u8, &'a [u8], &'a [u8])> {
+        let tag = *self.buf.get(0)?;
+        let first = *self.buf.get(1)? as usize;
+
+        let (len, header) = match first {
+            0..=0x7f => (first, 2),
+            0x81..=0x83 => {
+                let count = first & 0x7f;
+                let len = self
+                    .buf
+                    .get(2..2 + count)?
+                    .iter()
+                    .fold(0, |acc, x| acc << 8 | *x as usize);
+                (len, 2 + count)
+            }
+            _ => return None,
+        };
+
+        let whole = self.buf.get(..header + len)?;
+        self.buf = &self.buf[header + len..];
+
+        Some((tag, &whole[header..], whole))
+    }
+
+    /// Reads the next value, which has to have `tag`.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (x, contents, _) if x == tag => Some(contents),
+            _ => None,
+        }
+    }
+
+    /// Reads the next value if it has `tag`.
+    fn optional(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.buf.first() == Some(&tag) {
+            self.expect(tag)
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// Returns the contents of a bit string without the count of unused bits.
+fn bit_string(contents: &[u8]) -> Option<&[u8]> {
+    match contents.split_first()? {
+        (0, rest) => Some(rest),
+        _ => None,
+    }
+}
+
+/// A parsed X.509 certificate.
+#[derive(Clone, Debug)]
+pub struct Certificate {
+    der: Vec<u8>,
+    tbs: Vec<u8>,
+    issuer: Vec<u8>,
+    subject: Vec<u8>,
+    /// Scheme the issuer signed the certificate with, `None` if we dont support it.
+    scheme: Option<u16>,
+    signature: Vec<u8>,
+    key: PublicKey,
+    dns_names: Vec<String>,
+    ips: Vec<Ipv4Addr>,
+    is_ca: bool,
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Self, ()> {
+        Self::parse(der).ok_or(())
+    }
+
+    fn parse(der: &[u8]) -> Option<Self> {
+        let mut outer = Der::new(Der::new(der).expect(TAG_SEQUENCE)?);
+        let (_, tbs_contents, tbs) = outer.read()?;
+        let scheme = signature_scheme(outer.expect(TAG_SEQUENCE)?);
+        let signature = bit_string(outer.expect(TAG_BIT_STRING)?)?;
+
+        let mut tbs_der = Der::new(tbs_contents);
+        tbs_der.optional(TAG_EXPLICIT_0);
+        tbs_der.expect(TAG_INTEGER)?;
+        tbs_der.expect(TAG_SEQUENCE)?;
+        let (_, _, issuer) = tbs_der.read()?;
+        tbs_der.expect(TAG_SEQUENCE)?;
+        let (_, _, subject) = tbs_der.read()?;
+        let key = public_key(tbs_der.expect(TAG_SEQUENCE)?)?;
+
+        let mut cert = Self {
+            der: der.to_vec(),
+            tbs: tbs.to_vec(),
+            issuer: issuer.to_vec(),
+            subject: subject.to_vec(),
+            scheme,
+            signature: signature.to_vec(),
+            key,
+            dns_names: Vec::new(),
+            ips: Vec::new(),
+            is_ca: false,
+        };
+
+        // the unique ids are skipped, we only care about the extensions.
+        while let Some((tag, contents, _)) = tbs_der.read() {
+            if tag == TAG_EXPLICIT_3 {
+                cert.parse_extensions(Der::new(contents).expect(TAG_SEQUENCE)?)?;
+            }
+        }
+
+        Some(cert)
+    }
+
+    fn parse_extensions(&mut self, data: &[u8]) -> Option<()> {
+        let mut extensions = Der::new(data);
+
+        while !extensions.is_empty() {
+            let mut extension = Der::new(extensions.expect(TAG_SEQUENCE)?);
+            let id = extension.expect(TAG_OID)?;
+            extension.optional(TAG_BOOLEAN);
+            let value = extension.expect(TAG_OCTET_STRING)?;
+
+            if id == OID_SUBJECT_ALT_NAME {
+                let mut names = Der::new(Der::new(value).expect(TAG_SEQUENCE)?);
+
+                while let Some((tag, name, _)) = names.read() {
+                    match tag {
+                        TAG_DNS_NAME => self.dns_names.push(str::from_utf8(name).ok()?.into()),
+                        TAG_IP_ADDRESS if name.len() == 4 => {
+                            self.ips.push([name[0], name[1], name[2], name[3]].into())
+                        }
+                        _ => {}
+                    }
+                }
+            } else if id == OID_BASIC_CONSTRAINTS {
+                let mut constraints = Der::new(Der::new(value).expect(TAG_SEQUENCE)?);
+                self.is_ca = constraints
+                    .optional(TAG_BOOLEAN)
+                    .map_or(false, |x| x != [0]);
+            }
+        }
+
+        Some(())
+    }
+
+    /// Returns the certificate in its DER encoding.
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    /// Returns whether the certificate is valid for the host `name`, a domain or a ip literal.
+    pub fn matches_name(&self, name: &str) -> bool {
+        if let Ok(ip) = name.parse::<Ipv4Addr>() {
+            return self.ips.contains(&ip);
+        }
+
+        let name = name.trim_end_matches('.');
+        self.dns_names.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('.');
+
+            // a wildcard only stands for the left most label.
+            match pattern.strip_prefix("*.") {
+                Some(suffix) => match name.split_once('.') {
+                    Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+                    None => false,
+                },
+                None => pattern.eq_ignore_ascii_case(name),
+            }
+        })
+    }
+
+    /// Returns whether `issuer` signed this certificate.
+    fn is_signed_by(&self, issuer: &Self) -> bool {
+        self.issuer == issuer.subject
+            && self.scheme.map_or(false, |scheme| {
+                issuer
+                    .key
+                    .verify(scheme, &self.tbs, &self.signature)
+                    .is_ok()
+            })
+    }
+}
+
+/// Maps a signature algorithm identifier to the TLS signature scheme of the same algorithm.
+fn signature_scheme(algorithm: &[u8]) -> Option<u16> {
+    match Der::new(algorithm).expect(TAG_OID)? {
+        OID_ECDSA_SHA256 => Some(ECDSA_P256_SHA256),
+        OID_ED25519 => Some(ED25519),
+        _ => None,
+    }
+}
+
+/// Parses a subject public key info.
+fn public_key(spki: &[u8]) -> Option<PublicKey> {
+    let mut spki = Der::new(spki);
+    let mut algorithm = Der::new(spki.expect(TAG_SEQUENCE)?);
+    let key = bit_string(spki.expect(TAG_BIT_STRING)?)?;
+
+    Some(match algorithm.expect(TAG_OID)? {
+        OID_ED25519 if key.len() == 32 => {
+            let mut x = [0; 32];
+            x.copy_from_slice(key);
+            PublicKey::Ed25519(x)
+        }
+        OID_EC_PUBLIC_KEY if algorithm.optional(TAG_OID) == Some(OID_PRIME256V1) => {
+            PublicKey::EcdsaP256(key.to_vec())
+        }
+        _ => PublicKey::Unsupported,
+    })
+}
+
+/// Function checks that `chain`, leaf first, leads up to one of `roots` and that the leaf is
+/// valid for `name`.
+pub fn verify_chain(chain: &[Certificate], roots: &[Certificate], name: &str) -> Result<(), ()> {
+    let mut current = chain.first().ok_or(())?;
+    if !current.matches_name(name) {
+        return Err(());
+    }
+
+    for _ in 0..MAX_CHAIN_DEPTH {
+        if roots
+            .iter()
+            .any(|x| x.der == current.der || current.is_signed_by(x))
+        {
+            return Ok(());
+        }
+
+        current = chain[1..]
+            .iter()
+            .find(|x| x.is_ca && current.is_signed_by(x))
+            .ok_or(())?;
+    }
+
+    Err(())
+}
+
+/// Returns the contents of every PEM block labeled `label` in `data`.
+fn pem_blocks(data: &[u8], label: &str) -> Vec<Vec<u8>> {
+    let text = match str::from_utf8(data) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut blocks = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(&begin) {
+        rest = &rest[start + begin.len()..];
+
+        let stop = match rest.find(&end) {
+            Some(x) => x,
+            None => break,
+        };
+
+        if let Some(block) = base64(&rest[..stop]) {
+            blocks.push(block);
+        }
+        rest = &rest[stop + end.len()..];
+    }
+
+    blocks
+}
+
+/// Decodes standard base64, ignoring whitespace.
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for x in text.bytes().filter(|x| !x.is_ascii_whitespace()) {
+        let value = match x {
+            b'A'..=b'Z' => x - b'A',
+            b'a'..=b'z' => x - b'a' + 26,
+            b'0'..=b'9' => x - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+
+        acc = acc << 6 | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(10).any(|x| x == b"-----BEGIN")
+}
+
+/// Function loads a certificate chain, leaf first, from PEM or from a single DER certificate.
+pub fn load_certs(data: &[u8]) -> Result<Vec<Certificate>, ()> {
+    if !is_pem(data) {
+        return Ok(vec![Certificate::from_der(data)?]);
+    }
+
+    let certs = pem_blocks(data, "CERTIFICATE")
+        .iter()
+        .map(|x| Certificate::from_der(x))
+        .collect::<Result<Vec<_>, ()>>()?;
+
+    if certs.is_empty() {
+        return Err(());
+    }
+
+    Ok(certs)
+}
+
+/// Function loads a Ed25519 or P-256 private key, either PKCS#8 or SEC1, from PEM or DER.
+pub fn load_key(data: &[u8]) -> Result<SigningKey, ()> {
+    if !is_pem(data) {
+        return pkcs8_key(data).or_else(|| sec1_key(data)).ok_or(())?;
+    }
+
+    if let Some(der) = pem_blocks(data, "PRIVATE KEY").first() {
+        return pkcs8_key(der).ok_or(())?;
+    }
+
+    if let Some(der) = pem_blocks(data, "EC PRIVATE KEY").first() {
+        return sec1_key(der).ok_or(())?;
+    }
+
+    Err(())
+}
+
+fn pkcs8_key(der: &[u8]) -> Option<Result<SigningKey, ()>> {
+    let mut info = Der::new(Der::new(der).expect(TAG_SEQUENCE)?);
+    info.expect(TAG_INTEGER)?;
+    let mut algorithm = Der::new(info.expect(TAG_SEQUENCE)?);
+    let key = info.expect(TAG_OCTET_STRING)?;
+
+    match algorithm.expect(TAG_OID)? {
+        OID_ED25519 => Some(SigningKey::ed25519(Der::new(key).expect(TAG_OCTET_STRING)?)),
+        OID_EC_PUBLIC_KEY if algorithm.optional(TAG_OID) == Some(OID_PRIME256V1) => sec1_key(key),
+        _ => Some(Err(())),
+    }
+}
+
+fn sec1_key(der: &[u8]) -> Option<Result<SigningKey, ()>> {
+    let mut key = Der::new(Der::new(der).expect(TAG_SEQUENCE)?);
+    key.expect(TAG_INTEGER)?;
+    let scalar = key.expect(TAG_OCTET_STRING)?;
+
+    if let Some(params) = key.optional(TAG_EXPLICIT_0) {
+        if Der::new(params).expect(TAG_OID)? != OID_PRIME256V1 {
+            return Some(Err(()));
+        }
+    }
+
+    Some(SigningKey::ecdsa_p256(scalar))
+}
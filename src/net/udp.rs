@@ -0,0 +1,148 @@
+use super::addr::SocketAddrV4;
+use super::ports::BindOptions;
+use super::ports::PortGuard;
+use super::stats;
+use super::stats::DropReason;
+use super::wire::ipaddr::Ipv4Addr;
+use super::wire::ipv4::Ipv4;
+use super::wire::ipv4::Ipv4Proto;
+use super::wire::udp::Udp;
+use super::wire::Packet;
+
+use crate::collections::HashMap;
+use crate::prelude::*;
+use crate::sync::mpsc::channel;
+use crate::sync::mpsc::UnboundedReceiver;
+use crate::sync::mpsc::UnboundedSender;
+
+use spin::RwLock;
+
+/// A datagram received by a socket.
+#[derive(Clone, Debug)]
+pub struct Datagram {
+    pub data: Vec<u8>,
+    /// Address the datagram was sent from.
+    pub src: SocketAddrV4,
+    /// Address the datagram was sent to, either one of our ips or a multicast group.
+    pub dst: Ipv4Addr,
+}
+
+type DatagramSender = UnboundedSender<Datagram>;
+
+pub struct UdpLayer {
+    /// Bound sockets, keyed by local address. Several sockets only share a address if they all
+    /// set `reuse_port`, each is known by the id of its port reservation.
+    sockets: RwLock<HashMap<SocketAddrV4, Vec<(usize, DatagramSender)>>>,
+}
+
+impl UdpLayer {
+    pub fn new() -> Self {
+        Self {
+            sockets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Function binds `addr`, returning the reservation of the address along with the queue
+    /// datagrams sent to it end up in. Binding port 0 picks a free ephemeral port, binding
+    /// `0.0.0.0` receives datagrams sent to any of our addresses.
+    pub fn bind(
+        &self,
+        addr: SocketAddrV4,
+        opts: BindOptions,
+    ) -> Result<(PortGuard, UnboundedReceiver<Datagram>), ()> {
+        let port = super::UDP_PORTS.bind(addr, opts)?;
+        let (tx, rx) = channel();
+
+        self.sockets
+            .write()
+            .entry(port.addr())
+            .or_default()
+            .push((port.id(), tx));
+
+        Ok((port, rx))
+    }
+
+    /// Function removes the socket bound with `port`, the reservation itself is released once
+    /// `port` is dropped.
+    pub fn unbind(&self, port: &PortGuard) {
+        let mut sockets = self.sockets.write();
+        let addr = port.addr();
+
+        if let Some(bound) = sockets.get_mut(&addr) {
+            bound.retain(|(x, _)| *x != port.id());
+
+            if bound.is_empty() {
+                sockets.remove(&addr);
+            }
+        }
+    }
+
+    /// Returns whether a socket receives datagrams sent to `dip:port`.
+    pub fn is_bound(&self, dip: Ipv4Addr, port: u16) -> bool {
+        let sockets = self.sockets.read();
+
+        sockets.contains_key(&SocketAddrV4::new(dip, port))
+            || sockets.contains_key(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+    }
+
+    pub async fn handle_packet(&self, packet: Udp, ctx: &Ipv4) -> Option<Udp> {
+        stats::UDP.rx(packet.as_bytes().len());
+
+        if !packet.verify_checksum(ctx.sip(), ctx.dip()) {
+            stats::UDP.dropped(DropReason::Checksum);
+            return None;
+        }
+
+        let datagram = Datagram {
+            data: packet.data().to_vec(),
+            src: SocketAddrV4::new(ctx.sip(), packet.src()),
+            dst: ctx.dip(),
+        };
+
+        // sockets bound to the exact address win over the ones bound to all of them.
+        let sockets = self.sockets.read();
+        let socket = sockets
+            .get(&SocketAddrV4::new(ctx.dip(), packet.dst()))
+            .or_else(|| sockets.get(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, packet.dst())));
+
+        match socket {
+            // every socket sharing the address gets a copy of a datagram sent to a group.
+            Some(bound) if ctx.dip().is_multicast() || ctx.dip().is_broadcast() => {
+                for (_, socket) in bound {
+                    let _ = socket.send(datagram.clone());
+                }
+            }
+            // otherwise each peer always ends up at the same one.
+            Some(bound) if !bound.is_empty() => {
+                let hash = u32::from(ctx.sip()) as usize + packet.src() as usize;
+                let _ = bound[hash % bound.len()].1.send(datagram);
+            }
+            _ => stats::UDP.dropped(DropReason::NoSocket),
+        }
+
+        // udp never answers on its own, sockets send their replies through `handle_tx`.
+        None
+    }
+
+    /// Function sends `data` from `sip:sport` to `dip:dport`.
+    pub async fn handle_tx(
+        &self,
+        data: &[u8],
+        sport: u16,
+        sip: Ipv4Addr,
+        dport: u16,
+        dip: Ipv4Addr,
+    ) {
+        let mut packet = Udp::zeroed();
+        packet.set_src(sport);
+        packet.set_dst(dport);
+        packet.set_data(data);
+        packet.set_checksum(sip, dip);
+
+        stats::UDP.tx(packet.as_bytes().len());
+
+        super::IP_LAYER
+            .handle_tx(packet.into_buf(), Ipv4Proto::UDP, dip, sip)
+            .await;
+    }
+}
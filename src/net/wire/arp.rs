@@ -1,12 +1,14 @@
+use crate::net::wire::buf::DEFAULT_TAILROOM;
+use crate::net::wire::buf::ETH2_HEADER_LEN;
 use crate::net::wire::Packet;
+use crate::net::wire::PacketBuf;
 use crate::net::wire::{eth2::EtherType, ipaddr::Ipv4Addr, mac::Mac};
 use crate::prelude::*;
 use core::convert::TryInto;
-use core::mem::transmute;
 use core::ops::RangeInclusive;
 use core::slice::SliceIndex;
 
-const MIN_ARP_LEN: usize = 27; // the minimum valid length of a arp packet is 27b
+const MIN_ARP_LEN: usize = 28; // the minimum valid length of a arp packet is 28b
 const ARP_HW_TYPE: RangeInclusive<usize> = 0..=1;
 const ARP_PROTO: RangeInclusive<usize> = 2..=3;
 const ARP_HW_SIZE: usize = 4;
@@ -20,8 +22,9 @@ const ARP_TIP: RangeInclusive<usize> = 24..=27;
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u16)]
 pub enum ArpOpcode {
-    ArpRequest,
+    ArpRequest = 1,
     ArpReply = 2,
+    Unknown,
 }
 
 impl ArpOpcode {
@@ -35,19 +38,24 @@ impl Into<u16> for ArpOpcode {
         match self {
             Self::ArpRequest => 1,
             Self::ArpReply => 2,
+            Self::Unknown => 0,
         }
     }
 }
 
 impl From<u16> for ArpOpcode {
     fn from(data: u16) -> Self {
-        unsafe { transmute::<u16, ArpOpcode>(data) }
+        match data {
+            1 => Self::ArpRequest,
+            2 => Self::ArpReply,
+            _ => Self::Unknown,
+        }
     }
 }
 
 /// This struct holds the structure of ARP packets.
 #[derive(Debug, Clone)]
-pub struct ArpPacket(Vec<u8>);
+pub struct ArpPacket(PacketBuf);
 
 // TODO: Make this generic to support `From::from` on `&mut [u8]` as well as `Vec<u8>`
 impl ArpPacket {
@@ -56,7 +64,7 @@ impl ArpPacket {
     }
 
     pub fn proto(&self) -> EtherType {
-        u16::from_be_bytes(self.0[ARP_HW_TYPE].try_into().expect("net: got no proto")).into()
+        u16::from_be_bytes(self.0[ARP_PROTO].try_into().expect("net: got no proto")).into()
     }
 
     pub fn hw_size(&self) -> u8 {
@@ -134,10 +142,16 @@ impl ArpPacket {
 
 impl super::Packet for ArpPacket {
     fn zeroed() -> Self {
-        Self(vec![0; MIN_ARP_LEN])
+        let mut this = Self(PacketBuf::new(ETH2_HEADER_LEN, MIN_ARP_LEN, DEFAULT_TAILROOM));
+        this.set_hw_type(1); // ethernet
+        this.set_proto(EtherType::IPv4);
+        this.set_hw_size(6);
+        this.set_proto_size(4);
+
+        this
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
         if bytes.len() < MIN_ARP_LEN {
             return Err(());
         }
@@ -145,7 +159,7 @@ impl super::Packet for ArpPacket {
         Ok(Self(bytes))
     }
 
-    fn into_bytes(self) -> Vec<u8> {
+    fn into_buf(self) -> PacketBuf {
         self.0
     }
 }
@@ -0,0 +1,170 @@
+//! Packet buffers with reserved headroom and tailroom.
+//!
+//! A `PacketBuf` is a window into a larger allocation. The bytes in front of the window
+//! (headroom) let lower layers prepend their headers without moving the payload, while the
+//! spare capacity behind it (tailroom) lets us append data without reallocating. On the receive
+//! side the window is moved forward as each layer strips its header, on the transmit side it is
+//! moved backwards as each layer prepends one.
+//!
+//! This saves the per layer copies of payloads, it doesnt make the stack zero copy. Copies are
+//! still made:
+//! * of every header kept around as context by `split_header`, which are small.
+//! * by `into_vec` when the window doesnt start at the front of the allocation, since drivers
+//!   take a plain `Vec<u8>`.
+//! * of the datagram quoted by icmp errors, of segments the firewall rejects and of fragments,
+//!   none of which are on the fast path.
+use crate::prelude::*;
+
+use core::ops::Deref;
+use core::ops::DerefMut;
+
+/// Size of a Ethernet II header.
+pub const ETH2_HEADER_LEN: usize = 14;
+/// Size of a IPv4 header without options.
+pub const IPV4_HEADER_LEN: usize = 20;
+/// Headroom reserved in front of transport packets, big enough to turn them into a frame
+/// without moving them.
+pub const TRANSPORT_HEADROOM: usize = ETH2_HEADER_LEN + IPV4_HEADER_LEN;
+/// Tailroom reserved behind freshly built packets.
+pub const DEFAULT_TAILROOM: usize = 0;
+
+/// A byte buffer with headroom and tailroom, dereferences to the bytes inside its window.
+#[derive(Clone)]
+pub struct PacketBuf {
+    /// The whole allocation, `buf.len()` marks the end of the window.
+    buf: Vec<u8>,
+    /// Start of the window.
+    start: usize,
+}
+
+impl PacketBuf {
+    /// Creates a zeroed buffer of `len` bytes.
+    ///
+    /// # Arguments
+    /// * `headroom` - Number of bytes reserved in front of the buffer.
+    /// * `len` - Size of the buffer itself.
+    /// * `tailroom` - Number of bytes reserved behind the buffer.
+    pub fn new(headroom: usize, len: usize, tailroom: usize) -> Self {
+        let mut buf = Vec::with_capacity(headroom + len + tailroom);
+        buf.resize(headroom + len, 0);
+
+        Self {
+            buf,
+            start: headroom,
+        }
+    }
+
+    /// Number of bytes that can be prepended without reallocating.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Number of bytes that can be appended without reallocating.
+    pub fn tailroom(&self) -> usize {
+        self.buf.capacity() - self.buf.len()
+    }
+
+    /// Grows the window by `len` zeroed bytes at the front and returns them. Only reallocates if
+    /// there isnt enough headroom.
+    pub fn prepend(&mut self, len: usize) -> &mut [u8] {
+        if self.start < len {
+            let mut buf = Vec::with_capacity(len + self.len() + self.tailroom());
+            buf.resize(len, 0);
+            buf.extend_from_slice(self);
+
+            self.buf = buf;
+            self.start = len;
+        }
+
+        self.start -= len;
+        let start = self.start;
+
+        let header = &mut self.buf[start..start + len];
+        for byte in header.iter_mut() {
+            *byte = 0;
+        }
+
+        header
+    }
+
+    /// Shrinks the window by `len` bytes at the front, the bytes stay around as headroom.
+    pub fn pull(&mut self, len: usize) {
+        assert!(len <= self.len(), "net: pulled past the end of a packet");
+        self.start += len;
+    }
+
+    /// Copies the first `len` bytes into a buffer of their own and pulls them from this one.
+    /// Used to keep a lower layer header around while the payload moves up the stack.
+    pub fn split_header(&mut self, len: usize) -> PacketBuf {
+        let header = PacketBuf::from(self[..len].to_vec());
+        self.pull(len);
+
+        header
+    }
+
+    /// Shortens the window to `len` bytes, does nothing if it is already shorter.
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(self.start + len);
+    }
+
+    /// Appends `data` at the end of the window.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the bytes inside the window. Any headroom left is removed by moving the window to
+    /// the front, frames built by our stack use up all of theirs so they are handed over as is.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        if self.start != 0 {
+            self.buf.drain(..self.start);
+        }
+
+        self.buf
+    }
+}
+
+impl From<Vec<u8>> for PacketBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        Self { buf, start: 0 }
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..]
+    }
+}
+
+impl AsRef<[u8]> for PacketBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PacketBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl PartialEq for PacketBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+impl Eq for PacketBuf {}
+
+impl core::fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_ref(), f)
+    }
+}
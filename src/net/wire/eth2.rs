@@ -1,27 +1,35 @@
 //! Zero Copy Ethernet II packet parser.
 
 use crate::net::frames::mac::Mac;
+use crate::net::frames::PacketBuf;
 use crate::prelude::*;
 use core::convert::{Into, TryInto};
 use core::mem::transmute;
-use core::ops::RangeFrom;
+use core::ops::Range;
 use core::ops::RangeInclusive;
 
 const ETH2_MIN_VALID_SIZE: usize = 14;
+/// Size of a header carrying a 802.1Q tag.
+const ETH2_VLAN_SIZE: usize = 18;
+/// Size of the 802.1Q tag itself, tpid and tci.
+const VLAN_TAG_LEN: usize = 4;
 /// Represents the range of 6 bytes pointing to the destination field.
 const ETH2_DST_OFFSET: RangeInclusive<usize> = 0..=5;
 /// Represents the range of 6 bytes pointing to the source field.
 const ETH2_SRC_OFFSET: RangeInclusive<usize> = 6..=11;
 /// Represents the range of bytes responsible for the dtype field.
 const ETH2_DTYPE_OFFSET: RangeInclusive<usize> = 12..=13;
-/// Represents the range of bytes responsible for the data field.
-const ETH2_DATA_OFFSET: RangeFrom<usize> = 14..;
+/// Represents the range of bytes holding the tag control information of a tagged frame.
+const ETH2_VLAN_TCI_OFFSET: RangeInclusive<usize> = 14..=15;
+/// Represents the range of the macs, which have to move when a tag is added or removed.
+const ETH2_MACS_OFFSET: Range<usize> = 0..12;
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[repr(u16)]
 pub enum EtherType {
     IPv4 = 0x0800,
     ARP = 0x0806,
+    VLAN = 0x8100,
     Ipv6 = 0x86dd,
     Unsupported,
 }
@@ -34,15 +42,72 @@ impl EtherType {
 
 impl From<u16> for EtherType {
     fn from(data: u16) -> Self {
-        unsafe { transmute::<u16, EtherType>(data) }
+        match data {
+            0x0800 => Self::IPv4,
+            0x0806 => Self::ARP,
+            0x8100 => Self::VLAN,
+            0x86dd => Self::Ipv6,
+            _ => Self::Unsupported,
+        }
+    }
+}
+
+/// A 802.1Q tag.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct VlanTag {
+    /// Priority code point.
+    pub pcp: u8,
+    /// Drop eligible indicator.
+    pub dei: bool,
+    /// Vlan identifier, only the low 12 bits are used.
+    pub id: u16,
+}
+
+impl VlanTag {
+    /// Creates a tag for vlan `id` with the default priority.
+    pub fn new(id: u16) -> Self {
+        Self {
+            pcp: 0,
+            dei: false,
+            id: id & 0x0fff,
+        }
+    }
+
+    pub fn raw(self) -> u16 {
+        ((self.pcp as u16 & 0x07) << 13) | ((self.dei as u16) << 12) | (self.id & 0x0fff)
+    }
+}
+
+impl From<u16> for VlanTag {
+    fn from(tci: u16) -> Self {
+        Self {
+            pcp: (tci >> 13) as u8,
+            dei: tci & (1 << 12) != 0,
+            id: tci & 0x0fff,
+        }
     }
 }
 
-/// Structure represents a basic Ethernet II frame.
-#[derive(Eq, PartialEq)]
-pub struct Ether2Frame(Vec<u8>);
+/// Structure represents a basic Ethernet II frame, optionally carrying a 802.1Q tag.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Ether2Frame(PacketBuf);
 
 impl Ether2Frame {
+    /// Wraps `payload` in a zeroed Ethernet II header. The header is written into the headroom
+    /// of `payload` so the payload itself isnt copied.
+    pub fn encapsulate(mut payload: PacketBuf) -> Self {
+        payload.prepend(ETH2_MIN_VALID_SIZE);
+        Self(payload)
+    }
+
+    /// Splits the frame into a copy of its header and its payload, the payload keeps the
+    /// header bytes as headroom so replies can be built in place.
+    pub fn split(mut self) -> (Self, PacketBuf) {
+        let header_len = self.header_len();
+        let header = self.0.split_header(header_len);
+        (Self(header), self.0)
+    }
+
     /// Returns the destination field value.
     pub fn dst(&self) -> Mac {
         self.0[ETH2_DST_OFFSET].into()
@@ -53,19 +118,49 @@ impl Ether2Frame {
         self.0[ETH2_SRC_OFFSET].into()
     }
 
-    /// Returns the dtype of the packet.
+    /// Returns whether the frame carries a 802.1Q tag.
+    fn is_tagged(&self) -> bool {
+        self.0.len() >= ETH2_VLAN_SIZE && self.0[ETH2_DTYPE_OFFSET] == EtherType::VLAN.raw().to_be_bytes()
+    }
+
+    /// Returns the size of the header, including the 802.1Q tag if there is one.
+    pub fn header_len(&self) -> usize {
+        if self.is_tagged() {
+            ETH2_VLAN_SIZE
+        } else {
+            ETH2_MIN_VALID_SIZE
+        }
+    }
+
+    /// Returns the dtype of the packet. For tagged frames this is the type of the payload, not
+    /// `EtherType::VLAN`.
     pub fn dtype(&self) -> EtherType {
+        self.raw_dtype().into()
+    }
+
+    /// Returns the ethertype of the frame as a number, for types `EtherType` doesnt know about.
+    pub fn raw_dtype(&self) -> u16 {
+        let offset = self.header_len() - 2;
         u16::from_be_bytes(
-            self.0[ETH2_DTYPE_OFFSET]
+            self.0[offset..offset + 2]
                 .try_into()
                 .expect("net: eth2 got null dtype"),
         )
-        .into()
+    }
+
+    /// Returns the 802.1Q tag of the frame if it has one.
+    pub fn vlan(&self) -> Option<VlanTag> {
+        if !self.is_tagged() {
+            return None;
+        }
+
+        let tci = u16::from_be_bytes(self.0[ETH2_VLAN_TCI_OFFSET].try_into().ok()?);
+        Some(tci.into())
     }
 
     /// Returns a reference to the data in the packet.
     pub fn data(&self) -> &[u8] {
-        &self.0[ETH2_DATA_OFFSET]
+        &self.0[self.header_len()..]
     }
 
     /// Sets the destination field value.
@@ -78,25 +173,52 @@ impl Ether2Frame {
         self.0[ETH2_SRC_OFFSET].copy_from_slice(src.as_ref());
     }
 
-    /// Sets the dtype field.
+    /// Sets the dtype field, for tagged frames this is the type of the payload.
     pub fn set_dtype(&mut self, dtype: EtherType) {
-        self.0[ETH2_DTYPE_OFFSET].copy_from_slice(&dtype.raw().to_be_bytes());
+        self.set_raw_dtype(dtype.raw());
+    }
+
+    pub fn set_raw_dtype(&mut self, dtype: u16) {
+        let offset = self.header_len() - 2;
+        self.0[offset..offset + 2].copy_from_slice(&dtype.to_be_bytes());
+    }
+
+    /// Tags the frame with `tag`, replacing the tag it already has. The macs are moved into the
+    /// headroom so the payload stays where it is.
+    pub fn push_vlan(&mut self, tag: VlanTag) {
+        if !self.is_tagged() {
+            self.0.prepend(VLAN_TAG_LEN);
+            self.0.copy_within(VLAN_TAG_LEN..VLAN_TAG_LEN + ETH2_MACS_OFFSET.end, 0);
+            self.0[ETH2_DTYPE_OFFSET].copy_from_slice(&EtherType::VLAN.raw().to_be_bytes());
+        }
+
+        self.0[ETH2_VLAN_TCI_OFFSET].copy_from_slice(&tag.raw().to_be_bytes());
+    }
+
+    /// Removes the 802.1Q tag of the frame, returning it.
+    pub fn pop_vlan(&mut self) -> Option<VlanTag> {
+        let tag = self.vlan()?;
+
+        self.0.copy_within(ETH2_MACS_OFFSET, VLAN_TAG_LEN);
+        self.0.pull(VLAN_TAG_LEN);
+
+        Some(tag)
     }
 
     /// Sets the data field.
     pub fn set_data<T: AsRef<[u8]>>(&mut self, data: T) {
         // remove the old data
-        self.0.truncate(ETH2_MIN_VALID_SIZE);
+        self.0.truncate(self.header_len());
         self.0.extend_from_slice(data.as_ref());
     }
 }
 
 impl super::Packet for Ether2Frame {
     fn zeroed() -> Self {
-        Self(vec![0; ETH2_MIN_VALID_SIZE])
+        Self(vec![0; ETH2_MIN_VALID_SIZE].into())
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
         if bytes.len() < ETH2_MIN_VALID_SIZE {
             return Err(());
         }
@@ -104,7 +226,7 @@ impl super::Packet for Ether2Frame {
         Ok(Self(bytes))
     }
 
-    fn into_bytes(self) -> Vec<u8> {
+    fn into_buf(self) -> PacketBuf {
         self.0
     }
 }
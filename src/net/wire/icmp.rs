@@ -1,10 +1,12 @@
+use super::buf::DEFAULT_TAILROOM;
+use super::buf::TRANSPORT_HEADROOM;
+use super::PacketBuf;
 use crate::prelude::*;
 use core::array::TryFromSliceError;
 use core::convert::From;
 use core::convert::Into;
 use core::convert::TryFrom;
 use core::convert::TryInto;
-use core::mem::transmute;
 use core::ops::{RangeFrom, RangeInclusive};
 
 const ICMP_ECHO_MIN_SIZE: usize = 8;
@@ -15,53 +17,188 @@ const ICMP_ECHO_IDENT: RangeInclusive<usize> = 4..=5;
 const ICMP_ECHO_SEQ: RangeInclusive<usize> = 6..=7;
 const ICMP_ECHO_DATA: RangeFrom<usize> = 8..;
 
-#[derive(Clone, Debug)]
-#[repr(u8)]
+/// Type of a ICMP message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum IcmpType {
-    EchoReply = 0x00,
-    Echo = 0x08,
+    EchoReply,
+    DestinationUnreachable,
+    SourceQuench,
+    Redirect,
+    Echo,
+    RouterAdvertisement,
+    RouterSolicitation,
+    TimeExceeded,
+    ParameterProblem,
+    Timestamp,
+    TimestampReply,
+    InformationRequest,
+    InformationReply,
+    AddressMaskRequest,
+    AddressMaskReply,
+    Unknown(u8),
 }
 
 impl IcmpType {
     pub fn raw(self) -> u8 {
-        unsafe { transmute::<IcmpType, u8>(self) }
+        match self {
+            Self::EchoReply => 0x00,
+            Self::DestinationUnreachable => 0x03,
+            Self::SourceQuench => 0x04,
+            Self::Redirect => 0x05,
+            Self::Echo => 0x08,
+            Self::RouterAdvertisement => 0x09,
+            Self::RouterSolicitation => 0x0a,
+            Self::TimeExceeded => 0x0b,
+            Self::ParameterProblem => 0x0c,
+            Self::Timestamp => 0x0d,
+            Self::TimestampReply => 0x0e,
+            Self::InformationRequest => 0x0f,
+            Self::InformationReply => 0x10,
+            Self::AddressMaskRequest => 0x11,
+            Self::AddressMaskReply => 0x12,
+            Self::Unknown(x) => x,
+        }
+    }
+
+    /// Returns whether this type is a error message, we must never reply to these with another
+    /// error (RFC 1122 3.2.2).
+    pub fn is_error(self) -> bool {
+        match self {
+            Self::DestinationUnreachable
+            | Self::SourceQuench
+            | Self::Redirect
+            | Self::TimeExceeded
+            | Self::ParameterProblem => true,
+            _ => false,
+        }
     }
 }
 
 impl From<u8> for IcmpType {
     fn from(i: u8) -> Self {
-        unsafe { transmute::<u8, IcmpType>(i) }
+        match i {
+            0x00 => Self::EchoReply,
+            0x03 => Self::DestinationUnreachable,
+            0x04 => Self::SourceQuench,
+            0x05 => Self::Redirect,
+            0x08 => Self::Echo,
+            0x09 => Self::RouterAdvertisement,
+            0x0a => Self::RouterSolicitation,
+            0x0b => Self::TimeExceeded,
+            0x0c => Self::ParameterProblem,
+            0x0d => Self::Timestamp,
+            0x0e => Self::TimestampReply,
+            0x0f => Self::InformationRequest,
+            0x10 => Self::InformationReply,
+            0x11 => Self::AddressMaskRequest,
+            0x12 => Self::AddressMaskReply,
+            x => Self::Unknown(x),
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-#[repr(u8)]
+/// Codes used by `IcmpType::DestinationUnreachable` messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum IcmpCode {
-    NetDown = 0x00,
-    HostDown = 0x01,
-    ProtocolDown = 0x02,
-    PortDown = 0x03,
-    FragNeeded = 0x04,
-    SourceRouteFailed = 0x05,
-    Unknown,
+    NetDown,
+    HostDown,
+    ProtocolDown,
+    PortDown,
+    FragNeeded,
+    SourceRouteFailed,
+    NetUnknown,
+    HostUnknown,
+    HostIsolated,
+    NetProhibited,
+    HostProhibited,
+    NetTosUnreachable,
+    HostTosUnreachable,
+    AdminProhibited,
+    HostPrecedenceViolation,
+    PrecedenceCutoff,
+    Unknown(u8),
 }
 
 impl IcmpCode {
     pub fn raw(self) -> u8 {
-        unsafe { transmute::<IcmpCode, u8>(self) }
+        match self {
+            Self::NetDown => 0x00,
+            Self::HostDown => 0x01,
+            Self::ProtocolDown => 0x02,
+            Self::PortDown => 0x03,
+            Self::FragNeeded => 0x04,
+            Self::SourceRouteFailed => 0x05,
+            Self::NetUnknown => 0x06,
+            Self::HostUnknown => 0x07,
+            Self::HostIsolated => 0x08,
+            Self::NetProhibited => 0x09,
+            Self::HostProhibited => 0x0a,
+            Self::NetTosUnreachable => 0x0b,
+            Self::HostTosUnreachable => 0x0c,
+            Self::AdminProhibited => 0x0d,
+            Self::HostPrecedenceViolation => 0x0e,
+            Self::PrecedenceCutoff => 0x0f,
+            Self::Unknown(x) => x,
+        }
     }
 }
 
 impl From<u8> for IcmpCode {
     fn from(i: u8) -> Self {
-        unsafe { transmute::<u8, IcmpCode>(i) }
+        match i {
+            0x00 => Self::NetDown,
+            0x01 => Self::HostDown,
+            0x02 => Self::ProtocolDown,
+            0x03 => Self::PortDown,
+            0x04 => Self::FragNeeded,
+            0x05 => Self::SourceRouteFailed,
+            0x06 => Self::NetUnknown,
+            0x07 => Self::HostUnknown,
+            0x08 => Self::HostIsolated,
+            0x09 => Self::NetProhibited,
+            0x0a => Self::HostProhibited,
+            0x0b => Self::NetTosUnreachable,
+            0x0c => Self::HostTosUnreachable,
+            0x0d => Self::AdminProhibited,
+            0x0e => Self::HostPrecedenceViolation,
+            0x0f => Self::PrecedenceCutoff,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+/// Codes used by `IcmpType::TimeExceeded` messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeExceededCode {
+    TtlExceeded,
+    FragmentReassembly,
+    Unknown(u8),
+}
+
+impl TimeExceededCode {
+    pub fn raw(self) -> u8 {
+        match self {
+            Self::TtlExceeded => 0x00,
+            Self::FragmentReassembly => 0x01,
+            Self::Unknown(x) => x,
+        }
+    }
+}
+
+impl From<u8> for TimeExceededCode {
+    fn from(i: u8) -> Self {
+        match i {
+            0x00 => Self::TtlExceeded,
+            0x01 => Self::FragmentReassembly,
+            x => Self::Unknown(x),
+        }
     }
 }
 
 /// Our basic ICMP packet struct.
 /// TODO: Better packet structure docs.
 #[derive(Clone)]
-pub struct Icmp(Vec<u8>);
+pub struct Icmp(PacketBuf);
 
 impl Icmp {
     pub fn packet_type(&self) -> IcmpType {
@@ -72,6 +209,12 @@ impl Icmp {
         self.0[ICMP_ECHO_CODE].into()
     }
 
+    /// Returns the code field without interpreting it, the meaning of the code depends on the
+    /// type of the message.
+    pub fn raw_code(&self) -> u8 {
+        self.0[ICMP_ECHO_CODE]
+    }
+
     pub fn checksum(&self) -> u16 {
         u16::from_be_bytes(
             self.0[ICMP_ECHO_CSUM]
@@ -100,6 +243,16 @@ impl Icmp {
         &self.0[ICMP_ECHO_DATA]
     }
 
+    /// Returns the mtu of the next hop carried by fragmentation needed messages (RFC 1191), older
+    /// routers leave it at zero.
+    pub fn next_hop_mtu(&self) -> u16 {
+        self.seq()
+    }
+
+    pub fn set_next_hop_mtu(&mut self, mtu: u16) {
+        self.set_seq(mtu);
+    }
+
     pub fn set_packet_type(&mut self, packet_type: IcmpType) {
         self.0[ICMP_ECHO_PACKET_TYPE] = packet_type.raw();
     }
@@ -108,6 +261,10 @@ impl Icmp {
         self.0[ICMP_ECHO_CODE] = code.raw();
     }
 
+    pub fn set_raw_code(&mut self, code: u8) {
+        self.0[ICMP_ECHO_CODE] = code;
+    }
+
     pub fn set_checksum(&mut self) {
         // set it to 0
         self.0[ICMP_ECHO_CSUM].copy_from_slice(&0u16.to_le_bytes());
@@ -116,6 +273,11 @@ impl Icmp {
         self.0[ICMP_ECHO_CSUM].copy_from_slice(&csum.to_le_bytes());
     }
 
+    /// Returns whether the checksum covering the whole message is correct.
+    pub fn verify_checksum(&self) -> bool {
+        super::ipv4::u32_to_u16(super::ipv4::checksum(&self.0)) == 0
+    }
+
     pub fn set_identifier(&mut self, identifier: u16) {
         self.0[ICMP_ECHO_IDENT].copy_from_slice(&identifier.to_be_bytes());
     }
@@ -128,14 +290,22 @@ impl Icmp {
         self.0.truncate(ICMP_ECHO_MIN_SIZE);
         self.0.extend_from_slice(data.as_ref());
     }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 impl super::Packet for Icmp {
     fn zeroed() -> Self {
-        Self(vec![0; ICMP_ECHO_MIN_SIZE])
+        Self(PacketBuf::new(
+            TRANSPORT_HEADROOM,
+            ICMP_ECHO_MIN_SIZE,
+            DEFAULT_TAILROOM,
+        ))
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
         if bytes.len() < ICMP_ECHO_MIN_SIZE {
             return Err(());
         }
@@ -143,7 +313,7 @@ impl super::Packet for Icmp {
         Ok(Self(bytes))
     }
 
-    fn into_bytes(self) -> Vec<u8> {
+    fn into_buf(self) -> PacketBuf {
         self.0
     }
 }
@@ -0,0 +1,192 @@
+use super::buf::DEFAULT_TAILROOM;
+use super::buf::TRANSPORT_HEADROOM;
+use super::ipaddr::Ipv4Addr;
+use super::Packet;
+use super::PacketBuf;
+use crate::prelude::*;
+use core::convert::TryInto;
+use core::ops::RangeInclusive;
+
+/// Size of IGMPv1/v2 messages, IGMPv3 queries are at least 12 bytes long.
+const IGMP_MIN_SIZE: usize = 8;
+const IGMP_TYPE: usize = 0;
+const IGMP_MAX_RESP: usize = 1;
+const IGMP_CSUM: RangeInclusive<usize> = 2..=3;
+const IGMP_GROUP: RangeInclusive<usize> = 4..=7;
+/// Number of group records in a IGMPv3 report.
+const IGMPV3_RECORDS: RangeInclusive<usize> = 6..=7;
+/// Size of a IGMPv3 group record without any sources.
+const IGMPV3_RECORD_LEN: usize = 8;
+
+/// Type of a IGMP message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IgmpType {
+    MembershipQuery,
+    V1MembershipReport,
+    V2MembershipReport,
+    LeaveGroup,
+    V3MembershipReport,
+    Unknown(u8),
+}
+
+impl IgmpType {
+    pub fn raw(self) -> u8 {
+        match self {
+            Self::MembershipQuery => 0x11,
+            Self::V1MembershipReport => 0x12,
+            Self::V2MembershipReport => 0x16,
+            Self::LeaveGroup => 0x17,
+            Self::V3MembershipReport => 0x22,
+            Self::Unknown(x) => x,
+        }
+    }
+}
+
+impl From<u8> for IgmpType {
+    fn from(i: u8) -> Self {
+        match i {
+            0x11 => Self::MembershipQuery,
+            0x12 => Self::V1MembershipReport,
+            0x16 => Self::V2MembershipReport,
+            0x17 => Self::LeaveGroup,
+            0x22 => Self::V3MembershipReport,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+/// Type of a group record in a IGMPv3 report.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IgmpRecordType {
+    ModeIsInclude,
+    ModeIsExclude,
+    ChangeToInclude,
+    ChangeToExclude,
+}
+
+impl IgmpRecordType {
+    pub fn raw(self) -> u8 {
+        match self {
+            Self::ModeIsInclude => 1,
+            Self::ModeIsExclude => 2,
+            Self::ChangeToInclude => 3,
+            Self::ChangeToExclude => 4,
+        }
+    }
+}
+
+/// Our basic IGMP packet struct, covers v1/v2 messages and v3 queries and reports.
+#[derive(Clone)]
+pub struct Igmp(PacketBuf);
+
+impl Igmp {
+    /// Function builds a IGMPv3 report holding a record of `record_type` without any sources for
+    /// each of `groups`.
+    pub fn v3_report(record_type: IgmpRecordType, groups: &[Ipv4Addr]) -> Self {
+        let mut this = Self::zeroed();
+        this.set_packet_type(IgmpType::V3MembershipReport);
+        this.0[IGMPV3_RECORDS].copy_from_slice(&(groups.len() as u16).to_be_bytes());
+
+        for group in groups {
+            let mut record = [0; IGMPV3_RECORD_LEN];
+            record[0] = record_type.raw();
+            record[4..8].copy_from_slice(group.as_ref());
+            this.0.extend_from_slice(&record);
+        }
+
+        this.set_checksum();
+        this
+    }
+
+    pub fn packet_type(&self) -> IgmpType {
+        self.0[IGMP_TYPE].into()
+    }
+
+    /// Returns the maximum time (ms) a host can wait before answering a query.
+    pub fn max_resp_time(&self) -> u64 {
+        let code = self.0[IGMP_MAX_RESP] as u64;
+
+        // IGMPv3 queries encode big values as a float, RFC 3376 4.1.1.
+        if self.is_v3_query() && code >= 128 {
+            let mant = code & 0x0f;
+            let exp = (code >> 4) & 0x07;
+            return ((mant | 0x10) << (exp + 3)) * 100;
+        }
+
+        code * 100
+    }
+
+    /// Returns whether this is a IGMPv3 query, these are longer than the older ones.
+    pub fn is_v3_query(&self) -> bool {
+        self.packet_type() == IgmpType::MembershipQuery && self.0.len() >= 12
+    }
+
+    /// Returns whether this is a IGMPv1 query, which doesnt carry a max response time.
+    pub fn is_v1_query(&self) -> bool {
+        self.packet_type() == IgmpType::MembershipQuery
+            && self.0.len() == IGMP_MIN_SIZE
+            && self.0[IGMP_MAX_RESP] == 0
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.0[IGMP_CSUM]
+                .try_into()
+                .expect("net: igmp got no checksum"),
+        )
+    }
+
+    /// Returns the group field, unspecified for general queries and v3 reports.
+    pub fn group(&self) -> Ipv4Addr {
+        self.0[IGMP_GROUP]
+            .try_into()
+            .expect("net: igmp got no group")
+    }
+
+    pub fn set_packet_type(&mut self, packet_type: IgmpType) {
+        self.0[IGMP_TYPE] = packet_type.raw();
+    }
+
+    pub fn set_group(&mut self, group: Ipv4Addr) {
+        self.0[IGMP_GROUP].copy_from_slice(group.as_ref());
+    }
+
+    pub fn set_checksum(&mut self) {
+        self.0[IGMP_CSUM].copy_from_slice(&0u16.to_le_bytes());
+
+        let csum = super::ipv4::u32_to_u16(super::ipv4::checksum(&self.0));
+        self.0[IGMP_CSUM].copy_from_slice(&csum.to_le_bytes());
+    }
+
+    /// Returns whether the checksum covering the whole message is correct.
+    pub fn verify_checksum(&self) -> bool {
+        super::ipv4::u32_to_u16(super::ipv4::checksum(&self.0)) == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl super::Packet for Igmp {
+    fn zeroed() -> Self {
+        // igmp goes out with the router alert option, which needs room too.
+        Self(PacketBuf::new(
+            TRANSPORT_HEADROOM + 4,
+            IGMP_MIN_SIZE,
+            DEFAULT_TAILROOM,
+        ))
+    }
+
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
+        if bytes.len() < IGMP_MIN_SIZE {
+            return Err(());
+        }
+
+        Ok(Self(bytes))
+    }
+
+    fn into_buf(self) -> PacketBuf {
+        self.0
+    }
+}
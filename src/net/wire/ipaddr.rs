@@ -1,5 +1,6 @@
 use core::array::TryFromSliceError;
 use core::convert::{AsRef, From, TryFrom, TryInto};
+use core::str::FromStr;
 
 /// Struct represents a IP version 4 address
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -9,18 +10,127 @@ pub struct Ipv4Addr {
 }
 
 impl Ipv4Addr {
+    /// The address of the loopback interface, `127.0.0.1`.
+    pub const LOCALHOST: Self = Self {
+        inner: [127, 0, 0, 1],
+    };
+
+    /// The unspecified address `0.0.0.0`, binding to it means binding to all of our addresses.
+    pub const UNSPECIFIED: Self = Self { inner: [0; 4] };
+
+    /// The limited broadcast address, `255.255.255.255`.
+    pub const BROADCAST: Self = Self { inner: [255; 4] };
+
     /// Method constructs a new IP from the given levels.
-    pub fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
         Self {
             inner: [a, b, c, d],
         }
     }
 
+    /// Returns whether this address is part of `127.0.0.0/8`.
+    pub fn is_loopback(&self) -> bool {
+        self.inner[0] == 127
+    }
+
+    /// Returns whether this is `0.0.0.0`.
+    pub fn is_unspecified(&self) -> bool {
+        self.inner == [0; 4]
+    }
+
+    /// Returns whether this address is part of one of the private ranges of RFC 1918,
+    /// `10.0.0.0/8`, `172.16.0.0/12` and `192.168.0.0/16`.
+    pub fn is_private(&self) -> bool {
+        match self.inner {
+            [10, ..] => true,
+            [172, b, ..] => b & 0xf0 == 16,
+            [192, 168, ..] => true,
+            _ => false,
+        }
+    }
+
+    /// Returns whether this address is part of `169.254.0.0/16`.
+    pub fn is_link_local(&self) -> bool {
+        self.inner[0] == 169 && self.inner[1] == 254
+    }
+
+    /// Returns whether this address is part of `224.0.0.0/4`.
+    pub fn is_multicast(&self) -> bool {
+        self.inner[0] & 0xf0 == 224
+    }
+
+    /// Returns whether this is the limited broadcast address, `255.255.255.255`.
+    pub fn is_broadcast(&self) -> bool {
+        self.inner == [255; 4]
+    }
+
+    /// Returns the four bytes making up this address.
+    pub fn octets(&self) -> [u8; 4] {
+        self.inner
+    }
+
+    /// Returns the address as a number in host byte order, use `u32::from` for the numeric value
+    /// of the address.
     pub fn raw(&self) -> u32 {
         unsafe { core::mem::transmute::<[u8; 4], u32>(self.inner) }
     }
 }
 
+impl From<u32> for Ipv4Addr {
+    fn from(data: u32) -> Self {
+        Self {
+            inner: data.to_be_bytes(),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for u32 {
+    fn from(ip: Ipv4Addr) -> Self {
+        u32::from_be_bytes(ip.inner)
+    }
+}
+
+/// Error returned when parsing a address fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AddrParseError(pub(crate) ());
+
+impl core::fmt::Display for AddrParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "invalid address syntax")
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = AddrParseError;
+
+    /// Parses a address in dotted decimal notation, like `10.0.0.1`. Octets with leading zeros
+    /// are rejected as they are ambiguous.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut inner = [0u8; 4];
+        let mut parts = s.split('.');
+
+        for octet in inner.iter_mut() {
+            let part = parts.next().ok_or(AddrParseError(()))?;
+
+            let valid = !part.is_empty()
+                && part.len() <= 3
+                && part.bytes().all(|x| x.is_ascii_digit())
+                && !(part.len() > 1 && part.starts_with('0'));
+            if !valid {
+                return Err(AddrParseError(()));
+            }
+
+            *octet = part.parse().map_err(|_| AddrParseError(()))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(AddrParseError(()));
+        }
+
+        Ok(Self { inner })
+    }
+}
+
 impl TryFrom<&[u8]> for Ipv4Addr {
     type Error = TryFromSliceError;
 
@@ -1,4 +1,7 @@
+use crate::net::frames::buf::DEFAULT_TAILROOM;
+use crate::net::frames::buf::ETH2_HEADER_LEN;
 use crate::net::frames::ipaddr::Ipv4Addr;
+use crate::net::frames::PacketBuf;
 use crate::prelude::*;
 use core::convert::From;
 use core::convert::Into;
@@ -20,13 +23,15 @@ const IPV4_PROTO_OFFSET: usize = 9;
 const IPV4_CHECKSUM_OFFSET: RangeInclusive<usize> = 10..=11;
 const IPV4_SIP_OFFSET: RangeInclusive<usize> = 12..=15;
 const IPV4_DIP_OFFSET: RangeInclusive<usize> = 16..=19;
-const IPV4_HEADER_OFFSET: RangeInclusive<usize> = 0..=19;
-const IPV4_DATA_OFFSET: usize = 20;
 
-#[derive(Clone, Copy, Debug)]
+/// Router alert option (RFC 2113), asks routers to look at the packet even if it isnt for them.
+pub const IPV4_ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Ipv4Proto {
     ICMP = 0x01,
+    IGMP = 0x02,
     TCP = 0x06,
     UDP = 0x11,
     Unknown,
@@ -40,7 +45,13 @@ impl Ipv4Proto {
 
 impl From<u8> for Ipv4Proto {
     fn from(i: u8) -> Self {
-        unsafe { transmute::<u8, Ipv4Proto>(i) }
+        match i {
+            0x01 => Self::ICMP,
+            0x02 => Self::IGMP,
+            0x06 => Self::TCP,
+            0x11 => Self::UDP,
+            _ => Self::Unknown,
+        }
     }
 }
 
@@ -48,9 +59,52 @@ impl From<u8> for Ipv4Proto {
 /// TODO: Use enums where possible
 /// TODO: Unit tests
 #[derive(Clone, Debug)]
-pub struct Ipv4(Vec<u8>);
+pub struct Ipv4(PacketBuf);
 
 impl Ipv4 {
+    /// Wraps `payload` in a IPv4 header with our defaults set, the header is written into the
+    /// headroom of `payload` so the payload itself isnt copied.
+    pub fn encapsulate(payload: PacketBuf) -> Self {
+        Self::encapsulate_with_options(payload, &[])
+    }
+
+    /// Wraps `payload` like `encapsulate`, with `options` after the header. The options have to
+    /// be padded to a multiple of 4 bytes.
+    pub fn encapsulate_with_options(mut payload: PacketBuf, options: &[u8]) -> Self {
+        assert!(
+            options.len() % 4 == 0 && options.len() <= 40,
+            "net: bad ipv4 options"
+        );
+
+        let data_len = payload.len();
+        payload.prepend(options.len()).copy_from_slice(options);
+        payload.prepend(IPV4_MIN_VALID_LENGTH);
+
+        let mut new_v4 = Self(payload);
+        new_v4.set_version(4);
+        new_v4.set_hdr_len(((IPV4_MIN_VALID_LENGTH + options.len()) / 4) as u8);
+        new_v4.set_flags(0x40);
+        new_v4.set_ttl(64);
+        new_v4.set_proto(Ipv4Proto::ICMP);
+        new_v4.set_sip(Ipv4Addr::new(127, 0, 0, 1));
+        new_v4.set_dip(Ipv4Addr::new(127, 0, 0, 1));
+        new_v4.set_len(data_len as u16);
+
+        new_v4
+    }
+
+    /// Splits the packet into a copy of its header and its payload. Ethernet padding past the
+    /// end of the datagram is trimmed from the payload.
+    pub fn split(mut self) -> (Self, PacketBuf) {
+        let hdr_len = self.header_end();
+        let data_len = self.data().len();
+
+        let header = self.0.split_header(hdr_len);
+        self.0.truncate(data_len);
+
+        (Self(header), self.0)
+    }
+
     pub fn set_version(&mut self, version: u8) {
         self.0[IPV4_VERSION_OFFSET] = version << 4 | (self.0[IPV4_HDR_LEN_OFFSET] & 0x0f);
     }
@@ -77,20 +131,44 @@ impl Ipv4 {
     }
 
     pub fn set_offset(&mut self, offset: u16) {
-        let value = ((self.flags() as u16) << 8) | offset;
+        let value = ((self.flags() as u16) << 13) | (offset & 0x1fff);
         self.0[IPV4_OFFSET_OFFSET].copy_from_slice(&value.to_be_bytes());
     }
 
+    /// Sets or clears the dont fragment flag, routers drop packets that have it set and dont fit
+    /// the next hop instead of fragmenting them.
+    pub fn set_dont_fragment(&mut self, df: bool) {
+        if df {
+            self.0[IPV4_FLAGS_OFFSET] |= 0x40;
+        } else {
+            self.0[IPV4_FLAGS_OFFSET] &= !0x40;
+        }
+    }
+
+    /// Sets or clears the more fragments flag.
+    pub fn set_more_fragments(&mut self, mf: bool) {
+        if mf {
+            self.0[IPV4_FLAGS_OFFSET] |= 0x20;
+        } else {
+            self.0[IPV4_FLAGS_OFFSET] &= !0x20;
+        }
+    }
+
     pub fn set_ttl(&mut self, ttl: u8) {
         self.0[IPV4_TTL_OFFSET] = ttl;
     }
 
     pub fn set_proto(&mut self, proto: Ipv4Proto) {
-        self.0[IPV4_PROTO_OFFSET] = proto.raw();
+        self.set_raw_proto(proto.raw());
+    }
+
+    pub fn set_raw_proto(&mut self, proto: u8) {
+        self.0[IPV4_PROTO_OFFSET] = proto;
     }
 
     pub fn set_checksum(&mut self) {
-        let csum = u32_to_u16(checksum(&self.0[IPV4_HEADER_OFFSET]));
+        self.0[IPV4_CHECKSUM_OFFSET].copy_from_slice(&[0, 0]);
+        let csum = u32_to_u16(checksum(self.header()));
         self.0[IPV4_CHECKSUM_OFFSET].copy_from_slice(&csum.to_ne_bytes());
     }
 
@@ -104,7 +182,7 @@ impl Ipv4 {
 
     pub fn set_data<T: AsRef<[u8]>>(&mut self, data: T) {
         let data_len = data.as_ref().len();
-        self.0.truncate(IPV4_MIN_VALID_LENGTH);
+        self.0.truncate(self.header_end());
         self.0.extend_from_slice(data.as_ref());
         self.set_len(data_len as u16)
     }
@@ -141,6 +219,10 @@ impl Ipv4 {
         self.0[IPV4_FLAGS_OFFSET] >> 5
     }
 
+    pub fn dont_fragment(&self) -> bool {
+        self.0[IPV4_FLAGS_OFFSET] & 0x40 != 0
+    }
+
     pub fn offset(&self) -> u16 {
         u16::from_be_bytes(
             self.0[IPV4_OFFSET_OFFSET]
@@ -154,7 +236,12 @@ impl Ipv4 {
     }
 
     pub fn proto(&self) -> Ipv4Proto {
-        self.0[IPV4_PROTO_OFFSET].into()
+        self.raw_proto().into()
+    }
+
+    /// Returns the protocol number of the packet, for protocols `Ipv4Proto` doesnt know about.
+    pub fn raw_proto(&self) -> u8 {
+        self.0[IPV4_PROTO_OFFSET]
     }
 
     pub fn checksum(&self) -> u16 {
@@ -178,12 +265,28 @@ impl Ipv4 {
     }
 
     pub fn data(&self) -> &[u8] {
-        let data_len = (self.len() - self.hdr_len()) as usize;
-        &self.0[IPV4_DATA_OFFSET..IPV4_DATA_OFFSET + data_len]
+        let start = self.header_end();
+        let data_len = self.len().saturating_sub(self.hdr_len()) as usize;
+        let end = (start + data_len).min(self.0.len());
+        self.0.get(start..end).unwrap_or(&[])
     }
 
+    /// Returns whether the header checksum is correct, options included.
+    pub fn verify_checksum(&self) -> bool {
+        u32_to_u16(checksum(self.header())) == 0
+    }
+
+    /// Returns the header, options included.
     pub fn header(&self) -> &[u8] {
-        &self.0[IPV4_HEADER_OFFSET]
+        &self.0[..self.header_end()]
+    }
+
+    /// Returns where the header ends, packets whose header length is out of bounds are treated
+    /// as having none or as being all header.
+    fn header_end(&self) -> usize {
+        (self.hdr_len() as usize)
+            .max(IPV4_MIN_VALID_LENGTH)
+            .min(self.0.len())
     }
 
     pub fn as_bytes(&self) -> &[u8] {
@@ -193,30 +296,24 @@ impl Ipv4 {
 
 impl super::Packet for Ipv4 {
     fn zeroed() -> Self {
-        let mut new_v4 = Self(vec![0; IPV4_MIN_VALID_LENGTH]);
-        new_v4.set_version(4);
-        new_v4.set_hdr_len(5);
-        new_v4.set_flags(0x40);
-        new_v4.set_ttl(64);
-        new_v4.set_proto(Ipv4Proto::ICMP);
-        new_v4.set_sip(Ipv4Addr::new(127, 0, 0, 1));
-        new_v4.set_dip(Ipv4Addr::new(127, 0, 0, 1));
-
-        new_v4
+        Self::encapsulate(PacketBuf::new(ETH2_HEADER_LEN, 0, DEFAULT_TAILROOM))
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
         if bytes.len() < IPV4_MIN_VALID_LENGTH {
             return Err(());
         }
 
-        let mut this = Self(bytes);
-        this.0[IPV4_CHECKSUM_OFFSET].copy_from_slice(&[0, 0]);
+        let this = Self(bytes);
+        let hdr_len = this.hdr_len() as usize;
+        if hdr_len < IPV4_MIN_VALID_LENGTH || hdr_len > this.0.len() {
+            return Err(());
+        }
 
         Ok(this)
     }
 
-    fn into_bytes(self) -> Vec<u8> {
+    fn into_buf(self) -> PacketBuf {
         self.0
     }
 }
@@ -1,3 +1,4 @@
+use super::ipaddr::Ipv4Addr;
 use core::convert::{AsRef, From, TryInto};
 use core::hash::{Hash, Hasher};
 
@@ -9,11 +10,37 @@ pub struct Mac {
 }
 
 impl Mac {
-    pub fn multicast() -> Self {
+    /// Returns the mac that frames sent to the multicast group `ip` are addressed to, the low 23
+    /// bits of the group are mapped into `01:00:5e:00:00:00` (RFC 1112 6.4).
+    pub fn multicast(ip: Ipv4Addr) -> Self {
+        let ip = ip.octets();
+
+        Self {
+            inner: [0x01, 0x00, 0x5e, ip[1] & 0x7f, ip[2], ip[3]],
+        }
+    }
+
+    /// Returns the broadcast address `ff:ff:ff:ff:ff:ff`.
+    pub fn broadcast() -> Self {
         Self {
             inner: [0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
         }
     }
+
+    /// Returns the all-zero address, used as the target of arp requests.
+    pub fn zeroed() -> Self {
+        Self { inner: [0; 6] }
+    }
+
+    /// Returns whether this is the broadcast address.
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::broadcast()
+    }
+
+    /// Returns whether this is a group address, which includes the broadcast address.
+    pub fn is_multicast(&self) -> bool {
+        self.inner[0] & 0x01 != 0
+    }
 }
 
 impl From<&[u8]> for Mac {
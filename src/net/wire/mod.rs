@@ -1,9 +1,13 @@
 /// Holds our ARP packet structure and parser.
 pub mod arp;
+/// Holds our packet buffer with headroom for headers.
+pub mod buf;
 /// Holds our Ethernet II packet structure and parser.
 pub mod eth2;
 /// Holds our ICMP packet structure and parser.
 pub mod icmp;
+/// Holds our IGMP packet structure and parser.
+pub mod igmp;
 /// Holds our IpAddr structure and parser.
 pub mod ipaddr;
 /// Holds our IPv4 packet structure and parser.
@@ -12,7 +16,10 @@ pub mod ipv4;
 pub mod mac;
 /// Holds our TCP packet structures.
 pub mod tcp;
-// pub mod udp;
+/// Holds our UDP datagram structure and parser.
+pub mod udp;
+
+pub use buf::PacketBuf;
 
 use crate::prelude::Vec;
 
@@ -20,13 +27,24 @@ use crate::prelude::Vec;
 pub trait Packet: Sized {
     /// Create a new packet that is zeroed out.
     fn zeroed() -> Self;
-    /// Parse a stream of bytes and construct a packet.
+    /// Parse a packet out of a buffer without copying it.
     /// Ideally this methid should return `Err(())` if
     /// the packet is corrupted or invalid in any form.
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()>;
+    fn from_buf(buf: PacketBuf) -> Result<Self, ()>;
+    /// Converts this packet into its buffer, any headroom left in front of it can be used by
+    /// lower layers to prepend their headers.
+    fn into_buf(self) -> PacketBuf;
+
+    /// Parse a stream of bytes and construct a packet.
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+        Self::from_buf(bytes.into())
+    }
+
     /// Converts this packet into a vector of bytes that are ready to be merged to the data section
     /// of other packets or ready to be sent down to the network driver.
-    fn into_bytes(self) -> Vec<u8>;
+    fn into_bytes(self) -> Vec<u8> {
+        self.into_buf().into_vec()
+    }
 }
 
 impl Packet for () {
@@ -34,11 +52,11 @@ impl Packet for () {
         ()
     }
 
-    fn from_bytes(_: Vec<u8>) -> Result<(), ()> {
+    fn from_buf(_: PacketBuf) -> Result<(), ()> {
         Err(())
     }
 
-    fn into_bytes(self) -> Vec<u8> {
-        Vec::new()
+    fn into_buf(self) -> PacketBuf {
+        Vec::new().into()
     }
 }
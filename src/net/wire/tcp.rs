@@ -1,4 +1,7 @@
+use super::buf::DEFAULT_TAILROOM;
+use super::buf::TRANSPORT_HEADROOM;
 use super::ipaddr::Ipv4Addr;
+use super::PacketBuf;
 use crate::prelude::*;
 use core::convert::TryInto;
 use core::ops::RangeFrom;
@@ -16,6 +19,8 @@ const TCP_CSUM: RangeInclusive<usize> = 16..=17;
 const TCP_URGENT_PTR: RangeInclusive<usize> = 18..=19;
 const TCP_OPTIONS: RangeInclusive<usize> = 20..=22;
 const TCP_DATA: RangeFrom<usize> = 20..;
+/// Kind of the maximum segment size option.
+const TCP_OPTION_MSS: u8 = 2;
 
 #[derive(Debug)]
 pub enum TcpStates {
@@ -56,7 +61,7 @@ impl Into<u8> for TcpFlag {
 }
 
 #[derive(Clone)]
-pub struct Tcp(Vec<u8>);
+pub struct Tcp(PacketBuf);
 
 impl Tcp {
     pub fn src(&self) -> u16 {
@@ -212,6 +217,15 @@ impl Tcp {
         self.0[TCP_CSUM].copy_from_slice(&super::ipv4::u32_to_u16(sum).to_ne_bytes());
     }
 
+    /// Returns whether the checksum is correct, the pseudo header is built from `src` and `dst`.
+    pub fn verify_checksum(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        let len = (self.0.len() as u16).to_be_bytes();
+        let pseudo = [src.as_ref(), dst.as_ref(), &[0, 0x06], &len].concat();
+        let sum = super::ipv4::checksum(&pseudo) + super::ipv4::checksum(self.0.as_ref());
+
+        super::ipv4::u32_to_u16(sum) == 0
+    }
+
     pub fn dlen(&self) -> usize {
         let tcp_data_offset = self.hlen() as usize;
         self.0[tcp_data_offset..].len()
@@ -226,6 +240,46 @@ impl Tcp {
         self.0.extend_from_slice(item.as_slice());
     }
 
+    /// Returns the maximum segment size option if the segment carries one.
+    pub fn mss(&self) -> Option<u16> {
+        let end = (self.hlen() as usize).min(self.0.len());
+        let mut options = self.0.get(TCP_MIN_LEN..end)?;
+
+        while let Some(&kind) = options.first() {
+            match kind {
+                // end of option list
+                0 => break,
+                // no-op, used for padding
+                1 => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+
+                    if kind == TCP_OPTION_MSS && len == 4 {
+                        return Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+
+                    options = &options[len..];
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Adds the maximum segment size option to the segment, only valid on segments without any
+    /// data or other options.
+    pub fn set_mss(&mut self, mss: u16) {
+        let [hi, lo] = mss.to_be_bytes();
+
+        self.0.truncate(TCP_MIN_LEN);
+        self.0.extend_from_slice(&[TCP_OPTION_MSS, 4, hi, lo]);
+        let words = ((TCP_MIN_LEN + 4) / 4) as u8;
+        self.0[TCP_DATA_OFFSET] = (self.0[TCP_DATA_OFFSET] & 0x0f) | (words << 4);
+    }
+
     pub fn set_hlen(&mut self, len: u8) {
         self.0[TCP_DATA_OFFSET] |= (len / 4) << 4
     }
@@ -245,10 +299,10 @@ impl Tcp {
 
 impl super::Packet for Tcp {
     fn zeroed() -> Self {
-        Self(vec![0; TCP_MIN_LEN])
+        Self(PacketBuf::new(TRANSPORT_HEADROOM, TCP_MIN_LEN, DEFAULT_TAILROOM))
     }
 
-    fn from_bytes(bytes: Vec<u8>) -> Result<Self, ()> {
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
         if bytes.len() < TCP_MIN_LEN {
             return Err(());
         }
@@ -256,7 +310,7 @@ impl super::Packet for Tcp {
         Ok(Self(bytes))
     }
 
-    fn into_bytes(self) -> Vec<u8> {
+    fn into_buf(self) -> PacketBuf {
         self.0
     }
 }
@@ -0,0 +1,140 @@
+use super::buf::DEFAULT_TAILROOM;
+use super::buf::TRANSPORT_HEADROOM;
+use super::ipaddr::Ipv4Addr;
+use super::PacketBuf;
+use crate::prelude::*;
+use core::convert::TryInto;
+use core::ops::RangeFrom;
+use core::ops::RangeInclusive;
+
+const UDP_HEADER_LEN: usize = 8;
+const UDP_SRC_PORT: RangeInclusive<usize> = 0..=1;
+const UDP_DST_PORT: RangeInclusive<usize> = 2..=3;
+const UDP_LEN: RangeInclusive<usize> = 4..=5;
+const UDP_CSUM: RangeInclusive<usize> = 6..=7;
+const UDP_DATA: RangeFrom<usize> = 8..;
+
+/// Our basic UDP datagram struct.
+#[derive(Clone)]
+pub struct Udp(PacketBuf);
+
+impl Udp {
+    pub fn src(&self) -> u16 {
+        u16::from_be_bytes(
+            self.0[UDP_SRC_PORT]
+                .try_into()
+                .expect("net: udp got null src"),
+        )
+    }
+
+    pub fn set_src(&mut self, src: u16) {
+        self.0[UDP_SRC_PORT].copy_from_slice(&src.to_be_bytes());
+    }
+
+    pub fn dst(&self) -> u16 {
+        u16::from_be_bytes(
+            self.0[UDP_DST_PORT]
+                .try_into()
+                .expect("net: udp got null dst"),
+        )
+    }
+
+    pub fn set_dst(&mut self, dst: u16) {
+        self.0[UDP_DST_PORT].copy_from_slice(&dst.to_be_bytes());
+    }
+
+    /// Returns the length field, which covers both the header and the data.
+    pub fn len(&self) -> u16 {
+        u16::from_be_bytes(self.0[UDP_LEN].try_into().expect("net: udp got null len"))
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.0[UDP_CSUM]
+                .try_into()
+                .expect("net: udp got null checksum"),
+        )
+    }
+
+    /// Returns the data of the datagram, ignoring any padding after it.
+    pub fn data(&self) -> &[u8] {
+        let end = (self.len() as usize).max(UDP_HEADER_LEN).min(self.0.len());
+        &self.0[UDP_HEADER_LEN..end]
+    }
+
+    /// Sets the data of the datagram along with the length field.
+    pub fn set_data<T: AsRef<[u8]>>(&mut self, data: T) {
+        self.0.truncate(UDP_HEADER_LEN);
+        self.0.extend_from_slice(data.as_ref());
+
+        let len = self.0.len() as u16;
+        self.0[UDP_LEN].copy_from_slice(&len.to_be_bytes());
+    }
+
+    fn pseudo_sum(&self, src: Ipv4Addr, dst: Ipv4Addr) -> u32 {
+        let len = (self.0.len() as u16).to_be_bytes();
+        let pseudo = [src.as_ref(), dst.as_ref(), &[0, 0x11], &len].concat();
+
+        super::ipv4::checksum(&pseudo) + super::ipv4::checksum(self.0.as_ref())
+    }
+
+    pub fn set_checksum(&mut self, src: Ipv4Addr, dst: Ipv4Addr) {
+        self.0[UDP_CSUM].copy_from_slice(&0u16.to_be_bytes());
+
+        // a checksum of zero means no checksum was computed, so it is sent as all ones instead.
+        let csum = match super::ipv4::u32_to_u16(self.pseudo_sum(src, dst)) {
+            0 => 0xffff,
+            x => x,
+        };
+
+        self.0[UDP_CSUM].copy_from_slice(&csum.to_ne_bytes());
+    }
+
+    /// Returns whether the checksum is correct, datagrams sent without a checksum always pass.
+    pub fn verify_checksum(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        self.checksum() == 0 || super::ipv4::u32_to_u16(self.pseudo_sum(src, dst)) == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl super::Packet for Udp {
+    fn zeroed() -> Self {
+        let mut this = Self(PacketBuf::new(
+            TRANSPORT_HEADROOM,
+            UDP_HEADER_LEN,
+            DEFAULT_TAILROOM,
+        ));
+        this.0[UDP_LEN].copy_from_slice(&(UDP_HEADER_LEN as u16).to_be_bytes());
+
+        this
+    }
+
+    fn from_buf(bytes: PacketBuf) -> Result<Self, ()> {
+        if bytes.len() < UDP_HEADER_LEN {
+            return Err(());
+        }
+
+        Ok(Self(bytes))
+    }
+
+    fn into_buf(self) -> PacketBuf {
+        self.0
+    }
+}
+
+impl core::fmt::Debug for Udp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Udp {{ src: {}, dst: {}, len: {}, csum: {:#x}, data: {:?} }}",
+            self.src(),
+            self.dst(),
+            self.len(),
+            self.checksum(),
+            &self.0[UDP_DATA],
+        )
+    }
+}